@@ -0,0 +1,43 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Passes the git commit and the build time to the compiler
+/// so they can be reported by the running server.
+/// The commit can be set with the GIT_COMMIT env variable
+/// when building outside of the repository.
+/// The gRPC service is generated from its proto file if the grpc feature is enabled.
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        tonic_build::configure()
+            .build_client(false)
+            .compile(&["proto/user_management.proto"], &["proto"])
+            .expect("Failed to compile the gRPC service");
+        println!("cargo:rerun-if-changed=proto/user_management.proto");
+    }
+
+    let commit = std::env::var("GIT_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(&["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    println!(
+        "cargo:rustc-env=BUILD_GIT_COMMIT={}",
+        commit.unwrap_or("unknown".to_string())
+    );
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+}
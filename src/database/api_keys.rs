@@ -0,0 +1,129 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::HashSet;
+
+use crate::database::models::ApiKey;
+use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::utils::error::DBError;
+use crate::utils::{create_secret_token, hash_secret_token};
+
+/// The number of characters of a key that are stored in plain text
+/// so that administrators can tell keys apart
+const KEY_PREFIX_LENGTH: usize = 8;
+const API_KEY_COLUMNS: &str = "id, name, prefix, permissions,
+    to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at,
+    to_char(last_used_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS last_used_at,
+    to_char(expires_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS expires_at";
+
+/// The table that stores the long-lived keys backend services authenticate with.
+/// A key belongs to a service account and is limited to a set of permissions.
+/// Only the hash of a key is stored.
+#[derive(Clone)]
+pub struct ApiKeys {
+    pool: PostgresPool,
+}
+
+impl Table for ApiKeys {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl ApiKeys {
+    /// Creates a key for the user that is limited to the given permissions
+    /// and returns it together with the key. The key can't be retrieved later.
+    /// The time the key expires at needs to be a valid RFC 3339 string.
+    pub fn create_key(
+        &self,
+        user_id: i32,
+        name: String,
+        permissions: Vec<String>,
+        expires_at: Option<String>,
+    ) -> DatabaseResult<(ApiKey, String)> {
+        let mut connection = self.pool.get()?;
+        let existing = connection
+            .query(
+                "SELECT name FROM permissions WHERE name = ANY ($1)",
+                &[&permissions],
+            )?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect::<HashSet<String>>();
+        if let Some(permission) = permissions.iter().find(|p| !existing.contains(*p)) {
+            return Err(DBError::GenericError(format!(
+                "The permission {} doesn't exist",
+                permission
+            )));
+        }
+        let key = create_secret_token();
+        let row = connection.query_one(
+            format!(
+                "INSERT INTO api_keys (key_hash, prefix, user_id, name, permissions, expires_at)
+                VALUES ($1, $2, $3, $4, $5, $6::TEXT::TIMESTAMPTZ) RETURNING {}",
+                API_KEY_COLUMNS
+            )
+            .as_str(),
+            &[
+                &hash_secret_token(&key),
+                &&key[..KEY_PREFIX_LENGTH],
+                &user_id,
+                &name,
+                &permissions,
+                &expires_at,
+            ],
+        )?;
+        log::debug!("Created api key {} for user {}", name, user_id);
+
+        Ok((ApiKey::from_row(row), key))
+    }
+
+    /// Returns all keys of the user
+    pub fn get_keys(&self, user_id: i32) -> DatabaseResult<Vec<ApiKey>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            format!(
+                "SELECT {} FROM api_keys WHERE user_id = $1 ORDER BY created_at",
+                API_KEY_COLUMNS
+            )
+            .as_str(),
+            &[&user_id],
+        )?;
+
+        Ok(rows.into_iter().map(ApiKey::from_row).collect())
+    }
+
+    /// Deletes a key of the user
+    pub fn delete_key(&self, user_id: i32, id: i32) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let deleted = connection.execute(
+            "DELETE FROM api_keys WHERE user_id = $1 AND id = $2",
+            &[&user_id, &id],
+        )?;
+
+        if deleted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the id of the user the key belongs to together with the permissions
+    /// the key is limited to if neither the key nor the user have expired
+    /// and the user is enabled
+    pub fn authenticate(&self, key: &str) -> DatabaseResult<Option<(i32, Vec<String>)>> {
+        let mut connection = self.pool.get()?;
+        let row = connection.query_opt(
+            "UPDATE api_keys SET last_used_at = NOW() FROM users
+            WHERE api_keys.key_hash = $1 AND users.id = api_keys.user_id
+            AND COALESCE(api_keys.expires_at > NOW(), TRUE)
+            AND NOT users.pending_approval AND COALESCE(users.expires_at > NOW(), TRUE)
+            AND users.active AND users.deleted_at IS NULL
+            RETURNING users.id, api_keys.permissions",
+            &[&hash_secret_token(key)],
+        )?;
+
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+}
@@ -0,0 +1,252 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::cell::RefCell;
+
+use postgres::Transaction;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::database::events::{self, Event};
+use crate::database::models::ChangeHistoryEntry;
+use crate::database::partitions;
+use crate::database::{DatabaseResult, PostgresPool, Table};
+
+pub const ENTITY_USER: &str = "user";
+pub const ENTITY_ROLE: &str = "role";
+
+/// The value stored instead of secrets like passwords
+const REDACTED: &str = "***";
+
+thread_local! {
+    static ACTOR: RefCell<Option<String>> = const { RefCell::new(None) };
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Forgets the actor and request id of the current thread when dropped
+struct ActorScope;
+
+impl Drop for ActorScope {
+    fn drop(&mut self) {
+        ACTOR.with(|actor| *actor.borrow_mut() = None);
+        REQUEST_ID.with(|id| *id.borrow_mut() = None);
+    }
+}
+
+/// Runs the given closure and forgets the actor and request id
+/// set while running it afterwards, even if the closure panics
+pub fn with_actor_scope<T, F: FnOnce() -> T>(func: F) -> T {
+    let _scope = ActorScope;
+    func()
+}
+
+/// Sets the id of the request or trace that caused all changes
+/// made by the current thread until the actor scope ends
+pub fn set_request_id(request_id: &str) {
+    REQUEST_ID.with(|id| *id.borrow_mut() = Some(request_id.to_string()));
+}
+
+/// Sets the email of the user that is responsible for all changes
+/// made by the current thread until the actor scope ends
+pub fn set_actor(email: &str) {
+    ACTOR.with(|actor| *actor.borrow_mut() = Some(email.to_string()));
+}
+
+fn current_actor() -> Option<String> {
+    ACTOR.with(|actor| actor.borrow().clone())
+}
+
+/// Creates the partitioned change history table. A change history that was
+/// created before the table was partitioned is moved into monthly partitions.
+pub(crate) fn create_partitioned_table(transaction: &mut Transaction) -> DatabaseResult<()> {
+    let partitioned: Option<bool> = transaction
+        .query_one(
+            "SELECT (SELECT relkind = 'p' FROM pg_class WHERE oid = to_regclass('change_history'))",
+            &[],
+        )?
+        .get(0);
+    let migrate = partitioned == Some(false);
+    if migrate {
+        log::info!("Moving the change history into a partitioned table");
+        transaction.batch_execute(
+            "ALTER TABLE change_history ADD COLUMN IF NOT EXISTS request_id VARCHAR(64);
+            ALTER TABLE change_history RENAME TO change_history_unpartitioned;
+            ALTER TABLE change_history_unpartitioned RENAME CONSTRAINT change_history_pkey TO change_history_unpartitioned_pkey;
+            ALTER SEQUENCE change_history_id_seq RENAME TO change_history_unpartitioned_id_seq;
+            DROP INDEX IF EXISTS change_history_entity_idx;",
+        )?;
+    }
+    transaction.batch_execute(
+        "CREATE TABLE IF NOT EXISTS change_history (
+                    id              SERIAL,
+                    entity_type     VARCHAR(32) NOT NULL,
+                    entity_id       INT NOT NULL,
+                    entity_name     VARCHAR(255) NOT NULL,
+                    changed_by      VARCHAR(255),
+                    changed_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                    changes         JSONB NOT NULL,
+                    request_id      VARCHAR(64),
+                    PRIMARY KEY (id, changed_at)
+                ) PARTITION BY RANGE (changed_at);
+                CREATE INDEX IF NOT EXISTS change_history_entity_idx ON change_history (entity_type, entity_id);",
+    )?;
+    if migrate {
+        let months = transaction.query(
+            "SELECT DISTINCT (EXTRACT(YEAR FROM changed_at AT TIME ZONE 'UTC') * 12
+                + EXTRACT(MONTH FROM changed_at AT TIME ZONE 'UTC') - 1)::INT
+            FROM change_history_unpartitioned",
+            &[],
+        )?;
+        for row in months {
+            partitions::create_partition(transaction, "change_history", row.get(0))?;
+        }
+        transaction.batch_execute(
+            "INSERT INTO change_history (id, entity_type, entity_id, entity_name, changed_by, changed_at, changes, request_id)
+            SELECT id, entity_type, entity_id, entity_name, changed_by, changed_at, changes, request_id
+            FROM change_history_unpartitioned;
+            SELECT setval(pg_get_serial_sequence('change_history', 'id'), COALESCE(MAX(id), 0) + 1, FALSE)
+            FROM change_history;
+            DROP TABLE change_history_unpartitioned;",
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The table that stores the before and after values of
+/// all changes made to users and roles
+#[derive(Clone)]
+pub struct ChangeHistory {
+    pool: PostgresPool,
+}
+
+/// The fields that were changed by a single update
+#[derive(Default)]
+pub struct Changes {
+    fields: Map<String, Value>,
+}
+
+impl Table for ChangeHistory {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl ChangeHistory {
+    /// Returns all changes of an entity starting with the latest one
+    pub fn get_history(
+        &self,
+        entity_type: &str,
+        entity_id: i32,
+    ) -> DatabaseResult<Vec<ChangeHistoryEntry>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            "SELECT id, entity_name, changed_by, request_id,
+                to_char(changed_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS changed_at,
+                changes
+            FROM change_history WHERE entity_type = $1 AND entity_id = $2
+            ORDER BY change_history.changed_at DESC, id DESC",
+            &[&entity_type, &entity_id],
+        )?;
+
+        Ok(rows.into_iter().map(ChangeHistoryEntry::from_row).collect())
+    }
+}
+
+impl Changes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the field if the value changed
+    pub fn field<T: Serialize + ?Sized>(mut self, name: &str, before: &T, after: &T) -> Self {
+        let before = serde_json::to_value(before).unwrap_or(Value::Null);
+        let after = serde_json::to_value(after).unwrap_or(Value::Null);
+        if before != after {
+            self.fields
+                .insert(name.to_string(), json!({"before": before, "after": after}));
+        }
+
+        self
+    }
+
+    /// Adds a field of a created entity
+    pub fn created<T: Serialize + ?Sized>(self, name: &str, value: &T) -> Self {
+        self.field(
+            name,
+            &Value::Null,
+            &serde_json::to_value(value).unwrap_or(Value::Null),
+        )
+    }
+
+    /// Adds a field of a deleted entity
+    pub fn deleted<T: Serialize + ?Sized>(self, name: &str, value: &T) -> Self {
+        self.field(
+            name,
+            &serde_json::to_value(value).unwrap_or(Value::Null),
+            &Value::Null,
+        )
+    }
+
+    /// Adds a secret field without storing its values
+    pub fn secret(mut self, name: &str, changed: bool) -> Self {
+        if changed {
+            self.fields.insert(
+                name.to_string(),
+                json!({"before": REDACTED, "after": REDACTED}),
+            );
+        }
+
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Stores the changes of an entity as part of the given transaction
+/// so that they are only recorded if the change is committed.
+/// A change event is added to the event outbox as well.
+pub fn record(
+    transaction: &mut Transaction,
+    entity_type: &str,
+    entity_id: i32,
+    entity_name: &str,
+    changes: Changes,
+) -> DatabaseResult<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let changed_by = current_actor();
+    let request_id = REQUEST_ID.with(|id| id.borrow().clone());
+    let changes = Value::Object(changes.fields);
+    transaction.execute(
+        "INSERT INTO change_history (entity_type, entity_id, entity_name, changed_by, changes, request_id) VALUES ($1, $2, $3, $4, $5, $6)",
+        &[
+            &entity_type,
+            &entity_id,
+            &entity_name,
+            &changed_by,
+            &changes,
+            &request_id,
+        ],
+    )?;
+    let event = if entity_type == ENTITY_ROLE {
+        Event::RoleChanged {
+            name: entity_name.to_string(),
+            changed_by,
+            changes,
+        }
+    } else {
+        Event::UserChanged {
+            email: entity_name.to_string(),
+            changed_by,
+            changes,
+        }
+    };
+    events::enqueue(transaction, event)?;
+
+    Ok(())
+}
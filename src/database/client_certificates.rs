@@ -0,0 +1,124 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use crate::database::models::ClientCertificate;
+use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::utils::error::DBError;
+
+const CERTIFICATE_COLUMNS: &str = "fingerprint, description,
+    to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at,
+    to_char(last_used_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS last_used_at";
+
+/// The table that maps the SHA-256 fingerprints of client certificates
+/// to the service accounts that authenticate with them
+#[derive(Clone)]
+pub struct ClientCertificates {
+    pool: PostgresPool,
+}
+
+impl Table for ClientCertificates {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl ClientCertificates {
+    /// Maps a certificate fingerprint to the user
+    pub fn add_certificate(
+        &self,
+        user_id: i32,
+        fingerprint: &str,
+        description: Option<String>,
+    ) -> DatabaseResult<ClientCertificate> {
+        let fingerprint = normalize_fingerprint(fingerprint).ok_or(DBError::GenericError(
+            "The fingerprint needs to be a hex encoded SHA-256 hash".to_string(),
+        ))?;
+        let mut connection = self.pool.get()?;
+        let row = connection
+            .query_opt(
+                format!(
+                    "INSERT INTO client_certificates (fingerprint, user_id, description) VALUES ($1, $2, $3)
+                    ON CONFLICT DO NOTHING RETURNING {}",
+                    CERTIFICATE_COLUMNS
+                )
+                .as_str(),
+                &[&fingerprint, &user_id, &description],
+            )?
+            .ok_or(DBError::RecordExists)?;
+        log::debug!(
+            "Mapped client certificate {} to user {}",
+            fingerprint,
+            user_id
+        );
+
+        Ok(ClientCertificate::from_row(row))
+    }
+
+    /// Returns all certificates that are mapped to the user
+    pub fn get_certificates(&self, user_id: i32) -> DatabaseResult<Vec<ClientCertificate>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            format!(
+                "SELECT {} FROM client_certificates WHERE user_id = $1 ORDER BY created_at",
+                CERTIFICATE_COLUMNS
+            )
+            .as_str(),
+            &[&user_id],
+        )?;
+
+        Ok(rows.into_iter().map(ClientCertificate::from_row).collect())
+    }
+
+    /// Removes the mapping of a certificate of the user
+    pub fn delete_certificate(&self, user_id: i32, fingerprint: &str) -> DatabaseResult<()> {
+        let fingerprint = normalize_fingerprint(fingerprint).ok_or(DBError::RecordDoesNotExist)?;
+        let mut connection = self.pool.get()?;
+        let deleted = connection.execute(
+            "DELETE FROM client_certificates WHERE user_id = $1 AND fingerprint = $2",
+            &[&user_id, &fingerprint],
+        )?;
+
+        if deleted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the id of the user the certificate is mapped to
+    /// if the user is approved, enabled and hasn't expired
+    pub fn get_user_id(&self, fingerprint: &str) -> DatabaseResult<Option<i32>> {
+        let fingerprint = match normalize_fingerprint(fingerprint) {
+            Some(fingerprint) => fingerprint,
+            None => return Ok(None),
+        };
+        let mut connection = self.pool.get()?;
+        let row = connection.query_opt(
+            "UPDATE client_certificates SET last_used_at = NOW() FROM users
+            WHERE client_certificates.fingerprint = $1 AND users.id = client_certificates.user_id
+            AND NOT users.pending_approval AND COALESCE(users.expires_at > NOW(), TRUE)
+            AND users.active AND users.deleted_at IS NULL
+            RETURNING users.id",
+            &[&fingerprint],
+        )?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+}
+
+/// Returns the lowercase hex representation of a SHA-256 fingerprint
+/// that can be written with colons or in upper case
+pub fn normalize_fingerprint(fingerprint: &str) -> Option<String> {
+    let fingerprint = fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    if fingerprint.len() == 64 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(fingerprint)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,200 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use crate::database::change_history::{self, Changes, ENTITY_USER};
+use crate::database::events::{self, Event};
+use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::utils::error::DBError;
+use crate::utils::{create_secret_token, hash_secret_token};
+
+const CONFIRMATION_HOURS: i32 = 24;
+const UNDO_DAYS: i32 = 7;
+
+/// The table that stores requested email changes until they are
+/// confirmed by the new address or undone by the old one
+#[derive(Clone)]
+pub struct EmailChanges {
+    pool: PostgresPool,
+}
+
+/// The result of confirming or undoing an email change
+pub struct AppliedEmailChange {
+    pub user_id: i32,
+    pub old_email: String,
+    pub new_email: String,
+}
+
+impl Table for EmailChanges {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl EmailChanges {
+    /// Requests an email change for a user. The change is applied when the
+    /// link sent to the new address is confirmed. The old address is notified
+    /// with a link to undo the change.
+    pub fn request_change(
+        &self,
+        user_id: i32,
+        old_email: &String,
+        new_email: &String,
+    ) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        if connection
+            .query_opt("SELECT id FROM users WHERE email = $1", &[new_email])?
+            .is_some()
+        {
+            return Err(DBError::GenericError(format!(
+                "A user for the email {} already exists!",
+                new_email
+            )));
+        }
+        let confirm_token = create_secret_token();
+        let undo_token = create_secret_token();
+        let mut transaction = connection.transaction()?;
+        transaction.execute(
+            "DELETE FROM email_changes WHERE user_id = $1 AND confirmed_at IS NULL",
+            &[&user_id],
+        )?;
+        transaction.execute(
+            "INSERT INTO email_changes (user_id, old_email, new_email, confirm_token, undo_token) VALUES ($1, $2, $3, $4, $5)",
+            &[
+                &user_id,
+                old_email,
+                new_email,
+                &hash_secret_token(&confirm_token),
+                &hash_secret_token(&undo_token),
+            ],
+        )?;
+        events::enqueue(
+            &mut transaction,
+            Event::EmailChangeRequested {
+                email: new_email.clone(),
+                old_email: old_email.clone(),
+                confirm_token,
+            },
+        )?;
+        events::enqueue(
+            &mut transaction,
+            Event::EmailChangeNotice {
+                email: old_email.clone(),
+                new_email: new_email.clone(),
+                undo_token,
+            },
+        )?;
+        transaction.commit()?;
+        log::debug!("Requested email change for user {}", user_id);
+
+        Ok(())
+    }
+
+    /// Applies the email change that belongs to the confirmation token
+    pub fn confirm_change(&self, token: &str) -> DatabaseResult<AppliedEmailChange> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "UPDATE email_changes SET confirmed_at = NOW()
+                WHERE confirm_token = $1 AND confirmed_at IS NULL AND undone_at IS NULL
+                AND created_at > NOW() - make_interval(hours => $2)
+                RETURNING user_id, old_email, new_email",
+                &[&hash_secret_token(token), &CONFIRMATION_HOURS],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let change = AppliedEmailChange {
+            user_id: row.get(0),
+            old_email: row.get(1),
+            new_email: row.get(2),
+        };
+        if transaction
+            .query_opt(
+                "SELECT id FROM users WHERE email = $1",
+                &[&change.new_email],
+            )?
+            .is_some()
+        {
+            return Err(DBError::GenericError(format!(
+                "A user for the email {} already exists!",
+                change.new_email
+            )));
+        }
+        let updated = transaction.execute(
+            "UPDATE users SET email = $2 WHERE id = $1 AND email = $3",
+            &[&change.user_id, &change.new_email, &change.old_email],
+        )?;
+        if updated == 0 {
+            return Err(DBError::GenericError(
+                "The email of the user changed since the change was requested".to_string(),
+            ));
+        }
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            change.user_id,
+            &change.new_email,
+            Changes::new().field("email", &change.old_email, &change.new_email),
+        )?;
+        events::enqueue(
+            &mut transaction,
+            Event::EmailChanged {
+                email: change.new_email.clone(),
+                old_email: change.old_email.clone(),
+            },
+        )?;
+        transaction.commit()?;
+
+        Ok(change)
+    }
+
+    /// Undoes an email change with the token sent to the old address.
+    /// If the change was already confirmed the old address is restored.
+    pub fn undo_change(&self, token: &str) -> DatabaseResult<AppliedEmailChange> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "UPDATE email_changes SET undone_at = NOW()
+                WHERE undo_token = $1 AND undone_at IS NULL
+                AND created_at > NOW() - make_interval(days => $2)
+                RETURNING user_id, old_email, new_email, confirmed_at IS NOT NULL",
+                &[&hash_secret_token(token), &UNDO_DAYS],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let change = AppliedEmailChange {
+            user_id: row.get(0),
+            old_email: row.get(1),
+            new_email: row.get(2),
+        };
+        let confirmed: bool = row.get(3);
+        if confirmed {
+            let updated = transaction.execute(
+                "UPDATE users SET email = $2 WHERE id = $1 AND email = $3",
+                &[&change.user_id, &change.old_email, &change.new_email],
+            )?;
+            if updated == 0 {
+                return Err(DBError::GenericError(
+                    "The email of the user changed since the change was confirmed".to_string(),
+                ));
+            }
+            change_history::record(
+                &mut transaction,
+                ENTITY_USER,
+                change.user_id,
+                &change.old_email,
+                Changes::new().field("email", &change.new_email, &change.old_email),
+            )?;
+        }
+        events::enqueue(
+            &mut transaction,
+            Event::EmailChangeUndone {
+                email: change.old_email.clone(),
+                new_email: change.new_email.clone(),
+            },
+        )?;
+        transaction.commit()?;
+
+        Ok(change)
+    }
+}
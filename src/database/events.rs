@@ -0,0 +1,276 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::process::Command;
+use std::thread::{self, Builder};
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use postgres::Transaction;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::utils::config;
+
+pub(crate) const ENV_NOTIFICATION_COMMAND: &str = "NOTIFICATION_COMMAND";
+pub(crate) const ENV_OUTBOX_POLL_INTERVAL: &str = "OUTBOX_POLL_INTERVAL_MS";
+pub(crate) const ENV_OUTBOX_MAX_ATTEMPTS: &str = "OUTBOX_MAX_ATTEMPTS";
+pub(crate) const DEFAULT_OUTBOX_POLL_INTERVAL: u64 = 1000;
+pub(crate) const DEFAULT_OUTBOX_MAX_ATTEMPTS: i32 = 20;
+const OUTBOX_BATCH_SIZE: i64 = 100;
+/// The time in seconds a claimed event is reserved for the relay that claimed it.
+/// Events of a relay that stopped while delivering them are claimed again afterwards.
+const OUTBOX_LEASE_SECONDS: f64 = 300.0;
+
+type EventHook = Box<dyn Fn(&Event) -> Result<(), String> + Send + Sync>;
+
+lazy_static::lazy_static! {
+    static ref EVENT_HOOKS: RwLock<Vec<EventHook>> = RwLock::new(Vec::new());
+    static ref NOTIFICATION_COMMAND: Option<String> = config::var(ENV_NOTIFICATION_COMMAND).ok();
+}
+
+/// Events that other parts of the system can be notified about
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    ApprovalRequested {
+        email: String,
+        name: String,
+    },
+    UserApproved {
+        email: String,
+        approved_by: String,
+    },
+    UserRejected {
+        email: String,
+        rejected_by: String,
+    },
+    EmailChangeRequested {
+        email: String,
+        old_email: String,
+        confirm_token: String,
+    },
+    EmailChangeNotice {
+        email: String,
+        new_email: String,
+        undo_token: String,
+    },
+    EmailChanged {
+        email: String,
+        old_email: String,
+    },
+    EmailChangeUndone {
+        email: String,
+        new_email: String,
+    },
+    UserChanged {
+        email: String,
+        changed_by: Option<String>,
+        changes: Value,
+    },
+    RoleChanged {
+        name: String,
+        changed_by: Option<String>,
+        changes: Value,
+    },
+    PermissionsCreated {
+        permissions: Vec<String>,
+    },
+    AccountExpiring {
+        email: String,
+        expires_at: String,
+    },
+    AccountExpired {
+        email: String,
+    },
+    VerificationRequested {
+        email: String,
+        name: String,
+        verification_token: String,
+    },
+    InvitationCreated {
+        email: String,
+        name: String,
+        invited_by: String,
+        invitation_token: String,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ApprovalRequested { .. } => "approval_requested",
+            Event::UserApproved { .. } => "user_approved",
+            Event::UserRejected { .. } => "user_rejected",
+            Event::EmailChangeRequested { .. } => "email_change_requested",
+            Event::EmailChangeNotice { .. } => "email_change_notice",
+            Event::EmailChanged { .. } => "email_changed",
+            Event::EmailChangeUndone { .. } => "email_change_undone",
+            Event::UserChanged { .. } => "user_changed",
+            Event::RoleChanged { .. } => "role_changed",
+            Event::PermissionsCreated { .. } => "permissions_created",
+            Event::AccountExpiring { .. } => "account_expiring",
+            Event::AccountExpired { .. } => "account_expired",
+            Event::VerificationRequested { .. } => "verification_requested",
+            Event::InvitationCreated { .. } => "invitation_created",
+        }
+    }
+}
+
+/// Registers a hook that is called for every delivered event.
+/// If a hook returns an error the event is delivered to all hooks again later.
+pub fn register_hook<F: Fn(&Event) -> Result<(), String> + Send + Sync + 'static>(hook: F) {
+    EVENT_HOOKS.write().push(Box::new(hook));
+}
+
+/// Stores the event in the outbox as part of the given transaction.
+/// The event is only delivered if the transaction is committed.
+pub fn enqueue(transaction: &mut Transaction, event: Event) -> DatabaseResult<()> {
+    log::debug!("Enqueueing event {}", event.name());
+    transaction.execute(
+        "INSERT INTO event_outbox (name, event) VALUES ($1, $2)",
+        &[
+            &event.name(),
+            &serde_json::to_value(&event).map_err(|e| e.to_string())?,
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// The outbox table that stores events until they were delivered
+/// to all hooks and the configured notification command.
+/// Events that still fail after the maximum number of attempts are kept as failed.
+/// Undelivered events can contain tokens that are sent to users.
+#[derive(Clone)]
+pub struct EventOutbox {
+    pool: PostgresPool,
+}
+
+impl Table for EventOutbox {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl EventOutbox {
+    /// Starts the relay thread that delivers the events of the outbox
+    pub fn start_relay(&self) {
+        let outbox = EventOutbox::clone(self);
+        let interval = Duration::from_millis(
+            config::var(ENV_OUTBOX_POLL_INTERVAL)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(DEFAULT_OUTBOX_POLL_INTERVAL),
+        );
+        let max_attempts = config::var(ENV_OUTBOX_MAX_ATTEMPTS)
+            .ok()
+            .and_then(|v| v.parse::<i32>().ok())
+            .unwrap_or(DEFAULT_OUTBOX_MAX_ATTEMPTS)
+            .max(1);
+
+        Builder::new()
+            .name("event-relay".to_string())
+            .spawn(move || loop {
+                match outbox.relay_batch(max_attempts) {
+                    Ok(count) if count as i64 >= OUTBOX_BATCH_SIZE => continue,
+                    Ok(_) => {}
+                    Err(e) => log::error!("Failed to relay events: {}", e),
+                }
+                thread::sleep(interval);
+            })
+            .unwrap();
+    }
+
+    /// Delivers the next batch of available events and returns the number of
+    /// handled events. The events are claimed before they are delivered so that
+    /// no rows stay locked while the hooks and the notification command run.
+    /// Delivered events are removed from the outbox, failed ones are retried later
+    /// with an increasing delay until the maximum number of attempts is reached.
+    fn relay_batch(&self, max_attempts: i32) -> DatabaseResult<usize> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            "UPDATE event_outbox SET status = 'delivering', attempts = attempts + 1,
+            available_at = NOW() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM event_outbox
+                WHERE status <> 'failed' AND available_at <= NOW()
+                ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, event, attempts",
+            &[&OUTBOX_BATCH_SIZE, &OUTBOX_LEASE_SECONDS],
+        )?;
+        let count = rows.len();
+
+        for row in rows {
+            let id: i64 = row.get(0);
+            let attempts: i32 = row.get(2);
+            let result = serde_json::from_value::<Event>(row.get(1))
+                .map_err(|e| format!("Invalid event: {}", e))
+                .and_then(|event| deliver(&event));
+
+            match result {
+                Ok(_) => {
+                    connection.execute("DELETE FROM event_outbox WHERE id = $1", &[&id])?;
+                }
+                Err(e) if attempts >= max_attempts => {
+                    log::error!(
+                        "Giving up on event {} after {} attempts: {}",
+                        id,
+                        attempts,
+                        e
+                    );
+                    connection.execute(
+                        "UPDATE event_outbox SET status = 'failed', last_error = $2 WHERE id = $1",
+                        &[&id, &e],
+                    )?;
+                }
+                Err(e) => {
+                    log::warn!("Failed to deliver event {}: {}", id, e);
+                    connection.execute(
+                        "UPDATE event_outbox SET status = 'pending', last_error = $2,
+                        available_at = NOW() + LEAST(attempts, 60) * INTERVAL '10 seconds'
+                        WHERE id = $1",
+                        &[&id, &e],
+                    )?;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+/// Passes the event to all registered hooks and the configured notification command
+fn deliver(event: &Event) -> Result<(), String> {
+    log::debug!("Delivering event {}", event.name());
+    for hook in EVENT_HOOKS.read().iter() {
+        hook(event)?;
+    }
+    if let Some(command) = NOTIFICATION_COMMAND.as_ref() {
+        run_notification_command(command, event)?;
+    }
+
+    Ok(())
+}
+
+/// Runs the notification command with the event passed in
+/// the FLOTTE_EVENT and FLOTTE_EVENT_DATA env variables
+fn run_notification_command(command: &str, event: &Event) -> Result<(), String> {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("FLOTTE_EVENT", event.name())
+        .env("FLOTTE_EVENT_DATA", data)
+        .status()
+        .map_err(|e| format!("Failed to run notification command: {}", e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Notification command failed with {}", status))
+    }
+}
@@ -0,0 +1,311 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::{HashMap, HashSet};
+use std::thread::{self, Builder};
+use std::time::Duration;
+
+use ldap3::{LdapConn, Scope, SearchEntry};
+
+use crate::database::user_roles::UserRoles;
+use crate::database::users::Users;
+use crate::database::{Database, DatabaseResult};
+use crate::utils::config;
+
+pub(crate) const ENV_LDAP_URL: &str = "LDAP_URL";
+pub(crate) const ENV_LDAP_BIND_DN: &str = "LDAP_BIND_DN";
+pub(crate) const ENV_LDAP_BIND_PASSWORD: &str = "LDAP_BIND_PASSWORD";
+pub(crate) const ENV_LDAP_USER_BASE_DN: &str = "LDAP_USER_BASE_DN";
+pub(crate) const ENV_LDAP_USER_FILTER: &str = "LDAP_USER_FILTER";
+pub(crate) const ENV_LDAP_EMAIL_ATTRIBUTE: &str = "LDAP_EMAIL_ATTRIBUTE";
+pub(crate) const ENV_LDAP_NAME_ATTRIBUTE: &str = "LDAP_NAME_ATTRIBUTE";
+pub(crate) const ENV_LDAP_GROUP_BASE_DN: &str = "LDAP_GROUP_BASE_DN";
+pub(crate) const ENV_LDAP_GROUP_FILTER: &str = "LDAP_GROUP_FILTER";
+pub(crate) const ENV_LDAP_GROUP_MEMBER_ATTRIBUTE: &str = "LDAP_GROUP_MEMBER_ATTRIBUTE";
+pub(crate) const ENV_LDAP_GROUP_ROLES: &str = "LDAP_GROUP_ROLES";
+pub(crate) const ENV_LDAP_SYNC_INTERVAL: &str = "LDAP_SYNC_INTERVAL";
+pub(crate) const DEFAULT_LDAP_USER_FILTER: &str = "(objectClass=inetOrgPerson)";
+pub(crate) const DEFAULT_LDAP_EMAIL_ATTRIBUTE: &str = "mail";
+pub(crate) const DEFAULT_LDAP_NAME_ATTRIBUTE: &str = "cn";
+pub(crate) const DEFAULT_LDAP_GROUP_FILTER: &str = "(objectClass=groupOfNames)";
+pub(crate) const DEFAULT_LDAP_GROUP_MEMBER_ATTRIBUTE: &str = "member";
+pub(crate) const DEFAULT_LDAP_SYNC_INTERVAL: u64 = 15 * 60;
+
+/// The connection and mapping settings of the directory the users are imported from
+#[derive(Clone, Debug)]
+pub struct LdapConfig {
+    pub url: String,
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub user_base_dn: String,
+    pub user_filter: String,
+    pub email_attribute: String,
+    pub name_attribute: String,
+    pub group_base_dn: Option<String>,
+    pub group_filter: String,
+    pub group_member_attribute: String,
+    /// The roles that are assigned to the members of a group by the cn of the group
+    pub group_roles: HashMap<String, String>,
+    pub interval: Duration,
+}
+
+impl LdapConfig {
+    /// Reads the settings from the env. Returns None if no LDAP url is configured.
+    pub fn from_env() -> Option<Self> {
+        let url = config::var(ENV_LDAP_URL).ok()?;
+        let user_base_dn = match config::var(ENV_LDAP_USER_BASE_DN) {
+            Ok(dn) => dn,
+            Err(_) => {
+                log::error!(
+                    "{} is set but {} is missing. The LDAP sync is disabled",
+                    ENV_LDAP_URL,
+                    ENV_LDAP_USER_BASE_DN
+                );
+                return None;
+            }
+        };
+        let group_roles = config::var(ENV_LDAP_GROUP_ROLES)
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.splitn(2, ':');
+                let group = parts.next()?.trim();
+                let role = parts.next()?.trim();
+                if group.is_empty() || role.is_empty() {
+                    None
+                } else {
+                    Some((group.to_ascii_lowercase(), role.to_string()))
+                }
+            })
+            .collect();
+
+        Some(Self {
+            url,
+            bind_dn: config::var(ENV_LDAP_BIND_DN).ok(),
+            bind_password: config::var(ENV_LDAP_BIND_PASSWORD).ok(),
+            user_base_dn,
+            user_filter: config::var(ENV_LDAP_USER_FILTER)
+                .unwrap_or(DEFAULT_LDAP_USER_FILTER.to_string()),
+            email_attribute: config::var(ENV_LDAP_EMAIL_ATTRIBUTE)
+                .unwrap_or(DEFAULT_LDAP_EMAIL_ATTRIBUTE.to_string()),
+            name_attribute: config::var(ENV_LDAP_NAME_ATTRIBUTE)
+                .unwrap_or(DEFAULT_LDAP_NAME_ATTRIBUTE.to_string()),
+            group_base_dn: config::var(ENV_LDAP_GROUP_BASE_DN).ok(),
+            group_filter: config::var(ENV_LDAP_GROUP_FILTER)
+                .unwrap_or(DEFAULT_LDAP_GROUP_FILTER.to_string()),
+            group_member_attribute: config::var(ENV_LDAP_GROUP_MEMBER_ATTRIBUTE)
+                .unwrap_or(DEFAULT_LDAP_GROUP_MEMBER_ATTRIBUTE.to_string()),
+            group_roles,
+            interval: Duration::from_secs(
+                config::var(ENV_LDAP_SYNC_INTERVAL)
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_LDAP_SYNC_INTERVAL),
+            ),
+        })
+    }
+}
+
+/// A user entry of the directory
+struct DirectoryUser {
+    dn: String,
+    email: String,
+    name: String,
+}
+
+/// Periodically imports the users of an LDAP directory and assigns roles
+/// based on the groups they are members of. Users whose entries are removed
+/// from the directory are disabled. Roles that aren't mapped to a group
+/// can still be managed by hand.
+#[derive(Clone)]
+pub struct LdapSync {
+    users: Users,
+    user_roles: UserRoles,
+    config: LdapConfig,
+}
+
+impl LdapSync {
+    /// Creates the sync if an LDAP directory is configured
+    pub fn from_env(database: &Database) -> Option<Self> {
+        Some(Self {
+            users: Users::clone(&database.users),
+            user_roles: UserRoles::clone(&database.user_roles),
+            config: LdapConfig::from_env()?,
+        })
+    }
+
+    /// Starts the background job that syncs the users in the configured interval
+    pub fn start_sync_job(&self) {
+        let sync = LdapSync::clone(self);
+
+        Builder::new()
+            .name("ldap-sync".to_string())
+            .spawn(move || loop {
+                if let Err(e) = sync.sync() {
+                    log::error!("Failed to sync the users of the LDAP directory: {}", e);
+                }
+                thread::sleep(sync.config.interval);
+            })
+            .unwrap();
+    }
+
+    /// Imports all users of the directory, updates the roles that are mapped
+    /// to groups and disables the users that were removed from the directory
+    fn sync(&self) -> DatabaseResult<()> {
+        let mut connection = LdapConn::new(&self.config.url)?;
+        if let (Some(dn), Some(password)) = (&self.config.bind_dn, &self.config.bind_password) {
+            connection.simple_bind(dn, password)?.success()?;
+        }
+        let directory_users = self.search_users(&mut connection)?;
+        let group_roles = self.search_group_roles(&mut connection)?;
+        connection.unbind()?;
+
+        let mut synced_dns = Vec::new();
+        for user in directory_users {
+            // users that fail to sync are still part of the directory and stay enabled
+            synced_dns.push(user.dn.clone());
+            let id = match self
+                .users
+                .sync_directory_user(&user.dn, &user.email, &user.name)
+            {
+                Ok(id) => id,
+                Err(e) => {
+                    log::error!("Failed to sync the directory user {}: {}", user.dn, e);
+                    continue;
+                }
+            };
+            let roles = group_roles
+                .get(&user.dn.to_ascii_lowercase())
+                .cloned()
+                .unwrap_or_default();
+            if let Err(e) = self.sync_roles(id, roles) {
+                log::error!("Failed to sync the roles of {}: {}", user.email, e);
+            }
+        }
+        if synced_dns.is_empty() {
+            log::warn!("The LDAP directory returned no users. Not disabling any users");
+            return Ok(());
+        }
+        let disabled = self.users.disable_directory_users(&synced_dns)?;
+        for email in &disabled {
+            log::info!(
+                "Disabled {} because it was removed from the directory",
+                email
+            );
+        }
+        log::debug!(
+            "Synced {} users from the LDAP directory and disabled {}",
+            synced_dns.len(),
+            disabled.len()
+        );
+
+        Ok(())
+    }
+
+    /// Returns all user entries that have an email address
+    fn search_users(&self, connection: &mut LdapConn) -> DatabaseResult<Vec<DirectoryUser>> {
+        let (entries, _) = connection
+            .search(
+                &self.config.user_base_dn,
+                Scope::Subtree,
+                &self.config.user_filter,
+                vec![&self.config.email_attribute, &self.config.name_attribute],
+            )?
+            .success()?;
+
+        Ok(entries
+            .into_iter()
+            .map(SearchEntry::construct)
+            .filter_map(|entry| {
+                let email = first_value(&entry, &self.config.email_attribute)?;
+                let name =
+                    first_value(&entry, &self.config.name_attribute).unwrap_or(email.clone());
+
+                Some(DirectoryUser {
+                    dn: entry.dn,
+                    email,
+                    name,
+                })
+            })
+            .collect())
+    }
+
+    /// Returns the roles of the mapped groups by the lowercase dn of their members
+    fn search_group_roles(
+        &self,
+        connection: &mut LdapConn,
+    ) -> DatabaseResult<HashMap<String, HashSet<String>>> {
+        let mut member_roles: HashMap<String, HashSet<String>> = HashMap::new();
+        let base_dn = match &self.config.group_base_dn {
+            Some(dn) if !self.config.group_roles.is_empty() => dn,
+            _ => return Ok(member_roles),
+        };
+        let (entries, _) = connection
+            .search(
+                base_dn,
+                Scope::Subtree,
+                &self.config.group_filter,
+                vec!["cn", &self.config.group_member_attribute],
+            )?
+            .success()?;
+
+        for entry in entries.into_iter().map(SearchEntry::construct) {
+            let role = match first_value(&entry, "cn")
+                .and_then(|cn| self.config.group_roles.get(&cn.to_ascii_lowercase()))
+            {
+                Some(role) => role,
+                None => continue,
+            };
+            for member in entry
+                .attrs
+                .get(&self.config.group_member_attribute)
+                .into_iter()
+                .flatten()
+            {
+                member_roles
+                    .entry(member.to_ascii_lowercase())
+                    .or_default()
+                    .insert(role.clone());
+            }
+        }
+
+        Ok(member_roles)
+    }
+
+    /// Replaces the roles of the user that are mapped to groups with the given roles
+    fn sync_roles(&self, user_id: i32, group_roles: HashSet<String>) -> DatabaseResult<()> {
+        let current = self
+            .user_roles
+            .by_user(user_id)?
+            .into_iter()
+            .map(|role| role.name)
+            .collect::<HashSet<String>>();
+        let managed = self
+            .config
+            .group_roles
+            .values()
+            .collect::<HashSet<&String>>();
+        let roles = current
+            .iter()
+            .filter(|role| !managed.contains(role))
+            .cloned()
+            .chain(group_roles)
+            .collect::<HashSet<String>>();
+        if roles != current {
+            self.user_roles
+                .update_roles(user_id, roles.into_iter().collect())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn first_value(entry: &SearchEntry, attribute: &str) -> Option<String> {
+    entry
+        .attrs
+        .get(attribute)
+        .and_then(|values| values.first())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
@@ -0,0 +1,111 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::utils::config;
+use crate::utils::error::{DBError, DatabaseResult};
+
+pub(crate) const ENV_MAX_USERS: &str = "MAX_USERS";
+pub(crate) const ENV_MAX_ROLES: &str = "MAX_ROLES";
+pub(crate) const ENV_MAX_SESSIONS_PER_USER: &str = "MAX_SESSIONS_PER_USER";
+pub(crate) const ENV_MAX_ORGANIZATION_MEMBERS: &str = "MAX_ORGANIZATION_MEMBERS";
+
+lazy_static::lazy_static! {
+    static ref LIMITS: ResourceLimits = ResourceLimits::from_env();
+}
+
+/// A resource whose number can be limited
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resource {
+    Users,
+    Roles,
+    SessionsPerUser,
+    OrganizationMembers,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 4] = [
+        Resource::Users,
+        Resource::Roles,
+        Resource::SessionsPerUser,
+        Resource::OrganizationMembers,
+    ];
+
+    /// Returns the name of the resource that is used in errors and metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resource::Users => "users",
+            Resource::Roles => "roles",
+            Resource::SessionsPerUser => "sessions_per_user",
+            Resource::OrganizationMembers => "organization_members",
+        }
+    }
+
+    fn env(&self) -> &'static str {
+        match self {
+            Resource::Users => ENV_MAX_USERS,
+            Resource::Roles => ENV_MAX_ROLES,
+            Resource::SessionsPerUser => ENV_MAX_SESSIONS_PER_USER,
+            Resource::OrganizationMembers => ENV_MAX_ORGANIZATION_MEMBERS,
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// The configured maximum numbers of resources. Users and roles are limited globally,
+/// sessions per user and members per organization. Resources without a
+/// configured limit are unlimited.
+pub struct ResourceLimits {
+    limits: [Option<u64>; 4],
+    rejections: [AtomicU64; 4],
+}
+
+impl ResourceLimits {
+    fn from_env() -> Self {
+        let mut limits = [None; 4];
+        for resource in &Resource::ALL {
+            limits[resource.index()] = config::var(resource.env())
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok());
+        }
+
+        Self {
+            limits,
+            rejections: Default::default(),
+        }
+    }
+
+    /// Returns the limits configured via the env
+    pub fn get() -> &'static Self {
+        &LIMITS
+    }
+
+    /// Returns the limit of the resource if one is configured
+    pub fn limit(&self, resource: Resource) -> Option<u64> {
+        self.limits[resource.index()]
+    }
+
+    /// Returns how often creating the resource was rejected because of the limit
+    pub fn rejections(&self, resource: Resource) -> u64 {
+        self.rejections[resource.index()].load(Ordering::Relaxed)
+    }
+
+    /// Returns an error if another resource can't be created
+    /// because the existing number already reached the limit
+    pub fn check(&self, resource: Resource, existing: u64) -> DatabaseResult<()> {
+        match self.limit(resource) {
+            Some(limit) if existing >= limit => {
+                self.rejections[resource.index()].fetch_add(1, Ordering::Relaxed);
+                log::debug!("The limit of {} {} is reached", limit, resource.name());
+
+                Err(DBError::LimitExceeded(resource, limit))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use crate::database::statement_cache::CachedClient;
+use crate::database::DatabaseResult;
+
+pub const LOGIN_SUCCEEDED: &str = "succeeded";
+pub const LOGIN_INVALID_PASSWORD: &str = "invalid_password";
+pub const LOGIN_UNKNOWN_USER: &str = "unknown_user";
+pub const LOGIN_PENDING_APPROVAL: &str = "pending_approval";
+pub const LOGIN_EXPIRED: &str = "expired";
+pub const LOGIN_UNVERIFIED: &str = "unverified";
+pub const LOGIN_DISABLED: &str = "disabled";
+pub const LOGIN_INVITATION_PENDING: &str = "invitation_pending";
+pub const LOGIN_IMPERSONATED: &str = "impersonated";
+
+/// Records a login attempt in the login_events table. The table is partitioned
+/// by month so that old attempts can be removed cheaply.
+/// The user id is only known if the email belongs to a user.
+pub fn record(
+    client: &mut CachedClient,
+    email: &String,
+    user_id: Option<i32>,
+    outcome: &str,
+) -> DatabaseResult<()> {
+    let statement = client
+        .prepare_cached("INSERT INTO login_events (user_id, email, outcome) VALUES ($1, $2, $3)")?;
+    client.execute(&statement, &[&user_id, email, &outcome])?;
+
+    Ok(())
+}
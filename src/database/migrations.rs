@@ -0,0 +1,150 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+//! The schema of the database is created and updated by the migrations in this module.
+//! Applied migrations are recorded in the schema_migrations table with a checksum
+//! so that every migration runs exactly once and changes to applied migrations are noticed.
+//! New migrations are added to the end of `MIGRATIONS` with the next version.
+
+use postgres::Transaction;
+use sha2::{Digest, Sha256};
+
+use crate::database::{change_history, DatabaseResult, PostgresPool};
+use crate::utils::error::DBError;
+
+/// The key of the advisory lock that keeps multiple instances from migrating at the same time
+const MIGRATION_LOCK_ID: i64 = 0x666c_6f74_7465;
+
+/// A single change to the schema of the database
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    kind: MigrationKind,
+}
+
+enum MigrationKind {
+    Sql(&'static str),
+    /// A migration that depends on the current state of the database
+    Code(fn(&mut Transaction) -> DatabaseResult<()>),
+}
+
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline",
+        kind: MigrationKind::Sql(include_str!("migrations/V1__baseline.sql")),
+    },
+    Migration {
+        version: 2,
+        name: "partitioned_change_history",
+        kind: MigrationKind::Code(change_history::create_partitioned_table),
+    },
+    Migration {
+        version: 3,
+        name: "user_attributes",
+        kind: MigrationKind::Sql(include_str!("migrations/V3__user_attributes.sql")),
+    },
+    Migration {
+        version: 4,
+        name: "event_outbox_status",
+        kind: MigrationKind::Sql(include_str!("migrations/V4__event_outbox_status.sql")),
+    },
+    Migration {
+        version: 5,
+        name: "service_accounts",
+        kind: MigrationKind::Sql(include_str!("migrations/V5__service_accounts.sql")),
+    },
+];
+
+impl Migration {
+    /// Returns the hex encoded SHA-256 of the sql of the migration
+    /// or of its name if the migration is implemented in code
+    pub fn checksum(&self) -> String {
+        let content = match self.kind {
+            MigrationKind::Sql(sql) => sql,
+            MigrationKind::Code(_) => self.name,
+        };
+
+        Sha256::digest(content.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn apply(&self, transaction: &mut Transaction) -> DatabaseResult<()> {
+        match self.kind {
+            MigrationKind::Sql(sql) => transaction.batch_execute(sql).map_err(DBError::from),
+            MigrationKind::Code(func) => func(transaction),
+        }
+    }
+}
+
+/// Returns the version of the newest migration
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Applies all migrations that haven't been applied yet in a single transaction.
+/// Fails without changing anything if the database has a newer schema
+/// than this server knows about or if an applied migration was changed.
+pub fn run(pool: &PostgresPool) -> DatabaseResult<()> {
+    let mut connection = pool.get()?;
+    let mut transaction = connection.transaction()?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_ID])?;
+    transaction.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+                    version         INT PRIMARY KEY,
+                    name            VARCHAR(255) NOT NULL,
+                    checksum        VARCHAR(64) NOT NULL,
+                    applied_at      TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                );",
+    )?;
+    let applied: Vec<(i32, String)> = transaction
+        .query(
+            "SELECT version, checksum FROM schema_migrations ORDER BY version",
+            &[],
+        )?
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+
+    if let Some((version, _)) = applied.last() {
+        if *version > latest_version() {
+            return Err(DBError::GenericError(format!(
+                "The database schema has the version {} but this server only knows the versions up to {}. Update the server before starting it",
+                version,
+                latest_version()
+            )));
+        }
+    }
+    for (version, checksum) in &applied {
+        if let Some(migration) = MIGRATIONS.iter().find(|m| m.version == *version) {
+            if migration.checksum() != *checksum {
+                return Err(DBError::GenericError(format!(
+                    "The migration V{}__{} was changed after it had been applied",
+                    migration.version, migration.name
+                )));
+            }
+        }
+    }
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|m| !applied.iter().any(|(version, _)| *version == m.version))
+    {
+        log::info!(
+            "Applying migration V{}__{}",
+            migration.version,
+            migration.name
+        );
+        migration.apply(&mut transaction)?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)",
+            &[&migration.version, &migration.name, &migration.checksum()],
+        )?;
+    }
+    transaction.commit()?;
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+-- The schema as it was created by the tables before migrations were tracked.
+-- Every statement only creates what's missing so that existing databases
+-- can be adopted by the migrations.
+
+CREATE TABLE IF NOT EXISTS event_outbox (
+    id              BIGSERIAL PRIMARY KEY,
+    name            VARCHAR(64) NOT NULL,
+    event           JSONB NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    available_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    attempts        INT NOT NULL DEFAULT 0,
+    last_error      TEXT
+);
+
+CREATE TABLE IF NOT EXISTS users (
+    id              SERIAL PRIMARY KEY,
+    name            VARCHAR(255) NOT NULL,
+    email           VARCHAR(255) UNIQUE NOT NULL,
+    password_hash   BYTEA NOT NULL,
+    salt            BYTEA NOT NULL
+);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS request_quota INTEGER;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS pending_approval BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS expiry_notified BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS expiry_processed BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS verification_token BYTEA UNIQUE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS verification_sent_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS ldap_dn TEXT UNIQUE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS ldap_disabled BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS must_change_password BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_algorithm VARCHAR(64) NOT NULL DEFAULT 'bcrypt';
+ALTER TABLE users ADD COLUMN IF NOT EXISTS pepper_id VARCHAR(32);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS active BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_login_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_seen_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS invitation_token BYTEA UNIQUE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS invited_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS users_deleted_at_idx ON users (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS users_email_domain_idx ON users (split_part(email, '@', 2));
+DO $$ BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'pg_trgm') THEN
+        CREATE INDEX IF NOT EXISTS users_search_idx ON users USING gin ((name || ' ' || email) gin_trgm_ops);
+    END IF;
+END $$;
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id                  BIGSERIAL PRIMARY KEY,
+    user_id             INT NOT NULL,
+    request_digest      BYTEA UNIQUE NOT NULL,
+    refresh_digest      BYTEA UNIQUE,
+    kind                JSONB NOT NULL,
+    request_expires_at  TIMESTAMPTZ NOT NULL,
+    refresh_expires_at  TIMESTAMPTZ NOT NULL,
+    expires_at          TIMESTAMPTZ NOT NULL,
+    idle_timeout        INT NOT NULL,
+    created_at          TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS sessions_user_idx ON sessions (user_id);
+CREATE INDEX IF NOT EXISTS sessions_expiry_idx ON sessions (refresh_expires_at);
+
+CREATE TABLE IF NOT EXISTS roles (
+    id              SERIAL PRIMARY KEY,
+    name            VARCHAR(128) UNIQUE NOT NULL,
+    description     VARCHAR(512)
+);
+ALTER TABLE roles ADD COLUMN IF NOT EXISTS enabled BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE roles ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS permissions (
+    id              SERIAL PRIMARY KEY,
+    name            VARCHAR(128) UNIQUE NOT NULL,
+    description     VARCHAR(512)
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id         INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role_id         INT NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    PRIMARY KEY  (user_id, role_id)
+);
+CREATE INDEX IF NOT EXISTS user_roles_role_idx ON user_roles (role_id);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role_id         INT NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    permission_id   INT NOT NULL REFERENCES permissions(id) ON DELETE CASCADE,
+    PRIMARY KEY (role_id, permission_id)
+);
+
+CREATE TABLE IF NOT EXISTS user_field_definitions (
+    id              SERIAL PRIMARY KEY,
+    name            VARCHAR(128) UNIQUE NOT NULL,
+    field_type      VARCHAR(32) NOT NULL,
+    required        BOOLEAN NOT NULL DEFAULT FALSE,
+    visibility      VARCHAR(32) NOT NULL DEFAULT 'public'
+);
+
+CREATE TABLE IF NOT EXISTS email_changes (
+    id              SERIAL PRIMARY KEY,
+    user_id         INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    old_email       VARCHAR(255) NOT NULL,
+    new_email       VARCHAR(255) NOT NULL,
+    confirm_token   BYTEA UNIQUE NOT NULL,
+    undo_token      BYTEA UNIQUE NOT NULL,
+    created_at      TIMESTAMP NOT NULL DEFAULT NOW(),
+    confirmed_at    TIMESTAMP,
+    undone_at       TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS organizations (
+    id              SERIAL PRIMARY KEY,
+    name            VARCHAR(128) UNIQUE NOT NULL,
+    description     VARCHAR(512),
+    parent_id       INT REFERENCES organizations(id) ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS organization_members (
+    organization_id INT NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    user_id         INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    PRIMARY KEY (organization_id, user_id)
+);
+CREATE TABLE IF NOT EXISTS organization_roles (
+    organization_id INT NOT NULL,
+    user_id         INT NOT NULL,
+    role_id         INT NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    PRIMARY KEY (organization_id, user_id, role_id),
+    FOREIGN KEY (organization_id, user_id) REFERENCES organization_members(organization_id, user_id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS client_certificates (
+    fingerprint     VARCHAR(64) PRIMARY KEY,
+    user_id         INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    description     VARCHAR(512),
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at    TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS client_certificates_user_idx ON client_certificates (user_id);
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id              SERIAL PRIMARY KEY,
+    key_hash        BYTEA UNIQUE NOT NULL,
+    prefix          VARCHAR(16) NOT NULL,
+    user_id         INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name            VARCHAR(128) NOT NULL,
+    permissions     TEXT[] NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at    TIMESTAMPTZ,
+    expires_at      TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS api_keys_user_idx ON api_keys (user_id);
+
+CREATE TABLE IF NOT EXISTS oauth_clients (
+    id              SERIAL PRIMARY KEY,
+    client_id       VARCHAR(64) UNIQUE NOT NULL,
+    secret_hash     BYTEA NOT NULL,
+    name            VARCHAR(128) NOT NULL,
+    redirect_uris   TEXT[] NOT NULL,
+    scopes          TEXT[] NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE TABLE IF NOT EXISTS oauth_consents (
+    client_id       INT NOT NULL REFERENCES oauth_clients(id) ON DELETE CASCADE,
+    user_id         INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    scopes          TEXT[] NOT NULL,
+    granted_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (client_id, user_id)
+);
+CREATE TABLE IF NOT EXISTS oauth_codes (
+    code            BYTEA PRIMARY KEY,
+    client_id       INT NOT NULL REFERENCES oauth_clients(id) ON DELETE CASCADE,
+    user_id         INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    redirect_uri    TEXT NOT NULL,
+    scopes          TEXT[] NOT NULL,
+    expires_at      TIMESTAMPTZ NOT NULL
+);
+ALTER TABLE oauth_codes ADD COLUMN IF NOT EXISTS nonce TEXT;
+
+CREATE TABLE IF NOT EXISTS login_events (
+    id              BIGSERIAL,
+    user_id         INT,
+    email           VARCHAR(255) NOT NULL,
+    outcome         VARCHAR(32) NOT NULL,
+    occurred_at     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (id, occurred_at)
+) PARTITION BY RANGE (occurred_at);
+CREATE INDEX IF NOT EXISTS login_events_user_idx ON login_events (user_id);
+
+CREATE TABLE IF NOT EXISTS webhooks (
+    id              SERIAL PRIMARY KEY,
+    url             VARCHAR(2048) NOT NULL,
+    secret          VARCHAR(128) NOT NULL,
+    events          TEXT[] NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id                  BIGSERIAL PRIMARY KEY,
+    webhook_id          INT NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+    event               VARCHAR(64) NOT NULL,
+    payload             JSONB NOT NULL,
+    status              VARCHAR(16) NOT NULL DEFAULT 'pending',
+    attempts            INT NOT NULL DEFAULT 0,
+    last_status_code    INT,
+    last_error          TEXT,
+    created_at          TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    available_at        TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    delivered_at        TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_idx
+    ON webhook_deliveries (webhook_id, id);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_pending_idx
+    ON webhook_deliveries (available_at) WHERE status = 'pending';
@@ -0,0 +1,2 @@
+-- Databases created before the custom user attributes were added lack the column
+ALTER TABLE users ADD COLUMN IF NOT EXISTS attributes JSONB NOT NULL DEFAULT '{}';
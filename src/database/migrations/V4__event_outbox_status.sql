@@ -0,0 +1,5 @@
+-- Events are claimed by the relay before they are delivered and
+-- are kept as failed once they ran out of attempts
+ALTER TABLE event_outbox ADD COLUMN IF NOT EXISTS status VARCHAR(16) NOT NULL DEFAULT 'pending';
+CREATE INDEX IF NOT EXISTS event_outbox_available_idx
+    ON event_outbox (available_at) WHERE status <> 'failed';
@@ -0,0 +1,5 @@
+-- Only service accounts get api keys and client certificates of other users.
+-- Accounts that already authenticate with them are service accounts.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS service_account BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE users SET service_account = TRUE
+WHERE id IN (SELECT user_id FROM api_keys UNION SELECT user_id FROM client_certificates);
@@ -2,10 +2,15 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
+use std::str::FromStr;
+
 use postgres::Row;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::database::DatabaseResult;
+use crate::utils::error::{DBError, FieldError};
+
 /// Record to store data in when retrieving rows from the users table
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UserRecord {
@@ -46,17 +51,143 @@ pub struct Role {
     pub id: i32,
     pub name: String,
     pub description: String,
+    pub enabled: bool,
+}
+
+/// A role that was moved to the trash and the time it is purged at
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct TrashedRole {
+    pub name: String,
+    pub description: Option<String>,
+    pub deleted_at: String,
+    pub purge_at: String,
+}
+
+impl TrashedRole {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            name: row.get("name"),
+            description: row.get("description"),
+            deleted_at: row.get("deleted_at"),
+            purge_at: row.get("purge_at"),
+        }
+    }
+}
+
+/// A user with the names of its roles as it is exported
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct UserExport {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub active: bool,
+    pub expires_at: Option<String>,
+    pub last_login_at: Option<String>,
+    pub last_seen_at: Option<String>,
+    pub roles: Vec<String>,
+    pub attributes: Value,
+}
+
+impl UserExport {
+    pub fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            name: row.get("name"),
+            email: row.get("email"),
+            active: row.get("active"),
+            expires_at: row.get("expires_at"),
+            last_login_at: row.get("last_login_at"),
+            last_seen_at: row.get("last_seen_at"),
+            roles: row.get("roles"),
+            attributes: row.get("attributes"),
+        }
+    }
+}
+
+/// A user that was moved to the trash and the time it is purged at
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct TrashedUser {
+    pub name: String,
+    pub email: String,
+    pub deleted_at: String,
+    pub purge_at: String,
+}
+
+impl TrashedUser {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            name: row.get("name"),
+            email: row.get("email"),
+            deleted_at: row.get("deleted_at"),
+            purge_at: row.get("purge_at"),
+        }
+    }
+}
+
+/// A role with the names of its permissions as it is exported and imported
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RoleExport {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub permissions: Vec<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// How roles that already exist are handled when importing roles
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictStrategy {
+    /// The existing role is kept
+    #[default]
+    Skip,
+    /// The existing role is replaced with the imported one
+    Overwrite,
+    /// The imported role is created with a suffix added to its name
+    Rename,
+}
+
+/// A role that was imported with a different name
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RenamedRole {
+    pub from: String,
+    pub to: String,
+}
+
+/// The outcome of a role import
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct RoleImport {
+    pub created: Vec<String>,
+    pub overwritten: Vec<String>,
+    pub skipped: Vec<String>,
+    pub renamed: Vec<RenamedRole>,
 }
 
 /// A CreatePermissionEntry data structure that is used as an argument for the
 /// bulk permission creation function of the Users Model and can directly be deserialized
 /// from the corresponding rcp message.
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct CreatePermissionsEntry {
     pub name: String,
     pub description: String,
 }
 
+/// The outcome of reconciling the permissions of a namespace
+/// with the permission set of a service
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct PermissionSync {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Permissions of the namespace that aren't part of the permission set.
+    /// They are not deleted since they might still be assigned to roles.
+    pub orphaned: Vec<String>,
+}
+
 /// Information about the user that doesn't contain any critical information
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct UserInformation {
@@ -64,6 +195,9 @@ pub struct UserInformation {
     pub name: String,
     pub email: String,
     pub attributes: Value,
+    pub last_login_at: Option<String>,
+    /// The last time the user made an authenticated request
+    pub last_seen_at: Option<String>,
 }
 
 impl UserInformation {
@@ -73,10 +207,321 @@ impl UserInformation {
             name: row.get("name"),
             email: row.get("email"),
             attributes: row.get("attributes"),
+            last_login_at: row.get("last_login_at"),
+            last_seen_at: row.get("last_seen_at"),
+        }
+    }
+}
+
+/// The public information of a user that services need to display it
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ResolvedUser {
+    pub name: String,
+    pub email: String,
+    pub active: bool,
+}
+
+impl ResolvedUser {
+    pub fn from_row(row: &Row) -> Self {
+        Self {
+            name: row.get("name"),
+            email: row.get("email"),
+            active: row.get("active"),
+        }
+    }
+}
+
+/// An organization or a team of an organization if it has a parent
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Organization {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub parent: Option<String>,
+}
+
+impl Organization {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            id: row.get("id"),
+            name: row.get("name"),
+            description: row.get("description"),
+            parent: row.get("parent"),
+        }
+    }
+}
+
+/// A client application that can request access to user accounts
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<String>,
+    pub created_at: String,
+}
+
+impl OAuthClient {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            client_id: row.get("client_id"),
+            name: row.get("name"),
+            redirect_uris: row.get("redirect_uris"),
+            scopes: row.get("scopes"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// The scopes a user granted to a client application
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OAuthConsent {
+    pub client_id: String,
+    pub client_name: String,
+    pub scopes: Vec<String>,
+    pub granted_at: String,
+}
+
+impl OAuthConsent {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            client_id: row.get("client_id"),
+            client_name: row.get("client_name"),
+            scopes: row.get("scopes"),
+            granted_at: row.get("granted_at"),
+        }
+    }
+}
+
+/// A client certificate that authenticates a service account
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ClientCertificate {
+    pub fingerprint: String,
+    pub description: Option<String>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+impl ClientCertificate {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            fingerprint: row.get("fingerprint"),
+            description: row.get("description"),
+            created_at: row.get("created_at"),
+            last_used_at: row.get("last_used_at"),
+        }
+    }
+}
+
+/// A key a backend service authenticates with. The key itself is only returned on creation.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    /// The first characters of the key to tell keys apart
+    pub prefix: String,
+    pub permissions: Vec<String>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub expires_at: Option<String>,
+}
+
+impl ApiKey {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            id: row.get("id"),
+            name: row.get("name"),
+            prefix: row.get("prefix"),
+            permissions: row.get("permissions"),
+            created_at: row.get("created_at"),
+            last_used_at: row.get("last_used_at"),
+            expires_at: row.get("expires_at"),
+        }
+    }
+}
+
+/// A url that is notified about user events. The secret the requests
+/// are signed with is only returned on creation.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub events: Vec<String>,
+    pub created_at: String,
+}
+
+impl Webhook {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            id: row.get("id"),
+            url: row.get("url"),
+            events: row.get("events"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// A delivery of an event to a webhook. The status is pending
+/// until the delivery succeeded or all attempts failed.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i32,
+    pub event: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub delivered_at: Option<String>,
+}
+
+impl WebhookDelivery {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            id: row.get("id"),
+            webhook_id: row.get("webhook_id"),
+            event: row.get("event"),
+            status: row.get("status"),
+            attempts: row.get("attempts"),
+            last_status_code: row.get("last_status_code"),
+            last_error: row.get("last_error"),
+            created_at: row.get("created_at"),
+            delivered_at: row.get("delivered_at"),
+        }
+    }
+}
+
+/// A member of an organization with the names of the roles
+/// the member has inside the organization
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OrganizationMember {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub roles: Vec<String>,
+}
+
+impl OrganizationMember {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            id: row.get("id"),
+            name: row.get("name"),
+            email: row.get("email"),
+            roles: row.get("roles"),
+        }
+    }
+}
+
+/// A recorded change of a user or role with the before
+/// and after values of all changed fields
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ChangeHistoryEntry {
+    pub id: i32,
+    pub entity_name: String,
+    pub changed_by: Option<String>,
+    pub changed_at: String,
+    pub changes: Value,
+    pub request_id: Option<String>,
+}
+
+impl ChangeHistoryEntry {
+    pub fn from_row(row: Row) -> Self {
+        Self {
+            id: row.get("id"),
+            entity_name: row.get("entity_name"),
+            changed_by: row.get("changed_by"),
+            changed_at: row.get("changed_at"),
+            changes: row.get("changes"),
+            request_id: row.get("request_id"),
+        }
+    }
+}
+
+/// The outcome of merging a duplicate account into a primary one
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct UserMerge {
+    pub user: UserInformation,
+    pub merged_email: String,
+    pub added_roles: Vec<String>,
+    pub added_attributes: Vec<String>,
+    pub moved_records: u64,
+}
+
+/// The part of a list that is requested. Without a limit all entries
+/// after the offset are returned.
+#[derive(Clone, Debug, Default)]
+pub struct Pagination {
+    pub limit: Option<i64>,
+    pub offset: i64,
+}
+
+/// The direction a list is sorted in
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(format!("Unknown sort order {}", s)),
         }
     }
 }
 
+/// The field a list is sorted by. Lists are sorted by id if no field is given.
+#[derive(Clone, Debug, Default)]
+pub struct Sorting {
+    pub field: Option<String>,
+    pub order: SortOrder,
+}
+
+impl Sorting {
+    /// Builds the ORDER BY clause of a query. Only the given columns can be
+    /// sorted by. Entries with the same value are sorted by id.
+    pub fn order_by(&self, columns: &[&str]) -> DatabaseResult<String> {
+        let direction = self.order.as_str();
+        match self.field.as_deref() {
+            None | Some("id") => Ok(format!("ORDER BY id {}", direction)),
+            Some(field) => columns
+                .iter()
+                .find(|column| **column == field)
+                .map(|column| format!("ORDER BY {} {}, id {}", column, direction, direction))
+                .ok_or(DBError::ValidationError(vec![FieldError::InvalidValue(
+                    "sort".to_string(),
+                )])),
+        }
+    }
+}
+
+/// The filters of the user list. All given filters need to match.
+#[derive(Clone, Debug, Default)]
+pub struct UserFilter {
+    /// Text that is contained in the name or email of the user
+    pub search: Option<String>,
+    /// The name of a role the user is assigned to
+    pub role: Option<String>,
+    pub email_domain: Option<String>,
+    /// Only users that haven't logged in or made a request since this time
+    pub inactive_since: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct UserFullInformation {
     pub id: i32,
@@ -84,6 +529,11 @@ pub struct UserFullInformation {
     pub email: String,
     pub attributes: Value,
     pub roles: Vec<Role>,
+    pub expires_at: Option<String>,
+    /// Disabled users can't log in
+    pub active: bool,
+    pub last_login_at: Option<String>,
+    pub last_seen_at: Option<String>,
 }
 
 impl From<UserRecord> for UserInformation {
@@ -93,6 +543,100 @@ impl From<UserRecord> for UserInformation {
             name: record.name,
             email: record.email,
             attributes: record.attributes,
+            last_login_at: None,
+            last_seen_at: None,
+        }
+    }
+}
+
+/// The type of a custom user field that values are validated against
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    Number,
+    Boolean,
+    Date,
+}
+
+impl FieldType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::String => "string",
+            FieldType::Number => "number",
+            FieldType::Boolean => "boolean",
+            FieldType::Date => "date",
+        }
+    }
+}
+
+impl FromStr for FieldType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(FieldType::String),
+            "number" => Ok(FieldType::Number),
+            "boolean" => Ok(FieldType::Boolean),
+            "date" => Ok(FieldType::Date),
+            _ => Err(format!("Unknown field type {}", s)),
+        }
+    }
+}
+
+/// Who is able to see the value of a custom user field.
+/// Private fields are only visible to the user itself and users
+/// that are allowed to update other users.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldVisibility {
+    Public,
+    Private,
+}
+
+impl FieldVisibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldVisibility::Public => "public",
+            FieldVisibility::Private => "private",
+        }
+    }
+}
+
+impl FromStr for FieldVisibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(FieldVisibility::Public),
+            "private" => Ok(FieldVisibility::Private),
+            _ => Err(format!("Unknown field visibility {}", s)),
+        }
+    }
+}
+
+/// A row of the user_field_definitions table that describes
+/// a custom field stored in the users attributes
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct UserFieldDefinition {
+    pub id: i32,
+    pub name: String,
+    pub field_type: FieldType,
+    pub required: bool,
+    pub visibility: FieldVisibility,
+}
+
+impl UserFieldDefinition {
+    pub fn from_row(row: Row) -> Self {
+        let field_type: String = row.get("field_type");
+        let visibility: String = row.get("visibility");
+
+        Self {
+            id: row.get("id"),
+            name: row.get("name"),
+            field_type: field_type.parse().unwrap_or(FieldType::String),
+            required: row.get("required"),
+            visibility: visibility.parse().unwrap_or(FieldVisibility::Private),
         }
     }
 }
@@ -0,0 +1,298 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::HashSet;
+
+use crate::database::models::{OAuthClient, OAuthConsent};
+use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::utils::error::DBError;
+use crate::utils::{constant_time_eq, create_secret_token, hash_secret_token};
+
+const AUTHORIZATION_CODE_SECONDS: i32 = 60;
+
+/// The OpenID Connect scope that requests an id token and access to the
+/// userinfo endpoint. Every client can request it in addition to its permissions.
+pub const OPENID_SCOPE: &str = "openid";
+const CLIENT_COLUMNS: &str = "client_id, name, redirect_uris, scopes,
+    to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at";
+
+/// The tables that store the client applications that can request access
+/// to user accounts with the authorization code flow, the scopes users
+/// consented to and the issued authorization codes.
+/// The scopes of a client are the names of the permissions it can be granted.
+#[derive(Clone)]
+pub struct OAuthClients {
+    pool: PostgresPool,
+}
+
+/// The user and scopes an authorization code was issued for
+pub struct AuthorizationGrant {
+    pub user_id: i32,
+    pub scopes: Vec<String>,
+    /// The nonce the client sent with the authorization request
+    /// that needs to be included in the id token
+    pub nonce: Option<String>,
+}
+
+impl Table for OAuthClients {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl OAuthClients {
+    /// Registers a new client and returns it together with its secret.
+    /// The secret is only stored as a hash and can't be retrieved later.
+    pub fn create_client(
+        &self,
+        name: String,
+        redirect_uris: Vec<String>,
+        scopes: Vec<String>,
+    ) -> DatabaseResult<(OAuthClient, String)> {
+        if redirect_uris.is_empty() {
+            return Err(DBError::GenericError(
+                "At least one redirect uri is required".to_string(),
+            ));
+        }
+        let mut connection = self.pool.get()?;
+        let existing = connection
+            .query(
+                "SELECT name FROM permissions WHERE name = ANY ($1)",
+                &[&scopes],
+            )?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect::<HashSet<String>>();
+        if let Some(scope) = scopes.iter().find(|s| !existing.contains(*s)) {
+            return Err(DBError::GenericError(format!(
+                "The scope {} isn't a permission",
+                scope
+            )));
+        }
+        let client_id = create_secret_token();
+        let secret = create_secret_token();
+        let row = connection.query_one(
+            format!(
+                "INSERT INTO oauth_clients (client_id, secret_hash, name, redirect_uris, scopes)
+                VALUES ($1, $2, $3, $4, $5) RETURNING {}",
+                CLIENT_COLUMNS
+            )
+            .as_str(),
+            &[
+                &client_id,
+                &hash_secret_token(&secret),
+                &name,
+                &redirect_uris,
+                &scopes,
+            ],
+        )?;
+        log::debug!("Registered oauth client {} ({})", name, client_id);
+
+        Ok((OAuthClient::from_row(row), secret))
+    }
+
+    /// Returns all registered clients
+    pub fn get_clients(&self) -> DatabaseResult<Vec<OAuthClient>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            format!("SELECT {} FROM oauth_clients ORDER BY name", CLIENT_COLUMNS).as_str(),
+            &[],
+        )?;
+
+        Ok(rows.into_iter().map(OAuthClient::from_row).collect())
+    }
+
+    /// Returns the client with the given client id
+    pub fn get_client(&self, client_id: &String) -> DatabaseResult<OAuthClient> {
+        let mut connection = self.pool.get()?;
+        let row = connection
+            .query_opt(
+                format!(
+                    "SELECT {} FROM oauth_clients WHERE client_id = $1",
+                    CLIENT_COLUMNS
+                )
+                .as_str(),
+                &[client_id],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+
+        Ok(OAuthClient::from_row(row))
+    }
+
+    /// Deletes a client together with all consents and codes issued for it
+    pub fn delete_client(&self, client_id: &String) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let deleted = connection.execute(
+            "DELETE FROM oauth_clients WHERE client_id = $1",
+            &[client_id],
+        )?;
+
+        if deleted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns if the user already consented to grant all of the scopes to the client
+    pub fn has_consent(
+        &self,
+        user_id: i32,
+        client_id: &String,
+        scopes: &Vec<String>,
+    ) -> DatabaseResult<bool> {
+        let mut connection = self.pool.get()?;
+        let row = connection.query_opt(
+            "SELECT oauth_consents.scopes @> $3 FROM oauth_consents, oauth_clients
+            WHERE oauth_clients.id = oauth_consents.client_id
+            AND oauth_consents.user_id = $1 AND oauth_clients.client_id = $2",
+            &[&user_id, client_id, scopes],
+        )?;
+
+        Ok(row.map(|row| row.get(0)).unwrap_or(false))
+    }
+
+    /// Records that the user consented to grant the scopes to the client.
+    /// Scopes that were granted before are kept.
+    pub fn grant_consent(
+        &self,
+        user_id: i32,
+        client_id: &String,
+        scopes: &Vec<String>,
+    ) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let inserted = connection.execute(
+            "INSERT INTO oauth_consents (client_id, user_id, scopes)
+            SELECT id, $1, $3 FROM oauth_clients WHERE client_id = $2
+            ON CONFLICT (client_id, user_id) DO UPDATE SET granted_at = NOW(),
+            scopes = ARRAY(SELECT DISTINCT unnest(oauth_consents.scopes || EXCLUDED.scopes))",
+            &[&user_id, client_id, scopes],
+        )?;
+
+        if inserted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns all clients the user consented to
+    pub fn get_consents(&self, user_id: i32) -> DatabaseResult<Vec<OAuthConsent>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            "SELECT oauth_clients.client_id, oauth_clients.name AS client_name, oauth_consents.scopes,
+                to_char(oauth_consents.granted_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS granted_at
+            FROM oauth_consents, oauth_clients
+            WHERE oauth_clients.id = oauth_consents.client_id AND oauth_consents.user_id = $1
+            ORDER BY oauth_clients.name",
+            &[&user_id],
+        )?;
+
+        Ok(rows.into_iter().map(OAuthConsent::from_row).collect())
+    }
+
+    /// Removes the consent of the user together with the codes
+    /// that were issued to the client but not exchanged yet
+    pub fn revoke_consent(&self, user_id: i32, client_id: &String) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let deleted = transaction.execute(
+            "DELETE FROM oauth_consents USING oauth_clients
+            WHERE oauth_clients.id = oauth_consents.client_id
+            AND oauth_consents.user_id = $1 AND oauth_clients.client_id = $2",
+            &[&user_id, client_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM oauth_codes USING oauth_clients
+            WHERE oauth_clients.id = oauth_codes.client_id
+            AND oauth_codes.user_id = $1 AND oauth_clients.client_id = $2",
+            &[&user_id, client_id],
+        )?;
+        transaction.commit()?;
+
+        if deleted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Issues a short-lived authorization code that the client can exchange for tokens
+    pub fn create_code(
+        &self,
+        user_id: i32,
+        client_id: &String,
+        redirect_uri: &String,
+        scopes: &Vec<String>,
+        nonce: Option<&String>,
+    ) -> DatabaseResult<String> {
+        let code = create_secret_token();
+        let mut connection = self.pool.get()?;
+        connection.execute("DELETE FROM oauth_codes WHERE expires_at < NOW()", &[])?;
+        let inserted = connection.execute(
+            "INSERT INTO oauth_codes (code, client_id, user_id, redirect_uri, scopes, nonce, expires_at)
+            SELECT $1, id, $2, $4, $5, $7, NOW() + make_interval(secs => $6)
+            FROM oauth_clients WHERE client_id = $3",
+            &[
+                &hash_secret_token(&code),
+                &user_id,
+                client_id,
+                redirect_uri,
+                scopes,
+                &(AUTHORIZATION_CODE_SECONDS as f64),
+                &nonce,
+            ],
+        )?;
+
+        if inserted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(code)
+        }
+    }
+
+    /// Exchanges an authorization code for the grant it was issued for.
+    /// Codes can only be used once by the client they were issued to
+    /// and with the same redirect uri that was used to request them.
+    pub fn exchange_code(
+        &self,
+        client_id: &String,
+        client_secret: &str,
+        code: &str,
+        redirect_uri: &String,
+    ) -> DatabaseResult<AuthorizationGrant> {
+        let mut connection = self.pool.get()?;
+        let secret_hash: Vec<u8> = connection
+            .query_opt(
+                "SELECT secret_hash FROM oauth_clients WHERE client_id = $1",
+                &[client_id],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?
+            .get(0);
+        if !constant_time_eq(&secret_hash, &hash_secret_token(client_secret)) {
+            return Err(DBError::GenericError("Invalid client secret".to_string()));
+        }
+        let row = connection
+            .query_opt(
+                "DELETE FROM oauth_codes USING oauth_clients
+                WHERE oauth_clients.id = oauth_codes.client_id
+                AND oauth_codes.code = $1 AND oauth_clients.client_id = $2
+                RETURNING oauth_codes.user_id, oauth_codes.redirect_uri, oauth_codes.scopes,
+                    oauth_codes.expires_at > NOW(), oauth_codes.nonce",
+                &[&hash_secret_token(code), client_id],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let issued_redirect_uri: String = row.get(1);
+        let valid: bool = row.get(3);
+        if !valid || &issued_redirect_uri != redirect_uri {
+            return Err(DBError::RecordDoesNotExist);
+        }
+
+        Ok(AuthorizationGrant {
+            user_id: row.get(0),
+            scopes: row.get(2),
+            nonce: row.get(4),
+        })
+    }
+}
@@ -0,0 +1,253 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::HashSet;
+
+use crate::database::limits::{Resource, ResourceLimits};
+use crate::database::models::{Organization, OrganizationMember};
+use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::utils::error::DBError;
+
+const ORGANIZATION_COLUMNS: &str =
+    "organizations.id, organizations.name, organizations.description, parents.name AS parent";
+
+/// The table that stores organizations and their nested teams
+/// together with the members and the roles they have inside an organization.
+/// Roles assigned inside an organization apply to all of its teams.
+#[derive(Clone)]
+pub struct Organizations {
+    pool: PostgresPool,
+}
+
+impl Table for Organizations {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl Organizations {
+    /// Creates a new organization. If a parent is given the organization
+    /// is created as a team of the parent.
+    pub fn create_organization(
+        &self,
+        name: String,
+        description: Option<String>,
+        parent: Option<String>,
+    ) -> DatabaseResult<Organization> {
+        let mut connection = self.pool.get()?;
+        if connection
+            .query_opt("SELECT id FROM organizations WHERE name = $1", &[&name])?
+            .is_some()
+        {
+            return Err(DBError::RecordExists);
+        }
+        let parent_id: Option<i32> = if let Some(parent) = &parent {
+            let row = connection
+                .query_opt("SELECT id FROM organizations WHERE name = $1", &[parent])?
+                .ok_or(DBError::RecordDoesNotExist)?;
+            Some(row.get(0))
+        } else {
+            None
+        };
+        let row = connection.query_one(
+            "INSERT INTO organizations (name, description, parent_id) VALUES ($1, $2, $3) RETURNING id",
+            &[&name, &description, &parent_id],
+        )?;
+
+        Ok(Organization {
+            id: row.get(0),
+            name,
+            description,
+            parent,
+        })
+    }
+
+    /// Returns the organization with the given name
+    pub fn get_organization(&self, name: &String) -> DatabaseResult<Organization> {
+        let mut connection = self.pool.get()?;
+        let row = connection
+            .query_opt(
+                format!(
+                    "SELECT {} FROM organizations LEFT JOIN organizations parents ON parents.id = organizations.parent_id WHERE organizations.name = $1",
+                    ORGANIZATION_COLUMNS
+                )
+                .as_str(),
+                &[name],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+
+        Ok(Organization::from_row(row))
+    }
+
+    /// Returns all organizations
+    pub fn get_organizations(&self) -> DatabaseResult<Vec<Organization>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            format!(
+                "SELECT {} FROM organizations LEFT JOIN organizations parents ON parents.id = organizations.parent_id",
+                ORGANIZATION_COLUMNS
+            )
+            .as_str(),
+            &[],
+        )?;
+
+        Ok(rows.into_iter().map(Organization::from_row).collect())
+    }
+
+    /// Returns the direct teams of an organization
+    pub fn get_teams(&self, name: &String) -> DatabaseResult<Vec<Organization>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            format!(
+                "SELECT {} FROM organizations, organizations parents WHERE parents.id = organizations.parent_id AND parents.name = $1",
+                ORGANIZATION_COLUMNS
+            )
+            .as_str(),
+            &[name],
+        )?;
+
+        Ok(rows.into_iter().map(Organization::from_row).collect())
+    }
+
+    /// Deletes an organization with all of its teams and memberships
+    pub fn delete_organization(&self, name: &String) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let deleted = connection.execute("DELETE FROM organizations WHERE name = $1", &[name])?;
+
+        if deleted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns all members of an organization with the roles they have inside it
+    pub fn get_members(&self, name: &String) -> DatabaseResult<Vec<OrganizationMember>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            "SELECT users.id, users.name, users.email,
+                ARRAY_REMOVE(ARRAY_AGG(roles.name ORDER BY roles.name), NULL) AS roles
+            FROM organizations
+            JOIN organization_members ON organization_members.organization_id = organizations.id
+            JOIN users ON users.id = organization_members.user_id AND users.deleted_at IS NULL
+            LEFT JOIN organization_roles ON organization_roles.organization_id = organizations.id
+                AND organization_roles.user_id = users.id
+            LEFT JOIN roles ON roles.id = organization_roles.role_id
+            WHERE organizations.name = $1
+            GROUP BY users.id, users.name, users.email",
+            &[name],
+        )?;
+
+        Ok(rows.into_iter().map(OrganizationMember::from_row).collect())
+    }
+
+    /// Adds a user to an organization or replaces the roles of an existing member
+    pub fn set_member(
+        &self,
+        name: &String,
+        user_id: i32,
+        roles: &Vec<String>,
+    ) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let organization_id: i32 = connection
+            .query_opt("SELECT id FROM organizations WHERE name = $1", &[name])?
+            .ok_or(DBError::RecordDoesNotExist)?
+            .get(0);
+        let role_ids = connection
+            .query(
+                "SELECT id, name FROM roles WHERE name = ANY ($1) AND deleted_at IS NULL",
+                &[roles],
+            )?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect::<Vec<(i32, String)>>();
+        if role_ids.len() != roles.iter().collect::<HashSet<&String>>().len() {
+            let missing = roles
+                .iter()
+                .filter(|role| !role_ids.iter().any(|(_, name)| &name == role))
+                .collect::<Vec<&String>>();
+            return Err(DBError::GenericError(format!(
+                "The roles {:?} don't exist",
+                missing
+            )));
+        }
+        let role_ids = role_ids.into_iter().map(|(id, _)| id).collect::<Vec<i32>>();
+
+        let mut transaction = connection.transaction()?;
+        let row = transaction.query_one(
+            "SELECT COUNT(*), COUNT(*) FILTER (WHERE user_id = $2) FROM organization_members
+            WHERE organization_id = $1",
+            &[&organization_id, &user_id],
+        )?;
+        let (member_count, is_member): (i64, i64) = (row.get(0), row.get(1));
+        if is_member == 0 {
+            ResourceLimits::get().check(Resource::OrganizationMembers, member_count as u64)?;
+        }
+        transaction.execute(
+            "INSERT INTO organization_members (organization_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&organization_id, &user_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM organization_roles WHERE organization_id = $1 AND user_id = $2",
+            &[&organization_id, &user_id],
+        )?;
+        transaction.execute(
+            "INSERT INTO organization_roles (organization_id, user_id, role_id) SELECT $1, $2, unnest($3::INT[])",
+            &[&organization_id, &user_id, &role_ids],
+        )?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Removes a user and its roles from an organization
+    pub fn remove_member(&self, name: &String, user_id: i32) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let deleted = connection.execute(
+            "DELETE FROM organization_members USING organizations
+            WHERE organizations.id = organization_members.organization_id
+            AND organizations.name = $1 AND organization_members.user_id = $2",
+            &[name, &user_id],
+        )?;
+
+        if deleted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the names of all permissions granted to the user inside the organization.
+    /// These are the permissions of the roles the user has in the organization
+    /// or in any organization above it. Global roles are not included.
+    pub fn get_permission_names(
+        &self,
+        user_id: i32,
+        name: &String,
+    ) -> DatabaseResult<HashSet<String>> {
+        let mut connection = self.pool.get()?;
+        let statement = connection.prepare_cached(
+            "\
+            WITH RECURSIVE ancestors AS (
+                SELECT id, parent_id FROM organizations WHERE name = $2
+                UNION
+                SELECT organizations.id, organizations.parent_id FROM organizations, ancestors
+                WHERE organizations.id = ancestors.parent_id
+            )
+            SELECT DISTINCT permissions.name
+            FROM ancestors, organization_roles, roles, role_permissions, permissions
+            WHERE organization_roles.user_id = $1
+            AND organization_roles.organization_id = ancestors.id
+            AND roles.id = organization_roles.role_id
+            AND roles.enabled
+            AND roles.deleted_at IS NULL
+            AND role_permissions.role_id = roles.id
+            AND permissions.id = role_permissions.permission_id
+        ",
+        )?;
+        let rows = connection.query(&statement, &[&user_id, name])?;
+
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+}
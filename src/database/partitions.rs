@@ -0,0 +1,209 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::thread::{self, Builder};
+use std::time::Duration;
+
+use chrono::{Datelike, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use postgres::Transaction;
+
+use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::utils::config;
+use crate::utils::error::DBError;
+
+pub(crate) const ENV_AUDIT_RETENTION_MONTHS: &str = "AUDIT_RETENTION_MONTHS";
+pub(crate) const ENV_AUDIT_ARCHIVE_DIR: &str = "AUDIT_ARCHIVE_DIR";
+pub(crate) const DEFAULT_AUDIT_RETENTION_MONTHS: u32 = 12;
+const RETENTION_JOB_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const ARCHIVE_BATCH_SIZE: i32 = 1000;
+
+/// The tables that are partitioned by month
+pub const PARTITIONED_TABLES: &[&str] = &["change_history", "login_events"];
+
+/// Maintains the monthly partitions of the audit tables.
+/// Partitions for the current and the next month are created ahead of time
+/// and partitions older than the retention window are archived and dropped.
+#[derive(Clone)]
+pub struct Partitions {
+    pool: PostgresPool,
+}
+
+impl Table for Partitions {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+
+    fn init(&self) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        create_upcoming_partitions(&mut transaction)?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+impl Partitions {
+    /// Starts the background job that creates upcoming partitions
+    /// and removes the ones that are older than the retention window
+    pub fn start_retention_job(&self) {
+        let partitions = Partitions::clone(self);
+        let retention_months = config::var(ENV_AUDIT_RETENTION_MONTHS)
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_AUDIT_RETENTION_MONTHS);
+        let archive_dir = config::var(ENV_AUDIT_ARCHIVE_DIR).ok();
+
+        Builder::new()
+            .name("partition-retention".to_string())
+            .spawn(move || loop {
+                if let Err(e) = partitions.apply_retention(retention_months, &archive_dir) {
+                    log::error!("Failed to maintain partitions: {}", e);
+                }
+                thread::sleep(RETENTION_JOB_INTERVAL);
+            })
+            .unwrap();
+    }
+
+    /// Creates the upcoming partitions and drops all partitions that only contain
+    /// entries older than the given number of months. If an archive directory is
+    /// given the entries are written to a gzipped json lines file first.
+    /// A retention of 0 months keeps all partitions.
+    fn apply_retention(
+        &self,
+        retention_months: u32,
+        archive_dir: &Option<String>,
+    ) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        create_upcoming_partitions(&mut transaction)?;
+        transaction.commit()?;
+        if retention_months == 0 {
+            return Ok(());
+        }
+        let oldest_kept = current_month() - retention_months as i32;
+
+        for table in PARTITIONED_TABLES {
+            let rows = connection.query(
+                "SELECT child.relname FROM pg_inherits
+                JOIN pg_class parent ON parent.oid = pg_inherits.inhparent
+                JOIN pg_class child ON child.oid = pg_inherits.inhrelid
+                WHERE parent.relname = $1",
+                &[table],
+            )?;
+            for row in rows {
+                let partition: String = row.get(0);
+                match parse_partition_month(table, &partition) {
+                    Some(month) if month < oldest_kept => {}
+                    _ => continue,
+                }
+                let mut transaction = connection.transaction()?;
+                if let Some(dir) = archive_dir {
+                    archive_partition(&mut transaction, &partition, Path::new(dir))?;
+                }
+                transaction.batch_execute(format!("DROP TABLE {}", partition).as_str())?;
+                transaction.commit()?;
+                log::info!("Dropped the expired partition {}", partition);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates the partition of the table that holds the entries of the given month
+/// where months are counted as `year * 12 + month - 1`
+pub fn create_partition(
+    transaction: &mut Transaction,
+    table: &str,
+    month: i32,
+) -> DatabaseResult<()> {
+    transaction.batch_execute(
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} PARTITION OF {} FOR VALUES FROM ('{}') TO ('{}')",
+            partition_name(table, month),
+            table,
+            month_start(month),
+            month_start(month + 1)
+        )
+        .as_str(),
+    )?;
+
+    Ok(())
+}
+
+/// Creates the partitions for the current and the next month of all partitioned tables
+fn create_upcoming_partitions(transaction: &mut Transaction) -> DatabaseResult<()> {
+    let month = current_month();
+    for table in PARTITIONED_TABLES {
+        create_partition(transaction, table, month)?;
+        create_partition(transaction, table, month + 1)?;
+    }
+
+    Ok(())
+}
+
+/// Writes all entries of the partition as json lines to a gzipped file in the directory
+fn archive_partition(
+    transaction: &mut Transaction,
+    partition: &str,
+    dir: &Path,
+) -> DatabaseResult<()> {
+    let path = dir.join(format!("{}.jsonl.gz", partition));
+    let file = File::create(&path).map_err(|e| {
+        DBError::GenericError(format!("Failed to create {}: {}", path.display(), e))
+    })?;
+    let mut writer = GzEncoder::new(BufWriter::new(file), Compression::default());
+    let portal = transaction.bind(
+        format!("SELECT row_to_json(p)::TEXT FROM {} p", partition).as_str(),
+        &[],
+    )?;
+    loop {
+        let rows = transaction.query_portal(&portal, ARCHIVE_BATCH_SIZE)?;
+        for row in &rows {
+            let line: String = row.get(0);
+            writeln!(writer, "{}", line).map_err(|e| {
+                DBError::GenericError(format!("Failed to write {}: {}", path.display(), e))
+            })?;
+        }
+        if (rows.len() as i32) < ARCHIVE_BATCH_SIZE {
+            break;
+        }
+    }
+    writer
+        .finish()
+        .and_then(|mut w| w.flush())
+        .map_err(|e| DBError::GenericError(format!("Failed to write {}: {}", path.display(), e)))?;
+    log::info!("Archived the partition {} to {}", partition, path.display());
+
+    Ok(())
+}
+
+fn current_month() -> i32 {
+    let now = Utc::now();
+    now.year() * 12 + now.month0() as i32
+}
+
+fn partition_name(table: &str, month: i32) -> String {
+    format!("{}_p{:04}_{:02}", table, month / 12, month % 12 + 1)
+}
+
+fn month_start(month: i32) -> String {
+    format!("{:04}-{:02}-01 00:00:00+00", month / 12, month % 12 + 1)
+}
+
+/// Returns the month of a partition created by this module
+fn parse_partition_month(table: &str, partition: &str) -> Option<i32> {
+    let suffix = partition.strip_prefix(table)?.strip_prefix("_p")?;
+    let mut parts = suffix.splitn(2, '_');
+    let year = parts.next()?.parse::<i32>().ok()?;
+    let month = parts.next()?.parse::<i32>().ok()?;
+
+    Some(year * 12 + month - 1)
+}
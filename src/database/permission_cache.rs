@@ -0,0 +1,138 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+
+use crate::utils::config;
+
+pub(crate) const ENV_PERMISSION_CACHE_TTL: &str = "PERMISSION_CACHE_TTL";
+pub(crate) const DEFAULT_PERMISSION_CACHE_TTL: u64 = 60;
+
+lazy_static::lazy_static! {
+    /// The cache that is shared by all tables that read or modify permission grants
+    pub(crate) static ref PERMISSION_CACHE: PermissionCache = PermissionCache::new();
+}
+
+/// A cache for the resolved permission names of users.
+/// Entries expire after the configured ttl and are invalidated
+/// by all writes that change which permissions a user is granted.
+#[derive(Clone, Debug)]
+pub struct PermissionCache {
+    state: Arc<RwLock<CacheState>>,
+    ttl: Duration,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    entries: HashMap<i32, CacheEntry>,
+    /// Increased by every invalidation so that permissions that were
+    /// read before the invalidation aren't stored afterwards
+    generation: u64,
+    /// The time expired entries were last removed
+    swept_at: Instant,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    created: Instant,
+    permissions: Arc<HashSet<String>>,
+}
+
+impl Default for PermissionCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PermissionCache {
+    pub fn new() -> Self {
+        let ttl = config::var(ENV_PERMISSION_CACHE_TTL)
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_PERMISSION_CACHE_TTL);
+
+        Self {
+            state: Arc::new(RwLock::new(CacheState {
+                entries: HashMap::new(),
+                generation: 0,
+                swept_at: Instant::now(),
+            })),
+            ttl: Duration::from_secs(ttl),
+        }
+    }
+
+    /// Returns the cached permissions of a user if they haven't expired
+    pub fn get(&self, user_id: i32) -> Option<Arc<HashSet<String>>> {
+        let state = self.state.read();
+        let entry = state.entries.get(&user_id)?;
+
+        if entry.created.elapsed() < self.ttl {
+            Some(Arc::clone(&entry.permissions))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the current generation of the cache. It needs to be read before
+    /// the permissions are loaded from the database and passed to [PermissionCache::insert].
+    pub fn generation(&self) -> u64 {
+        self.state.read().generation
+    }
+
+    /// Stores the resolved permissions of a user that were loaded in the given generation.
+    /// Nothing is stored if the cache was invalidated since then
+    /// or if the ttl is configured to be zero.
+    pub fn insert(
+        &self,
+        user_id: i32,
+        generation: u64,
+        permissions: HashSet<String>,
+    ) -> Arc<HashSet<String>> {
+        let permissions = Arc::new(permissions);
+        if self.ttl.as_secs() == 0 {
+            return permissions;
+        }
+        let mut state = self.state.write();
+        if state.generation != generation {
+            return permissions;
+        }
+        if state.swept_at.elapsed() >= self.ttl {
+            let ttl = self.ttl;
+            state
+                .entries
+                .retain(|_, entry| entry.created.elapsed() < ttl);
+            state.swept_at = Instant::now();
+        }
+        state.entries.insert(
+            user_id,
+            CacheEntry {
+                created: Instant::now(),
+                permissions: Arc::clone(&permissions),
+            },
+        );
+
+        permissions
+    }
+
+    /// Removes the cached permissions of a single user
+    pub fn invalidate_user(&self, user_id: i32) {
+        log::trace!("Invalidating cached permissions of user {}", user_id);
+        let mut state = self.state.write();
+        state.generation += 1;
+        state.entries.remove(&user_id);
+    }
+
+    /// Removes all cached permissions. This needs to be called
+    /// whenever a change affects an unknown set of users (e.g. role changes).
+    pub fn invalidate_all(&self) {
+        log::trace!("Invalidating all cached permissions");
+        let mut state = self.state.write();
+        state.generation += 1;
+        state.entries.clear();
+    }
+}
@@ -2,8 +2,11 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
-use crate::database::models::{CreatePermissionsEntry, Permission};
+use crate::database::events::{self, Event};
+use crate::database::models::{CreatePermissionsEntry, Permission, PermissionSync};
+use crate::database::permission_cache::PERMISSION_CACHE;
 use crate::database::{DatabaseResult, PostgresPool, Table, ADMIN_ROLE_NAME};
+use crate::utils::error::DBError;
 use std::collections::HashSet;
 use std::iter::FromIterator;
 
@@ -16,6 +19,21 @@ pub(crate) const USER_UPDATE_PERM: &str = "USER_UPDATE";
 pub(crate) const USER_VIEW_PERM: &str = "USER_VIEW";
 pub(crate) const USER_CREATE_PERM: &str = "USER_CREATE";
 pub(crate) const USER_DELETE_PERM: &str = "USER_DELETE";
+pub(crate) const USER_APPROVE_PERM: &str = "USER_APPROVE";
+pub(crate) const USER_ROLES_UPDATE_PERM: &str = "USER_ROLES_UPDATE";
+pub(crate) const USER_IMPERSONATE_PERM: &str = "USER_IMPERSONATE";
+
+pub(crate) const USER_FIELDS_MANAGE_PERM: &str = "USER_FIELDS_MANAGE";
+
+pub(crate) const ORGANIZATION_VIEW_PERM: &str = "ORGANIZATION_VIEW";
+pub(crate) const ORGANIZATION_MANAGE_PERM: &str = "ORGANIZATION_MANAGE";
+
+pub(crate) const PERMISSION_MANAGE_PERM: &str = "PERMISSION_MANAGE";
+
+pub(crate) const CONFIG_VIEW_PERM: &str = "CONFIG_VIEW";
+pub(crate) const OAUTH_CLIENT_MANAGE_PERM: &str = "OAUTH_CLIENT_MANAGE";
+pub(crate) const API_KEY_MANAGE_PERM: &str = "API_KEY_MANAGE";
+pub(crate) const WEBHOOK_MANAGE_PERM: &str = "WEBHOOK_MANAGE";
 
 pub(crate) const USER_MANAGEMENT_PERMISSIONS: &[(&'static str, &'static str)] = &[
     (ROLE_CREATE_PERM, "Allows the user to create roles"),
@@ -29,8 +47,58 @@ pub(crate) const USER_MANAGEMENT_PERMISSIONS: &[(&'static str, &'static str)] =
     (USER_VIEW_PERM, "Allows to see information of users"),
     (USER_CREATE_PERM, "Allows the creation of new users"),
     (USER_DELETE_PERM, "Allows the deletion of users"),
+    (
+        USER_APPROVE_PERM,
+        "Allows approving and rejecting registered users",
+    ),
+    (
+        USER_ROLES_UPDATE_PERM,
+        "Allows assigning roles to users and removing them",
+    ),
+    (
+        USER_IMPERSONATE_PERM,
+        "Allows acting as users that don't have more permissions than the own user",
+    ),
+    (
+        USER_FIELDS_MANAGE_PERM,
+        "Allows creating, changing and deleting custom user fields",
+    ),
+    (
+        ORGANIZATION_VIEW_PERM,
+        "Allows to see organizations, their teams and members",
+    ),
+    (
+        ORGANIZATION_MANAGE_PERM,
+        "Allows creating and deleting teams and managing the members of organizations",
+    ),
+    (
+        PERMISSION_MANAGE_PERM,
+        "Allows services to synchronize their permissions",
+    ),
+    (
+        CONFIG_VIEW_PERM,
+        "Allows to see the effective configuration of the server",
+    ),
+    (
+        OAUTH_CLIENT_MANAGE_PERM,
+        "Allows registering and deleting client applications",
+    ),
+    (
+        API_KEY_MANAGE_PERM,
+        "Allows creating and deleting the api keys of service accounts",
+    ),
+    (
+        WEBHOOK_MANAGE_PERM,
+        "Allows registering and deleting webhooks and viewing their deliveries",
+    ),
 ];
 
+/// Returns if the permission is one of the built-in permissions
+/// that can't be deleted or renamed
+pub fn is_system_permission(name: &str) -> bool {
+    USER_MANAGEMENT_PERMISSIONS.iter().any(|(n, _)| *n == name)
+}
+
 /// The permissions table that stores defined
 #[derive(Clone)]
 pub struct Permissions {
@@ -41,18 +109,6 @@ impl Table for Permissions {
     fn new(pool: PostgresPool) -> Self {
         Self { pool }
     }
-
-    fn init(&self) -> DatabaseResult<()> {
-        self.pool.get()?.batch_execute(
-            "CREATE TABLE IF NOT EXISTS permissions (
-                        id              SERIAL PRIMARY KEY,
-                        name            VARCHAR(128) UNIQUE NOT NULL,
-                        description     VARCHAR(512)
-                    );",
-        )?;
-
-        Ok(())
-    }
 }
 
 impl Permissions {
@@ -65,6 +121,7 @@ impl Permissions {
         let mut connection = self.pool.get()?;
         let mut transaction = connection.transaction()?;
         let mut created_permissions = Vec::new();
+        let mut new_permissions = Vec::new();
 
         let _: Vec<DatabaseResult<()>> = permissions
             .iter()
@@ -89,6 +146,7 @@ impl Permissions {
                         )
                     }
 
+                    new_permissions.push(permission.name.clone());
                     created_permissions.push(permission);
                 } else {
                     created_permissions.push(serde_postgres::from_row(&exists.unwrap())?);
@@ -97,11 +155,139 @@ impl Permissions {
                 Ok(())
             })
             .collect();
+        if !new_permissions.is_empty() {
+            events::enqueue(
+                &mut transaction,
+                Event::PermissionsCreated {
+                    permissions: new_permissions,
+                },
+            )?;
+        }
         transaction.commit()?;
+        PERMISSION_CACHE.invalidate_all();
 
         Ok(created_permissions)
     }
 
+    /// Reconciles the permissions of a namespace with the given permission set.
+    /// All names need to be prefixed with the namespace followed by an underscore.
+    /// Missing permissions are created and assigned to the admin role, changed
+    /// descriptions are updated and permissions of the namespace that aren't
+    /// in the set are reported as orphaned. Running it again with the same set
+    /// doesn't change anything.
+    pub fn sync_permissions(
+        &self,
+        namespace: &str,
+        permissions: &Vec<CreatePermissionsEntry>,
+    ) -> DatabaseResult<PermissionSync> {
+        let prefix = format!("{}_", namespace);
+        if namespace.is_empty()
+            || USER_MANAGEMENT_PERMISSIONS
+                .iter()
+                .any(|(name, _)| name.starts_with(&prefix))
+        {
+            return Err(DBError::GenericError(format!(
+                "The namespace {} can't be synchronized",
+                namespace
+            )));
+        }
+        let mut names = HashSet::new();
+        for entry in permissions {
+            if is_system_permission(&entry.name) {
+                return Err(DBError::SystemRecord(entry.name.clone()));
+            }
+            if !entry.name.starts_with(&prefix) || entry.name.len() == prefix.len() {
+                return Err(DBError::GenericError(format!(
+                    "The permission {} is not part of the namespace {}",
+                    entry.name, namespace
+                )));
+            }
+            if !names.insert(entry.name.clone()) {
+                return Err(DBError::GenericError(format!(
+                    "The permission {} is contained more than once",
+                    entry.name
+                )));
+            }
+        }
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let mut sync = PermissionSync::default();
+
+        for CreatePermissionsEntry { name, description } in permissions {
+            let existing = transaction.query_opt(
+                "SELECT id, description FROM permissions WHERE name = $1 FOR UPDATE",
+                &[name],
+            )?;
+            match existing {
+                Some(row) => {
+                    let current: Option<String> = row.get(1);
+                    if current.as_ref() == Some(description) {
+                        sync.unchanged.push(name.clone());
+                    } else {
+                        let id: i32 = row.get(0);
+                        transaction.execute(
+                            "UPDATE permissions SET description = $2 WHERE id = $1",
+                            &[&id, description],
+                        )?;
+                        sync.updated.push(name.clone());
+                    }
+                }
+                None => {
+                    transaction.execute(
+                        "WITH inserted AS (
+                            INSERT INTO permissions (name, description) VALUES ($2, $3) RETURNING id
+                        )
+                        INSERT INTO role_permissions (role_id, permission_id)
+                        SELECT roles.id, inserted.id FROM roles, inserted WHERE roles.name = $1",
+                        &[&ADMIN_ROLE_NAME, name, description],
+                    )?;
+                    sync.created.push(name.clone());
+                }
+            }
+        }
+        let rows = transaction.query(
+            "SELECT name FROM permissions WHERE left(name, length($1)) = $1 AND NOT name = ANY ($2) ORDER BY name",
+            &[&prefix, &names.into_iter().collect::<Vec<String>>()],
+        )?;
+        sync.orphaned = rows.into_iter().map(|row| row.get(0)).collect();
+
+        if !sync.created.is_empty() {
+            events::enqueue(
+                &mut transaction,
+                Event::PermissionsCreated {
+                    permissions: sync.created.clone(),
+                },
+            )?;
+        }
+        transaction.commit()?;
+        PERMISSION_CACHE.invalidate_all();
+
+        Ok(sync)
+    }
+
+    /// Assigns all system permissions to the admin role again
+    /// in case the assignments were removed directly in the database
+    pub fn restore_admin_permissions(&self) -> DatabaseResult<()> {
+        let names = USER_MANAGEMENT_PERMISSIONS
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<String>>();
+        let mut connection = self.pool.get()?;
+        let restored = connection.execute(
+            "INSERT INTO role_permissions (role_id, permission_id)
+            SELECT roles.id, permissions.id FROM roles, permissions
+            WHERE roles.name = $1 AND permissions.name = ANY ($2)
+            ON CONFLICT DO NOTHING",
+            &[&ADMIN_ROLE_NAME, &names],
+        )?;
+        if restored > 0 {
+            log::warn!("Restored {} permissions of the admin role", restored);
+            PERMISSION_CACHE.invalidate_all();
+        }
+
+        Ok(())
+    }
+
     /// Returns a list of permission IDs that don't exist in the database
     pub fn get_not_existing(&self, permissions_vec: &Vec<i32>) -> DatabaseResult<Vec<i32>> {
         let permissions = HashSet::from_iter(permissions_vec.iter().cloned());
@@ -0,0 +1,142 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use r2d2::event::{CheckinEvent, CheckoutEvent, HandleEvent, TimeoutEvent};
+
+use crate::utils::config;
+use crate::utils::telemetry;
+
+pub(crate) const ENV_CONNECTION_HOLD_WARN: &str = "DB_CONNECTION_HOLD_WARN_MS";
+pub(crate) const DEFAULT_CONNECTION_HOLD_WARN: u64 = 5000;
+
+/// Keeps track of how long connections are checked out of the pool
+/// so that handlers holding on to a connection can be found
+#[derive(Debug)]
+pub struct PoolMonitor {
+    active_checkouts: Mutex<HashMap<u64, Instant>>,
+    checkouts: AtomicU64,
+    timeouts: AtomicU64,
+    long_checkouts: AtomicU64,
+    max_checkout_ms: AtomicU64,
+    warn_threshold: Duration,
+}
+
+/// Statistics about the checkouts of pooled connections
+#[derive(Clone, Debug)]
+pub struct CheckoutStats {
+    /// The number of checkouts since the start
+    pub checkouts: u64,
+    /// The number of times no connection was available in time
+    pub timeouts: u64,
+    /// The number of checkouts that were held longer than the warning threshold
+    pub long_checkouts: u64,
+    /// The longest time a connection was held in milliseconds
+    pub max_checkout_ms: u64,
+    /// The time the longest currently checked out connection is held in milliseconds
+    pub longest_active_checkout_ms: u64,
+}
+
+impl Default for PoolMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PoolMonitor {
+    pub fn new() -> Self {
+        Self {
+            active_checkouts: Mutex::new(HashMap::new()),
+            checkouts: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            long_checkouts: AtomicU64::new(0),
+            max_checkout_ms: AtomicU64::new(0),
+            warn_threshold: Duration::from_millis(
+                config::var(ENV_CONNECTION_HOLD_WARN)
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_CONNECTION_HOLD_WARN),
+            ),
+        }
+    }
+
+    /// Returns the current checkout statistics
+    pub fn stats(&self) -> CheckoutStats {
+        let longest_active_checkout_ms = self
+            .active_checkouts
+            .lock()
+            .values()
+            .map(|started| started.elapsed().as_millis() as u64)
+            .max()
+            .unwrap_or(0);
+
+        CheckoutStats {
+            checkouts: self.checkouts.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            long_checkouts: self.long_checkouts.load(Ordering::Relaxed),
+            max_checkout_ms: self.max_checkout_ms.load(Ordering::Relaxed),
+            longest_active_checkout_ms,
+        }
+    }
+}
+
+/// The handler for pool events that reports to the monitor
+#[derive(Debug)]
+pub struct PoolEventHandler {
+    monitor: Arc<PoolMonitor>,
+}
+
+impl PoolEventHandler {
+    pub fn new(monitor: &Arc<PoolMonitor>) -> Self {
+        Self {
+            monitor: Arc::clone(monitor),
+        }
+    }
+}
+
+impl HandleEvent for PoolEventHandler {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        telemetry::record_pool_wait(event.duration());
+        self.monitor.checkouts.fetch_add(1, Ordering::Relaxed);
+        self.monitor
+            .active_checkouts
+            .lock()
+            .insert(event.connection_id(), Instant::now());
+    }
+
+    fn handle_timeout(&self, event: TimeoutEvent) {
+        self.monitor.timeouts.fetch_add(1, Ordering::Relaxed);
+        let stats = self.monitor.stats();
+        log::warn!(
+            "No database connection became available within {}ms. The longest checked out connection is held for {}ms",
+            event.timeout().as_millis(),
+            stats.longest_active_checkout_ms
+        );
+    }
+
+    fn handle_checkin(&self, event: CheckinEvent) {
+        self.monitor
+            .active_checkouts
+            .lock()
+            .remove(&event.connection_id());
+        let duration = event.duration();
+        self.monitor
+            .max_checkout_ms
+            .fetch_max(duration.as_millis() as u64, Ordering::Relaxed);
+
+        if duration >= self.monitor.warn_threshold {
+            self.monitor.long_checkouts.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "Database connection {} was held for {}ms",
+                event.connection_id(),
+                duration.as_millis()
+            );
+        }
+    }
+}
@@ -0,0 +1,381 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use postgres::types::Json;
+use postgres::Row;
+
+use crate::database::tokens::{
+    decode_digest, initial_refresh_ttl, SessionInfo, SessionStore, TokenKind, TokenStoreEntry,
+    TokenStoreStats, GUEST_USER_ID, REQUEST_TOKEN_EXPIRE_SECONDS, SESSION_IDLE_TIMEOUT,
+    SESSION_LIFETIME, SESSION_MAX_LIFETIME, SESSION_SLIDING_EXPIRY,
+};
+use crate::database::{DatabaseResult, PostgresPool};
+use crate::utils::error::DBError;
+use crate::utils::get_user_id_from_token;
+
+/// The columns of a session that are needed to create a token store entry.
+/// Expired ttls are returned as -1.
+macro_rules! entry_columns {
+    () => {
+        "user_id, kind,
+        GREATEST(EXTRACT(EPOCH FROM request_expires_at - NOW()), -1)::INT,
+        GREATEST(EXTRACT(EPOCH FROM refresh_expires_at - NOW()), -1)::INT"
+    };
+}
+
+/// Marks a session as active which restarts the idle timeout
+macro_rules! touch_session {
+    () => {
+        "refresh_expires_at = LEAST(expires_at, NOW() + idle_timeout * INTERVAL '1 second')"
+    };
+}
+
+/// The end of the lifetime of a session that was just used with sliding expiry.
+/// It's moved forward by the lifetime ($2) but never beyond the maximum lifetime ($3).
+/// Guest tokens keep their expiration.
+macro_rules! sliding_expires_at {
+    () => {
+        "CASE WHEN refresh_digest IS NULL THEN expires_at ELSE GREATEST(
+            expires_at,
+            LEAST(NOW() + $2::INT * INTERVAL '1 second', created_at + $3::INT * INTERVAL '1 second')
+        ) END"
+    };
+}
+
+/// Marks a session as active and extends its lifetime
+macro_rules! touch_sliding_session {
+    () => {
+        concat!(
+            "expires_at = ",
+            sliding_expires_at!(),
+            ", refresh_expires_at = LEAST(",
+            sliding_expires_at!(),
+            ", NOW() + idle_timeout * INTERVAL '1 second')"
+        )
+    };
+}
+
+/// Stores the sessions in the sessions table so that they survive restarts
+/// and can be shared between multiple instances
+#[derive(Clone)]
+pub struct PostgresSessionStore {
+    pool: PostgresPool,
+}
+
+impl PostgresSessionStore {
+    pub fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl SessionStore for PostgresSessionStore {
+    /// The sessions table is created by the migrations
+    fn init(&self) -> DatabaseResult<()> {
+        Ok(())
+    }
+
+    fn get_by_request_token(&self, request_token: &str) -> DatabaseResult<Option<TokenStoreEntry>> {
+        let digest = match decode_digest(request_token) {
+            Some(digest) => digest,
+            None => return Ok(None),
+        };
+        // validating request tokens is the most frequent query so it's prepared once per connection
+        let mut connection = self.pool.get()?;
+        let row = if *SESSION_SLIDING_EXPIRY {
+            let statement = connection.prepare_cached(concat!(
+                "UPDATE sessions SET ",
+                touch_sliding_session!(),
+                " WHERE request_digest = $1
+                AND request_expires_at > NOW() AND refresh_expires_at > NOW()
+                RETURNING ",
+                entry_columns!()
+            ))?;
+            connection.query_opt(
+                &statement,
+                &[
+                    &digest,
+                    &(*SESSION_LIFETIME as i32),
+                    &(*SESSION_MAX_LIFETIME as i32),
+                ],
+            )?
+        } else {
+            let statement = connection.prepare_cached(concat!(
+                "UPDATE sessions SET ",
+                touch_session!(),
+                " WHERE request_digest = $1
+                AND request_expires_at > NOW() AND refresh_expires_at > NOW()
+                RETURNING ",
+                entry_columns!()
+            ))?;
+            connection.query_opt(&statement, &[&digest])?
+        };
+
+        Ok(row.as_ref().map(entry_from_row))
+    }
+
+    fn get_by_refresh_token(&self, refresh_token: &str) -> DatabaseResult<Option<TokenStoreEntry>> {
+        log::trace!("Retrieving user by refresh token.");
+        let digest = match decode_digest(refresh_token) {
+            Some(digest) => digest,
+            None => return Ok(None),
+        };
+        let mut connection = self.pool.get()?;
+        let statement = connection.prepare_cached(concat!(
+            "SELECT ",
+            entry_columns!(),
+            " FROM sessions WHERE refresh_digest = $1 AND refresh_expires_at > NOW()"
+        ))?;
+        let row = connection.query_opt(&statement, &[&digest])?;
+
+        Ok(row.as_ref().map(entry_from_row))
+    }
+
+    fn set_request_token(
+        &self,
+        refresh_token: &str,
+        request_token: &str,
+    ) -> DatabaseResult<Option<TokenStoreEntry>> {
+        let refresh_digest = match decode_digest(refresh_token) {
+            Some(digest) => digest,
+            None => return Ok(None),
+        };
+        let request_digest = decode_digest(request_token)
+            .ok_or(DBError::GenericError("Invalid request token".to_string()))?;
+        let mut connection = self.pool.get()?;
+        let statement = connection.prepare_cached(concat!(
+            "UPDATE sessions SET request_digest = $2,
+            request_expires_at = NOW() + $3::INT * INTERVAL '1 second', ",
+            touch_session!(),
+            " WHERE refresh_digest = $1 AND refresh_expires_at > NOW()
+            RETURNING ",
+            entry_columns!()
+        ))?;
+        let row = connection.query_opt(
+            &statement,
+            &[
+                &refresh_digest,
+                &request_digest,
+                &(REQUEST_TOKEN_EXPIRE_SECONDS as i32),
+            ],
+        )?;
+        if row.is_some() {
+            log::trace!("Request TTL reset");
+        }
+
+        Ok(row.as_ref().map(entry_from_row))
+    }
+
+    fn insert_guest(
+        &self,
+        request_token: &str,
+        ttl: u32,
+        permissions: Vec<String>,
+        limit: usize,
+    ) -> DatabaseResult<()> {
+        let digest = decode_digest(request_token)
+            .ok_or(DBError::GenericError("Invalid token length".to_string()))?;
+        let mut connection = self.pool.get()?;
+        let guest_tokens: i64 = connection
+            .query_one(
+                "SELECT COUNT(*) FROM sessions WHERE user_id = $1 AND request_expires_at > NOW()",
+                &[&GUEST_USER_ID],
+            )?
+            .get(0);
+        if guest_tokens as usize >= limit {
+            return Err(DBError::GenericError("Too many guest tokens".to_string()));
+        }
+        connection.execute(
+            "INSERT INTO sessions (user_id, request_digest, kind, request_expires_at,
+                refresh_expires_at, expires_at, idle_timeout)
+            VALUES ($1, $2, $3, NOW() + $4::INT * INTERVAL '1 second',
+                NOW() + $4::INT * INTERVAL '1 second', NOW() + $4::INT * INTERVAL '1 second', $4)",
+            &[
+                &GUEST_USER_ID,
+                &digest,
+                &Json(TokenKind::Guest(permissions)),
+                &(ttl as i32),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn insert_session(
+        &self,
+        request_token: &str,
+        refresh_token: &str,
+        kind: TokenKind,
+    ) -> DatabaseResult<()> {
+        let user_id = get_user_id_from_token(refresh_token)
+            .ok_or(DBError::GenericError("Invalid refresh token".to_string()))?;
+        let (request_digest, refresh_digest) =
+            match (decode_digest(request_token), decode_digest(refresh_token)) {
+                (Some(request_digest), Some(refresh_digest)) => (request_digest, refresh_digest),
+                _ => return Err(DBError::GenericError("Invalid token length".to_string())),
+            };
+        let mut connection = self.pool.get()?;
+        let statement = connection.prepare_cached(
+            "INSERT INTO sessions (user_id, request_digest, refresh_digest, kind, request_expires_at,
+                refresh_expires_at, expires_at, idle_timeout)
+            VALUES ($1, $2, $3, $4, NOW() + $5::INT * INTERVAL '1 second',
+                NOW() + $6::INT * INTERVAL '1 second', NOW() + $7::INT * INTERVAL '1 second', $8)",
+        )?;
+        connection.execute(
+            &statement,
+            &[
+                &user_id,
+                &request_digest,
+                &refresh_digest,
+                &Json(kind),
+                &(REQUEST_TOKEN_EXPIRE_SECONDS as i32),
+                &(initial_refresh_ttl() as i32),
+                &(*SESSION_LIFETIME as i32),
+                &(*SESSION_IDLE_TIMEOUT as i32),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn extend_request_ttl(&self, request_token: &str) -> DatabaseResult<Option<TokenStoreEntry>> {
+        let digest = match decode_digest(request_token) {
+            Some(digest) => digest,
+            None => return Ok(None),
+        };
+        let row = self.pool.get()?.query_opt(
+            concat!(
+                "UPDATE sessions SET ",
+                touch_session!(),
+                ", request_expires_at = LEAST(
+                    NOW() + $2::INT * INTERVAL '1 second',
+                    expires_at,
+                    NOW() + idle_timeout * INTERVAL '1 second'
+                ) WHERE request_digest = $1 AND refresh_digest IS NOT NULL
+                AND request_expires_at > NOW() AND refresh_expires_at > NOW()
+                RETURNING ",
+                entry_columns!()
+            ),
+            &[&digest, &(REQUEST_TOKEN_EXPIRE_SECONDS as i32)],
+        )?;
+
+        Ok(row.as_ref().map(entry_from_row))
+    }
+
+    fn remove_by_request_token(&self, request_token: &str) -> DatabaseResult<bool> {
+        let digest = match decode_digest(request_token) {
+            Some(digest) => digest,
+            None => return Ok(false),
+        };
+        let removed = self.pool.get()?.execute(
+            "DELETE FROM sessions WHERE request_digest = $1
+            AND request_expires_at > NOW() AND refresh_expires_at > NOW()",
+            &[&digest],
+        )?;
+        log::trace!("Tokens invalidated.");
+
+        Ok(removed > 0)
+    }
+
+    fn remove_client(&self, user_id: Option<i32>, client_id: &str) -> DatabaseResult<usize> {
+        let removed = self.pool.get()?.execute(
+            "DELETE FROM sessions WHERE kind -> 'Client' ->> 'client_id' = $2
+            AND ($1::INT IS NULL OR user_id = $1)",
+            &[&user_id, &client_id],
+        )?;
+
+        Ok(removed as usize)
+    }
+
+    fn active_sessions(&self, user_id: i32) -> DatabaseResult<usize> {
+        let count: i64 = self
+            .pool
+            .get()?
+            .query_one(
+                "SELECT COUNT(*) FROM sessions WHERE user_id = $1 AND refresh_expires_at > NOW()",
+                &[&user_id],
+            )?
+            .get(0);
+
+        Ok(count as usize)
+    }
+
+    fn list_sessions(&self, user_id: i32) -> DatabaseResult<Vec<SessionInfo>> {
+        let rows = self.pool.get()?.query(
+            concat!(
+                "SELECT ",
+                entry_columns!(),
+                ", to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"')
+                FROM sessions WHERE user_id = $1 AND refresh_expires_at > NOW()
+                ORDER BY created_at"
+            ),
+            &[&user_id],
+        )?;
+
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let entry = entry_from_row(row);
+                SessionInfo::new(
+                    entry.kind(),
+                    row.get(4),
+                    entry.request_ttl(),
+                    entry.refresh_ttl(),
+                )
+            })
+            .collect())
+    }
+
+    fn remove_user(&self, user_id: i32) -> DatabaseResult<usize> {
+        let removed = self
+            .pool
+            .get()?
+            .execute("DELETE FROM sessions WHERE user_id = $1", &[&user_id])?;
+
+        Ok(removed as usize)
+    }
+
+    fn stats(&self) -> DatabaseResult<TokenStoreStats> {
+        let mut connection = self.pool.get()?;
+        let sessions_per_user = connection
+            .query(
+                "SELECT COUNT(*) FROM sessions WHERE refresh_expires_at > NOW()
+                GROUP BY user_id ORDER BY 1",
+                &[],
+            )?
+            .iter()
+            .map(|row| row.get::<_, i64>(0) as usize)
+            .collect::<Vec<usize>>();
+        let row = connection.query_one(
+            "SELECT COUNT(*), pg_total_relation_size('sessions') FROM sessions",
+            &[],
+        )?;
+        let stored_entries: i64 = row.get(0);
+        let size_bytes: i64 = row.get(1);
+
+        Ok(TokenStoreStats {
+            active_sessions: sessions_per_user.iter().sum(),
+            users: sessions_per_user.len(),
+            stored_entries: stored_entries as usize,
+            size_bytes: size_bytes as usize,
+            sessions_per_user,
+        })
+    }
+
+    fn clear_expired(&self) -> DatabaseResult<usize> {
+        log::trace!("Clearing expired tokens...");
+        let removed = self.pool.get()?.execute(
+            "DELETE FROM sessions WHERE refresh_expires_at <= NOW()",
+            &[],
+        )?;
+        log::trace!("Cleared {} expired tokens", removed);
+
+        Ok(removed as usize)
+    }
+}
+
+fn entry_from_row(row: &Row) -> TokenStoreEntry {
+    let kind: Json<TokenKind> = row.get(1);
+
+    TokenStoreEntry::new(row.get(0), kind.0, row.get(2), row.get(3))
+}
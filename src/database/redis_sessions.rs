@@ -0,0 +1,616 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{TimeZone, Utc};
+use r2d2::{Pool, PooledConnection};
+use redis::{Client, Commands, Pipeline};
+use serde::{Deserialize, Serialize};
+
+use crate::database::tokens::{
+    decode_digest, SessionInfo, SessionStore, TokenKind, TokenStoreEntry, TokenStoreStats,
+    GUEST_USER_ID, REQUEST_TOKEN_EXPIRE_SECONDS, SESSION_IDLE_TIMEOUT, SESSION_LIFETIME,
+    SESSION_MAX_LIFETIME, SESSION_SLIDING_EXPIRY,
+};
+use crate::utils::error::{DBError, DatabaseResult};
+use crate::utils::get_user_id_from_token;
+
+pub(crate) const ENV_REDIS_URL: &str = "REDIS_URL";
+pub(crate) const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1/";
+const SESSION_PREFIX: &str = "flotte:session:";
+const REQUEST_PREFIX: &str = "flotte:request:";
+const USER_PREFIX: &str = "flotte:user:";
+/// The number of times an update is retried when the session changes concurrently
+const MAX_UPDATE_ATTEMPTS: usize = 5;
+
+type RedisConnection = PooledConnection<Client>;
+
+/// A session as it is stored in redis. Timestamps are unix timestamps in seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RedisSession {
+    user_id: i32,
+    kind: TokenKind,
+    request_digest: String,
+    request_expires_at: u64,
+    expires_at: u64,
+    idle_timeout: u64,
+    last_active_at: u64,
+    /// Missing for sessions that were stored before the creation time was recorded
+    #[serde(default)]
+    created_at: Option<u64>,
+}
+
+impl RedisSession {
+    /// Returns the time at which the refresh token expires
+    fn refresh_expires_at(&self) -> u64 {
+        min(self.expires_at, self.last_active_at + self.idle_timeout)
+    }
+
+    /// Moves the end of the lifetime forward after the session was used.
+    /// It never exceeds the maximum lifetime. Sessions without a recorded
+    /// creation time and guest tokens keep their expiration.
+    fn slide_expiry(&mut self, now: u64) {
+        if let (Some(created_at), false) = (self.created_at, self.is_guest()) {
+            let max_expires_at = created_at + *SESSION_MAX_LIFETIME as u64;
+            self.expires_at = max(
+                self.expires_at,
+                min(now + *SESSION_LIFETIME as u64, max_expires_at),
+            );
+        }
+    }
+
+    fn is_guest(&self) -> bool {
+        matches!(self.kind, TokenKind::Guest(_))
+    }
+
+    /// Returns if the request token with the digest belongs to the session
+    /// and neither the request token nor the session has expired
+    fn accepts_request_token(&self, digest: &str, now: u64) -> bool {
+        self.request_digest == digest
+            && self.request_expires_at > now
+            && self.refresh_expires_at() > now
+    }
+
+    fn to_entry(&self, now: u64) -> TokenStoreEntry {
+        TokenStoreEntry::new(
+            self.user_id,
+            self.kind.clone(),
+            ttl(self.request_expires_at, now),
+            ttl(self.refresh_expires_at(), now),
+        )
+    }
+
+    fn to_info(&self, now: u64) -> SessionInfo {
+        SessionInfo::new(
+            &self.kind,
+            self.created_at
+                .and_then(|created_at| Utc.timestamp_opt(created_at as i64, 0).single())
+                .map(|created_at| created_at.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+            ttl(self.request_expires_at, now),
+            ttl(self.refresh_expires_at(), now),
+        )
+    }
+}
+
+/// Stores the sessions in redis so that multiple instances behind
+/// a load balancer can share them. Sessions are stored as json with
+/// an expiration so that redis removes them once the refresh token expired.
+/// The request token and the user of a session are indexed with separate keys.
+#[derive(Clone)]
+pub struct RedisSessionStore {
+    pool: Pool<Client>,
+}
+
+impl RedisSessionStore {
+    /// Creates a new store for the redis server with the given url.
+    /// Connections are established when they are first needed.
+    pub fn new(url: &str) -> DatabaseResult<Self> {
+        let client = Client::open(url)?;
+
+        Ok(Self {
+            pool: Pool::builder().build_unchecked(client),
+        })
+    }
+
+    /// Returns the session with the given id if it's stored
+    fn load(
+        &self,
+        connection: &mut RedisConnection,
+        id: &str,
+    ) -> DatabaseResult<Option<RedisSession>> {
+        let value: Option<String> = connection.get(format!("{}{}", SESSION_PREFIX, id))?;
+        match value {
+            Some(value) => serde_json::from_str(&value)
+                .map(Some)
+                .map_err(|e| DBError::GenericError(format!("Invalid stored session: {}", e))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the id and the session the request token belongs to
+    /// if neither the request token nor the session has expired
+    fn load_by_request_token(
+        &self,
+        connection: &mut RedisConnection,
+        request_token: &str,
+        now: u64,
+    ) -> DatabaseResult<Option<(String, RedisSession)>> {
+        let digest = match decode_digest(request_token) {
+            Some(digest) => to_hex(&digest),
+            None => return Ok(None),
+        };
+        let id: Option<String> = connection.get(format!("{}{}", REQUEST_PREFIX, digest))?;
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        Ok(self
+            .load(connection, &id)?
+            .filter(|session| session.accepts_request_token(&digest, now))
+            .map(|session| (id, session)))
+    }
+
+    /// Stores a new session and its indexes with an expiration at the end of the session
+    fn save(
+        &self,
+        connection: &mut RedisConnection,
+        id: &str,
+        session: &RedisSession,
+        now: u64,
+    ) -> DatabaseResult<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        queue_save(&mut pipe, id, session, now)?;
+        pipe.query::<()>(&mut **connection)?;
+
+        Ok(())
+    }
+
+    /// Loads the session, changes it with the function and stores it again.
+    /// The session key is watched so that the transaction fails if the session
+    /// was changed or removed in the meantime. A logout or revocation is therefore
+    /// never undone. The update is retried with the current state of the session
+    /// in that case. The function returns false if the session can't be updated.
+    fn update<F>(
+        &self,
+        connection: &mut RedisConnection,
+        id: &str,
+        now: u64,
+        mut func: F,
+    ) -> DatabaseResult<Option<RedisSession>>
+    where
+        F: FnMut(&mut RedisSession) -> bool,
+    {
+        let key = format!("{}{}", SESSION_PREFIX, id);
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            redis::cmd("WATCH")
+                .arg(&key)
+                .query::<()>(&mut **connection)?;
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            let result = self.load(connection, id).and_then(|session| {
+                let mut session = match session {
+                    Some(session) => session,
+                    None => return Ok(None),
+                };
+                if !func(&mut session) {
+                    return Ok(None);
+                }
+                queue_save(&mut pipe, id, &session, now)?;
+
+                Ok(Some(session))
+            });
+            let session = match result {
+                Ok(Some(session)) => session,
+                result => {
+                    // the connection returns to the pool and must not keep watching the key
+                    redis::cmd("UNWATCH").query::<()>(&mut **connection)?;
+                    return result;
+                }
+            };
+            // EXEC returns nil if the watched session was changed
+            let executed: Option<()> = pipe.query(&mut **connection)?;
+            if executed.is_some() {
+                return Ok(Some(session));
+            }
+            log::debug!("Session changed while it was updated. Retrying...");
+        }
+
+        Err(DBError::GenericError(
+            "The session was changed too often while it was updated".to_string(),
+        ))
+    }
+
+    /// Removes the session and its indexes
+    fn remove(
+        &self,
+        connection: &mut RedisConnection,
+        id: &str,
+        session: &RedisSession,
+    ) -> DatabaseResult<()> {
+        connection.del::<_, ()>(&[
+            format!("{}{}", SESSION_PREFIX, id),
+            format!("{}{}", REQUEST_PREFIX, session.request_digest),
+        ])?;
+        connection.srem::<_, _, ()>(format!("{}{}", USER_PREFIX, session.user_id), id)?;
+
+        Ok(())
+    }
+
+    /// Returns the stored sessions of the user and removes
+    /// the ids of sessions that expired from the index
+    fn user_sessions(
+        &self,
+        connection: &mut RedisConnection,
+        user_id: i32,
+    ) -> DatabaseResult<Vec<(String, RedisSession)>> {
+        let key = format!("{}{}", USER_PREFIX, user_id);
+        let ids: Vec<String> = connection.smembers(&key)?;
+        let mut sessions = Vec::new();
+        for id in ids {
+            match self.load(connection, &id)? {
+                Some(session) => sessions.push((id, session)),
+                None => connection.srem::<_, _, ()>(&key, &id)?,
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    /// Returns all stored sessions
+    fn all_sessions(
+        &self,
+        connection: &mut RedisConnection,
+    ) -> DatabaseResult<Vec<(String, RedisSession)>> {
+        let keys: Vec<String> = connection
+            .scan_match(format!("{}*", SESSION_PREFIX))?
+            .collect();
+        let mut sessions = Vec::new();
+        for key in keys {
+            let id = key.trim_start_matches(SESSION_PREFIX).to_string();
+            if let Some(session) = self.load(connection, &id)? {
+                sessions.push((id, session));
+            }
+        }
+
+        Ok(sessions)
+    }
+}
+
+impl SessionStore for RedisSessionStore {
+    fn init(&self) -> DatabaseResult<()> {
+        redis::cmd("PING").query::<String>(&mut *self.pool.get()?)?;
+
+        Ok(())
+    }
+
+    fn get_by_request_token(&self, request_token: &str) -> DatabaseResult<Option<TokenStoreEntry>> {
+        let mut connection = self.pool.get()?;
+        let now = unix_now();
+        let (id, digest) = match self.load_by_request_token(&mut connection, request_token, now)? {
+            Some((id, session)) => (id, session.request_digest),
+            None => return Ok(None),
+        };
+        let session = self.update(&mut connection, &id, now, |session| {
+            if !session.accepts_request_token(&digest, now) {
+                return false;
+            }
+            session.last_active_at = now;
+            if *SESSION_SLIDING_EXPIRY {
+                session.slide_expiry(now);
+            }
+            true
+        })?;
+
+        Ok(session.map(|session| session.to_entry(now)))
+    }
+
+    fn get_by_refresh_token(&self, refresh_token: &str) -> DatabaseResult<Option<TokenStoreEntry>> {
+        log::trace!("Retrieving user by refresh token.");
+        let id = match decode_digest(refresh_token) {
+            Some(digest) => to_hex(&digest),
+            None => return Ok(None),
+        };
+        let now = unix_now();
+        let session = self.load(&mut self.pool.get()?, &id)?;
+
+        Ok(session
+            .filter(|s| !s.is_guest() && s.refresh_expires_at() > now)
+            .map(|s| s.to_entry(now)))
+    }
+
+    fn set_request_token(
+        &self,
+        refresh_token: &str,
+        request_token: &str,
+    ) -> DatabaseResult<Option<TokenStoreEntry>> {
+        let id = match decode_digest(refresh_token) {
+            Some(digest) => to_hex(&digest),
+            None => return Ok(None),
+        };
+        let request_digest = decode_digest(request_token)
+            .map(|digest| to_hex(&digest))
+            .ok_or(DBError::GenericError("Invalid request token".to_string()))?;
+        let mut connection = self.pool.get()?;
+        let now = unix_now();
+        let mut previous_digest = None;
+        let session = self.update(&mut connection, &id, now, |session| {
+            if session.is_guest() || session.refresh_expires_at() <= now {
+                return false;
+            }
+            previous_digest = Some(session.request_digest.clone());
+            session.request_digest = request_digest.clone();
+            session.request_expires_at = now + REQUEST_TOKEN_EXPIRE_SECONDS as u64;
+            session.last_active_at = now;
+            true
+        })?;
+        if let Some(previous_digest) = previous_digest.filter(|_| session.is_some()) {
+            // the previous request token is rejected anyway as its digest no longer matches
+            connection.del::<_, ()>(format!("{}{}", REQUEST_PREFIX, previous_digest))?;
+            log::trace!("Request TTL reset");
+        }
+
+        Ok(session.map(|session| session.to_entry(now)))
+    }
+
+    fn insert_session(
+        &self,
+        request_token: &str,
+        refresh_token: &str,
+        kind: TokenKind,
+    ) -> DatabaseResult<()> {
+        let user_id = get_user_id_from_token(refresh_token)
+            .ok_or(DBError::GenericError("Invalid refresh token".to_string()))?;
+        let (request_digest, refresh_digest) =
+            match (decode_digest(request_token), decode_digest(refresh_token)) {
+                (Some(request_digest), Some(refresh_digest)) => (request_digest, refresh_digest),
+                _ => return Err(DBError::GenericError("Invalid token length".to_string())),
+            };
+        let now = unix_now();
+        let session = RedisSession {
+            user_id,
+            kind,
+            request_digest: to_hex(&request_digest),
+            request_expires_at: now + REQUEST_TOKEN_EXPIRE_SECONDS as u64,
+            expires_at: now + *SESSION_LIFETIME as u64,
+            idle_timeout: *SESSION_IDLE_TIMEOUT as u64,
+            last_active_at: now,
+            created_at: Some(now),
+        };
+
+        self.save(
+            &mut self.pool.get()?,
+            &to_hex(&refresh_digest),
+            &session,
+            now,
+        )
+    }
+
+    fn insert_guest(
+        &self,
+        request_token: &str,
+        ttl: u32,
+        permissions: Vec<String>,
+        limit: usize,
+    ) -> DatabaseResult<()> {
+        let digest = decode_digest(request_token)
+            .map(|digest| to_hex(&digest))
+            .ok_or(DBError::GenericError("Invalid token length".to_string()))?;
+        let mut connection = self.pool.get()?;
+        let now = unix_now();
+        let guest_tokens = self
+            .user_sessions(&mut connection, GUEST_USER_ID)?
+            .iter()
+            .filter(|(_, s)| s.request_expires_at > now)
+            .count();
+        if guest_tokens >= limit {
+            return Err(DBError::GenericError("Too many guest tokens".to_string()));
+        }
+        let session = RedisSession {
+            user_id: GUEST_USER_ID,
+            kind: TokenKind::Guest(permissions),
+            request_digest: digest.clone(),
+            request_expires_at: now + ttl as u64,
+            expires_at: now + ttl as u64,
+            idle_timeout: ttl as u64,
+            last_active_at: now,
+            created_at: Some(now),
+        };
+
+        self.save(&mut connection, &format!("guest:{}", digest), &session, now)
+    }
+
+    fn extend_request_ttl(&self, request_token: &str) -> DatabaseResult<Option<TokenStoreEntry>> {
+        let mut connection = self.pool.get()?;
+        let now = unix_now();
+        let (id, digest) = match self.load_by_request_token(&mut connection, request_token, now)? {
+            Some((id, session)) if !session.is_guest() => (id, session.request_digest),
+            _ => return Ok(None),
+        };
+        let session = self.update(&mut connection, &id, now, |session| {
+            if !session.accepts_request_token(&digest, now) {
+                return false;
+            }
+            session.last_active_at = now;
+            session.request_expires_at = min(
+                now + REQUEST_TOKEN_EXPIRE_SECONDS as u64,
+                session.refresh_expires_at(),
+            );
+            true
+        })?;
+
+        Ok(session.map(|session| session.to_entry(now)))
+    }
+
+    fn remove_by_request_token(&self, request_token: &str) -> DatabaseResult<bool> {
+        let mut connection = self.pool.get()?;
+        match self.load_by_request_token(&mut connection, request_token, unix_now())? {
+            Some((id, session)) => {
+                self.remove(&mut connection, &id, &session)?;
+                log::trace!("Tokens invalidated.");
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn remove_client(&self, user_id: Option<i32>, client_id: &str) -> DatabaseResult<usize> {
+        let mut connection = self.pool.get()?;
+        let sessions = match user_id {
+            Some(user_id) => self.user_sessions(&mut connection, user_id)?,
+            None => self.all_sessions(&mut connection)?,
+        };
+        let mut removed = 0;
+        for (id, session) in sessions {
+            match &session.kind {
+                TokenKind::Client { client_id: c, .. } if c == client_id => {
+                    self.remove(&mut connection, &id, &session)?;
+                    removed += 1;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn active_sessions(&self, user_id: i32) -> DatabaseResult<usize> {
+        let now = unix_now();
+        let sessions = self.user_sessions(&mut self.pool.get()?, user_id)?;
+
+        Ok(sessions
+            .iter()
+            .filter(|(_, s)| s.refresh_expires_at() > now)
+            .count())
+    }
+
+    fn list_sessions(&self, user_id: i32) -> DatabaseResult<Vec<SessionInfo>> {
+        let now = unix_now();
+        let mut sessions = self.user_sessions(&mut self.pool.get()?, user_id)?;
+        sessions.retain(|(_, s)| s.refresh_expires_at() > now);
+        sessions.sort_by_key(|(_, s)| s.created_at);
+
+        Ok(sessions.iter().map(|(_, s)| s.to_info(now)).collect())
+    }
+
+    fn remove_user(&self, user_id: i32) -> DatabaseResult<usize> {
+        let mut connection = self.pool.get()?;
+        let sessions = self.user_sessions(&mut connection, user_id)?;
+        for (id, session) in &sessions {
+            self.remove(&mut connection, id, session)?;
+        }
+
+        Ok(sessions.len())
+    }
+
+    fn stats(&self) -> DatabaseResult<TokenStoreStats> {
+        let mut connection = self.pool.get()?;
+        let now = unix_now();
+        let sessions = self.all_sessions(&mut connection)?;
+        let mut users: HashMap<i32, usize> = HashMap::new();
+        for (_, session) in &sessions {
+            if session.refresh_expires_at() > now {
+                *users.entry(session.user_id).or_insert(0) += 1;
+            }
+        }
+        let mut sessions_per_user = users.values().cloned().collect::<Vec<usize>>();
+        sessions_per_user.sort();
+        let size_bytes = sessions
+            .iter()
+            .filter_map(|(_, s)| serde_json::to_string(s).ok())
+            .map(|s| s.len())
+            .sum();
+
+        Ok(TokenStoreStats {
+            active_sessions: sessions_per_user.iter().sum(),
+            users: sessions_per_user.len(),
+            stored_entries: sessions.len(),
+            size_bytes,
+            sessions_per_user,
+        })
+    }
+
+    /// Redis removes expired sessions by itself so only
+    /// the user indexes are cleared from ids of expired sessions
+    fn clear_expired(&self) -> DatabaseResult<usize> {
+        log::trace!("Clearing expired tokens...");
+        let mut connection = self.pool.get()?;
+        let keys: Vec<String> = connection
+            .scan_match(format!("{}*", USER_PREFIX))?
+            .collect();
+        let mut removed = 0;
+        for key in keys {
+            let ids: Vec<String> = connection.smembers(&key)?;
+            for id in ids {
+                let exists: bool = connection.exists(format!("{}{}", SESSION_PREFIX, id))?;
+                if !exists {
+                    connection.srem::<_, _, ()>(&key, &id)?;
+                    removed += 1;
+                }
+            }
+        }
+        log::trace!("Cleared {} expired tokens", removed);
+
+        Ok(removed)
+    }
+}
+
+/// Adds the commands to the pipeline that store the session and its indexes
+/// with an expiration at the end of the session. Expired sessions are removed.
+fn queue_save(
+    pipe: &mut Pipeline,
+    id: &str,
+    session: &RedisSession,
+    now: u64,
+) -> DatabaseResult<()> {
+    let ttl = session.refresh_expires_at().saturating_sub(now) as usize;
+    let request_ttl = session.request_expires_at.saturating_sub(now) as usize;
+    let user_key = format!("{}{}", USER_PREFIX, session.user_id);
+    if ttl == 0 {
+        pipe.del(&[
+            format!("{}{}", SESSION_PREFIX, id),
+            format!("{}{}", REQUEST_PREFIX, session.request_digest),
+        ])
+        .ignore()
+        .srem(user_key, id)
+        .ignore();
+        return Ok(());
+    }
+    let value = serde_json::to_string(session)
+        .map_err(|e| DBError::GenericError(format!("Failed to store session: {}", e)))?;
+    pipe.set_ex(format!("{}{}", SESSION_PREFIX, id), value, ttl)
+        .ignore();
+    if request_ttl > 0 {
+        pipe.set_ex(
+            format!("{}{}", REQUEST_PREFIX, session.request_digest),
+            id,
+            request_ttl,
+        )
+        .ignore();
+    }
+    pipe.sadd(user_key, id).ignore();
+
+    Ok(())
+}
+
+/// Returns the remaining seconds until the timestamp or -1 if it has passed
+fn ttl(expires_at: u64, now: u64) -> i32 {
+    max(expires_at as i64 - now as i64, -1) as i32
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -2,14 +2,35 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
-use crate::database::models::Role;
-use crate::database::role_permissions::RolePermissions;
-use crate::database::{
-    DatabaseResult, PostgresPool, Table, ADMIN_ROLE_NAME, DEFAULT_ADMIN_EMAIL, ENV_ADMIN_EMAIL,
+use crate::database::change_history::{self, Changes, ENTITY_ROLE};
+use crate::database::limits::{Resource, ResourceLimits};
+use crate::database::models::{
+    ConflictStrategy, Pagination, RenamedRole, Role, RoleExport, RoleImport, Sorting, TrashedRole,
 };
+use crate::database::permission_cache::PERMISSION_CACHE;
+use crate::database::role_permissions::RolePermissions;
+use crate::database::{DatabaseResult, PostgresPool, Table, ADMIN_ROLE_NAME};
+use crate::utils::config;
 use crate::utils::error::DBError;
-use std::collections::HashSet;
+use postgres::Transaction;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
+use std::thread::{self, Builder};
+use std::time::Duration;
+
+pub(crate) const ENV_ROLE_TRASH_RETENTION_DAYS: &str = "ROLE_TRASH_RETENTION_DAYS";
+pub(crate) const DEFAULT_ROLE_TRASH_RETENTION_DAYS: i32 = 30;
+const PURGE_JOB_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const ROLE_COLUMNS: &str = "roles.id, roles.name, roles.description, roles.enabled";
+/// The columns the role list can be sorted by
+const ROLE_SORT_COLUMNS: &[&str] = &["id", "name"];
+
+lazy_static::lazy_static! {
+    static ref ROLE_TRASH_RETENTION_DAYS: i32 = config::var(ENV_ROLE_TRASH_RETENTION_DAYS)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_ROLE_TRASH_RETENTION_DAYS);
+}
 
 /// The role table that stores
 /// all defined roles
@@ -26,26 +47,13 @@ impl Table for Roles {
             pool,
         }
     }
-
-    fn init(&self) -> DatabaseResult<()> {
-        self.pool.get()?.batch_execute(
-            "
-            CREATE TABLE IF NOT EXISTS roles (
-            id              SERIAL PRIMARY KEY,
-            name            VARCHAR(128) UNIQUE NOT NULL,
-            description     VARCHAR(512)
-        );",
-        )?;
-
-        Ok(())
-    }
 }
 
 impl Roles {
     /// Creates a new role with the given permissions
     /// that are then automatically assigned to the role
     ///
-    /// The role is automatically assigned to the default admin user
+    /// The role is automatically assigned to all members of the admin role
     pub fn create_role(
         &self,
         name: String,
@@ -61,36 +69,262 @@ impl Roles {
         }
 
         log::trace!("Preparing transaction");
-        let admin_email = dotenv::var(ENV_ADMIN_EMAIL).unwrap_or(DEFAULT_ADMIN_EMAIL.to_string());
         let mut transaction = connection.transaction()?;
+        let role = Self::insert_role(&mut transaction, &name, &description, true, &permissions)?;
+        transaction.commit()?;
+        PERMISSION_CACHE.invalidate_all();
 
+        Ok(role)
+    }
+
+    /// Inserts a role with its permissions as part of the transaction
+    /// and assigns it to all members of the admin role
+    fn insert_role(
+        transaction: &mut Transaction,
+        name: &String,
+        description: &Option<String>,
+        enabled: bool,
+        permissions: &HashSet<i32>,
+    ) -> DatabaseResult<Role> {
+        let role_count: i64 = transaction
+            .query_one("SELECT COUNT(*) FROM roles", &[])?
+            .get(0);
+        ResourceLimits::get().check(Resource::Roles, role_count as u64)?;
         let row = transaction.query_one(
-            "INSERT INTO roles (name, description) VALUES ($1, $2) RETURNING *",
-            &[&name, &description],
+            format!(
+                "INSERT INTO roles (name, description, enabled) VALUES ($1, $2, $3) RETURNING {}",
+                ROLE_COLUMNS
+            )
+            .as_str(),
+            &[name, description, &enabled],
         )?;
         let role: Role = serde_postgres::from_row(&row)?;
         for permission in permissions {
             transaction.execute(
                 "INSERT INTO role_permissions (role_id, permission_id) VALUES ($1, $2);",
-                &[&role.id, &permission],
+                &[&role.id, permission],
             )?;
         }
+        let permission_names = Self::permission_names(transaction, role.id)?;
+        change_history::record(
+            transaction,
+            ENTITY_ROLE,
+            role.id,
+            &role.name,
+            Changes::new()
+                .created("name", &role.name)
+                .created("description", &role.description)
+                .created("enabled", &role.enabled)
+                .created("permissions", &permission_names),
+        )?;
         if let Err(e) = transaction.execute(
-            "INSERT INTO user_roles (user_id, role_id) VALUES ((SELECT id FROM users WHERE email = $1), $2)",
-            &[&admin_email, &role.id],
+            "INSERT INTO user_roles (user_id, role_id) SELECT user_roles.user_id, $2 FROM user_roles, roles WHERE roles.id = user_roles.role_id AND roles.name = $1",
+            &[&ADMIN_ROLE_NAME, &role.id],
         ) {
-            log::debug!("Failed to add role to admin user: {}", e);
+            log::debug!("Failed to add role to admin users: {}", e);
         }
 
+        Ok(role)
+    }
+
+    /// Returns all roles except the admin role with the names of their permissions
+    pub fn export_roles(&self) -> DatabaseResult<Vec<RoleExport>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            "SELECT roles.name, roles.description, roles.enabled,
+                ARRAY_REMOVE(ARRAY_AGG(permissions.name ORDER BY permissions.name), NULL)
+            FROM roles
+            LEFT JOIN role_permissions ON role_permissions.role_id = roles.id
+            LEFT JOIN permissions ON permissions.id = role_permissions.permission_id
+            WHERE roles.name <> $1 AND roles.deleted_at IS NULL
+            GROUP BY roles.id ORDER BY roles.name",
+            &[&ADMIN_ROLE_NAME],
+        )?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| RoleExport {
+                name: row.get(0),
+                description: row.get(1),
+                enabled: row.get(2),
+                permissions: row.get(3),
+            })
+            .collect())
+    }
+
+    /// Imports exported roles in a single transaction. Roles that already exist
+    /// are handled according to the strategy. The admin role is always skipped.
+    /// All permissions of the imported roles need to exist.
+    pub fn import_roles(
+        &self,
+        roles: &Vec<RoleExport>,
+        strategy: ConflictStrategy,
+    ) -> DatabaseResult<RoleImport> {
+        let mut names = HashSet::new();
+        if let Some(role) = roles.iter().find(|role| !names.insert(&role.name)) {
+            return Err(DBError::GenericError(format!(
+                "The role {} is contained more than once",
+                role.name
+            )));
+        }
+        let permission_names = roles
+            .iter()
+            .flat_map(|role| role.permissions.iter().cloned())
+            .collect::<HashSet<String>>()
+            .into_iter()
+            .collect::<Vec<String>>();
+        let mut connection = self.pool.get()?;
+        let permission_ids = connection
+            .query(
+                "SELECT name, id FROM permissions WHERE name = ANY ($1)",
+                &[&permission_names],
+            )?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect::<HashMap<String, i32>>();
+        let mut missing = permission_names
+            .iter()
+            .filter(|name| !permission_ids.contains_key(*name))
+            .collect::<Vec<&String>>();
+        if !missing.is_empty() {
+            missing.sort();
+            return Err(DBError::GenericError(format!(
+                "The permissions {:?} don't exist",
+                missing
+            )));
+        }
+
+        let mut transaction = connection.transaction()?;
+        let mut result = RoleImport::default();
+        for imported in roles {
+            let permissions = imported
+                .permissions
+                .iter()
+                .map(|name| permission_ids[name])
+                .collect::<HashSet<i32>>();
+            if imported.name == ADMIN_ROLE_NAME {
+                result.skipped.push(imported.name.clone());
+                continue;
+            }
+            let existing = transaction.query_opt(
+                format!(
+                    "SELECT {} FROM roles WHERE name = $1 FOR UPDATE",
+                    ROLE_COLUMNS
+                )
+                .as_str(),
+                &[&imported.name],
+            )?;
+            let existing = if let Some(row) = existing {
+                serde_postgres::from_row::<Role>(&row)?
+            } else {
+                Self::insert_role(
+                    &mut transaction,
+                    &imported.name,
+                    &imported.description,
+                    imported.enabled,
+                    &permissions,
+                )?;
+                result.created.push(imported.name.clone());
+                continue;
+            };
+
+            match strategy {
+                ConflictStrategy::Skip => result.skipped.push(imported.name.clone()),
+                ConflictStrategy::Overwrite => {
+                    Self::overwrite_role(&mut transaction, &existing, imported, &permissions)?;
+                    result.overwritten.push(imported.name.clone())
+                }
+                ConflictStrategy::Rename => {
+                    let name = Self::free_role_name(&mut transaction, &imported.name)?;
+                    Self::insert_role(
+                        &mut transaction,
+                        &name,
+                        &imported.description,
+                        imported.enabled,
+                        &permissions,
+                    )?;
+                    result.renamed.push(RenamedRole {
+                        from: imported.name.clone(),
+                        to: name,
+                    });
+                }
+            }
+        }
         transaction.commit()?;
+        PERMISSION_CACHE.invalidate_all();
 
-        Ok(role)
+        Ok(result)
+    }
+
+    /// Replaces the description, state and permissions of an existing role
+    fn overwrite_role(
+        transaction: &mut Transaction,
+        existing: &Role,
+        imported: &RoleExport,
+        permissions: &HashSet<i32>,
+    ) -> DatabaseResult<()> {
+        let old_permission_names = Self::permission_names(transaction, existing.id)?;
+        let row = transaction.query_one(
+            format!(
+                "UPDATE roles SET description = $2, enabled = $3 WHERE id = $1 RETURNING {}",
+                ROLE_COLUMNS
+            )
+            .as_str(),
+            &[&existing.id, &imported.description, &imported.enabled],
+        )?;
+        let role = serde_postgres::from_row::<Role>(&row)?;
+        transaction.execute(
+            "DELETE FROM role_permissions WHERE role_id = $1",
+            &[&existing.id],
+        )?;
+        transaction.execute(
+            "INSERT INTO role_permissions (role_id, permission_id) SELECT $1, unnest($2::INT[])",
+            &[
+                &existing.id,
+                &permissions.iter().cloned().collect::<Vec<i32>>(),
+            ],
+        )?;
+        let new_permission_names = Self::permission_names(transaction, existing.id)?;
+        change_history::record(
+            transaction,
+            ENTITY_ROLE,
+            role.id,
+            &role.name,
+            Changes::new()
+                .field("description", &existing.description, &role.description)
+                .field("enabled", &existing.enabled, &role.enabled)
+                .field("permissions", &old_permission_names, &new_permission_names),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the name with the lowest numeric suffix that isn't used by a role
+    fn free_role_name(transaction: &mut Transaction, name: &String) -> DatabaseResult<String> {
+        let mut suffix = 2;
+        loop {
+            let candidate = format!("{}_{}", name, suffix);
+            if transaction
+                .query_opt("SELECT id FROM roles WHERE name = $1", &[&candidate])?
+                .is_none()
+            {
+                return Ok(candidate);
+            }
+            suffix += 1;
+        }
     }
 
     /// Returns information for a role
     pub fn get_role(&self, name: String) -> DatabaseResult<Role> {
         let mut connection = self.pool.get()?;
-        let result = connection.query_opt("SELECT * FROM roles WHERE roles.name = $1", &[&name])?;
+        let result = connection.query_opt(
+            format!(
+                "SELECT {} FROM roles WHERE roles.name = $1 AND deleted_at IS NULL",
+                ROLE_COLUMNS
+            )
+            .as_str(),
+            &[&name],
+        )?;
 
         if let Some(row) = result {
             Ok(serde_postgres::from_row::<Role>(&row)?)
@@ -99,17 +333,32 @@ impl Roles {
         }
     }
 
-    /// Returns a list of all roles
-    pub fn get_roles(&self) -> DatabaseResult<Vec<Role>> {
+    /// Returns the requested page of roles together with the total number of roles
+    pub fn get_roles(
+        &self,
+        sorting: &Sorting,
+        pagination: &Pagination,
+    ) -> DatabaseResult<(Vec<Role>, i64)> {
+        let order_by = sorting.order_by(ROLE_SORT_COLUMNS)?;
         let mut connection = self.pool.get()?;
-        let results = connection.query("SELECT * FROM roles", &[])?;
+        let total: i64 = connection
+            .query_one("SELECT COUNT(*) FROM roles WHERE deleted_at IS NULL", &[])?
+            .get(0);
+        let results = connection.query(
+            format!(
+                "SELECT {} FROM roles WHERE deleted_at IS NULL {} LIMIT $1 OFFSET $2",
+                ROLE_COLUMNS, order_by
+            )
+            .as_str(),
+            &[&pagination.limit, &pagination.offset],
+        )?;
         let mut roles = Vec::new();
 
         for row in results {
             roles.push(serde_postgres::from_row::<Role>(&row)?);
         }
 
-        Ok(roles)
+        Ok((roles, total))
     }
 
     pub fn update_role(
@@ -120,18 +369,26 @@ impl Roles {
         permissions: Vec<i32>,
     ) -> DatabaseResult<Role> {
         if old_name == ADMIN_ROLE_NAME {
-            return Err(DBError::GenericError(
-                "The admin role can't be altered!".to_string(),
-            ));
+            return Err(DBError::SystemRecord(ADMIN_ROLE_NAME.to_string()));
         }
         let permissions = HashSet::from_iter(permissions.into_iter());
         let mut connection = self.pool.get()?;
         let mut transaction = connection.transaction()?;
 
-        let id: i32 = transaction
-            .query_opt("SELECT id FROM roles WHERE name = $1", &[&old_name])?
-            .ok_or(DBError::RecordDoesNotExist)?
-            .get(0);
+        let old_role: Role = serde_postgres::from_row(
+            &transaction
+                .query_opt(
+                    format!(
+                        "SELECT {} FROM roles WHERE name = $1 AND deleted_at IS NULL FOR UPDATE",
+                        ROLE_COLUMNS
+                    )
+                    .as_str(),
+                    &[&old_name],
+                )?
+                .ok_or(DBError::RecordDoesNotExist)?,
+        )?;
+        let id = old_role.id;
+        let old_permission_names = Self::permission_names(&mut transaction, id)?;
         let name_exists =
             transaction.query_opt("SELECT id FROM roles WHERE name = $1", &[&name])?;
         if name_exists.is_some() {
@@ -141,7 +398,11 @@ impl Roles {
             )));
         }
         let update_result = transaction.query_one(
-            "UPDATE roles SET name = $3, description = $2 WHERE id = $1 RETURNING *",
+            format!(
+                "UPDATE roles SET name = $3, description = $2 WHERE id = $1 RETURNING {}",
+                ROLE_COLUMNS
+            )
+            .as_str(),
             &[&id, &description, &name],
         )?;
         let current_permissions = transaction
@@ -152,42 +413,217 @@ impl Roles {
             .into_iter()
             .map(|r| -> i32 { r.get(0) })
             .collect::<HashSet<i32>>();
-        let new_permissions = permissions.difference(&current_permissions);
-        let deleted_permissions = current_permissions.difference(&permissions);
+        let new_permissions = permissions
+            .difference(&current_permissions)
+            .cloned()
+            .collect::<Vec<i32>>();
+        let deleted_permissions = current_permissions
+            .difference(&permissions)
+            .cloned()
+            .collect::<Vec<i32>>();
 
-        for new in new_permissions {
-            transaction.query(
-                "INSERT INTO role_permissions (role_id, permission_id) VALUES ($1, $2)",
-                &[&id, new],
+        if !new_permissions.is_empty() {
+            transaction.execute(
+                "INSERT INTO role_permissions (role_id, permission_id) SELECT $1, unnest($2::INT[])",
+                &[&id, &new_permissions],
             )?;
         }
-        for deleted in deleted_permissions {
-            transaction.query(
-                "DELETE FROM role_permissions WHERE role_id = $1 AND permission_id = $2",
-                &[&id, deleted],
+        if !deleted_permissions.is_empty() {
+            transaction.execute(
+                "DELETE FROM role_permissions WHERE role_id = $1 AND permission_id = ANY($2)",
+                &[&id, &deleted_permissions],
             )?;
         }
+        let role = serde_postgres::from_row::<Role>(&update_result)?;
+        let new_permission_names = Self::permission_names(&mut transaction, id)?;
+        change_history::record(
+            &mut transaction,
+            ENTITY_ROLE,
+            id,
+            &role.name,
+            Changes::new()
+                .field("name", &old_role.name, &role.name)
+                .field("description", &old_role.description, &role.description)
+                .field("permissions", &old_permission_names, &new_permission_names),
+        )?;
         transaction.commit()?;
+        PERMISSION_CACHE.invalidate_all();
 
-        Ok(serde_postgres::from_row::<Role>(&update_result)?)
+        Ok(role)
     }
 
-    /// Deletes a role if it exists
-    pub fn delete_role(&self, name: &String) -> DatabaseResult<()> {
+    /// Enables or disables a role. The permissions of a disabled role
+    /// aren't granted to its members until the role is enabled again.
+    pub fn set_enabled(&self, name: &String, enabled: bool) -> DatabaseResult<Role> {
         if name == ADMIN_ROLE_NAME {
-            return Err(DBError::GenericError(
-                "The admin role can't be altered!".to_string(),
-            ));
+            return Err(DBError::SystemRecord(ADMIN_ROLE_NAME.to_string()));
         }
         let mut connection = self.pool.get()?;
-        let result = connection.query_opt("SELECT id FROM roles WHERE name = $1", &[name])?;
+        let mut transaction = connection.transaction()?;
+        let old_enabled: bool = transaction
+            .query_opt(
+                "SELECT enabled FROM roles WHERE name = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[name],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?
+            .get(0);
+        let row = transaction.query_one(
+            format!(
+                "UPDATE roles SET enabled = $2 WHERE name = $1 RETURNING {}",
+                ROLE_COLUMNS
+            )
+            .as_str(),
+            &[name, &enabled],
+        )?;
+        let role = serde_postgres::from_row::<Role>(&row)?;
+        change_history::record(
+            &mut transaction,
+            ENTITY_ROLE,
+            role.id,
+            &role.name,
+            Changes::new().field("enabled", &old_enabled, &enabled),
+        )?;
+        transaction.commit()?;
+        PERMISSION_CACHE.invalidate_all();
 
-        if result.is_none() {
-            Err(DBError::RecordDoesNotExist)
-        } else {
-            connection.query("DELETE FROM roles WHERE name = $1", &[name])?;
+        Ok(role)
+    }
+
+    /// Moves a role to the trash. Trashed roles don't grant their permissions
+    /// but keep their assignments until they are restored or purged.
+    /// Returns the time after which the role is purged.
+    pub fn delete_role(&self, name: &String) -> DatabaseResult<String> {
+        if name == ADMIN_ROLE_NAME {
+            return Err(DBError::SystemRecord(ADMIN_ROLE_NAME.to_string()));
+        }
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "UPDATE roles SET deleted_at = NOW() WHERE name = $1 AND deleted_at IS NULL
+                RETURNING id, to_char((deleted_at + make_interval(days => $2)) AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"')",
+                &[name, &*ROLE_TRASH_RETENTION_DAYS],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let id: i32 = row.get(0);
+        change_history::record(
+            &mut transaction,
+            ENTITY_ROLE,
+            id,
+            name,
+            Changes::new().field("trashed", &false, &true),
+        )?;
+        transaction.commit()?;
+        PERMISSION_CACHE.invalidate_all();
+
+        Ok(row.get(1))
+    }
+
+    /// Returns all roles in the trash
+    pub fn get_trashed_roles(&self) -> DatabaseResult<Vec<TrashedRole>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            "SELECT name, description,
+                to_char(deleted_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS deleted_at,
+                to_char((deleted_at + make_interval(days => $1)) AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS purge_at
+            FROM roles WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC",
+            &[&*ROLE_TRASH_RETENTION_DAYS],
+        )?;
+
+        Ok(rows.into_iter().map(TrashedRole::from_row).collect())
+    }
+
+    /// Restores a trashed role together with its assignments
+    pub fn restore_role(&self, name: &String) -> DatabaseResult<Role> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                format!(
+                    "UPDATE roles SET deleted_at = NULL WHERE name = $1 AND deleted_at IS NOT NULL RETURNING {}",
+                    ROLE_COLUMNS
+                )
+                .as_str(),
+                &[name],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let role = serde_postgres::from_row::<Role>(&row)?;
+        change_history::record(
+            &mut transaction,
+            ENTITY_ROLE,
+            role.id,
+            &role.name,
+            Changes::new().field("trashed", &true, &false),
+        )?;
+        transaction.commit()?;
+        PERMISSION_CACHE.invalidate_all();
+
+        Ok(role)
+    }
 
-            Ok(())
+    /// Starts the background job that permanently deletes roles
+    /// that have been in the trash for longer than the retention window
+    pub fn start_purge_job(&self) {
+        let roles = Roles::clone(self);
+
+        Builder::new()
+            .name("role-purge".to_string())
+            .spawn(move || loop {
+                if let Err(e) = roles.purge_trashed_roles() {
+                    log::error!("Failed to purge trashed roles: {}", e);
+                }
+                thread::sleep(PURGE_JOB_INTERVAL);
+            })
+            .unwrap();
+    }
+
+    /// Permanently deletes the roles whose retention window ended
+    /// together with their assignments
+    fn purge_trashed_roles(&self) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let rows = transaction.query(
+            format!(
+                "SELECT {} FROM roles
+                WHERE deleted_at < NOW() - make_interval(days => $1) FOR UPDATE",
+                ROLE_COLUMNS
+            )
+            .as_str(),
+            &[&*ROLE_TRASH_RETENTION_DAYS],
+        )?;
+        for row in rows {
+            let role = serde_postgres::from_row::<Role>(&row)?;
+            let permission_names = Self::permission_names(&mut transaction, role.id)?;
+            transaction.execute("DELETE FROM roles WHERE id = $1", &[&role.id])?;
+            change_history::record(
+                &mut transaction,
+                ENTITY_ROLE,
+                role.id,
+                &role.name,
+                Changes::new()
+                    .deleted("name", &role.name)
+                    .deleted("description", &role.description)
+                    .deleted("permissions", &permission_names),
+            )?;
+            log::info!("Purged the trashed role {}", role.name);
         }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Returns the sorted names of the permissions assigned to a role
+    fn permission_names(
+        transaction: &mut Transaction,
+        role_id: i32,
+    ) -> DatabaseResult<Vec<String>> {
+        let rows = transaction.query(
+            "SELECT permissions.name FROM role_permissions, permissions
+            WHERE role_permissions.role_id = $1 AND permissions.id = role_permissions.permission_id
+            ORDER BY permissions.name",
+            &[&role_id],
+        )?;
+
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
     }
 }
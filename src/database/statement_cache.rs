@@ -0,0 +1,226 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::time::Instant;
+
+use postgres::types::ToSql;
+use postgres::{NoTls, Row, Statement, ToStatement};
+use r2d2::ManageConnection;
+use r2d2_postgres::PostgresConnectionManager;
+
+use crate::utils::config;
+use crate::utils::error::{DatabaseClient, PostgresError};
+
+pub(crate) const ENV_STATEMENT_TIMEOUT: &str = "DB_STATEMENT_TIMEOUT_MS";
+pub(crate) const ENV_SLOW_QUERY_THRESHOLD: &str = "DB_SLOW_QUERY_MS";
+pub(crate) const DEFAULT_STATEMENT_TIMEOUT: u64 = 30000;
+pub(crate) const DEFAULT_SLOW_QUERY_THRESHOLD: u128 = 500;
+
+lazy_static::lazy_static! {
+    static ref STATEMENT_TIMEOUT: u64 = config::var(ENV_STATEMENT_TIMEOUT)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_STATEMENT_TIMEOUT);
+    static ref SLOW_QUERY_THRESHOLD: u128 = config::var(ENV_SLOW_QUERY_THRESHOLD)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_SLOW_QUERY_THRESHOLD);
+}
+
+/// Describes a query in the slow query log without the values of its parameters
+pub trait QueryDescription {
+    fn describe(&self) -> String;
+}
+
+impl QueryDescription for str {
+    fn describe(&self) -> String {
+        self.split_whitespace().collect::<Vec<&str>>().join(" ")
+    }
+}
+
+impl QueryDescription for String {
+    fn describe(&self) -> String {
+        self.as_str().describe()
+    }
+}
+
+impl QueryDescription for Statement {
+    fn describe(&self) -> String {
+        format!(
+            "<prepared statement ({}) returning ({})>",
+            self.params()
+                .iter()
+                .map(|t| t.name())
+                .collect::<Vec<&str>>()
+                .join(", "),
+            self.columns()
+                .iter()
+                .map(|c| c.name())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )
+    }
+}
+
+/// A postgres client that keeps the statements prepared on
+/// its connection so they can be reused by later requests
+pub struct CachedClient {
+    client: DatabaseClient,
+    statements: HashMap<&'static str, Statement>,
+}
+
+impl CachedClient {
+    fn new(client: DatabaseClient) -> Self {
+        Self {
+            client,
+            statements: HashMap::new(),
+        }
+    }
+
+    /// Returns the prepared statement for the query. The query is only
+    /// parsed by the server the first time it's used on this connection.
+    pub fn prepare_cached(&mut self, query: &'static str) -> Result<Statement, PostgresError> {
+        if let Some(statement) = self.statements.get(query) {
+            return Ok(statement.clone());
+        }
+        log::trace!("Preparing statement {}", query);
+        let statement = self.client.prepare(query)?;
+        self.statements.insert(query, statement.clone());
+
+        Ok(statement)
+    }
+
+    /// Executes a statement and logs it if it's slow
+    pub fn execute<T>(
+        &mut self,
+        query: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<u64, PostgresError>
+    where
+        T: ?Sized + ToStatement + QueryDescription,
+    {
+        let _span = crate::telemetry_span!("sql_query", db.statement = query.describe());
+        let started = Instant::now();
+        let result = self.client.execute(query, params);
+        log_if_slow(query, params.len(), started);
+
+        result
+    }
+
+    /// Executes a query and logs it if it's slow
+    pub fn query<T>(
+        &mut self,
+        query: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, PostgresError>
+    where
+        T: ?Sized + ToStatement + QueryDescription,
+    {
+        let _span = crate::telemetry_span!("sql_query", db.statement = query.describe());
+        let started = Instant::now();
+        let result = self.client.query(query, params);
+        log_if_slow(query, params.len(), started);
+
+        result
+    }
+
+    /// Executes a query that returns exactly one row and logs it if it's slow
+    pub fn query_one<T>(
+        &mut self,
+        query: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Row, PostgresError>
+    where
+        T: ?Sized + ToStatement + QueryDescription,
+    {
+        let _span = crate::telemetry_span!("sql_query", db.statement = query.describe());
+        let started = Instant::now();
+        let result = self.client.query_one(query, params);
+        log_if_slow(query, params.len(), started);
+
+        result
+    }
+
+    /// Executes a query that returns at most one row and logs it if it's slow
+    pub fn query_opt<T>(
+        &mut self,
+        query: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<Row>, PostgresError>
+    where
+        T: ?Sized + ToStatement + QueryDescription,
+    {
+        let _span = crate::telemetry_span!("sql_query", db.statement = query.describe());
+        let started = Instant::now();
+        let result = self.client.query_opt(query, params);
+        log_if_slow(query, params.len(), started);
+
+        result
+    }
+}
+
+/// Logs the query with the number of its parameters
+/// if it took longer than the configured threshold
+fn log_if_slow<T: ?Sized + QueryDescription>(query: &T, param_count: usize, started: Instant) {
+    let duration = started.elapsed().as_millis();
+    if duration >= *SLOW_QUERY_THRESHOLD {
+        log::warn!(
+            "Slow query took {}ms ({} parameters redacted): {}",
+            duration,
+            param_count,
+            query.describe()
+        );
+    }
+}
+
+impl Deref for CachedClient {
+    type Target = DatabaseClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl DerefMut for CachedClient {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.client
+    }
+}
+
+/// A connection manager for the r2d2 pool that creates
+/// clients with a statement cache
+#[derive(Debug)]
+pub struct CachingConnectionManager {
+    inner: PostgresConnectionManager<NoTls>,
+}
+
+impl CachingConnectionManager {
+    pub fn new(inner: PostgresConnectionManager<NoTls>) -> Self {
+        Self { inner }
+    }
+}
+
+impl ManageConnection for CachingConnectionManager {
+    type Connection = CachedClient;
+    type Error = PostgresError;
+
+    /// Connects to the database and sets the configured statement timeout
+    /// so that no query can block a worker indefinitely
+    fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        let mut client = self.inner.connect()?;
+        client.batch_execute(&format!("SET statement_timeout = {}", *STATEMENT_TIMEOUT))?;
+
+        Ok(CachedClient::new(client))
+    }
+
+    fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        self.inner.is_valid(&mut conn.client)
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        self.inner.has_broken(&mut conn.client)
+    }
+}
@@ -2,17 +2,68 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
-use std::cmp::max;
-use std::collections::HashMap;
-use std::time::Instant;
+use std::cmp::min;
+use std::sync::Arc;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use zeroize::Zeroize;
 
+use crate::database::postgres_sessions::PostgresSessionStore;
+use crate::database::redis_sessions::{RedisSessionStore, DEFAULT_REDIS_URL, ENV_REDIS_URL};
+use crate::database::{DatabaseResult, PostgresPool};
+use crate::utils::config::{self, Config};
+use crate::utils::error::DBError;
 use crate::utils::{create_user_token, get_user_id_from_token, TOKEN_LENGTH};
 
-const REQUEST_TOKEN_EXPIRE_SECONDS: u32 = 60 * 10;
-const REFRESH_TOKEN_EXPIRE_SECONDS: u32 = 60 * 60 * 24;
+pub(crate) const REQUEST_TOKEN_EXPIRE_SECONDS: u32 = 60 * 10;
+const TOKEN_DIGEST_LENGTH: usize = 32;
+pub(crate) const ENV_SESSION_LIFETIME: &str = "SESSION_LIFETIME";
+pub(crate) const ENV_SESSION_IDLE_TIMEOUT: &str = "SESSION_IDLE_TIMEOUT";
+pub(crate) const DEFAULT_SESSION_LIFETIME: u32 = 60 * 60 * 24;
+pub(crate) const DEFAULT_SESSION_IDLE_TIMEOUT: u32 = 60 * 60;
+pub(crate) const ENV_SESSION_SLIDING_EXPIRY: &str = "SESSION_SLIDING_EXPIRY";
+pub(crate) const ENV_SESSION_MAX_LIFETIME: &str = "SESSION_MAX_LIFETIME";
+pub(crate) const DEFAULT_SESSION_MAX_LIFETIME: u32 = 60 * 60 * 24 * 7;
+pub(crate) const ENV_SESSION_STORE: &str = "SESSION_STORE";
+pub(crate) const DEFAULT_SESSION_STORE: &str = "postgres";
+
+lazy_static::lazy_static! {
+    pub(crate) static ref SESSION_LIFETIME: u32 = Config::get().sessions.lifetime;
+    pub(crate) static ref SESSION_IDLE_TIMEOUT: u32 = Config::get().sessions.idle_timeout;
+    /// If validating a request token moves the end of the session lifetime forward
+    /// so that active sessions only expire after the maximum lifetime
+    pub(crate) static ref SESSION_SLIDING_EXPIRY: bool = Config::get().sessions.sliding_expiry;
+    pub(crate) static ref SESSION_MAX_LIFETIME: u32 = Config::get().sessions.max_lifetime;
+}
+
+/// Returns the ttl of the refresh token of a new session
+pub(crate) fn initial_refresh_ttl() -> u32 {
+    min(*SESSION_LIFETIME, *SESSION_IDLE_TIMEOUT)
+}
+
+/// Returns the SHA-256 digest of the token bytes that is
+/// stored instead of the token itself
+fn token_digest(token: &[u8]) -> [u8; TOKEN_DIGEST_LENGTH] {
+    let mut digest = [0u8; TOKEN_DIGEST_LENGTH];
+    digest.copy_from_slice(&Sha256::digest(token));
+
+    digest
+}
+
+/// Decodes the token and returns the digest that is stored for it
+pub(crate) fn decode_digest(token: &str) -> Option<Vec<u8>> {
+    let token = base64::decode(token).ok()?;
+    if token.len() != TOKEN_LENGTH {
+        return None;
+    }
+
+    Some(token_digest(&token).to_vec())
+}
+
+/// The user id encoded in guest tokens. It doesn't belong to any user
+/// since the ids of the users table start at 1.
+pub const GUEST_USER_ID: i32 = 0;
 
 /// A struct to store session tokens of a user in a API-readable format
 #[derive(Clone, Debug, Zeroize, Serialize, JsonSchema)]
@@ -22,6 +73,10 @@ pub struct SessionTokens {
     pub refresh_token: String,
     pub request_ttl: i32,
     pub refresh_ttl: i32,
+    /// A signed JWT with the same ttl as the request token that
+    /// services can verify without asking the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<String>,
 }
 
 impl SessionTokens {
@@ -31,7 +86,8 @@ impl SessionTokens {
             request_token: base64::encode(create_user_token(user_id)),
             refresh_token: base64::encode(create_user_token(user_id)),
             request_ttl: REQUEST_TOKEN_EXPIRE_SECONDS as i32,
-            refresh_ttl: REFRESH_TOKEN_EXPIRE_SECONDS as i32,
+            refresh_ttl: initial_refresh_ttl() as i32,
+            jwt: None,
         }
     }
 
@@ -41,21 +97,25 @@ impl SessionTokens {
             request_token,
             refresh_token,
             request_ttl: REQUEST_TOKEN_EXPIRE_SECONDS as i32,
-            refresh_ttl: REFRESH_TOKEN_EXPIRE_SECONDS as i32,
+            refresh_ttl: initial_refresh_ttl() as i32,
+            jwt: None,
         }
     }
 
-    /// Creates a new session tokens instance from a token store
-    /// entry
-    pub fn from_entry(other: &TokenStoreEntry) -> Option<Self> {
-        let request_token = other.request_token().unwrap_or("".to_string());
-        let refresh_token = other.refresh_token()?;
+    /// Creates a new session tokens instance from the refresh token
+    /// and the token store entry it belongs to.
+    /// The request token is empty since the store only knows its digest.
+    pub fn from_entry(refresh_token: &str, other: &TokenStoreEntry) -> Option<Self> {
+        if other.refresh_ttl() <= 0 {
+            return None;
+        }
 
         Some(Self {
-            refresh_token,
-            request_token,
+            refresh_token: refresh_token.to_string(),
+            request_token: String::new(),
             request_ttl: other.request_ttl(),
             refresh_ttl: other.refresh_ttl(),
+            jwt: None,
         })
     }
 
@@ -63,6 +123,7 @@ impl SessionTokens {
     pub fn refresh(&mut self) {
         self.request_token = base64::encode(create_user_token(self.get_user_id()));
         self.request_ttl = REQUEST_TOKEN_EXPIRE_SECONDS as i32;
+        self.jwt = None;
         log::trace!("Request token refreshed.")
     }
 
@@ -72,10 +133,11 @@ impl SessionTokens {
     }
 
     /// Saves the tokens into the database
-    pub fn store(&self, token_store: &mut TokenStore) -> Result<(), String> {
-        if let Some(tokens) = token_store.get_by_refresh_token(&self.refresh_token) {
-            tokens.set_request_token(self.request_token.clone());
-        } else {
+    pub fn store(&self, token_store: &dyn SessionStore) -> DatabaseResult<()> {
+        if token_store
+            .set_request_token(&self.refresh_token, &self.request_token)?
+            .is_none()
+        {
             token_store.insert(&self.request_token, &self.refresh_token)?;
         }
 
@@ -83,234 +145,316 @@ impl SessionTokens {
     }
 }
 
-/// A store entry for tokens that keeps track of the token
-/// expirations and provides an abstracted access to those.
-/// The tokens are stored as their actual bytes representation
-/// to decrease the memory impact
-#[derive(Clone, Debug)]
-pub struct TokenStoreEntry {
-    request_token: [u8; TOKEN_LENGTH],
-    request_ttl: u32,
-    refresh_token: [u8; TOKEN_LENGTH],
-    refresh_ttl: u32,
-    ttl_start: Instant,
+/// An anonymous token that only grants the contained permissions.
+/// Guest tokens can't be refreshed.
+#[derive(Clone, Debug, Zeroize, Serialize, JsonSchema)]
+#[zeroize(drop)]
+pub struct GuestToken {
+    pub request_token: String,
+    pub request_ttl: i32,
+    pub permissions: Vec<String>,
 }
 
-impl TokenStoreEntry {
-    /// Creates a new token store entry with the given tokens
-    /// and sets the expiration to the configured maximum token lifetime
-    pub fn new(request_token: &String, refresh_token: &String) -> Result<Self, String> {
-        let request_token = base64::decode(request_token).unwrap();
-        let refresh_token = &base64::decode(refresh_token).unwrap();
-        if request_token.len() != TOKEN_LENGTH || refresh_token.len() != TOKEN_LENGTH {
-            return Err("Invalid token length".to_string());
+impl GuestToken {
+    /// Creates a new guest token with the given permissions
+    pub fn new(ttl: u32, permissions: Vec<String>) -> Self {
+        Self {
+            request_token: base64::encode(create_user_token(GUEST_USER_ID)),
+            request_ttl: ttl as i32,
+            permissions,
         }
-        let mut req_token = [0u8; TOKEN_LENGTH];
-        let mut ref_token = [0u8; TOKEN_LENGTH];
-        req_token.copy_from_slice(&request_token);
-        ref_token.copy_from_slice(&refresh_token);
-
-        Ok(Self {
-            request_token: req_token,
-            refresh_token: ref_token,
-            request_ttl: REQUEST_TOKEN_EXPIRE_SECONDS,
-            refresh_ttl: REFRESH_TOKEN_EXPIRE_SECONDS,
-            ttl_start: Instant::now(),
-        })
     }
 
-    /// Returns the ttl for the request token that is
-    /// calculated from the stored instant.
-    /// If the token is expired -1 is returned.
-    pub fn request_ttl(&self) -> i32 {
-        max(
-            self.request_ttl as i64 - self.ttl_start.elapsed().as_secs() as i64,
-            -1,
-        ) as i32
+    /// Saves the token into the token store
+    pub fn store(&self, token_store: &dyn SessionStore, limit: usize) -> DatabaseResult<()> {
+        token_store.insert_guest(
+            &self.request_token,
+            self.request_ttl as u32,
+            self.permissions.clone(),
+            limit,
+        )
     }
+}
 
-    /// Returns the ttl for the refresh token
-    /// that is calculated from the stored instant.
-    /// If the token is expired -1 is returned.
-    pub fn refresh_ttl(&self) -> i32 {
-        max(
-            self.refresh_ttl as i64 - self.ttl_start.elapsed().as_secs() as i64,
-            -1,
-        ) as i32
-    }
+/// A request token that acts as a user on behalf of an administrator.
+/// Impersonation tokens can't be refreshed or extended.
+#[derive(Clone, Debug, Zeroize, Serialize, JsonSchema)]
+#[zeroize(drop)]
+pub struct ImpersonationToken {
+    pub request_token: String,
+    pub request_ttl: i32,
+    /// The email of the impersonated user
+    pub email: String,
+}
+
+/// The type of a token store entry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TokenKind {
+    /// A login session of a user
+    Session,
+    /// An anonymous token with whitelisted permissions
+    Guest(Vec<String>),
+    /// A session of a user that was issued to a client application.
+    /// It only grants the permissions of the user that are part of the scopes.
+    Client {
+        client_id: String,
+        scopes: Vec<String>,
+    },
+    /// A session of a user that needs to change the password first.
+    /// It grants no permissions and can only be used to change the password.
+    PasswordChange,
+    /// A session that an administrator uses to act as the user.
+    /// It grants the permissions of the user and ends with its request token.
+    Impersonation { admin_id: i32 },
+}
 
-    /// Returns the request token if it hasn't expired
-    pub fn request_token(&self) -> Option<String> {
-        if self.request_ttl() > 0 {
-            Some(base64::encode(&self.request_token))
-        } else {
-            None
+/// A session loaded from the token store with the ttls
+/// of its tokens at the time it was loaded.
+/// The refresh token expires when the session exceeds its lifetime
+/// or when it wasn't used for longer than the idle timeout.
+#[derive(Clone, Debug)]
+pub struct TokenStoreEntry {
+    user_id: i32,
+    kind: TokenKind,
+    request_ttl: i32,
+    refresh_ttl: i32,
+}
+
+impl TokenStoreEntry {
+    pub(crate) fn new(user_id: i32, kind: TokenKind, request_ttl: i32, refresh_ttl: i32) -> Self {
+        Self {
+            user_id,
+            kind,
+            request_ttl,
+            refresh_ttl,
         }
     }
 
-    /// Returns the refresh token if it hasn't expired
-    pub fn refresh_token(&self) -> Option<String> {
-        if self.refresh_ttl() > 0 {
-            Some(base64::encode(&self.refresh_token))
-        } else {
-            None
-        }
+    /// Returns the id of the user the session belongs to
+    pub fn user_id(&self) -> i32 {
+        self.user_id
     }
 
-    /// Sets a new request token and resets
-    /// the expiration time for the request and refresh token
-    pub fn set_request_token(&mut self, token: String) -> i32 {
-        self.request_token
-            .copy_from_slice(base64::decode(token).unwrap().as_slice());
-        self.reset_timer();
-        self.request_ttl = REQUEST_TOKEN_EXPIRE_SECONDS;
-        self.refresh_ttl = REFRESH_TOKEN_EXPIRE_SECONDS;
-        log::trace!("TTLs reset");
-
-        self.request_ttl as i32
+    /// Returns the type of the entry
+    pub fn kind(&self) -> &TokenKind {
+        &self.kind
     }
 
-    /// Resets the timer that keeps track of the tokens expiration times
-    /// before resetting the current expiration times are stored so the ttl
-    /// for both tokens won't reset
-    fn reset_timer(&mut self) {
-        log::trace!("Resetting timer...");
-        log::trace!(
-            "req_ttl: {}, ref_ttl: {}",
-            self.request_ttl,
-            self.refresh_ttl
-        );
-        self.request_ttl = max(self.request_ttl(), 0) as u32;
-        self.refresh_ttl = max(self.refresh_ttl(), 0) as u32;
-        self.ttl_start = Instant::now();
-        log::trace!(
-            "req_ttl: {}, ref_ttl: {}",
-            self.request_ttl,
-            self.refresh_ttl
-        );
+    /// Returns the ttl for the request token.
+    /// If the token is expired -1 is returned.
+    pub fn request_ttl(&self) -> i32 {
+        self.request_ttl
     }
 
-    /// Invalidates the token entry which causes it to be deleted with the next
-    /// clearing of expired tokens by the token store. The
-    pub(crate) fn invalidate(&mut self) {
-        self.request_ttl = 0;
-        self.refresh_ttl = 0;
-        log::trace!("Tokens invalidated.");
+    /// Returns the ttl for the refresh token which is the time until
+    /// either the session lifetime or the idle timeout is reached.
+    /// If the token is expired -1 is returned.
+    pub fn refresh_ttl(&self) -> i32 {
+        self.refresh_ttl
     }
 }
 
-#[derive(Debug)]
-pub struct TokenStore {
-    tokens: HashMap<i32, Vec<TokenStoreEntry>>,
+/// Statistics about the sessions kept in the token store
+#[derive(Clone, Debug, Default)]
+pub struct TokenStoreStats {
+    /// The number of sessions whose refresh token hasn't expired
+    pub active_sessions: usize,
+    /// The number of users with at least one active session
+    pub users: usize,
+    /// The number of stored entries including expired ones that haven't been cleared
+    pub stored_entries: usize,
+    /// The storage used by the stored entries in bytes
+    pub size_bytes: usize,
+    /// The sorted number of active sessions per user
+    pub(crate) sessions_per_user: Vec<usize>,
 }
 
-impl TokenStore {
-    pub fn new() -> Self {
-        Self {
-            tokens: HashMap::new(),
-        }
+impl TokenStoreStats {
+    /// Returns the maximum number of sessions a single user has
+    pub fn max_sessions_per_user(&self) -> usize {
+        self.sessions_per_user.last().cloned().unwrap_or(0)
     }
 
-    /// Returns the token store entry for a given request token
-    pub fn get_by_request_token(&mut self, request_token: &String) -> Option<&mut TokenStoreEntry> {
-        let user_id = get_user_id_from_token(&request_token)?;
-
-        if let Some(user_tokens) = self.tokens.get_mut(&user_id) {
-            user_tokens.iter_mut().find(|e| {
-                if let Some(token) = e.request_token() {
-                    &token == request_token
-                } else {
-                    false
-                }
-            })
-        } else {
-            None
+    /// Returns the given percentile (0.0 - 1.0) of sessions per user
+    pub fn sessions_per_user_percentile(&self, percentile: f64) -> usize {
+        if self.sessions_per_user.is_empty() {
+            return 0;
         }
-    }
-
-    /// Returns the token store entry by the given refresh token
-    pub fn get_by_refresh_token(&mut self, refresh_token: &String) -> Option<&mut TokenStoreEntry> {
-        log::trace!("Retrieving user by refresh token.");
-        let user_id = get_user_id_from_token(&refresh_token)?;
-        log::trace!("UserID is {}", user_id);
-
-        if let Some(user_tokens) = self.tokens.get_mut(&user_id) {
-            user_tokens.iter_mut().find(|e| {
-                if let Some(token) = e.refresh_token() {
-                    token.eq(refresh_token)
-                } else {
-                    false
-                }
-            })
-        } else {
-            log::trace!("No tokens found for user");
-            None
-        }
-    }
+        let index = ((self.sessions_per_user.len() - 1) as f64 * percentile).round() as usize;
 
-    /// Sets the request token for a given refresh token
-    /// Also clears all expired token entries.
-    pub fn set_request_token(&mut self, refresh_token: &String, request_token: &String) {
-        self.clear_expired();
-        let user_id = get_user_id_from_token(&request_token).unwrap();
-        if let Some(user_tokens) = self.tokens.get_mut(&user_id) {
-            user_tokens.iter_mut().for_each(|e| {
-                if let Some(ref_token) = &e.refresh_token() {
-                    if ref_token == refresh_token {
-                        e.set_request_token(request_token.to_string());
-                        log::debug!("New request token set for userId {}", user_id);
-                    }
-                }
-            });
-        }
+        self.sessions_per_user[index.min(self.sessions_per_user.len() - 1)]
     }
+}
 
-    /// Inserts a new pair of request and refresh token
-    pub fn insert(&mut self, request_token: &String, refresh_token: &String) -> Result<(), String> {
-        let user_id =
-            get_user_id_from_token(refresh_token).ok_or("Invalid request token".to_string())?;
-        let user_tokens = if let Some(user_tokens) = self.tokens.get_mut(&user_id) {
-            user_tokens
-        } else {
-            self.tokens.insert(user_id, Vec::new());
+/// An active session of a user as it is listed to the user and administrators.
+/// The digests of the tokens are never part of it.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct SessionInfo {
+    /// The type of the session: session, client, password_change or impersonation
+    pub kind: String,
+    /// The client application the session was issued to
+    pub client_id: Option<String>,
+    /// The scopes the client application was granted
+    pub scopes: Option<Vec<String>>,
+    /// The id of the administrator that impersonates the user
+    pub impersonated_by: Option<i32>,
+    /// The time the session was created. Unknown for sessions created by older versions.
+    pub created_at: Option<String>,
+    /// The ttl of the current request token. -1 if it has expired.
+    pub request_ttl: i32,
+    /// The ttl of the refresh token
+    pub refresh_ttl: i32,
+}
 
-            self.tokens.get_mut(&user_id).unwrap()
+impl SessionInfo {
+    pub(crate) fn new(
+        kind: &TokenKind,
+        created_at: Option<String>,
+        request_ttl: i32,
+        refresh_ttl: i32,
+    ) -> Self {
+        let mut info = Self {
+            kind: String::new(),
+            client_id: None,
+            scopes: None,
+            impersonated_by: None,
+            created_at,
+            request_ttl,
+            refresh_ttl,
         };
-        if let Some(tokens) = user_tokens.iter_mut().find(|t| {
-            if let Some(token) = t.refresh_token() {
-                &token == refresh_token
-            } else {
-                false
+        info.kind = match kind {
+            TokenKind::Session => "session",
+            TokenKind::Guest(_) => "guest",
+            TokenKind::Client { client_id, scopes } => {
+                info.client_id = Some(client_id.clone());
+                info.scopes = Some(scopes.clone());
+                "client"
+            }
+            TokenKind::PasswordChange => "password_change",
+            TokenKind::Impersonation { admin_id } => {
+                info.impersonated_by = Some(*admin_id);
+                "impersonation"
             }
-        }) {
-            tokens.set_request_token(request_token.clone());
-        } else {
-            let entry = TokenStoreEntry::new(request_token, refresh_token)?;
-            user_tokens.push(entry);
         }
+        .to_string();
 
-        Ok(())
+        info
+    }
+}
+
+/// A backend that stores the sessions. Only the SHA-256 digests of the tokens
+/// are stored so that the stored entries can't be used to authenticate.
+/// Backends that are shared between multiple instances allow
+/// running them behind a load balancer without sticky sessions.
+pub trait SessionStore: Send + Sync {
+    /// Prepares the backend for storing sessions
+    fn init(&self) -> DatabaseResult<()>;
+
+    /// Returns the token store entry for a given request token if neither
+    /// the request token nor the session has expired.
+    /// Using the request token counts as activity of the session. With sliding expiry
+    /// it also extends the lifetime of the session up to the maximum lifetime.
+    fn get_by_request_token(&self, request_token: &str) -> DatabaseResult<Option<TokenStoreEntry>>;
+
+    /// Returns the token store entry by the given refresh token
+    /// if the refresh token hasn't expired
+    fn get_by_refresh_token(&self, refresh_token: &str) -> DatabaseResult<Option<TokenStoreEntry>>;
+
+    /// Sets the request token for a given refresh token and resets its expiration time.
+    /// The refresh of the token counts as activity of the session.
+    /// Returns the updated entry if the refresh token is valid.
+    fn set_request_token(
+        &self,
+        refresh_token: &str,
+        request_token: &str,
+    ) -> DatabaseResult<Option<TokenStoreEntry>>;
+
+    /// Inserts a new session of the given type
+    /// and sets the expiration to the configured session lifetime
+    fn insert_session(
+        &self,
+        request_token: &str,
+        refresh_token: &str,
+        kind: TokenKind,
+    ) -> DatabaseResult<()>;
+
+    /// Inserts a new guest token that expires after the given ttl.
+    /// Guest tokens have no refresh token. An error is returned if the store
+    /// already holds the given number of valid guest tokens.
+    fn insert_guest(
+        &self,
+        request_token: &str,
+        ttl: u32,
+        permissions: Vec<String>,
+        limit: usize,
+    ) -> DatabaseResult<()>;
+
+    /// Restarts the ttl of the given request token without replacing it.
+    /// The ttl never exceeds the remaining ttl of the session.
+    /// Guest tokens can't be extended.
+    fn extend_request_ttl(&self, request_token: &str) -> DatabaseResult<Option<TokenStoreEntry>>;
+
+    /// Removes the session the valid request token belongs to
+    /// and returns if a session was removed
+    fn remove_by_request_token(&self, request_token: &str) -> DatabaseResult<bool>;
+
+    /// Removes the sessions issued to the client application. If a user is given
+    /// only the sessions of that user are removed. Returns the number of removed entries.
+    fn remove_client(&self, user_id: Option<i32>, client_id: &str) -> DatabaseResult<usize>;
+
+    /// Returns the number of sessions of a user whose refresh token hasn't expired
+    fn active_sessions(&self, user_id: i32) -> DatabaseResult<usize>;
+
+    /// Returns the sessions of a user whose refresh token hasn't expired
+    /// ordered by their creation time
+    fn list_sessions(&self, user_id: i32) -> DatabaseResult<Vec<SessionInfo>>;
+
+    /// Removes all sessions of a user and returns the number of removed entries
+    fn remove_user(&self, user_id: i32) -> DatabaseResult<usize>;
+
+    /// Returns statistics about the stored sessions
+    fn stats(&self) -> DatabaseResult<TokenStoreStats>;
+
+    /// Deletes all expired sessions from the store and
+    /// returns the number of deleted sessions
+    fn clear_expired(&self) -> DatabaseResult<usize>;
+
+    /// Inserts a new pair of request and refresh token of a login session
+    fn insert(&self, request_token: &str, refresh_token: &str) -> DatabaseResult<()> {
+        self.insert_session(request_token, refresh_token, TokenKind::Session)
     }
 
-    /// Deletes all expired tokens from the store
-    pub fn clear_expired(&mut self) {
-        log::trace!("Clearing expired tokens...");
-        for (key, entry) in &self.tokens.clone() {
-            log::trace!("Before: {} tokens for user {}", entry.len(), key);
-            self.tokens.insert(
-                *key,
-                entry
-                    .iter()
-                    .cloned()
-                    .filter(|e| e.refresh_ttl() > 0)
-                    .collect(),
-            );
-            log::trace!(
-                "After: {} tokens for user {}",
-                self.tokens.get(key).unwrap().len(),
-                key
-            );
+    /// Inserts the tokens of a session that was issued to a client application
+    fn insert_client(
+        &self,
+        request_token: &str,
+        refresh_token: &str,
+        client_id: String,
+        scopes: Vec<String>,
+    ) -> DatabaseResult<()> {
+        self.insert_session(
+            request_token,
+            refresh_token,
+            TokenKind::Client { client_id, scopes },
+        )
+    }
+}
+
+/// Creates the session store that is configured in the env.
+/// Sessions are stored in postgres by default.
+pub fn session_store_from_env(pool: &PostgresPool) -> DatabaseResult<Arc<dyn SessionStore>> {
+    let backend = config::var(ENV_SESSION_STORE).unwrap_or(DEFAULT_SESSION_STORE.to_string());
+    match backend.as_str() {
+        "postgres" => Ok(Arc::new(PostgresSessionStore::new(PostgresPool::clone(
+            pool,
+        )))),
+        "redis" => {
+            let url = config::var(ENV_REDIS_URL).unwrap_or(DEFAULT_REDIS_URL.to_string());
+            Ok(Arc::new(RedisSessionStore::new(&url)?))
         }
-        log::trace!("Clearing expired tokens cleared");
+        other => Err(DBError::GenericError(format!(
+            "Unknown session store '{}'",
+            other
+        ))),
     }
 }
@@ -0,0 +1,203 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use chrono::NaiveDate;
+use serde_json::{Map, Value};
+
+use crate::database::models::{FieldType, FieldVisibility, UserFieldDefinition};
+use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::utils::error::{DBError, FieldError};
+
+/// The table that stores the definitions of custom user fields
+/// that are stored in the attributes column of the users table
+#[derive(Clone)]
+pub struct UserFields {
+    pool: PostgresPool,
+}
+
+impl Table for UserFields {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl UserFields {
+    /// Returns all defined user fields
+    pub fn get_definitions(&self) -> DatabaseResult<Vec<UserFieldDefinition>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query("SELECT * FROM user_field_definitions ORDER BY name", &[])?;
+
+        Ok(rows
+            .into_iter()
+            .map(UserFieldDefinition::from_row)
+            .collect())
+    }
+
+    /// Creates a new field definition
+    pub fn create_definition(
+        &self,
+        name: String,
+        field_type: FieldType,
+        required: bool,
+        visibility: FieldVisibility,
+    ) -> DatabaseResult<UserFieldDefinition> {
+        let mut connection = self.pool.get()?;
+        let exists = connection.query_opt(
+            "SELECT id FROM user_field_definitions WHERE name = $1",
+            &[&name],
+        )?;
+
+        if exists.is_some() {
+            return Err(DBError::RecordExists);
+        }
+        let row = connection.query_one(
+            "INSERT INTO user_field_definitions (name, field_type, required, visibility) VALUES ($1, $2, $3, $4) RETURNING *",
+            &[&name, &field_type.as_str(), &required, &visibility.as_str()],
+        )?;
+
+        Ok(UserFieldDefinition::from_row(row))
+    }
+
+    /// Updates the type, visibility and requirement of a field definition.
+    /// Existing values of the field are not converted.
+    pub fn update_definition(
+        &self,
+        old_name: &String,
+        name: String,
+        field_type: FieldType,
+        required: bool,
+        visibility: FieldVisibility,
+    ) -> DatabaseResult<UserFieldDefinition> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let id: i32 = transaction
+            .query_opt(
+                "SELECT id FROM user_field_definitions WHERE name = $1",
+                &[old_name],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?
+            .get(0);
+
+        if old_name != &name
+            && transaction
+                .query_opt(
+                    "SELECT id FROM user_field_definitions WHERE name = $1",
+                    &[&name],
+                )?
+                .is_some()
+        {
+            return Err(DBError::GenericError(format!(
+                "A user field with the name {} already exists!",
+                name
+            )));
+        }
+        let row = transaction.query_one(
+            "UPDATE user_field_definitions SET name = $2, field_type = $3, required = $4, visibility = $5 WHERE id = $1 RETURNING *",
+            &[&id, &name, &field_type.as_str(), &required, &visibility.as_str()],
+        )?;
+        if old_name != &name {
+            transaction.execute(
+                "UPDATE users SET attributes = (attributes - $1::text) || jsonb_build_object($2::text, attributes -> $1::text) WHERE attributes ? $1::text",
+                &[old_name, &name],
+            )?;
+        }
+        transaction.commit()?;
+
+        Ok(UserFieldDefinition::from_row(row))
+    }
+
+    /// Deletes a field definition. The stored values are kept in the
+    /// attributes of the users.
+    pub fn delete_definition(&self, name: &String) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let deleted = connection.execute(
+            "DELETE FROM user_field_definitions WHERE name = $1",
+            &[name],
+        )?;
+
+        if deleted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Validates the given attributes against the field definitions.
+    /// Attributes that don't have a definition are accepted as they are.
+    pub fn validate_attributes(&self, attributes: &Value) -> DatabaseResult<()> {
+        let empty = Map::new();
+        let attributes = match attributes {
+            Value::Object(map) => map,
+            Value::Null => &empty,
+            _ => {
+                return Err(DBError::GenericError(
+                    "The attributes need to be an object".to_string(),
+                ))
+            }
+        };
+        let mut errors = Vec::new();
+
+        for definition in self.get_definitions()? {
+            match attributes.get(&definition.name) {
+                None | Some(Value::Null) => {
+                    if definition.required {
+                        errors.push(FieldError::Required(definition.name.clone()));
+                    }
+                }
+                Some(value) => {
+                    if !definition.field_type.matches(value) {
+                        errors.push(FieldError::TypeMismatch(
+                            definition.name.clone(),
+                            definition.field_type.as_str().to_string(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(DBError::ValidationError(errors))
+        }
+    }
+
+    /// Removes all attributes from the given value that are defined as
+    /// private fields
+    pub fn filter_private(&self, attributes: Value) -> DatabaseResult<Value> {
+        Ok(remove_private_fields(&self.get_definitions()?, attributes))
+    }
+}
+
+/// Removes all attributes from the given value that are private fields
+/// of the given definitions. Used to filter many users with the
+/// definitions loaded once.
+pub fn remove_private_fields(definitions: &[UserFieldDefinition], attributes: Value) -> Value {
+    if let Value::Object(mut map) = attributes {
+        for definition in definitions {
+            if definition.visibility == FieldVisibility::Private {
+                map.remove(&definition.name);
+            }
+        }
+
+        Value::Object(map)
+    } else {
+        attributes
+    }
+}
+
+impl FieldType {
+    /// Returns if the given json value is a valid value for the field type
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            FieldType::String => value.is_string(),
+            FieldType::Number => value.is_number(),
+            FieldType::Boolean => value.is_boolean(),
+            FieldType::Date => value
+                .as_str()
+                .map(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").is_ok())
+                .unwrap_or(false),
+        }
+    }
+}
@@ -2,10 +2,15 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
-use crate::database::models::Role;
-use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::database::change_history::{self, Changes, ENTITY_USER};
+use crate::database::models::{Role, UserInformation};
+use crate::database::permission_cache::PERMISSION_CACHE;
+use crate::database::permissions::USER_MANAGEMENT_PERMISSIONS;
+use crate::database::users::USER_INFORMATION_COLUMNS;
+use crate::database::{admin_accounts, DatabaseResult, PostgresPool, Table, ADMIN_ROLE_NAME};
 use crate::utils::error::DBError;
-use std::collections::HashSet;
+use postgres::Transaction;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 
 /// A table that stores the relation between users and roles
@@ -18,64 +23,201 @@ impl Table for UserRoles {
     fn new(pool: PostgresPool) -> Self {
         Self { pool }
     }
-
-    fn init(&self) -> DatabaseResult<()> {
-        self.pool
-            .get()?
-            .batch_execute(
-                "
-        CREATE TABLE IF NOT EXISTS user_roles (
-            user_id         INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
-            role_id         INT NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
-            PRIMARY KEY  (user_id, role_id)
-        );",
-            )
-            .map_err(DBError::from)
-    }
 }
 
 impl UserRoles {
     /// Returns all roles a user is asigned to
     pub fn by_user(&self, user_id: i32) -> DatabaseResult<Vec<Role>> {
         let mut connection = self.pool.get()?;
-        let rows = connection.query(
-            "SELECT * FROM user_roles, roles WHERE user_id = $1 AND roles.id = user_roles.role_id",
-            &[&user_id],
+        let statement = connection.prepare_cached(
+            "SELECT roles.id, roles.name, roles.description, roles.enabled FROM user_roles, roles
+            WHERE user_id = $1 AND roles.id = user_roles.role_id AND roles.deleted_at IS NULL",
         )?;
+        let rows = connection.query(&statement, &[&user_id])?;
 
         serde_postgres::from_rows(&rows).map_err(DBError::from)
     }
 
+    /// Returns all users that are assigned to the role
+    pub fn by_role(&self, role_id: i32) -> DatabaseResult<Vec<UserInformation>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            format!(
+                "SELECT {} FROM user_roles, users
+                WHERE user_roles.role_id = $1 AND users.id = user_roles.user_id AND users.deleted_at IS NULL
+                ORDER BY users.email",
+                USER_INFORMATION_COLUMNS
+            )
+            .as_str(),
+            &[&role_id],
+        )?;
+
+        Ok(rows.into_iter().map(UserInformation::from_row).collect())
+    }
+
+    /// Adds the roles with the given names to the user and returns the
+    /// number of roles that weren't assigned before
+    pub fn add_roles(&self, user_id: i32, roles: &Vec<String>) -> DatabaseResult<u64> {
+        let mut connection = self.pool.get()?;
+        let added = connection.execute(
+            "INSERT INTO user_roles (user_id, role_id) SELECT $1, id FROM roles WHERE name = ANY ($2) AND deleted_at IS NULL ON CONFLICT DO NOTHING",
+            &[&user_id, roles],
+        )?;
+        if added > 0 {
+            PERMISSION_CACHE.invalidate_user(user_id);
+        }
+
+        Ok(added)
+    }
+
+    /// Assigns the roles to the user in addition to the roles it already has
+    /// and returns all of its roles. Nothing is assigned if one of the roles doesn't exist.
+    /// Services can't assign the admin role or roles that grant built-in permissions.
+    pub fn assign_roles(&self, user_id: i32, roles: Vec<String>) -> DatabaseResult<Vec<Role>> {
+        let system_permissions = USER_MANAGEMENT_PERMISSIONS
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<String>>();
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let email: String = transaction
+            .query_opt(
+                "SELECT email FROM users WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[&user_id],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?
+            .get(0);
+        let existing = transaction
+            .query(
+                "SELECT roles.name, EXISTS (
+                    SELECT 1 FROM role_permissions, permissions
+                    WHERE role_permissions.role_id = roles.id
+                    AND permissions.id = role_permissions.permission_id
+                    AND permissions.name = ANY ($2)
+                ) FROM roles WHERE roles.name = ANY ($1) AND roles.deleted_at IS NULL",
+                &[&roles, &system_permissions],
+            )?
+            .into_iter()
+            .map(|row| -> (String, bool) { (row.get(0), row.get(1)) })
+            .collect::<HashMap<String, bool>>();
+        if let Some(missing) = roles.iter().find(|name| !existing.contains_key(*name)) {
+            return Err(DBError::GenericError(format!(
+                "The role {} does not exist",
+                missing
+            )));
+        }
+        if let Some((name, _)) = existing
+            .iter()
+            .find(|(name, system)| **system || *name == ADMIN_ROLE_NAME)
+        {
+            return Err(DBError::GenericError(format!(
+                "The role {} grants built-in permissions and can't be assigned",
+                name
+            )));
+        }
+        let old_role_names = Self::role_names(&mut transaction, user_id)?;
+        let added = transaction.execute(
+            "INSERT INTO user_roles (user_id, role_id) SELECT $1, id FROM roles WHERE name = ANY ($2) AND deleted_at IS NULL ON CONFLICT DO NOTHING",
+            &[&user_id, &roles],
+        )?;
+        if added > 0 {
+            let new_role_names = Self::role_names(&mut transaction, user_id)?;
+            change_history::record(
+                &mut transaction,
+                ENTITY_USER,
+                user_id,
+                &email,
+                Changes::new().field("roles", &old_role_names, &new_role_names),
+            )?;
+        }
+        transaction.commit()?;
+        if added > 0 {
+            PERMISSION_CACHE.invalidate_user(user_id);
+        }
+
+        self.by_user(user_id)
+    }
+
+    /// Returns the sorted names of the roles of the user
+    fn role_names(transaction: &mut Transaction, user_id: i32) -> DatabaseResult<Vec<String>> {
+        let mut names = transaction
+            .query(
+                "SELECT roles.name FROM roles, user_roles WHERE roles.id = user_roles.role_id AND user_roles.user_id = $1 AND roles.deleted_at IS NULL",
+                &[&user_id],
+            )?
+            .into_iter()
+            .map(|row| -> String { row.get(0) })
+            .collect::<Vec<String>>();
+        names.sort();
+
+        Ok(names)
+    }
+
     pub fn update_roles(&self, user_id: i32, roles: Vec<String>) -> DatabaseResult<Vec<Role>> {
         let mut connection = self.pool.get()?;
         let mut transaction = connection.transaction()?;
+        let email: String = transaction
+            .query_opt("SELECT email FROM users WHERE id = $1", &[&user_id])?
+            .ok_or(DBError::RecordDoesNotExist)?
+            .get(0);
+        if let Some(account) = admin_accounts().into_iter().find(|a| a.email == email) {
+            if let Some(role) = account.roles.iter().find(|r| !roles.contains(r)) {
+                return Err(DBError::SystemRecord(format!("{} of {}", role, email)));
+            }
+        }
+        let mut old_role_names = transaction
+            .query(
+                "SELECT roles.name FROM roles, user_roles WHERE roles.id = user_roles.role_id AND user_roles.user_id = $1 AND roles.deleted_at IS NULL",
+                &[&user_id],
+            )?
+            .into_iter()
+            .map(|row| -> String { row.get(0) })
+            .collect::<Vec<String>>();
+        old_role_names.sort();
         let role_ids_result = transaction.query(
-            "SELECT roles.id FROM roles WHERE roles.name = ANY ($1)",
+            "SELECT roles.id FROM roles WHERE roles.name = ANY ($1) AND roles.deleted_at IS NULL",
             &[&roles],
         )?;
         let role_ids: Vec<i32> = serde_postgres::from_rows(role_ids_result.iter())?;
         let role_ids: HashSet<i32> = HashSet::from_iter(role_ids.into_iter());
-        let role_result = transaction.query("SELECT roles.id FROM roles, user_roles WHERE roles.id = user_roles.role_id AND user_roles.user_id = $1", &[&user_id])?;
+        let role_result = transaction.query("SELECT roles.id FROM roles, user_roles WHERE roles.id = user_roles.role_id AND user_roles.user_id = $1 AND roles.deleted_at IS NULL", &[&user_id])?;
         let current_roles: Vec<i32> = serde_postgres::from_rows(role_result.iter())?;
 
         let current_roles = HashSet::from_iter(current_roles.into_iter());
-        let added_roles: HashSet<&i32> = role_ids.difference(&current_roles).collect();
-        let removed_roles: HashSet<&i32> = current_roles.difference(&role_ids).collect();
+        let added_roles: Vec<i32> = role_ids.difference(&current_roles).cloned().collect();
+        let removed_roles: Vec<i32> = current_roles.difference(&role_ids).cloned().collect();
 
-        for role in removed_roles {
-            transaction.query(
-                "DELETE FROM user_roles WHERE role_id = $1 AND user_id = $2",
-                &[role, &user_id],
+        if !removed_roles.is_empty() {
+            transaction.execute(
+                "DELETE FROM user_roles WHERE role_id = ANY($1) AND user_id = $2",
+                &[&removed_roles, &user_id],
             )?;
         }
-        for role in added_roles {
-            transaction.query(
-                "INSERT INTO user_roles (user_id, role_id) VALUES ($1, $2)",
-                &[&user_id, role],
+        if !added_roles.is_empty() {
+            transaction.execute(
+                "INSERT INTO user_roles (user_id, role_id) SELECT $1, unnest($2::INT[])",
+                &[&user_id, &added_roles],
             )?;
         }
+        let mut new_role_names = transaction
+            .query(
+                "SELECT roles.name FROM roles, user_roles WHERE roles.id = user_roles.role_id AND user_roles.user_id = $1 AND roles.deleted_at IS NULL",
+                &[&user_id],
+            )?
+            .into_iter()
+            .map(|row| -> String { row.get(0) })
+            .collect::<Vec<String>>();
+        new_role_names.sort();
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            user_id,
+            &email,
+            Changes::new().field("roles", &old_role_names, &new_role_names),
+        )?;
         transaction.commit()?;
+        PERMISSION_CACHE.invalidate_user(user_id);
 
-        Ok(self.by_user(user_id)?)
+        self.by_user(user_id)
     }
 }
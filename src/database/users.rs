@@ -2,83 +2,440 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::thread::{self, Builder};
+use std::time::{Duration, Instant};
 
 use parking_lot::Mutex;
 use zeroize::{Zeroize, Zeroizing};
 
-use crate::database::models::{Permission, UserInformation, UserRecord};
-use crate::database::tokens::{SessionTokens, TokenStore};
+use crate::database::change_history::{self, Changes, ENTITY_USER};
+use crate::database::events::{self, Event};
+use crate::database::limits::{Resource, ResourceLimits};
+use crate::database::login_events;
+use crate::database::models::{
+    Pagination, Permission, ResolvedUser, Sorting, TrashedUser, UserExport, UserFilter,
+    UserInformation, UserMerge, UserRecord,
+};
+use crate::database::permission_cache::PERMISSION_CACHE;
+use crate::database::postgres_sessions::PostgresSessionStore;
+use crate::database::tokens::{
+    GuestToken, ImpersonationToken, SessionInfo, SessionStore, SessionTokens, TokenKind,
+    TokenStoreEntry, TokenStoreStats,
+};
 use crate::database::user_roles::UserRoles;
-use crate::database::{DatabaseResult, PostgresPool, Table, DEFAULT_ADMIN_EMAIL};
+use crate::database::{admin_accounts, DatabaseResult, PostgresPool, Table};
+use crate::utils::config;
 use crate::utils::error::DBError;
-use crate::utils::{create_salt, hash_password};
-use serde_json::Value;
+use crate::utils::hashing::{PasswordAlgorithm, Pepper};
+use crate::utils::{
+    constant_time_eq, create_salt, create_secret_token, generate_password, hash_password,
+    hash_secret_token, jwt, verify_encoded_token,
+};
+use postgres::Transaction;
+use serde_json::{Map, Value};
+
+pub(crate) const ENV_ACCOUNT_EXPIRY_NOTICE_HOURS: &str = "ACCOUNT_EXPIRY_NOTICE_HOURS";
+pub(crate) const DEFAULT_ACCOUNT_EXPIRY_NOTICE_HOURS: i32 = 72;
+const EXPIRY_JOB_INTERVAL: Duration = Duration::from_secs(60);
+pub(crate) const ENV_GUEST_PERMISSIONS: &str = "GUEST_PERMISSIONS";
+pub(crate) const ENV_GUEST_TOKEN_TTL: &str = "GUEST_TOKEN_TTL";
+pub(crate) const ENV_GUEST_TOKEN_LIMIT: &str = "GUEST_TOKEN_LIMIT";
+pub(crate) const DEFAULT_GUEST_TOKEN_TTL: u32 = 60 * 5;
+pub(crate) const DEFAULT_GUEST_TOKEN_LIMIT: usize = 10000;
+pub(crate) const ENV_REQUIRE_EMAIL_VERIFICATION: &str = "REQUIRE_EMAIL_VERIFICATION";
+const VERIFICATION_HOURS: i32 = 72;
+const INVITATION_DAYS: i32 = 7;
+pub(crate) const ENV_USER_TRASH_RETENTION_DAYS: &str = "USER_TRASH_RETENTION_DAYS";
+pub(crate) const DEFAULT_USER_TRASH_RETENTION_DAYS: i32 = 30;
+const PURGE_JOB_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// The number of users that are read from the export cursor at once
+const EXPORT_BATCH_SIZE: i32 = 500;
+/// How often the last activity of a user is written at most
+const LAST_SEEN_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    /// The time the last activity of each user was written
+    static ref LAST_SEEN: Mutex<HashMap<i32, Instant>> = Mutex::new(HashMap::new());
+    static ref GUEST_PERMISSIONS: Vec<String> = config::var(ENV_GUEST_PERMISSIONS)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(String::from)
+        .collect();
+    static ref GUEST_TOKEN_TTL: u32 = config::var(ENV_GUEST_TOKEN_TTL)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_GUEST_TOKEN_TTL);
+    static ref GUEST_TOKEN_LIMIT: usize = config::var(ENV_GUEST_TOKEN_LIMIT)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_GUEST_TOKEN_LIMIT);
+    /// The user lookups are prepared once per connection as they run for most requests
+    static ref GET_USER_QUERY: String = format!(
+        "SELECT {} FROM users WHERE id = $1 AND deleted_at IS NULL",
+        USER_INFORMATION_COLUMNS
+    );
+    static ref GET_USER_BY_EMAIL_QUERY: String = format!(
+        "SELECT {} FROM users WHERE email = $1 AND deleted_at IS NULL",
+        USER_INFORMATION_COLUMNS
+    );
+    static ref REQUIRE_EMAIL_VERIFICATION: bool = config::var(ENV_REQUIRE_EMAIL_VERIFICATION)
+        .map(|v| v == "true")
+        .unwrap_or(false);
+    static ref USER_TRASH_RETENTION_DAYS: i32 = config::var(ENV_USER_TRASH_RETENTION_DAYS)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_USER_TRASH_RETENTION_DAYS);
+}
+const EXPIRES_AT_COLUMN: &str =
+    "to_char(expires_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS expires_at";
 
 /// Table that stores users with their email addresses and hashed passwords
 #[derive(Clone)]
 pub struct Users {
     pool: PostgresPool,
     user_roles: UserRoles,
-    token_store: Arc<Mutex<TokenStore>>,
+    token_store: Arc<dyn SessionStore>,
 }
 
 impl Table for Users {
     fn new(pool: PostgresPool) -> Self {
-        Self {
-            user_roles: UserRoles::new(PostgresPool::clone(&pool)),
-            pool,
-            token_store: Arc::new(Mutex::new(TokenStore::new())),
-        }
+        let token_store = Arc::new(PostgresSessionStore::new(PostgresPool::clone(&pool)));
+
+        Self::with_session_store(pool, token_store)
     }
 
     fn init(&self) -> DatabaseResult<()> {
-        self.pool.get()?.batch_execute(
-            "CREATE TABLE IF NOT EXISTS users (
-            id              SERIAL PRIMARY KEY,
-            name            VARCHAR(255) NOT NULL,
-            email           VARCHAR(255) UNIQUE NOT NULL,
-            password_hash   BYTEA NOT NULL,
-            salt            BYTEA NOT NULL,
-            attributes      JSONB NOT NULL DEFAULT '{}'
-        );",
-        )?;
+        self.token_store.init()?;
 
         Ok(())
     }
 }
 
+/// The columns of the public information of a user
+pub(crate) const USER_INFORMATION_COLUMNS: &str = "users.id, users.name, users.email, users.attributes,
+    to_char(users.last_login_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS last_login_at,
+    to_char(users.last_seen_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS last_seen_at";
+/// The columns the user list can be sorted by
+const USER_SORT_COLUMNS: &[&str] = &["id", "name", "email"];
+
+/// The condition of the user list. The search text is matched against the name and
+/// email, which uses a trigram index if the pg_trgm extension is installed.
+const USER_FILTER_CONDITION: &str = "NOT pending_approval AND deleted_at IS NULL
+    AND ($1::TEXT IS NULL OR (name || ' ' || email) ILIKE $1)
+    AND ($2::TEXT IS NULL OR id IN (
+        SELECT user_roles.user_id FROM user_roles, roles
+        WHERE roles.id = user_roles.role_id AND roles.name = $2 AND roles.deleted_at IS NULL
+    ))
+    AND ($3::TEXT IS NULL OR split_part(email, '@', 2) = $3)
+    AND ($4::TEXT IS NULL OR COALESCE(last_seen_at, last_login_at, '-infinity') < $4::TEXT::TIMESTAMPTZ)";
+
+/// Returns the attributes with the changes applied. Attributes that are
+/// changed to null are removed.
+pub fn apply_attribute_changes(attributes: &Value, changes: &Map<String, Value>) -> Value {
+    let mut attributes = match attributes {
+        Value::Object(map) => map.clone(),
+        _ => Map::new(),
+    };
+    for (key, value) in changes {
+        if value.is_null() {
+            attributes.remove(key);
+        } else {
+            attributes.insert(key.clone(), value.clone());
+        }
+    }
+
+    Value::Object(attributes)
+}
+
+/// Escapes the wildcards of a LIKE pattern so that the text is matched literally
+fn escape_like_pattern(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// The state a new user is created in
+enum NewUserState<'a> {
+    Verified,
+    /// The user needs to verify the email address
+    Unverified,
+    /// The user needs to verify the email address and be approved by an administrator
+    PendingApproval,
+    /// The user sets the password when accepting the invitation of the given user
+    Invited(&'a String),
+}
+
+/// A new password hash together with the salt and the identifiers
+/// of the algorithm and pepper that need to be stored with it
+struct PasswordHash {
+    hash: Zeroizing<Vec<u8>>,
+    salt: Zeroizing<Vec<u8>>,
+    algorithm: String,
+    pepper_id: Option<String>,
+}
+
+impl PasswordHash {
+    /// Hashes the password with a new salt and the configured algorithm and pepper
+    fn create(password: &String) -> DatabaseResult<Self> {
+        let salt = Zeroizing::new(create_salt().to_vec());
+        let algorithm = PasswordAlgorithm::configured();
+        let pepper = Pepper::current();
+        let hash = Zeroizing::new(hash_password(
+            password.as_bytes(),
+            &*salt,
+            algorithm,
+            pepper,
+        )?);
+
+        Ok(Self {
+            hash,
+            salt,
+            algorithm: algorithm.to_string(),
+            pepper_id: pepper.map(|p| p.id.clone()),
+        })
+    }
+}
+
 impl Users {
-    /// Creates a new user and returns an error if the user already exists.
-    /// When creating the user first a salt is generated, then the password is hashed
-    /// with BCrypt and the given salt. The salt and the hashed password are then stored into the database
+    /// Creates the users table that stores the sessions in the given session store
+    pub fn with_session_store(pool: PostgresPool, token_store: Arc<dyn SessionStore>) -> Self {
+        Self {
+            user_roles: UserRoles::new(PostgresPool::clone(&pool)),
+            pool,
+            token_store,
+        }
+    }
+
+    /// Creates a new user whose email address isn't verified yet and returns an error
+    /// if the user already exists. When creating the user first a salt is generated,
+    /// then the password is hashed with the configured algorithm and the given salt.
+    /// The salt, the hashed password and the algorithm are then stored into the database.
+    /// The verification token is sent to the user with the verification_requested event.
     pub fn create_user(
         &self,
         name: String,
         email: String,
         password: String,
         attributes: Value,
+    ) -> DatabaseResult<UserRecord> {
+        self.insert_user(name, email, password, attributes, NewUserState::Unverified)
+    }
+
+    /// Creates a new user whose email address is already known to be valid
+    pub fn create_verified_user(
+        &self,
+        name: String,
+        email: String,
+        password: String,
+        attributes: Value,
+    ) -> DatabaseResult<UserRecord> {
+        self.insert_user(name, email, password, attributes, NewUserState::Verified)
+    }
+
+    /// Creates a user that can't log in until the invitation was accepted.
+    /// The invitation token is sent to the user with the invitation_created event
+    /// and lets the user set the password, so that nobody else needs to know it.
+    pub fn invite_user(
+        &self,
+        name: String,
+        email: String,
+        attributes: Value,
+        invited_by: &String,
+    ) -> DatabaseResult<UserRecord> {
+        self.insert_user(
+            name,
+            email,
+            generate_password(),
+            attributes,
+            NewUserState::Invited(invited_by),
+        )
+    }
+
+    fn insert_user(
+        &self,
+        name: String,
+        email: String,
+        password: String,
+        attributes: Value,
+        state: NewUserState,
     ) -> DatabaseResult<UserRecord> {
         let mut connection = self.pool.get()?;
         let mut password = Zeroizing::new(password);
         log::trace!("Creating user {} with email  {}", name, email);
 
-        if !connection
-            .query("SELECT email FROM users WHERE email = $1", &[&email])?
-            .is_empty()
-        {
+        if let Some(row) = connection.query_opt(
+            "SELECT deleted_at IS NOT NULL FROM users WHERE email = $1",
+            &[&email],
+        )? {
             log::trace!("Failed to create user: Record exists!");
-            return Err(DBError::RecordExists);
+            return if row.get(0) {
+                Err(DBError::GenericError(format!(
+                    "The user {} is in the trash and needs to be restored",
+                    email
+                )))
+            } else {
+                Err(DBError::RecordExists)
+            };
         }
-        let salt = Zeroizing::new(create_salt());
-        let pw_hash =
-            hash_password(password.as_bytes(), &*salt).map_err(|e| DBError::GenericError(e))?;
+        let user_count: i64 = connection
+            .query_one("SELECT COUNT(*) FROM users WHERE deleted_at IS NULL", &[])?
+            .get(0);
+        ResourceLimits::get().check(Resource::Users, user_count as u64)?;
+        let pw_hash = PasswordHash::create(&password)?;
         password.zeroize();
-        let row = connection.query_one("
-            INSERT INTO users (name, email, password_hash, salt, attributes) VALUES ($1, $2, $3, $4, $5) RETURNING *;
-        ", &[&name, &email, &pw_hash.to_vec(), &salt.to_vec(), &attributes])?;
+        let pending_approval = matches!(state, NewUserState::PendingApproval);
+        let email_verified = matches!(state, NewUserState::Verified);
+        let verification_token = match state {
+            NewUserState::Unverified | NewUserState::PendingApproval => Some(create_secret_token()),
+            _ => None,
+        };
+        let invitation_token = match state {
+            NewUserState::Invited(_) => Some(create_secret_token()),
+            _ => None,
+        };
+        let mut transaction = connection.transaction()?;
+        let row = transaction.query_one("
+            INSERT INTO users (name, email, password_hash, salt, attributes, pending_approval, email_verified, verification_token, verification_sent_at, password_algorithm, pepper_id, invitation_token, invited_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, CASE WHEN $8::BYTEA IS NULL THEN NULL ELSE NOW() END, $9, $10, $11, CASE WHEN $11::BYTEA IS NULL THEN NULL ELSE NOW() END) RETURNING *;
+        ", &[&name, &email, &*pw_hash.hash, &*pw_hash.salt, &attributes, &pending_approval, &email_verified, &verification_token.as_deref().map(hash_secret_token), &pw_hash.algorithm, &pw_hash.pepper_id, &invitation_token.as_deref().map(hash_secret_token)])?;
+        let record = UserRecord::from_row(row);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            record.id,
+            &record.email,
+            Changes::new()
+                .created("name", &record.name)
+                .created("email", &record.email)
+                .created("attributes", &record.attributes),
+        )?;
+        if pending_approval {
+            events::enqueue(
+                &mut transaction,
+                Event::ApprovalRequested {
+                    email: record.email.clone(),
+                    name: record.name.clone(),
+                },
+            )?;
+        }
+        if let Some(verification_token) = verification_token {
+            events::enqueue(
+                &mut transaction,
+                Event::VerificationRequested {
+                    email: record.email.clone(),
+                    name: record.name.clone(),
+                    verification_token,
+                },
+            )?;
+        }
+        if let (Some(invitation_token), NewUserState::Invited(invited_by)) =
+            (invitation_token, state)
+        {
+            events::enqueue(
+                &mut transaction,
+                Event::InvitationCreated {
+                    email: record.email.clone(),
+                    name: record.name.clone(),
+                    invited_by: invited_by.clone(),
+                    invitation_token,
+                },
+            )?;
+        }
+        transaction.commit()?;
+
+        Ok(record)
+    }
+
+    /// Marks the email address of the user as verified if the token was issued
+    /// for it and hasn't expired
+    pub fn verify_email(&self, email: &String, token: &str) -> DatabaseResult<UserInformation> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                format!("UPDATE users SET email_verified = TRUE, verification_token = NULL, verification_sent_at = NULL
+                WHERE email = $1 AND verification_token = $2 AND NOT email_verified AND deleted_at IS NULL
+                AND verification_sent_at > NOW() - make_interval(hours => $3)
+                RETURNING {}", USER_INFORMATION_COLUMNS).as_str(),
+                &[email, &hash_secret_token(token), &VERIFICATION_HOURS],
+            )?
+            .ok_or(DBError::GenericError(
+                "Invalid or expired verification token".to_string(),
+            ))?;
+        let user = UserInformation::from_row(row);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            user.id,
+            &user.email,
+            Changes::new().field("email_verified", &false, &true),
+        )?;
+        transaction.commit()?;
+        log::debug!("Verified the email address of user {}", user.email);
+
+        Ok(user)
+    }
+
+    /// Returns the user an invitation that hasn't expired was sent to
+    pub fn get_invited_user(&self, token: &str) -> DatabaseResult<UserInformation> {
+        let mut connection = self.pool.get()?;
+        let row = connection
+            .query_opt(
+                format!(
+                    "SELECT {} FROM users WHERE invitation_token = $1 AND deleted_at IS NULL
+                    AND invited_at > NOW() - make_interval(days => $2)",
+                    USER_INFORMATION_COLUMNS
+                )
+                .as_str(),
+                &[&hash_secret_token(token), &INVITATION_DAYS],
+            )?
+            .ok_or(DBError::GenericError(
+                "Invalid or expired invitation".to_string(),
+            ))?;
+
+        Ok(UserInformation::from_row(row))
+    }
+
+    /// Sets the password of an invited user so that it can log in.
+    /// The email address is verified since the invitation was sent to it.
+    pub fn accept_invitation(
+        &self,
+        token: &str,
+        password: &String,
+    ) -> DatabaseResult<UserInformation> {
+        let pw_hash = PasswordHash::create(password)?;
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                format!("UPDATE users SET password_hash = $2, salt = $3, password_algorithm = $4, pepper_id = $5,
+                email_verified = TRUE, invitation_token = NULL, invited_at = NULL
+                WHERE invitation_token = $1 AND deleted_at IS NULL
+                AND invited_at > NOW() - make_interval(days => $6)
+                RETURNING {}", USER_INFORMATION_COLUMNS).as_str(),
+                &[&hash_secret_token(token), &*pw_hash.hash, &*pw_hash.salt, &pw_hash.algorithm, &pw_hash.pepper_id, &INVITATION_DAYS],
+            )?
+            .ok_or(DBError::GenericError(
+                "Invalid or expired invitation".to_string(),
+            ))?;
+        let user = UserInformation::from_row(row);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            user.id,
+            &user.email,
+            Changes::new()
+                .field("invitation_accepted", &false, &true)
+                .secret("password", true),
+        )?;
+        transaction.commit()?;
+        log::debug!("User {} accepted the invitation", user.email);
 
-        Ok(UserRecord::from_row(row))
+        Ok(user)
     }
 
     /// Updates a user
@@ -98,15 +455,25 @@ impl Users {
             attributes,
         );
         let mut connection = self.pool.get()?;
-        if connection
-            .query_opt("SELECT email FROM users WHERE email = $1", &[&old_email])?
-            .is_none()
-        {
+        let mut transaction = connection.transaction()?;
+        let old_record = transaction
+            .query_opt(
+                format!(
+                    "SELECT {} FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+                    USER_INFORMATION_COLUMNS
+                )
+                .as_str(),
+                &[&old_email],
+            )?
+            .map(UserInformation::from_row);
+        let old_record = if let Some(record) = old_record {
+            record
+        } else {
             log::trace!("Failed to create user: Record doesn't exist!");
             return Err(DBError::RecordDoesNotExist);
-        }
+        };
         if old_email != email
-            && connection
+            && transaction
                 .query_opt("SELECT email FROM users WHERE email = $1", &[&email])?
                 .is_some()
         {
@@ -117,81 +484,968 @@ impl Users {
             )));
         }
         let new_record = if let Some(password) = password {
-            let salt = Zeroizing::new(create_salt());
-            let pw_hash =
-                hash_password(password.as_bytes(), &*salt).map_err(|e| DBError::GenericError(e))?;
-            connection.query_one(
-                "UPDATE users SET name = $1, email = $2, password_hash = $3, salt = $4, attributes = $5 WHERE email = $6 RETURNING *",
-                &[&name, &email, &pw_hash.to_vec(), &salt.to_vec(), &attributes, &old_email],
+            let pw_hash = PasswordHash::create(password)?;
+            transaction.query_one(
+                format!("UPDATE users SET name = $1, email = $2, password_hash = $3, salt = $4, attributes = $5, password_algorithm = $7, pepper_id = $8 WHERE email = $6 RETURNING {}", USER_INFORMATION_COLUMNS).as_str(),
+                &[&name, &email, &*pw_hash.hash, &*pw_hash.salt, &attributes, &old_email, &pw_hash.algorithm, &pw_hash.pepper_id],
             )?
         } else {
-            connection.query_one(
-                "UPDATE users SET name = $1, email = $2, attributes = $3 WHERE email = $4 RETURNING *",
+            transaction.query_one(
+                format!("UPDATE users SET name = $1, email = $2, attributes = $3 WHERE email = $4 RETURNING {}", USER_INFORMATION_COLUMNS).as_str(),
                 &[&name, &email, &attributes, &old_email],
             )?
         };
+        let new_record = UserInformation::from_row(new_record);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            new_record.id,
+            &new_record.email,
+            Changes::new()
+                .field("name", &old_record.name, &new_record.name)
+                .field("email", &old_record.email, &new_record.email)
+                .field("attributes", &old_record.attributes, &new_record.attributes)
+                .secret("password", password.is_some()),
+        )?;
+        transaction.commit()?;
+
+        Ok(new_record)
+    }
+
+    /// Adds, replaces or removes single attributes of a user
+    /// without touching the other attributes
+    pub fn update_attributes(
+        &self,
+        email: &String,
+        changes: &Map<String, Value>,
+    ) -> DatabaseResult<Value> {
+        log::trace!("Updating attributes of user {}: {:?}", email, changes);
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "SELECT id, attributes FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[&email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let id: i32 = row.get(0);
+        let old_attributes: Value = row.get(1);
+        let attributes = apply_attribute_changes(&old_attributes, changes);
+        transaction.execute(
+            "UPDATE users SET attributes = $1 WHERE id = $2",
+            &[&attributes, &id],
+        )?;
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            id,
+            email,
+            Changes::new().field("attributes", &old_attributes, &attributes),
+        )?;
+        transaction.commit()?;
 
-        Ok(UserInformation::from_row(new_record))
+        Ok(attributes)
     }
 
     /// Returns information about a user by Id
     pub fn get_user(&self, id: i32) -> DatabaseResult<UserInformation> {
         log::trace!("Looking up entry for user with id {}", id);
         let mut connection = self.pool.get()?;
+        let statement = connection.prepare_cached(GET_USER_QUERY.as_str())?;
         let result = connection
+            .query_opt(&statement, &[&id])?
+            .ok_or(DBError::RecordDoesNotExist)?;
+
+        Ok(UserInformation::from_row(result))
+    }
+
+    /// Returns if the user verified their email address
+    pub fn is_email_verified(&self, id: i32) -> DatabaseResult<bool> {
+        let mut connection = self.pool.get()?;
+        let row = connection
             .query_opt(
-                "SELECT id, name, email, attributes FROM users WHERE id = $1",
+                "SELECT email_verified FROM users WHERE id = $1 AND deleted_at IS NULL",
                 &[&id],
             )?
             .ok_or(DBError::RecordDoesNotExist)?;
 
-        Ok(UserInformation::from_row(result))
+        Ok(row.get(0))
     }
 
     /// Returns the user record by email
     pub fn get_user_by_email(&self, email: &String) -> DatabaseResult<UserInformation> {
         log::trace!("Looking up entry for user with email {}", email);
         let mut connection = self.pool.get()?;
+        let statement = connection.prepare_cached(GET_USER_BY_EMAIL_QUERY.as_str())?;
         let result = connection
-            .query_opt(
-                "SELECT id, name, email, attributes FROM users WHERE email = $1",
-                &[email],
-            )?
+            .query_opt(&statement, &[email])?
             .ok_or(DBError::RecordDoesNotExist)?;
 
         Ok(UserInformation::from_row(result))
     }
 
-    /// Returns all users
-    pub fn get_users(&self) -> DatabaseResult<Vec<UserInformation>> {
-        log::trace!("Returning a list of all users...");
+    /// Returns the requested page of users that match the filter
+    /// together with the total number of matching users
+    pub fn get_users(
+        &self,
+        filter: &UserFilter,
+        sorting: &Sorting,
+        pagination: &Pagination,
+    ) -> DatabaseResult<(Vec<UserInformation>, i64)> {
+        log::trace!("Returning a list of users...");
+        let order_by = sorting.order_by(USER_SORT_COLUMNS)?;
         let mut connection = self.pool.get()?;
-        let results = connection.query("SELECT id, name, email, attributes FROM users", &[])?;
+        let search = filter
+            .search
+            .as_ref()
+            .map(|search| format!("%{}%", escape_like_pattern(search)));
+        let email_domain = filter
+            .email_domain
+            .as_ref()
+            .map(|domain| domain.trim_start_matches('@').to_ascii_lowercase());
+        let total: i64 = connection
+            .query_one(
+                format!("SELECT COUNT(*) FROM users WHERE {}", USER_FILTER_CONDITION).as_str(),
+                &[&search, &filter.role, &email_domain, &filter.inactive_since],
+            )?
+            .get(0);
+        let results = connection.query(
+            format!(
+                "SELECT {} FROM users WHERE {}
+                {} LIMIT $5 OFFSET $6",
+                USER_INFORMATION_COLUMNS, USER_FILTER_CONDITION, order_by
+            )
+            .as_str(),
+            &[
+                &search,
+                &filter.role,
+                &email_domain,
+                &filter.inactive_since,
+                &pagination.limit,
+                &pagination.offset,
+            ],
+        )?;
         let mut users = Vec::new();
 
         for result in results {
             users.push(UserInformation::from_row(result));
         }
 
-        Ok(users)
+        Ok((users, total))
+    }
+
+    /// Passes all users together with the names of their roles to the callback.
+    /// The users are read from a cursor in batches so that large exports
+    /// don't need to be loaded into memory at once.
+    pub fn export_users<F>(&self, mut callback: F) -> DatabaseResult<()>
+    where
+        F: FnMut(UserExport) -> DatabaseResult<()>,
+    {
+        log::trace!("Exporting all users...");
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let portal = transaction.bind(
+            format!(
+                "SELECT {}, users.active, {},
+                ARRAY(
+                    SELECT roles.name FROM user_roles, roles
+                    WHERE user_roles.user_id = users.id AND roles.id = user_roles.role_id
+                    AND roles.deleted_at IS NULL ORDER BY roles.name
+                )::TEXT[] AS roles
+                FROM users WHERE NOT pending_approval AND deleted_at IS NULL ORDER BY users.id",
+                USER_INFORMATION_COLUMNS, EXPIRES_AT_COLUMN
+            )
+            .as_str(),
+            &[],
+        )?;
+        loop {
+            let rows = transaction.query_portal(&portal, EXPORT_BATCH_SIZE)?;
+            for row in &rows {
+                callback(UserExport::from_row(row))?;
+            }
+            if (rows.len() as i32) < EXPORT_BATCH_SIZE {
+                break;
+            }
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Returns the name, email and state of all users with the given ids.
+    /// A user is active if it was approved, hasn't expired and isn't disabled.
+    /// Ids that don't belong to a user are missing in the result.
+    pub fn resolve_users(&self, ids: &Vec<i32>) -> DatabaseResult<HashMap<i32, ResolvedUser>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            "SELECT id, name, email,
+                active AND NOT pending_approval AND COALESCE(expires_at > NOW(), TRUE) AS active
+            FROM users WHERE id = ANY ($1) AND deleted_at IS NULL",
+            &[ids],
+        )?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("id"), ResolvedUser::from_row(row)))
+            .collect())
+    }
+
+    /// Creates a new user that can't log in until it was approved
+    pub fn create_pending_user(
+        &self,
+        name: String,
+        email: String,
+        password: String,
+        attributes: Value,
+    ) -> DatabaseResult<UserRecord> {
+        self.insert_user(
+            name,
+            email,
+            password,
+            attributes,
+            NewUserState::PendingApproval,
+        )
+    }
+
+    /// Returns all users that are waiting for approval
+    pub fn get_pending_users(&self) -> DatabaseResult<Vec<UserInformation>> {
+        let mut connection = self.pool.get()?;
+        let results = connection.query(
+            format!(
+                "SELECT {} FROM users WHERE pending_approval AND deleted_at IS NULL",
+                USER_INFORMATION_COLUMNS
+            )
+            .as_str(),
+            &[],
+        )?;
+
+        Ok(results.into_iter().map(UserInformation::from_row).collect())
+    }
+
+    /// Approves a pending user so that it can log in
+    pub fn approve_user(
+        &self,
+        email: &String,
+        approved_by: &str,
+    ) -> DatabaseResult<UserInformation> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                format!("UPDATE users SET pending_approval = FALSE WHERE email = $1 AND pending_approval AND deleted_at IS NULL
+                RETURNING {}", USER_INFORMATION_COLUMNS).as_str(),
+                &[email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let user = UserInformation::from_row(row);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            user.id,
+            &user.email,
+            Changes::new().field("pending_approval", &true, &false),
+        )?;
+        events::enqueue(
+            &mut transaction,
+            Event::UserApproved {
+                email: email.clone(),
+                approved_by: approved_by.to_string(),
+            },
+        )?;
+        transaction.commit()?;
+
+        Ok(user)
+    }
+
+    /// Rejects a pending user by deleting it
+    pub fn reject_user(&self, email: &String, rejected_by: &str) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                format!(
+                    "DELETE FROM users WHERE email = $1 AND pending_approval AND deleted_at IS NULL
+                RETURNING {}",
+                    USER_INFORMATION_COLUMNS
+                )
+                .as_str(),
+                &[email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        Self::record_deletion(&mut transaction, UserInformation::from_row(row))?;
+        events::enqueue(
+            &mut transaction,
+            Event::UserRejected {
+                email: email.clone(),
+                rejected_by: rejected_by.to_string(),
+            },
+        )?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Returns the number of requests per minute the user is allowed to make.
+    /// None means that the user isn't limited.
+    pub fn get_request_quota(&self, id: i32) -> DatabaseResult<Option<i32>> {
+        let mut connection = self.pool.get()?;
+        let result = connection
+            .query_opt("SELECT request_quota FROM users WHERE id = $1", &[&id])?
+            .ok_or(DBError::RecordDoesNotExist)?;
+
+        Ok(result.get(0))
+    }
+
+    /// Sets the number of requests per minute the user is allowed to make
+    pub fn set_request_quota(&self, email: &String, quota: Option<i32>) -> DatabaseResult<()> {
+        log::trace!("Setting request quota of user {} to {:?}", email, quota);
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "UPDATE users SET request_quota = $2 FROM (SELECT id, request_quota FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE) old
+                WHERE users.id = old.id RETURNING users.id, old.request_quota",
+                &[email, &quota],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let old_quota: Option<i32> = row.get(1);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            row.get(0),
+            email,
+            Changes::new().field("request_quota", &old_quota, &quota),
+        )?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Returns if the account of the user is enabled
+    pub fn is_active(&self, id: i32) -> DatabaseResult<bool> {
+        let mut connection = self.pool.get()?;
+        let row = connection
+            .query_opt(
+                "SELECT active FROM users WHERE id = $1 AND deleted_at IS NULL",
+                &[&id],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+
+        Ok(row.get(0))
+    }
+
+    /// Enables or disables the account of a user. Disabled users keep their
+    /// records and roles but can't log in. All sessions of a disabled user are
+    /// revoked. Returns the number of revoked sessions.
+    pub fn set_active(&self, email: &String, active: bool) -> DatabaseResult<usize> {
+        log::trace!("Setting active state of user {} to {}", email, active);
+        if !active && admin_accounts().iter().any(|a| &a.email == email) {
+            return Err(DBError::SystemRecord(email.clone()));
+        }
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "SELECT id, active FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let id: i32 = row.get(0);
+        let old_active: bool = row.get(1);
+        transaction.execute(
+            "UPDATE users SET active = $2 WHERE id = $1",
+            &[&id, &active],
+        )?;
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            id,
+            email,
+            Changes::new().field("active", &old_active, &active),
+        )?;
+        transaction.commit()?;
+
+        if active {
+            Ok(0)
+        } else {
+            self.revoke_sessions(id)
+        }
+    }
+
+    /// Returns the time the account of the user expires at as RFC 3339 string
+    pub fn get_expiry(&self, id: i32) -> DatabaseResult<Option<String>> {
+        let mut connection = self.pool.get()?;
+        let row = connection
+            .query_opt(
+                format!("SELECT {} FROM users WHERE id = $1", EXPIRES_AT_COLUMN).as_str(),
+                &[&id],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+
+        Ok(row.get(0))
+    }
+
+    /// Sets the time the account of the user expires at.
+    /// The time needs to be a valid RFC 3339 string. None removes the expiry.
+    pub fn set_expiry(&self, email: &String, expires_at: Option<String>) -> DatabaseResult<()> {
+        log::trace!("Setting expiry of user {} to {:?}", email, expires_at);
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let old_row = transaction
+            .query_opt(
+                format!(
+                    "SELECT id, {} FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+                    EXPIRES_AT_COLUMN
+                )
+                .as_str(),
+                &[email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let id: i32 = old_row.get(0);
+        let old_expiry: Option<String> = old_row.get(1);
+        let row = transaction.query_one(
+            format!(
+                "UPDATE users SET expires_at = $2::TEXT::TIMESTAMPTZ, expiry_notified = FALSE, expiry_processed = FALSE
+                WHERE id = $1 RETURNING {}",
+                EXPIRES_AT_COLUMN
+            )
+            .as_str(),
+            &[&id, &expires_at],
+        )?;
+        let new_expiry: Option<String> = row.get(0);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            id,
+            email,
+            Changes::new().field("expires_at", &old_expiry, &new_expiry),
+        )?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Starts the background job that notifies users about the upcoming
+    /// expiry of their accounts, revokes the sessions of expired accounts
+    /// and clears expired sessions from the token store
+    pub fn start_expiry_job(&self) {
+        let users = Users::clone(self);
+        let notice_hours = config::var(ENV_ACCOUNT_EXPIRY_NOTICE_HOURS)
+            .ok()
+            .and_then(|v| v.parse::<i32>().ok())
+            .unwrap_or(DEFAULT_ACCOUNT_EXPIRY_NOTICE_HOURS);
+
+        Builder::new()
+            .name("account-expiry".to_string())
+            .spawn(move || loop {
+                if let Err(e) = users.process_expiries(notice_hours) {
+                    log::error!("Failed to process account expiries: {}", e);
+                }
+                if let Err(e) = users.token_store.clear_expired() {
+                    log::error!("Failed to clear expired sessions: {}", e);
+                }
+                thread::sleep(EXPIRY_JOB_INTERVAL);
+            })
+            .unwrap();
+    }
+
+    /// Sends the expiry notices for accounts that expire within the given
+    /// number of hours and purges the sessions of expired accounts
+    fn process_expiries(&self, notice_hours: i32) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let expiring = transaction.query(
+            format!(
+                "UPDATE users SET expiry_notified = TRUE
+                WHERE NOT expiry_notified AND expires_at > NOW() AND deleted_at IS NULL
+                AND expires_at <= NOW() + make_interval(hours => $1)
+                RETURNING email, {}",
+                EXPIRES_AT_COLUMN
+            )
+            .as_str(),
+            &[&notice_hours],
+        )?;
+        for row in expiring {
+            events::enqueue(
+                &mut transaction,
+                Event::AccountExpiring {
+                    email: row.get(0),
+                    expires_at: row.get(1),
+                },
+            )?;
+        }
+        let expired = transaction.query(
+            "UPDATE users SET expiry_processed = TRUE
+            WHERE NOT expiry_processed AND expires_at <= NOW() AND deleted_at IS NULL
+            RETURNING id, email",
+            &[],
+        )?;
+        let mut expired_ids = Vec::new();
+        for row in expired {
+            expired_ids.push(row.get::<_, i32>(0));
+            events::enqueue(
+                &mut transaction,
+                Event::AccountExpired { email: row.get(1) },
+            )?;
+        }
+        transaction.commit()?;
+
+        for id in expired_ids {
+            let revoked = self.revoke_sessions(id)?;
+            log::info!(
+                "The account of user {} expired. Revoked {} sessions",
+                id,
+                revoked
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Creates or updates the user imported from the directory entry with the given dn
+    /// and returns its id. An existing user with the same email is linked to the entry.
+    /// Users that were disabled because their entry was missing are enabled again.
+    pub fn sync_directory_user(
+        &self,
+        dn: &String,
+        email: &String,
+        name: &String,
+    ) -> DatabaseResult<i32> {
+        let mut connection = self.pool.get()?;
+        let existing = connection.query_opt(
+            "SELECT id FROM users WHERE ldap_dn = $1 OR (ldap_dn IS NULL AND email = $2)
+            ORDER BY ldap_dn IS NULL LIMIT 1",
+            &[dn, email],
+        )?;
+        let id = match existing {
+            Some(row) => row.get(0),
+            None => {
+                log::info!("Importing user {} from the directory", email);
+                self.create_verified_user(
+                    name.clone(),
+                    email.clone(),
+                    generate_password(),
+                    Value::Object(Default::default()),
+                )?
+                .id
+            }
+        };
+        let mut transaction = connection.transaction()?;
+        let old_row = transaction.query_one(
+            "SELECT name, email, ldap_dn, ldap_disabled FROM users WHERE id = $1 FOR UPDATE",
+            &[&id],
+        )?;
+        let old_name: String = old_row.get(0);
+        let old_email: String = old_row.get(1);
+        let old_dn: Option<String> = old_row.get(2);
+        let was_disabled: bool = old_row.get(3);
+        transaction.execute(
+            "UPDATE users SET name = $2, email = $3, ldap_dn = $4, ldap_disabled = FALSE,
+                expires_at = CASE WHEN ldap_disabled THEN NULL ELSE expires_at END,
+                expiry_notified = expiry_notified AND NOT ldap_disabled,
+                expiry_processed = expiry_processed AND NOT ldap_disabled
+            WHERE id = $1",
+            &[&id, name, email, dn],
+        )?;
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            id,
+            email,
+            Changes::new()
+                .field("name", &old_name, name)
+                .field("email", &old_email, email)
+                .field("ldap_dn", &old_dn, &Some(dn.clone()))
+                .field("ldap_disabled", &was_disabled, &false),
+        )?;
+        transaction.commit()?;
+
+        Ok(id)
+    }
+
+    /// Disables the users imported from the directory whose entries aren't part of the given dns.
+    /// Their accounts expire immediately so that the expiry job revokes their sessions.
+    /// Returns the emails of the disabled users.
+    pub fn disable_directory_users(&self, dns: &Vec<String>) -> DatabaseResult<Vec<String>> {
+        let admin_emails = admin_accounts()
+            .into_iter()
+            .map(|a| a.email)
+            .collect::<Vec<String>>();
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let rows = transaction.query(
+            "UPDATE users SET ldap_disabled = TRUE, expires_at = NOW(),
+                expiry_notified = TRUE, expiry_processed = FALSE
+            WHERE ldap_dn IS NOT NULL AND NOT ldap_disabled
+            AND NOT (ldap_dn = ANY($1)) AND NOT (email = ANY($2))
+            RETURNING id, email",
+            &[dns, &admin_emails],
+        )?;
+        let mut emails = Vec::new();
+        for row in rows {
+            let id: i32 = row.get(0);
+            let email: String = row.get(1);
+            change_history::record(
+                &mut transaction,
+                ENTITY_USER,
+                id,
+                &email,
+                Changes::new().field("ldap_disabled", &false, &true),
+            )?;
+            emails.push(email);
+        }
+        transaction.commit()?;
+
+        Ok(emails)
     }
 
-    /// Deletes a user if it's not the admin user
-    pub fn delete_user(&self, email: &String) -> DatabaseResult<()> {
+    /// Moves a user to the trash if it's not an admin user. Trashed users can't
+    /// log in and are hidden until they are restored or purged.
+    /// All sessions of the user are revoked. Returns the time after which the user is purged.
+    pub fn delete_user(&self, email: &String) -> DatabaseResult<String> {
         log::trace!("Deleting user with email {}", email);
-        if email == DEFAULT_ADMIN_EMAIL {
+        if admin_accounts().iter().any(|a| &a.email == email) {
+            return Err(DBError::SystemRecord(email.clone()));
+        }
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "UPDATE users SET deleted_at = NOW() WHERE email = $1 AND deleted_at IS NULL
+                RETURNING id, to_char((deleted_at + make_interval(days => $2)) AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"')",
+                &[email, &*USER_TRASH_RETENTION_DAYS],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let id: i32 = row.get(0);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            id,
+            email,
+            Changes::new().field("deleted", &false, &true),
+        )?;
+        transaction.commit()?;
+        PERMISSION_CACHE.invalidate_user(id);
+        let revoked = self.revoke_sessions(id)?;
+        log::debug!("Revoked {} sessions of the deleted user {}", revoked, email);
+
+        Ok(row.get(1))
+    }
+
+    /// Returns all users in the trash
+    pub fn get_trashed_users(&self) -> DatabaseResult<Vec<TrashedUser>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            "SELECT name, email,
+                to_char(deleted_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS deleted_at,
+                to_char((deleted_at + make_interval(days => $1)) AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS purge_at
+            FROM users WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC",
+            &[&*USER_TRASH_RETENTION_DAYS],
+        )?;
+
+        Ok(rows.into_iter().map(TrashedUser::from_row).collect())
+    }
+
+    /// Restores a trashed user together with its roles and memberships
+    pub fn restore_user(&self, email: &String) -> DatabaseResult<UserInformation> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                format!(
+                    "UPDATE users SET deleted_at = NULL WHERE email = $1 AND deleted_at IS NOT NULL
+                RETURNING {}",
+                    USER_INFORMATION_COLUMNS
+                )
+                .as_str(),
+                &[email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let user = UserInformation::from_row(row);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            user.id,
+            &user.email,
+            Changes::new().field("deleted", &true, &false),
+        )?;
+        transaction.commit()?;
+        PERMISSION_CACHE.invalidate_user(user.id);
+
+        Ok(user)
+    }
+
+    /// Starts the background job that permanently deletes users
+    /// that have been in the trash for longer than the retention window
+    pub fn start_purge_job(&self) {
+        let users = Users::clone(self);
+
+        Builder::new()
+            .name("user-purge".to_string())
+            .spawn(move || loop {
+                if let Err(e) = users.purge_trashed_users() {
+                    log::error!("Failed to purge trashed users: {}", e);
+                }
+                thread::sleep(PURGE_JOB_INTERVAL);
+            })
+            .unwrap();
+    }
+
+    /// Permanently deletes the users whose retention window ended
+    fn purge_trashed_users(&self) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let rows = transaction.query(
+            format!(
+                "DELETE FROM users WHERE deleted_at < NOW() - make_interval(days => $1)
+            RETURNING {}",
+                USER_INFORMATION_COLUMNS
+            )
+            .as_str(),
+            &[&*USER_TRASH_RETENTION_DAYS],
+        )?;
+        for row in rows {
+            let user = UserInformation::from_row(row);
+            log::info!("Purged the trashed user {}", user.email);
+            Self::record_deletion(&mut transaction, user)?;
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Sets a new password for the user and revokes all of its sessions.
+    /// The user needs to change the password on the next login.
+    pub fn reset_password(&self, email: &String, password: &String) -> DatabaseResult<()> {
+        log::trace!("Resetting password of user {}", email);
+        self.set_password(email, password, true)
+    }
+
+    /// Changes the password of the user and lifts the requirement to change it.
+    /// All sessions of the user are revoked.
+    pub fn change_password(&self, email: &String, password: &String) -> DatabaseResult<()> {
+        log::trace!("Changing password of user {}", email);
+        self.set_password(email, password, false)
+    }
+
+    fn set_password(
+        &self,
+        email: &String,
+        password: &String,
+        must_change_password: bool,
+    ) -> DatabaseResult<()> {
+        let pw_hash = PasswordHash::create(password)?;
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "SELECT id, must_change_password FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let id: i32 = row.get(0);
+        let old_must_change: bool = row.get(1);
+        transaction.execute(
+            "UPDATE users SET password_hash = $2, salt = $3, must_change_password = $4, password_algorithm = $5, pepper_id = $6 WHERE id = $1",
+            &[&id, &*pw_hash.hash, &*pw_hash.salt, &must_change_password, &pw_hash.algorithm, &pw_hash.pepper_id],
+        )?;
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            id,
+            email,
+            Changes::new().secret("password", true).field(
+                "must_change_password",
+                &old_must_change,
+                &must_change_password,
+            ),
+        )?;
+        transaction.commit()?;
+        let revoked = self.revoke_sessions(id)?;
+        log::debug!("Revoked {} sessions of user {}", revoked, email);
+
+        Ok(())
+    }
+
+    /// Merges the duplicate account into the primary one. The primary user gets all roles
+    /// of the duplicate and all attributes it doesn't have yet. Records referencing the
+    /// duplicate like organization memberships, api keys, client certificates, oauth consents,
+    /// login events and the change history are moved to the primary user before the
+    /// duplicate is deleted and the sessions of both accounts are revoked.
+    /// With dry_run the changes are rolled back and only the result is returned.
+    pub fn merge_users(
+        &self,
+        primary_email: &String,
+        duplicate_email: &String,
+        dry_run: bool,
+    ) -> DatabaseResult<UserMerge> {
+        log::trace!(
+            "Merging user {} into {} (dry run: {})",
+            duplicate_email,
+            primary_email,
+            dry_run
+        );
+        if primary_email == duplicate_email {
             return Err(DBError::GenericError(
-                "the admin user can't be deleted".to_string(),
+                "a user can't be merged into itself".to_string(),
             ));
         }
+        if admin_accounts().iter().any(|a| &a.email == duplicate_email) {
+            return Err(DBError::SystemRecord(duplicate_email.clone()));
+        }
         let mut connection = self.pool.get()?;
-        let exists = connection.query_opt("SELECT id FROM users WHERE email = $1", &[email])?;
-        if exists.is_none() {
-            return Err(DBError::RecordDoesNotExist);
+        let mut transaction = connection.transaction()?;
+        let primary = transaction
+            .query_opt(
+                "SELECT id, attributes FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[primary_email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let duplicate = transaction
+            .query_opt(
+                "SELECT id, attributes FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[duplicate_email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let primary_id: i32 = primary.get(0);
+        let duplicate_id: i32 = duplicate.get(0);
+        let primary_attributes: Value = primary.get(1);
+        let duplicate_attributes: Value = duplicate.get(1);
+
+        let added_roles = transaction
+            .query(
+                "INSERT INTO user_roles (user_id, role_id)
+                SELECT $1, role_id FROM user_roles WHERE user_id = $2
+                ON CONFLICT DO NOTHING
+                RETURNING (SELECT name FROM roles WHERE roles.id = role_id)",
+                &[&primary_id, &duplicate_id],
+            )?
+            .into_iter()
+            .map(|row| -> String { row.get(0) })
+            .collect::<Vec<String>>();
+
+        let added_attributes = match (&primary_attributes, &duplicate_attributes) {
+            (Value::Object(primary), Value::Object(duplicate)) => duplicate
+                .keys()
+                .filter(|key| !primary.contains_key(*key))
+                .cloned()
+                .collect::<Vec<String>>(),
+            _ => Vec::new(),
+        };
+        let row = transaction.query_one(
+            format!(
+                "UPDATE users SET attributes = $2 || attributes WHERE id = $1 RETURNING {}",
+                USER_INFORMATION_COLUMNS
+            )
+            .as_str(),
+            &[&primary_id, &duplicate_attributes],
+        )?;
+        let user = UserInformation::from_row(row);
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            user.id,
+            &user.email,
+            Changes::new()
+                .field("attributes", &primary_attributes, &user.attributes)
+                .field("merged_roles", &Vec::<String>::new(), &added_roles)
+                .created("merged_user", duplicate_email),
+        )?;
+
+        let mut moved_records = transaction.execute(
+            "UPDATE email_changes SET user_id = $1 WHERE user_id = $2",
+            &[&primary_id, &duplicate_id],
+        )?;
+        moved_records += transaction.execute(
+            "INSERT INTO organization_members (organization_id, user_id)
+            SELECT organization_id, $1 FROM organization_members WHERE user_id = $2
+            ON CONFLICT DO NOTHING",
+            &[&primary_id, &duplicate_id],
+        )?;
+        moved_records += transaction.execute(
+            "INSERT INTO organization_roles (organization_id, user_id, role_id)
+            SELECT organization_id, $1, role_id FROM organization_roles WHERE user_id = $2
+            ON CONFLICT DO NOTHING",
+            &[&primary_id, &duplicate_id],
+        )?;
+        moved_records += transaction.execute(
+            "UPDATE api_keys SET user_id = $1 WHERE user_id = $2",
+            &[&primary_id, &duplicate_id],
+        )?;
+        moved_records += transaction.execute(
+            "UPDATE client_certificates SET user_id = $1 WHERE user_id = $2",
+            &[&primary_id, &duplicate_id],
+        )?;
+        moved_records += transaction.execute(
+            "INSERT INTO oauth_consents (client_id, user_id, scopes, granted_at)
+            SELECT client_id, $1, scopes, granted_at FROM oauth_consents WHERE user_id = $2
+            ON CONFLICT DO NOTHING",
+            &[&primary_id, &duplicate_id],
+        )?;
+        moved_records += transaction.execute(
+            "UPDATE login_events SET user_id = $1 WHERE user_id = $2",
+            &[&primary_id, &duplicate_id],
+        )?;
+        moved_records += transaction.execute(
+            "UPDATE change_history SET entity_id = $1 WHERE entity_type = $2 AND entity_id = $3",
+            &[&primary_id, &ENTITY_USER, &duplicate_id],
+        )?;
+        let row = transaction.query_one(
+            format!(
+                "DELETE FROM users WHERE id = $1 RETURNING {}",
+                USER_INFORMATION_COLUMNS
+            )
+            .as_str(),
+            &[&duplicate_id],
+        )?;
+        Self::record_deletion(&mut transaction, UserInformation::from_row(row))?;
+
+        if dry_run {
+            transaction.rollback()?;
+        } else {
+            transaction.commit()?;
+            PERMISSION_CACHE.invalidate_user(primary_id);
+            PERMISSION_CACHE.invalidate_user(duplicate_id);
+            let revoked = self.revoke_sessions(primary_id)? + self.revoke_sessions(duplicate_id)?;
+            log::debug!(
+                "Merged user {} into {} and revoked {} sessions",
+                duplicate_email,
+                primary_email,
+                revoked
+            );
         }
-        connection.query("DELETE FROM users WHERE email = $1", &[email])?;
 
-        Ok(())
+        Ok(UserMerge {
+            user,
+            merged_email: duplicate_email.clone(),
+            added_roles,
+            added_attributes,
+            moved_records,
+        })
+    }
+
+    /// Stores the values of a deleted user in the change history
+    fn record_deletion(transaction: &mut Transaction, user: UserInformation) -> DatabaseResult<()> {
+        change_history::record(
+            transaction,
+            ENTITY_USER,
+            user.id,
+            &user.email,
+            Changes::new()
+                .deleted("name", &user.name)
+                .deleted("email", &user.email)
+                .deleted("attributes", &user.attributes),
+        )
+    }
+
+    /// Returns the active sessions of a user
+    pub fn list_sessions(&self, id: i32) -> DatabaseResult<Vec<SessionInfo>> {
+        self.token_store.list_sessions(id)
+    }
+
+    /// Invalidates all sessions of a user and returns the number of revoked sessions
+    pub fn revoke_sessions(&self, id: i32) -> DatabaseResult<usize> {
+        self.token_store.remove_user(id)
     }
 
     /// Creates new tokens for a user login that can be used by services
@@ -202,37 +1456,183 @@ impl Users {
         password: &String,
     ) -> DatabaseResult<SessionTokens> {
         log::trace!("Creating new tokens for user with email {}", email);
-        if self.validate_login(&email, password)? {
+        let row = {
             let mut connection = self.pool.get()?;
-            let row = connection.query_one("SELECT id FROM users WHERE email = $1", &[&email])?;
-            let id: i32 = row.get(0);
-
-            let tokens = SessionTokens::new(id);
-            tokens.store(&mut self.token_store.lock())?;
+            let statement = connection.prepare_cached(
+                "SELECT id, pending_approval, COALESCE(expires_at <= NOW(), FALSE), email_verified, must_change_password, active,
+                invitation_token IS NOT NULL
+                FROM users WHERE email = $1 AND deleted_at IS NULL",
+            )?;
+            connection.query_opt(&statement, &[&email])?
+        };
+        let row = match row {
+            Some(row) => row,
+            None => {
+                self.record_login(email, None, login_events::LOGIN_UNKNOWN_USER);
+                return Err(DBError::GenericError(format!(
+                    "No user with the email '{}' found",
+                    email
+                )));
+            }
+        };
+        let id: i32 = row.get(0);
+        let pending_approval: bool = row.get(1);
+        let expired: bool = row.get(2);
+        let email_verified: bool = row.get(3);
+        let must_change_password: bool = row.get(4);
+        let active: bool = row.get(5);
+        let invited: bool = row.get(6);
+        if !self.validate_login(email, password)? {
+            self.record_login(email, Some(id), login_events::LOGIN_INVALID_PASSWORD);
+            return Err(DBError::GenericError("Invalid password".to_string()));
+        }
+        if !active {
+            self.record_login(email, Some(id), login_events::LOGIN_DISABLED);
+            return Err(DBError::GenericError("The account is disabled".to_string()));
+        }
+        if invited {
+            self.record_login(email, Some(id), login_events::LOGIN_INVITATION_PENDING);
+            return Err(DBError::GenericError(
+                "The invitation hasn't been accepted yet".to_string(),
+            ));
+        }
+        if pending_approval {
+            self.record_login(email, Some(id), login_events::LOGIN_PENDING_APPROVAL);
+            return Err(DBError::GenericError(
+                "The account is waiting for approval".to_string(),
+            ));
+        }
+        if expired {
+            self.record_login(email, Some(id), login_events::LOGIN_EXPIRED);
+            return Err(DBError::GenericError("The account has expired".to_string()));
+        }
+        if !email_verified && *REQUIRE_EMAIL_VERIFICATION {
+            self.record_login(email, Some(id), login_events::LOGIN_UNVERIFIED);
+            return Err(DBError::GenericError(
+                "The email address hasn't been verified".to_string(),
+            ));
+        }
 
-            Ok(tokens)
+        let mut tokens = SessionTokens::new(id);
+        ResourceLimits::get().check(
+            Resource::SessionsPerUser,
+            self.token_store.active_sessions(id)? as u64,
+        )?;
+        if must_change_password {
+            // the session can only be used to change the password
+            self.token_store.insert_session(
+                &tokens.request_token,
+                &tokens.refresh_token,
+                TokenKind::PasswordChange,
+            )?;
         } else {
-            Err(DBError::GenericError("Invalid password".to_string()))
+            tokens.store(&*self.token_store)?;
+            self.issue_jwt(id, &mut tokens)?;
+        }
+        self.pool.get()?.execute(
+            "UPDATE users SET last_login_at = NOW() WHERE id = $1",
+            &[&id],
+        )?;
+        self.record_login(email, Some(id), login_events::LOGIN_SUCCEEDED);
+
+        Ok(tokens)
+    }
+
+    /// Adds a JWT containing the enabled roles of the user to the tokens if JWTs are enabled
+    fn issue_jwt(&self, id: i32, tokens: &mut SessionTokens) -> DatabaseResult<()> {
+        if !jwt::is_enabled() {
+            return Ok(());
+        }
+        let roles = self
+            .user_roles
+            .by_user(id)?
+            .into_iter()
+            .filter(|role| role.enabled)
+            .map(|role| role.name)
+            .collect();
+        tokens.jwt = jwt::create_token(id, roles, tokens.request_ttl)?;
+
+        Ok(())
+    }
+
+    /// Stores the outcome of a login attempt. Failing to store it doesn't affect the login.
+    fn record_login(&self, email: &String, id: Option<i32>, outcome: &str) {
+        let result = self
+            .pool
+            .get()
+            .map_err(DBError::from)
+            .and_then(|mut connection| login_events::record(&mut connection, email, id, outcome));
+        if let Err(e) = result {
+            log::error!("Failed to record the login of {}: {}", email, e);
         }
     }
 
     /// Validates a request token and returns if it's valid and the
     /// ttl of the token
     pub fn validate_request_token(&self, token: &String) -> DatabaseResult<(bool, i32)> {
-        let mut store = self.token_store.lock();
-        let entry = store.get_by_request_token(&token);
+        match self.get_request_token_entry(token)? {
+            Some(entry) => Ok((true, entry.request_ttl())),
+            None => Ok((false, -1)),
+        }
+    }
 
-        if let Some(entry) = entry {
-            Ok((true, entry.request_ttl()))
-        } else {
-            Ok((false, -1))
+    /// Returns the session of a valid request token
+    pub fn get_request_token_entry(
+        &self,
+        token: &String,
+    ) -> DatabaseResult<Option<TokenStoreEntry>> {
+        if !verify_encoded_token(token) {
+            return Ok(None);
+        }
+        let entry = self.token_store.get_by_request_token(token)?;
+        if let Some(entry) = &entry {
+            self.touch_session(entry.user_id(), entry.kind());
+        }
+
+        Ok(entry)
+    }
+
+    /// Stores the time of the last request of the user the session belongs to.
+    /// The time is written at most once a minute per user and failing to write
+    /// it doesn't affect the request.
+    fn touch_session(&self, user_id: i32, kind: &TokenKind) {
+        if !matches!(kind, TokenKind::Session | TokenKind::Client { .. }) {
+            return;
+        }
+        {
+            let mut last_seen = LAST_SEEN.lock();
+            let now = Instant::now();
+            match last_seen.get(&user_id) {
+                Some(time) if now.duration_since(*time) < LAST_SEEN_INTERVAL => return,
+                _ => last_seen.insert(user_id, now),
+            };
+        }
+        let result = self
+            .pool
+            .get()
+            .map_err(DBError::from)
+            .and_then(|mut connection| {
+                let statement = connection
+                    .prepare_cached("UPDATE users SET last_seen_at = NOW() WHERE id = $1")?;
+                connection
+                    .execute(&statement, &[&user_id])
+                    .map_err(DBError::from)
+            });
+        if let Err(e) = result {
+            log::error!(
+                "Failed to store the last activity of user {}: {}",
+                user_id,
+                e
+            );
         }
     }
 
     /// Validates a refresh token and returns if it's valid and the ttl
     pub fn validate_refresh_token(&self, token: &String) -> DatabaseResult<(bool, i32)> {
-        let mut store = self.token_store.lock();
-        let entry = store.get_by_refresh_token(&token);
+        if !verify_encoded_token(token) {
+            return Ok((false, -1));
+        }
+        let entry = self.token_store.get_by_refresh_token(token)?;
 
         if let Some(entry) = entry {
             Ok((true, entry.refresh_ttl()))
@@ -241,16 +1641,239 @@ impl Users {
         }
     }
 
+    /// Creates an anonymous token that only grants the requested permissions.
+    /// Only permissions that are whitelisted in the env can be requested,
+    /// if none are requested all whitelisted permissions are granted.
+    pub fn create_guest_token(
+        &self,
+        permissions: Option<Vec<String>>,
+    ) -> DatabaseResult<GuestToken> {
+        if GUEST_PERMISSIONS.is_empty() {
+            return Err(DBError::GenericError(
+                "Guest tokens are disabled".to_string(),
+            ));
+        }
+        let permissions = permissions.unwrap_or_else(|| GUEST_PERMISSIONS.clone());
+        if let Some(permission) = permissions.iter().find(|p| !GUEST_PERMISSIONS.contains(p)) {
+            return Err(DBError::GenericError(format!(
+                "The permission {} can't be granted to guests",
+                permission
+            )));
+        }
+        let token = GuestToken::new(*GUEST_TOKEN_TTL, permissions);
+        token.store(&*self.token_store, *GUEST_TOKEN_LIMIT)?;
+
+        Ok(token)
+    }
+
+    /// Creates a request token that acts as the user on behalf of the administrator
+    /// with the given id. The id of the administrator is stored with the session.
+    pub fn create_impersonation_token(
+        &self,
+        user: &UserInformation,
+        admin_id: i32,
+    ) -> DatabaseResult<ImpersonationToken> {
+        let tokens = SessionTokens::new(user.id);
+        self.token_store.insert_session(
+            &tokens.request_token,
+            &tokens.refresh_token,
+            TokenKind::Impersonation { admin_id },
+        )?;
+        self.record_login(&user.email, Some(user.id), login_events::LOGIN_IMPERSONATED);
+
+        Ok(ImpersonationToken {
+            request_token: tokens.request_token.clone(),
+            request_ttl: tokens.request_ttl,
+            email: user.email.clone(),
+        })
+    }
+
+    /// Returns the names of the permissions a valid request token grants.
+    /// For guest tokens these are the permissions of the token,
+    /// for all other tokens the permissions of the user.
+    pub fn get_token_permissions(&self, token: &String) -> DatabaseResult<Option<Vec<String>>> {
+        if !verify_encoded_token(token) {
+            return Ok(None);
+        }
+        let entry = match self.token_store.get_by_request_token(token)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let user_id = entry.user_id();
+        self.touch_session(user_id, entry.kind());
+        let permissions = match entry.kind().clone() {
+            TokenKind::Guest(permissions) => permissions,
+            TokenKind::PasswordChange => Vec::new(),
+            TokenKind::Session | TokenKind::Impersonation { .. } => self
+                .get_permission_names(user_id)?
+                .iter()
+                .cloned()
+                .collect(),
+            TokenKind::Client { scopes, .. } => self
+                .get_permission_names(user_id)?
+                .iter()
+                .filter(|p| scopes.contains(p))
+                .cloned()
+                .collect(),
+        };
+
+        Ok(Some(permissions))
+    }
+
+    /// Returns if the user needs to change the password before the account can be used
+    pub fn must_change_password(&self, id: i32) -> DatabaseResult<bool> {
+        let mut connection = self.pool.get()?;
+        let row = connection
+            .query_opt(
+                "SELECT must_change_password FROM users WHERE id = $1 AND deleted_at IS NULL",
+                &[&id],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+
+        Ok(row.get(0))
+    }
+
+    /// Sets if the user needs to change the password on the next login
+    pub fn set_must_change_password(&self, email: &String, value: bool) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "SELECT id, must_change_password FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let id: i32 = row.get(0);
+        let old_value: bool = row.get(1);
+        transaction.execute(
+            "UPDATE users SET must_change_password = $2 WHERE id = $1",
+            &[&id, &value],
+        )?;
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            id,
+            email,
+            Changes::new().field("must_change_password", &old_value, &value),
+        )?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Returns if the user is an account of a service that authenticates
+    /// with api keys or client certificates
+    pub fn is_service_account(&self, id: i32) -> DatabaseResult<bool> {
+        let mut connection = self.pool.get()?;
+        let row = connection
+            .query_opt(
+                "SELECT service_account FROM users WHERE id = $1 AND deleted_at IS NULL",
+                &[&id],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+
+        Ok(row.get(0))
+    }
+
+    /// Sets if the user is an account of a service
+    pub fn set_service_account(&self, email: &String, value: bool) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let row = transaction
+            .query_opt(
+                "SELECT id, service_account FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[email],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?;
+        let id: i32 = row.get(0);
+        let old_value: bool = row.get(1);
+        transaction.execute(
+            "UPDATE users SET service_account = $2 WHERE id = $1",
+            &[&id, &value],
+        )?;
+        change_history::record(
+            &mut transaction,
+            ENTITY_USER,
+            id,
+            email,
+            Changes::new().field("service_account", &old_value, &value),
+        )?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Creates tokens for a user that authorized a client application.
+    /// The tokens only grant the permissions of the user that are part of the scopes.
+    pub fn create_client_tokens(
+        &self,
+        id: i32,
+        client_id: &str,
+        scopes: Vec<String>,
+    ) -> DatabaseResult<SessionTokens> {
+        let mut connection = self.pool.get()?;
+        let inactive: bool = connection
+            .query_opt(
+                "SELECT NOT active OR pending_approval OR COALESCE(expires_at <= NOW(), FALSE) OR (NOT email_verified AND $2)
+                FROM users WHERE id = $1 AND deleted_at IS NULL",
+                &[&id, &*REQUIRE_EMAIL_VERIFICATION],
+            )?
+            .ok_or(DBError::RecordDoesNotExist)?
+            .get(0);
+        if inactive {
+            return Err(DBError::GenericError("The account is inactive".to_string()));
+        }
+        let tokens = SessionTokens::new(id);
+        ResourceLimits::get().check(
+            Resource::SessionsPerUser,
+            self.token_store.active_sessions(id)? as u64,
+        )?;
+        self.token_store.insert_client(
+            &tokens.request_token,
+            &tokens.refresh_token,
+            client_id.to_string(),
+            scopes,
+        )?;
+
+        Ok(tokens)
+    }
+
+    /// Invalidates the sessions issued to a client application. If a user is given
+    /// only the sessions of that user are invalidated.
+    pub fn revoke_client_sessions(
+        &self,
+        id: Option<i32>,
+        client_id: &str,
+    ) -> DatabaseResult<usize> {
+        self.token_store.remove_client(id, client_id)
+    }
+
     /// Returns a new request token for a given refresh token
     /// if the refresh token is valid
     pub fn refresh_tokens(&self, refresh_token: &String) -> DatabaseResult<SessionTokens> {
-        let mut token_store = self.token_store.lock();
-        let tokens = token_store.get_by_refresh_token(refresh_token);
+        if !verify_encoded_token(refresh_token) {
+            return Err(DBError::GenericError("Invalid refresh token!".to_string()));
+        }
+        let entry = self.token_store.get_by_refresh_token(refresh_token)?;
+        // the roles in a JWT would grant more than password change
+        // or scoped client sessions are allowed to do
+        let issues_jwt = match entry.as_ref().map(|e| e.kind()) {
+            Some(TokenKind::PasswordChange) | Some(TokenKind::Client { .. }) => false,
+            Some(TokenKind::Impersonation { .. }) => {
+                return Err(DBError::GenericError(
+                    "Impersonation tokens can't be refreshed".to_string(),
+                ))
+            }
+            _ => true,
+        };
 
-        if let Some(mut tokens) = tokens.and_then(|t| SessionTokens::from_entry(t)) {
+        if let Some(mut tokens) = entry.and_then(|t| SessionTokens::from_entry(refresh_token, &t)) {
             log::trace!("Tokens found. Refreshing...");
             tokens.refresh();
-            tokens.store(&mut token_store)?;
+            tokens.store(&*self.token_store)?;
+            if issues_jwt {
+                self.issue_jwt(tokens.get_user_id(), &mut tokens)?;
+            }
             log::trace!("Tokens successfully refreshed.");
 
             Ok(tokens)
@@ -260,70 +1883,169 @@ impl Users {
         }
     }
 
-    pub fn delete_tokens(&self, request_token: &String) -> DatabaseResult<bool> {
-        let mut token_store = self.token_store.lock();
-        let tokens = token_store.get_by_request_token(request_token);
+    /// Extends the ttl of a valid request token of a session and returns
+    /// the remaining ttls of the request and refresh token
+    pub fn extend_session(&self, request_token: &str) -> DatabaseResult<(i32, i32)> {
+        let entry = self
+            .token_store
+            .get_by_request_token(request_token)?
+            .ok_or(DBError::GenericError("Invalid request token".to_string()))?;
+        match entry.kind() {
+            TokenKind::Guest(_) => {
+                return Err(DBError::GenericError(
+                    "Guest tokens can't be extended".to_string(),
+                ))
+            }
+            TokenKind::Impersonation { .. } => {
+                return Err(DBError::GenericError(
+                    "Impersonation tokens can't be extended".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        let entry = self
+            .token_store
+            .extend_request_ttl(request_token)?
+            .ok_or(DBError::GenericError("Invalid request token".to_string()))?;
 
-        if let Some(tokens) = tokens {
-            tokens.invalidate();
+        Ok((entry.request_ttl(), entry.refresh_ttl()))
+    }
 
+    pub fn delete_tokens(&self, request_token: &str) -> DatabaseResult<bool> {
+        if self.token_store.remove_by_request_token(request_token)? {
             Ok(true)
         } else {
             Err(DBError::GenericError("Invalid request token!".to_string()))
         }
     }
 
+    /// Returns statistics about the sessions in the token store
+    pub fn token_store_stats(&self) -> DatabaseResult<TokenStoreStats> {
+        self.token_store.stats()
+    }
+
     /// Returns if the user has the given permission
     pub fn has_permission(&self, id: i32, permission: &str) -> DatabaseResult<bool> {
+        Ok(self.get_permission_names(id)?.contains(permission))
+    }
+
+    /// Returns the names of all permissions granted to the user.
+    /// The result is cached until the grants of the user change.
+    pub fn get_permission_names(&self, id: i32) -> DatabaseResult<Arc<HashSet<String>>> {
+        if let Some(permissions) = PERMISSION_CACHE.get(id) {
+            return Ok(permissions);
+        }
+        let generation = PERMISSION_CACHE.generation();
         let mut connection = self.pool.get()?;
-        let row = connection.query_opt(
+        let statement = connection.prepare_cached(
             "\
-            SELECT * FROM user_roles, role_permissions, permissions
+            SELECT permissions.name FROM user_roles, roles, role_permissions, permissions
             WHERE user_roles.user_id = $1 
+            AND user_roles.role_id = roles.id
+            AND roles.enabled
+            AND roles.deleted_at IS NULL
             AND user_roles.role_id = role_permissions.role_id
             AND role_permissions.permission_id = permissions.id
-            AND permissions.name = $2
-            LIMIT 1
         ",
-            &[&id, &permission],
         )?;
-        Ok(row.is_some())
+        let rows = connection.query(&statement, &[&id])?;
+        let permissions = rows
+            .into_iter()
+            .map(|row| -> String { row.get(0) })
+            .collect::<HashSet<String>>();
+
+        Ok(PERMISSION_CACHE.insert(id, generation, permissions))
     }
 
     /// Validates the login data of the user by creating the hash for the given password
-    /// and comparing it with the database entry
+    /// with the algorithm and pepper the stored hash was created with and comparing both.
+    /// If the login is valid and the hash was created with a different algorithm
+    /// or pepper than the configured one the password is hashed again.
     pub fn validate_login(&self, email: &String, password: &String) -> DatabaseResult<bool> {
         let mut connection = self.pool.get()?;
+        let statement = connection.prepare_cached(
+            "SELECT password_hash, salt, password_algorithm, pepper_id FROM users WHERE email = $1 AND deleted_at IS NULL",
+        )?;
         let row = connection
-            .query_opt(
-                "SELECT password_hash, salt FROM users WHERE email = $1",
-                &[&email],
-            )?
+            .query_opt(&statement, &[&email])?
             .ok_or(DBError::GenericError(format!(
                 "No user with the email '{}' found",
                 &email
             )))?;
         let original_pw_hash: Zeroizing<Vec<u8>> = Zeroizing::new(row.get(0));
         let salt: Zeroizing<Vec<u8>> = Zeroizing::new(row.get(1));
-        let pw_hash =
-            hash_password(password.as_bytes(), &*salt).map_err(|e| DBError::GenericError(e))?;
+        let algorithm = PasswordAlgorithm::parse(row.get(2)).map_err(DBError::GenericError)?;
+        let pepper_id: Option<String> = row.get(3);
+        let pepper = match &pepper_id {
+            Some(id) => Some(Pepper::by_id(id).ok_or(DBError::GenericError(format!(
+                "The pepper {} of the password hash is not configured",
+                id
+            )))?),
+            None => None,
+        };
+        let pw_hash = Zeroizing::new(hash_password(
+            password.as_bytes(),
+            &*salt,
+            &algorithm,
+            pepper,
+        )?);
+        if !constant_time_eq(&pw_hash, original_pw_hash.as_slice()) {
+            return Ok(false);
+        }
+        if &algorithm != PasswordAlgorithm::configured()
+            || pepper_id.as_deref() != Pepper::current().map(|p| p.id.as_str())
+        {
+            if let Err(e) = self.rehash_password(email, password, &original_pw_hash) {
+                log::error!("Failed to rehash the password of {}: {}", email, e);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Replaces the hash of the password with one created with the configured
+    /// algorithm and pepper. The hash is only replaced if the password wasn't
+    /// changed in the meantime.
+    fn rehash_password(
+        &self,
+        email: &String,
+        password: &String,
+        old_hash: &[u8],
+    ) -> DatabaseResult<()> {
+        let pw_hash = PasswordHash::create(password)?;
+        let mut connection = self.pool.get()?;
+        let updated = connection.execute(
+            "UPDATE users SET password_hash = $1, salt = $2, password_algorithm = $3, pepper_id = $4 WHERE email = $5 AND password_hash = $6",
+            &[&*pw_hash.hash, &*pw_hash.salt, &pw_hash.algorithm, &pw_hash.pepper_id, email, &old_hash],
+        )?;
+        if updated > 0 {
+            log::debug!(
+                "Rehashed the password of {} with {}",
+                email,
+                pw_hash.algorithm
+            );
+        }
 
-        Ok(pw_hash == *original_pw_hash.as_slice())
+        Ok(())
     }
 
     pub fn get_permissions(&self, email: &String) -> DatabaseResult<Vec<Permission>> {
         let mut connection = self.pool.get()?;
-        let results = connection.query(
+        let statement = connection.prepare_cached(
             "\
             SELECT permissions.id, permissions.name, permissions.description
-            FROM permissions, role_permissions, user_roles, users
+            FROM permissions, role_permissions, user_roles, roles, users
             WHERE users.email = $1
+            AND users.deleted_at IS NULL
             AND users.id = user_roles.user_id
+            AND roles.id = user_roles.role_id
+            AND roles.enabled
+            AND roles.deleted_at IS NULL
             AND role_permissions.role_id = user_roles.role_id
             AND permissions.id = role_permissions.permission_id
         ",
-            &[&email],
         )?;
+        let results = connection.query(&statement, &[&email])?;
         let permissions: Vec<Permission> = serde_postgres::from_rows(results.iter())?;
 
         Ok(permissions)
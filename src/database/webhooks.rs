@@ -0,0 +1,329 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::thread::{self, Builder};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::database::events::{self, Event};
+use crate::database::models::{Webhook, WebhookDelivery};
+use crate::database::{DatabaseResult, PostgresPool, Table};
+use crate::utils::config::Config;
+use crate::utils::create_secret_token;
+use crate::utils::error::DBError;
+
+pub(crate) const ENV_WEBHOOK_POLL_INTERVAL: &str = "WEBHOOK_POLL_INTERVAL_MS";
+pub(crate) const ENV_WEBHOOK_TIMEOUT: &str = "WEBHOOK_TIMEOUT_MS";
+pub(crate) const ENV_WEBHOOK_MAX_ATTEMPTS: &str = "WEBHOOK_MAX_ATTEMPTS";
+pub(crate) const DEFAULT_WEBHOOK_POLL_INTERVAL: u64 = 1000;
+pub(crate) const DEFAULT_WEBHOOK_TIMEOUT: u64 = 5000;
+pub(crate) const DEFAULT_WEBHOOK_MAX_ATTEMPTS: i32 = 8;
+
+pub const USER_CREATED: &str = "user.created";
+pub const USER_UPDATED: &str = "user.updated";
+pub const USER_DELETED: &str = "user.deleted";
+pub const USER_ROLES_CHANGED: &str = "user.roles_changed";
+/// The events webhooks can subscribe to
+pub const WEBHOOK_EVENTS: &[&str] = &[USER_CREATED, USER_UPDATED, USER_DELETED, USER_ROLES_CHANGED];
+
+const DELIVERY_BATCH_SIZE: i64 = 50;
+const DELIVERY_LOG_LIMIT: i64 = 100;
+const SIGNATURE_HEADER: &str = "X-Flotte-Signature";
+const EVENT_HEADER: &str = "X-Flotte-Event";
+const DELIVERY_HEADER: &str = "X-Flotte-Delivery";
+const TIMESTAMP_HEADER: &str = "X-Flotte-Timestamp";
+const WEBHOOK_COLUMNS: &str = "id, url, events,
+    to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at";
+const DELIVERY_COLUMNS: &str =
+    "id, webhook_id, event, status, attempts, last_status_code, last_error,
+    to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at,
+    to_char(delivered_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS delivered_at";
+
+/// The table of the registered webhooks and the log of their deliveries.
+/// Every matching user event is stored as a delivery for each subscribed webhook
+/// and posted to its url with a HMAC-SHA256 signature of the body.
+/// The secret is stored in plain text since it is needed to sign the requests.
+#[derive(Clone)]
+pub struct Webhooks {
+    pool: PostgresPool,
+}
+
+impl Table for Webhooks {
+    fn new(pool: PostgresPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl Webhooks {
+    /// Registers a webhook for the given events and returns it together
+    /// with the secret the requests are signed with. The secret can't be retrieved later.
+    pub fn create_webhook(
+        &self,
+        url: String,
+        events: Vec<String>,
+    ) -> DatabaseResult<(Webhook, String)> {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err(DBError::GenericError(
+                "The webhook url needs to be a http or https url".to_string(),
+            ));
+        }
+        if events.is_empty() {
+            return Err(DBError::GenericError(
+                "At least one event is required".to_string(),
+            ));
+        }
+        if let Some(event) = events
+            .iter()
+            .find(|e| !WEBHOOK_EVENTS.contains(&e.as_str()))
+        {
+            return Err(DBError::GenericError(format!(
+                "The event {} doesn't exist",
+                event
+            )));
+        }
+        let secret = create_secret_token();
+        let mut connection = self.pool.get()?;
+        let row = connection.query_one(
+            format!(
+                "INSERT INTO webhooks (url, secret, events) VALUES ($1, $2, $3) RETURNING {}",
+                WEBHOOK_COLUMNS
+            )
+            .as_str(),
+            &[&url, &secret, &events],
+        )?;
+        log::debug!("Registered webhook for {}", url);
+
+        Ok((Webhook::from_row(row), secret))
+    }
+
+    /// Returns all registered webhooks
+    pub fn get_webhooks(&self) -> DatabaseResult<Vec<Webhook>> {
+        let mut connection = self.pool.get()?;
+        let rows = connection.query(
+            format!("SELECT {} FROM webhooks ORDER BY id", WEBHOOK_COLUMNS).as_str(),
+            &[],
+        )?;
+
+        Ok(rows.into_iter().map(Webhook::from_row).collect())
+    }
+
+    /// Deletes a webhook together with its deliveries
+    pub fn delete_webhook(&self, id: i32) -> DatabaseResult<()> {
+        let mut connection = self.pool.get()?;
+        let deleted = connection.execute("DELETE FROM webhooks WHERE id = $1", &[&id])?;
+
+        if deleted == 0 {
+            Err(DBError::RecordDoesNotExist)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the latest deliveries of a webhook
+    pub fn get_deliveries(&self, id: i32) -> DatabaseResult<Vec<WebhookDelivery>> {
+        let mut connection = self.pool.get()?;
+        if connection
+            .query_opt("SELECT id FROM webhooks WHERE id = $1", &[&id])?
+            .is_none()
+        {
+            return Err(DBError::RecordDoesNotExist);
+        }
+        let rows = connection.query(
+            format!(
+                "SELECT {} FROM webhook_deliveries WHERE webhook_id = $1 ORDER BY id DESC LIMIT $2",
+                DELIVERY_COLUMNS
+            )
+            .as_str(),
+            &[&id, &DELIVERY_LOG_LIMIT],
+        )?;
+
+        Ok(rows.into_iter().map(WebhookDelivery::from_row).collect())
+    }
+
+    /// Queues a delivery of the event for all webhooks that subscribed to it
+    fn enqueue(&self, event: &str, payload: &Value) -> DatabaseResult<u64> {
+        let mut connection = self.pool.get()?;
+        let count = connection.execute(
+            "INSERT INTO webhook_deliveries (webhook_id, event, payload)
+            SELECT id, $1, $2 FROM webhooks WHERE $1 = ANY (events)",
+            &[&event, payload],
+        )?;
+
+        Ok(count)
+    }
+
+    /// Registers the event hook that queues the deliveries and starts
+    /// the thread that sends them
+    pub fn start_delivery_job(&self) {
+        let webhooks = Webhooks::clone(self);
+        events::register_hook(move |event| {
+            for (name, payload) in webhook_events(event) {
+                webhooks
+                    .enqueue(name, &payload)
+                    .map_err(|e| format!("Failed to queue webhook deliveries: {}", e))?;
+            }
+            Ok(())
+        });
+        let webhooks = Webhooks::clone(self);
+        let settings = &Config::get().webhooks;
+        let interval = Duration::from_millis(settings.poll_interval);
+        let timeout = Duration::from_millis(settings.timeout);
+        let max_attempts = settings.max_attempts.max(1);
+
+        Builder::new()
+            .name("webhook-delivery".to_string())
+            .spawn(move || loop {
+                match webhooks.deliver_batch(timeout, max_attempts) {
+                    Ok(count) if count as i64 >= DELIVERY_BATCH_SIZE => continue,
+                    Ok(_) => {}
+                    Err(e) => log::error!("Failed to deliver webhooks: {}", e),
+                }
+                thread::sleep(interval);
+            })
+            .unwrap();
+    }
+
+    /// Sends the next batch of pending deliveries and returns the number of handled ones.
+    /// Failed deliveries are retried with an exponential backoff until
+    /// the maximum number of attempts is reached.
+    fn deliver_batch(&self, timeout: Duration, max_attempts: i32) -> DatabaseResult<usize> {
+        let mut connection = self.pool.get()?;
+        let mut transaction = connection.transaction()?;
+        let rows = transaction.query(
+            "SELECT d.id, d.event, d.payload, w.url, w.secret
+            FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+            WHERE d.status = 'pending' AND d.available_at <= NOW()
+            ORDER BY d.id LIMIT $1 FOR UPDATE OF d SKIP LOCKED",
+            &[&DELIVERY_BATCH_SIZE],
+        )?;
+        let count = rows.len();
+
+        for row in rows {
+            let id: i64 = row.get(0);
+            let event: String = row.get(1);
+            let payload: Value = row.get(2);
+            let url: String = row.get(3);
+            let secret: String = row.get(4);
+
+            match send(id, &event, &payload, &url, &secret, timeout) {
+                Ok(status) => {
+                    transaction.execute(
+                        "UPDATE webhook_deliveries SET status = 'delivered', attempts = attempts + 1,
+                        last_status_code = $2, last_error = NULL, delivered_at = NOW() WHERE id = $1",
+                        &[&id, &(status as i32)],
+                    )?;
+                }
+                Err((status, e)) => {
+                    log::warn!("Failed to deliver webhook {} to {}: {}", id, url, e);
+                    transaction.execute(
+                        "UPDATE webhook_deliveries SET attempts = attempts + 1,
+                        last_status_code = $2, last_error = $3,
+                        status = CASE WHEN attempts + 1 >= $4 THEN 'failed' ELSE 'pending' END,
+                        available_at = NOW() + POWER(2, LEAST(attempts, 12)) * INTERVAL '10 seconds'
+                        WHERE id = $1",
+                        &[&id, &status.map(|s| s as i32), &e, &max_attempts],
+                    )?;
+                }
+            }
+        }
+        transaction.commit()?;
+
+        Ok(count)
+    }
+}
+
+/// Returns the webhook events with their payloads for an event of the outbox.
+/// Changes to the roles of a user are reported separately from changes to its fields.
+/// Creations are recognized by the email that is only missing before a user was created.
+/// Users are deleted when they are moved to the trash or their record is removed.
+fn webhook_events(event: &Event) -> Vec<(&'static str, Value)> {
+    let (email, changed_by, changes) = match event {
+        Event::UserChanged {
+            email,
+            changed_by,
+            changes,
+        } => (email, changed_by, changes),
+        _ => return Vec::new(),
+    };
+    let mut fields = match changes.as_object() {
+        Some(fields) => fields.clone(),
+        None => return Vec::new(),
+    };
+    let mut result = Vec::new();
+
+    if let Some(roles) = fields.remove("roles") {
+        result.push((
+            USER_ROLES_CHANGED,
+            json!({"email": email, "changed_by": changed_by, "roles": roles}),
+        ));
+    }
+    if !fields.is_empty() {
+        let email_change = fields.get("email");
+        let name = if matches!(email_change, Some(c) if c["before"].is_null()) {
+            USER_CREATED
+        } else if matches!(email_change, Some(c) if c["after"].is_null())
+            || matches!(fields.get("deleted"), Some(c) if c["after"] == true)
+        {
+            USER_DELETED
+        } else {
+            USER_UPDATED
+        };
+        result.push((
+            name,
+            json!({"email": email, "changed_by": changed_by, "changes": fields}),
+        ));
+    }
+
+    result
+}
+
+/// Posts the payload to the url and returns the status code of the response.
+/// The signature is the hex encoded HMAC-SHA256 of the timestamp and the body
+/// joined with a dot so that receivers can reject replayed requests.
+fn send(
+    id: i64,
+    event: &str,
+    payload: &Value,
+    url: &str,
+    secret: &str,
+    timeout: Duration,
+) -> Result<u16, (Option<u16>, String)> {
+    let body = json!({"id": id, "event": event, "data": payload}).to_string();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_string();
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    let signature = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    let response = ureq::post(url)
+        .timeout(timeout)
+        .set("Content-Type", "application/json")
+        .set(EVENT_HEADER, event)
+        .set(DELIVERY_HEADER, &id.to_string())
+        .set(TIMESTAMP_HEADER, &timestamp)
+        .set(SIGNATURE_HEADER, &format!("sha256={}", signature))
+        .send_string(&body);
+
+    match response {
+        Ok(response) => Ok(response.status()),
+        Err(ureq::Error::Status(status, _)) => {
+            Err((Some(status), format!("Received status {}", status)))
+        }
+        Err(e) => Err((None, e.to_string())),
+    }
+}
@@ -2,6 +2,7 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
+use std::process;
 use std::thread;
 use std::thread::Builder;
 
@@ -11,19 +12,64 @@ use crossbeam_utils::sync::WaitGroup;
 use env_logger::Env;
 use log::{Level, LevelFilter};
 
+use flotte_user_management::database::broker::EventPublisher;
+use flotte_user_management::database::ldap_sync::LdapSync;
 use flotte_user_management::database::Database;
+#[cfg(feature = "grpc")]
+use flotte_user_management::server::grpc_server::UserGrpcServer;
+#[cfg(not(feature = "hyper-server"))]
 use flotte_user_management::server::http_server::UserHttpServer;
+#[cfg(feature = "hyper-server")]
+use flotte_user_management::server::hyper_server::UserHttpServer;
+use flotte_user_management::server::readiness::READINESS;
 use flotte_user_management::server::user_rpc::UserRpcServer;
+use flotte_user_management::utils::config::Config;
+use flotte_user_management::utils::telemetry;
 
 fn main() {
     init_logger();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Failed to load the configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    telemetry::init();
     // Create a new database and initialize it
-    let database = Database::new().unwrap();
+    let database = Database::new(config).unwrap();
     database.init().unwrap();
+    READINESS.set_database_initialized();
+
+    if std::env::args().nth(1).as_deref() == Some("rotate-admin") {
+        let password = database.rotate_admin_password().unwrap();
+        println!("New admin password: {}", password);
+        return;
+    }
+
+    // Publish the changes of users, roles and permissions if a broker is configured
+    if let Some(publisher) = EventPublisher::from_env() {
+        publisher.start();
+    }
+    // Notify the registered webhooks about user events
+    database.webhooks.start_delivery_job();
+    // Deliver the events that are stored in the outbox
+    database.event_outbox.start_relay();
+    // Notify and log out users whose accounts expire
+    database.users.start_expiry_job();
+    // Create upcoming partitions and remove the expired ones
+    database.partitions.start_retention_job();
+    // Import users and their roles from the LDAP directory if one is configured
+    if let Some(ldap_sync) = LdapSync::from_env(&database) {
+        ldap_sync.start_sync_job();
+    }
+    // Permanently delete roles and users that were in the trash for too long
+    database.roles.start_purge_job();
+    database.users.start_purge_job();
 
     // Create the required servers
-    let rpc_server = UserRpcServer::new(&database);
-    let http_server = UserHttpServer::new(&database);
+    let rpc_server = UserRpcServer::new(&database, config);
+    let http_server = UserHttpServer::new(&database, config);
 
     // Create a new waitgroup that is used to wait for both servers to exit
     let wg = WaitGroup::new();
@@ -50,8 +96,23 @@ fn main() {
             .unwrap();
     }
 
-    // Wait for both servers to exit
+    #[cfg(feature = "grpc")]
+    {
+        let wg = WaitGroup::clone(&wg);
+        let grpc_server = UserGrpcServer::new(&database);
+        // Serve the rpc operations via gRPC as well
+        Builder::new()
+            .name("grpc".to_string())
+            .spawn(move || {
+                grpc_server.start();
+                std::mem::drop(wg);
+            })
+            .unwrap();
+    }
+
+    // Wait for the servers to exit
     wg.wait();
+    telemetry::shutdown();
 }
 
 /// Initializes the env_logger with a custom format
@@ -0,0 +1,236 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::cell::{Cell, RefCell};
+use std::time::Instant;
+
+use rand::Rng;
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde_json::{Map, Value};
+
+use crate::utils::config;
+
+pub(crate) const ENV_ACCESS_LOG: &str = "ACCESS_LOG";
+const LOG_TARGET: &str = "access_log";
+const REDACTED: &str = "***";
+const SENSITIVE_KEYS: &[&str] = &["password", "token", "secret"];
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    static USER_ID: Cell<Option<i32>> = const { Cell::new(None) };
+}
+
+/// Forgets the request id and user of the current thread when dropped
+struct RequestScope;
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        REQUEST_ID.with(|id| *id.borrow_mut() = None);
+        USER_ID.with(|id| id.set(None));
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref ACCESS_LOG_ENABLED: bool = config::var(ENV_ACCESS_LOG).unwrap_or("false".to_string()) == "true";
+}
+
+/// Returns if the access log was enabled via the env
+pub fn is_enabled() -> bool {
+    *ACCESS_LOG_ENABLED
+}
+
+/// Returns the given request id or creates a new random one
+pub fn request_id(provided: Option<&str>) -> String {
+    provided
+        .filter(|id| !id.is_empty() && id.len() <= 64)
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| format!("{:016x}", rand::thread_rng().gen::<u64>()))
+}
+
+/// Runs the given closure with the request id being set for the current thread
+/// so that it can be included in log messages created while handling the request.
+/// The request id is forgotten afterwards, even if the closure panics.
+pub fn with_request_id<T, F: FnOnce() -> T>(request_id: &str, func: F) -> T {
+    let _scope = RequestScope;
+    REQUEST_ID.with(|id| *id.borrow_mut() = Some(request_id.to_string()));
+    func()
+}
+
+/// Sets the id of the user whose token was verified for the request
+/// that is handled by the current thread
+pub fn set_user_id(user_id: i32) {
+    USER_ID.with(|id| id.set(Some(user_id)));
+}
+
+/// Returns the id of the user whose token was verified for the request
+/// that is handled by the current thread
+pub fn current_user_id() -> Option<i32> {
+    USER_ID.with(|id| id.get())
+}
+
+/// Returns the id of the request that is handled by the current thread
+pub fn current_request_id() -> String {
+    REQUEST_ID.with(|id| id.borrow().clone().unwrap_or("-".to_string()))
+}
+
+/// Writes an access log entry for a http request if the access log is enabled
+pub fn log_http(
+    request_id: &str,
+    method: &str,
+    path: &str,
+    status: u16,
+    started: Instant,
+    user_id: Option<i32>,
+) {
+    if is_enabled() {
+        log::info!(
+            target: LOG_TARGET,
+            "[{}] HTTP {} {} {} {}ms user={}",
+            request_id,
+            method,
+            path,
+            status,
+            started.elapsed().as_millis(),
+            format_user(user_id)
+        );
+    }
+}
+
+/// Writes an access log entry for a rpc message if the access log is enabled
+pub fn log_rpc(
+    request_id: &str,
+    method: &str,
+    success: bool,
+    started: Instant,
+    user_id: Option<i32>,
+) {
+    if is_enabled() {
+        log::info!(
+            target: LOG_TARGET,
+            "[{}] RPC {} {} {}ms user={}",
+            request_id,
+            method,
+            if success { "OK" } else { "ERROR" },
+            started.elapsed().as_millis(),
+            format_user(user_id)
+        );
+    }
+}
+
+/// Returns a printable version of a json body with all sensitive fields redacted
+pub fn redacted_body(body: &str) -> String {
+    match serde_json::from_str::<Value>(body) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes of non-json data>", body.len()),
+    }
+}
+
+/// Returns a printable version of a msgpack encoded rpc payload
+/// with all sensitive fields redacted.
+/// Structs encoded as arrays are named with the given fields first
+/// so that their sensitive fields can be found by name.
+pub fn redacted_msgpack(data: &[u8], fields: &[&str]) -> String {
+    match rmp_serde::from_read::<_, Value>(data) {
+        Ok(mut value) => {
+            if let Value::Array(values) = &value {
+                if !fields.is_empty() && values.len() <= fields.len() {
+                    value = Value::Object(
+                        fields
+                            .iter()
+                            .map(|field| field.to_string())
+                            .zip(values.iter().cloned())
+                            .collect::<Map<String, Value>>(),
+                    );
+                }
+            }
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes of non-msgpack data>", data.len()),
+    }
+}
+
+/// Returns the names of the fields of a struct in the order
+/// they have when the struct is encoded as an array
+pub fn field_names<T: DeserializeOwned>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+
+    fields
+}
+
+/// A deserializer that only records the fields of the struct that is deserialized
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("only structs have field names"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("the field names were recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Replaces the values of all fields that contain passwords, tokens or secrets
+pub fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if SENSITIVE_KEYS.iter().any(|k| key.contains(k)) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+fn format_user(user_id: Option<i32>) -> String {
+    user_id.map(|id| id.to_string()).unwrap_or("-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::messages::OrganizationPermissionsRequest;
+
+    #[test]
+    fn positional_requests_are_redacted_by_field_name() {
+        let fields = field_names::<OrganizationPermissionsRequest>();
+        assert_eq!(fields, &["token", "organization"]);
+        let data = rmp_serde::to_vec(&("secret-token", "org")).unwrap();
+        let logged = redacted_msgpack(&data, fields);
+        assert!(!logged.contains("secret-token"));
+        assert!(logged.contains("org"));
+    }
+
+    #[test]
+    fn named_requests_are_redacted() {
+        let data =
+            rmp_serde::to_vec_named(&serde_json::json!({ "token": "secret-token" })).unwrap();
+        assert!(!redacted_msgpack(&data, &[]).contains("secret-token"));
+    }
+}
@@ -0,0 +1,63 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+//! Batches contain multiple rpc messages so that they can be handled in a single
+//! round trip. Every message of a batch is encoded as its method (4 bytes),
+//! the length of its data as big endian u32 and the data itself.
+//! The response is a batch with one response per message in the same order.
+
+use byteorder::{BigEndian, ByteOrder};
+use msgrpc::message::Message;
+
+use crate::server::rpc_methods::BATCH;
+
+pub(crate) const MAX_BATCH_SIZE: usize = 100;
+const HEADER_LENGTH: usize = 8;
+
+/// Splits the data of a batch message into its messages
+pub fn decode_batch(mut data: &[u8]) -> Result<Vec<Message>, String> {
+    let mut messages = Vec::new();
+
+    while !data.is_empty() {
+        if messages.len() >= MAX_BATCH_SIZE {
+            return Err(format!(
+                "A batch can contain at most {} messages",
+                MAX_BATCH_SIZE
+            ));
+        }
+        if data.len() < HEADER_LENGTH {
+            return Err("The batch contains an incomplete message header".to_string());
+        }
+        let mut method = [0u8; 4];
+        method.copy_from_slice(&data[..4]);
+        let length = BigEndian::read_u32(&data[4..HEADER_LENGTH]) as usize;
+        let end = HEADER_LENGTH
+            .checked_add(length)
+            .filter(|end| *end <= data.len())
+            .ok_or("The batch contains an incomplete message".to_string())?;
+        messages.push(Message::new(method, data[HEADER_LENGTH..end].to_vec()));
+        data = &data[end..];
+    }
+
+    Ok(messages)
+}
+
+/// Combines the responses to the messages of a batch into a single message
+pub fn encode_batch(responses: Vec<Message>) -> Message {
+    let mut data = Vec::with_capacity(
+        responses
+            .iter()
+            .map(|response| HEADER_LENGTH + response.data.len())
+            .sum(),
+    );
+    for response in responses {
+        let mut header = [0u8; HEADER_LENGTH];
+        header[..4].copy_from_slice(&response.method);
+        BigEndian::write_u32(&mut header[4..], response.data.len() as u32);
+        data.extend_from_slice(&header);
+        data.extend_from_slice(&response.data);
+    }
+
+    Message::new(BATCH, data)
+}
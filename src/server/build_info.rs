@@ -0,0 +1,66 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+
+/// Information about the running build of the server
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_timestamp: String,
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// Returns the information about the current build
+    pub fn get() -> Self {
+        let timestamp = option_env!("BUILD_TIMESTAMP")
+            .and_then(|t| t.parse::<i64>().ok())
+            .and_then(|t| Utc.timestamp_opt(t, 0).single())
+            .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or("unknown".to_string());
+
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: option_env!("BUILD_GIT_COMMIT").unwrap_or("unknown"),
+            build_timestamp: timestamp,
+            features: Self::features(),
+        }
+    }
+
+    /// Returns the server components and backends this build was compiled with
+    fn features() -> Vec<&'static str> {
+        let mut features = Vec::new();
+        if cfg!(feature = "hyper-server") {
+            features.push("http-hyper");
+        } else {
+            features.push("http-rouille");
+        }
+        features.push("rpc");
+        features.push("rpc-deflate");
+        if cfg!(feature = "grpc") {
+            features.push("grpc");
+        }
+        features.push("token-store-memory");
+        features.push("database-postgres");
+        if cfg!(feature = "telemetry") {
+            features.push("telemetry-otlp");
+        }
+
+        features
+    }
+
+    /// Returns a single line description of the build
+    pub fn summary(&self) -> String {
+        format!(
+            "{} {} ({}) built {}",
+            env!("CARGO_PKG_NAME"),
+            self.version,
+            self.git_commit,
+            self.build_timestamp
+        )
+    }
+}
@@ -0,0 +1,202 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::io::{Read, Write};
+
+use brotli2::write::BrotliEncoder;
+use flate2::read::DeflateDecoder;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use msgrpc::message::Message;
+use rouille::{Request, Response, ResponseBody};
+
+use crate::server::rpc_methods::DEFLATE;
+use crate::utils::config;
+
+pub(crate) const ENV_RPC_COMPRESSION_THRESHOLD: &str = "RPC_COMPRESSION_THRESHOLD";
+pub(crate) const DEFAULT_RPC_COMPRESSION_THRESHOLD: usize = 1024;
+pub(crate) const ENV_HTTP_COMPRESSION_THRESHOLD: &str = "HTTP_COMPRESSION_THRESHOLD";
+pub(crate) const DEFAULT_HTTP_COMPRESSION_THRESHOLD: usize = 1024;
+const BROTLI_QUALITY: u32 = 5;
+/// The paths whose responses are compressed. Responses that contain tokens
+/// are never compressed as that would make them vulnerable to BREACH.
+const COMPRESSED_PATHS: &[&str] = &["/users", "/roles", "/openapi.json"];
+const MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024;
+/// The algorithms the responses of an rpc connection can be compressed with
+const MESSAGE_COMPRESSION_ALGORITHMS: &[&str] = &["deflate"];
+
+lazy_static::lazy_static! {
+    static ref COMPRESSION_THRESHOLD: usize = config::var(ENV_RPC_COMPRESSION_THRESHOLD)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_RPC_COMPRESSION_THRESHOLD);
+    static ref HTTP_COMPRESSION_THRESHOLD: usize = config::var(ENV_HTTP_COMPRESSION_THRESHOLD)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_HTTP_COMPRESSION_THRESHOLD);
+}
+
+/// The content encodings responses can be compressed with
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+
+impl ContentEncoding {
+    fn name(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+
+    /// Returns the encoding with the highest quality in the Accept-Encoding header.
+    /// Brotli is preferred if both are accepted with the same quality.
+    fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let mut best: Option<(ContentEncoding, f32)> = None;
+
+        for entry in accept_encoding.split(',') {
+            let mut parts = entry.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .filter_map(|q| q.trim().parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+            let encoding = match name.as_str() {
+                "br" => ContentEncoding::Brotli,
+                "gzip" | "*" => ContentEncoding::Gzip,
+                _ => continue,
+            };
+            let better = match best {
+                Some((current, best_quality)) => {
+                    quality > best_quality
+                        || (quality == best_quality
+                            && encoding == ContentEncoding::Brotli
+                            && current != ContentEncoding::Brotli)
+                }
+                None => true,
+            };
+            if quality > 0.0 && better {
+                best = Some((encoding, quality));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Brotli => {
+                let mut encoder = BrotliEncoder::new(Vec::new(), BROTLI_QUALITY);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compresses the response of the large list endpoints with the encoding
+/// the client prefers if it is larger than the configured threshold
+pub fn compress_response(request: &Request, mut response: Response) -> Response {
+    if !COMPRESSED_PATHS.contains(&request.url().as_str()) {
+        return response;
+    }
+    response = response.with_additional_header("Vary", "Accept-Encoding");
+    let encoding = match request
+        .header("Accept-Encoding")
+        .and_then(ContentEncoding::negotiate)
+    {
+        Some(encoding) => encoding,
+        None => return response,
+    };
+    if response
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"))
+    {
+        return response;
+    }
+    let (mut reader, size) =
+        std::mem::replace(&mut response.data, ResponseBody::empty()).into_reader_and_size();
+    let mut data = Vec::with_capacity(size.unwrap_or(0));
+    if let Err(e) = reader.read_to_end(&mut data) {
+        log::error!("Failed to read the response body: {}", e);
+        return Response::text("Failed to read the response").with_status_code(500);
+    }
+    if data.len() < *HTTP_COMPRESSION_THRESHOLD {
+        response.data = ResponseBody::from_data(data);
+        return response;
+    }
+
+    match encoding.compress(&data) {
+        Ok(compressed) => {
+            response.data = ResponseBody::from_data(compressed);
+            response.with_additional_header("Content-Encoding", encoding.name())
+        }
+        Err(e) => {
+            log::warn!("Failed to compress the response: {}", e);
+            response.data = ResponseBody::from_data(data);
+            response
+        }
+    }
+}
+
+/// Returns the algorithm the responses of an rpc connection are compressed with.
+/// It's the first of the algorithms the client listed in the handshake that the server implements.
+pub fn negotiate_message_compression(algorithms: &[String]) -> Option<&'static str> {
+    algorithms.iter().find_map(|name| {
+        MESSAGE_COMPRESSION_ALGORITHMS
+            .iter()
+            .find(|algorithm| name.eq_ignore_ascii_case(algorithm))
+            .copied()
+    })
+}
+
+/// Unpacks a message that was sent with the DEFLATE method.
+/// The data of such a message is the deflate compressed method
+/// followed by the data of the wrapped message.
+pub fn decompress_message(data: &[u8]) -> Result<Message, String> {
+    let mut decompressed = Vec::new();
+    DeflateDecoder::new(data)
+        .take(MAX_DECOMPRESSED_SIZE)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| format!("Failed to decompress message: {}", e))?;
+    if decompressed.len() < 4 {
+        return Err("The compressed message is missing a method".to_string());
+    }
+    let data = decompressed.split_off(4);
+    let mut method = [0u8; 4];
+    method.copy_from_slice(&decompressed);
+
+    Ok(Message::new(method, data))
+}
+
+/// Wraps the response to a compressed request in a DEFLATE message
+/// if its data is larger than the configured threshold.
+/// Smaller messages are returned unchanged.
+pub fn compress_message(message: Message) -> Message {
+    if message.data.len() < *COMPRESSION_THRESHOLD {
+        return message;
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    let result = encoder
+        .write_all(&message.method)
+        .and_then(|_| encoder.write_all(&message.data))
+        .and_then(|_| encoder.finish());
+
+    match result {
+        Ok(data) => Message::new(DEFLATE, data),
+        Err(e) => {
+            log::warn!("Failed to compress message: {}", e);
+            message
+        }
+    }
+}
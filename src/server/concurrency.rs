@@ -0,0 +1,89 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// Limits the number of requests that are processed at the same time.
+/// Requests exceeding the limit are queued up to the configured queue size
+/// and rejected if the queue is full or the queue timeout is reached.
+pub struct ConcurrencyLimiter {
+    state: Mutex<LimiterState>,
+    condvar: Condvar,
+    max_active: usize,
+    max_queued: usize,
+    queue_timeout: Duration,
+}
+
+struct LimiterState {
+    active: usize,
+    queued: usize,
+}
+
+/// A permit to process a request. The slot is released when the permit is dropped.
+pub struct RequestPermit<'a> {
+    limiter: &'a ConcurrencyLimiter,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_active: usize, max_queued: usize, queue_timeout: Duration) -> Self {
+        Self {
+            state: Mutex::new(LimiterState {
+                active: 0,
+                queued: 0,
+            }),
+            condvar: Condvar::new(),
+            max_active,
+            max_queued,
+            queue_timeout,
+        }
+    }
+
+    /// Returns the number of requests that can be handled without rejecting any
+    pub fn capacity(&self) -> usize {
+        self.max_active + self.max_queued
+    }
+
+    /// Tries to acquire a slot for processing a request. If all slots are taken
+    /// the call waits in the queue until a slot is free. None is returned
+    /// when the queue is full or the wait timed out.
+    pub fn acquire(&self) -> Option<RequestPermit<'_>> {
+        let mut state = self.state.lock();
+
+        if state.active < self.max_active {
+            state.active += 1;
+            return Some(RequestPermit { limiter: self });
+        }
+        if state.queued >= self.max_queued {
+            log::debug!("Request queue is full. Rejecting request.");
+            return None;
+        }
+        state.queued += 1;
+        let deadline = Instant::now() + self.queue_timeout;
+
+        while state.active >= self.max_active {
+            if self.condvar.wait_until(&mut state, deadline).timed_out() {
+                break;
+            }
+        }
+        state.queued -= 1;
+
+        if state.active < self.max_active {
+            state.active += 1;
+            Some(RequestPermit { limiter: self })
+        } else {
+            log::debug!("Request timed out in queue. Rejecting request.");
+            None
+        }
+    }
+}
+
+impl<'a> Drop for RequestPermit<'a> {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock();
+        state.active -= 1;
+        self.limiter.condvar.notify_one();
+    }
+}
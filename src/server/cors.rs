@@ -0,0 +1,124 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use rouille::{Request, Response};
+
+use crate::utils::config::{self, Config, CorsSettings};
+
+pub(crate) const ENV_CORS_ALLOWED_ORIGINS: &str = "CORS_ALLOWED_ORIGINS";
+pub(crate) const ENV_CORS_ALLOWED_HEADERS: &str = "CORS_ALLOWED_HEADERS";
+pub(crate) const ENV_CORS_MAX_AGE: &str = "CORS_MAX_AGE";
+pub(crate) const ENV_CORS_ALLOW_CREDENTIALS: &str = "CORS_ALLOW_CREDENTIALS";
+pub(crate) const DEFAULT_CORS_ALLOWED_HEADERS: &str =
+    "Authorization,Content-Type,Accept-Language,Api-Version,X-Api-Key,X-Request-Id,traceparent";
+pub(crate) const DEFAULT_CORS_MAX_AGE: u32 = 600;
+/// The env that enabled CORS for all origins before the allow-list existed
+const ENV_ENABLE_CORS: &str = "ENABLE_CORS";
+
+const ALLOWED_METHODS: &str = "GET,HEAD,PUT,PATCH,POST,DELETE,OPTIONS";
+/// The response headers the browser exposes to the frontend
+const EXPOSED_HEADERS: &str = "Api-Version,Retry-After,X-Request-Id,X-RateLimit-Limit,X-RateLimit-Remaining,X-RateLimit-Reset";
+const WILDCARD: &str = "*";
+
+lazy_static::lazy_static! {
+    pub static ref CORS: CorsConfig = CorsConfig::from_config(&Config::get().cors);
+}
+
+/// The origins that are allowed to make cross origin requests to the api.
+/// Requests from other origins are answered without CORS headers
+/// so that browsers block the response.
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    allowed_origins: Vec<String>,
+    allowed_headers: String,
+    max_age: u32,
+    allow_credentials: bool,
+}
+
+impl CorsConfig {
+    /// Reads the settings from the configuration. CORS is disabled if no origins are configured.
+    fn from_config(settings: &CorsSettings) -> Self {
+        if config::var(ENV_ENABLE_CORS).is_ok() {
+            log::warn!(
+                "{} is no longer supported. Configure the allowed origins with {} instead",
+                ENV_ENABLE_CORS,
+                ENV_CORS_ALLOWED_ORIGINS
+            );
+        }
+        let mut allow_credentials = settings.allow_credentials;
+
+        if allow_credentials
+            && settings
+                .allowed_origins
+                .iter()
+                .any(|origin| origin == WILDCARD)
+        {
+            log::error!(
+                "{} can't be combined with the wildcard origin. Credentials are not allowed",
+                ENV_CORS_ALLOW_CREDENTIALS
+            );
+            allow_credentials = false;
+        }
+
+        Self {
+            allowed_origins: settings.allowed_origins.clone(),
+            allowed_headers: settings.allowed_headers.clone(),
+            max_age: settings.max_age,
+            allow_credentials,
+        }
+    }
+
+    /// Returns if the request is a preflight request of a browser
+    pub fn is_preflight(request: &Request) -> bool {
+        request.method() == "OPTIONS" && request.header("Access-Control-Request-Method").is_some()
+    }
+
+    /// Returns the value of the Access-Control-Allow-Origin header for the origin
+    /// of the request or None if the origin isn't allowed
+    fn allowed_origin(&self, request: &Request) -> Option<String> {
+        let origin = request.header("Origin")?;
+
+        if self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            Some(origin.to_string())
+        } else if self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == WILDCARD)
+        {
+            Some(WILDCARD.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Adds the CORS headers to the response if the origin of the request is allowed.
+    /// Preflight requests additionally get the allowed methods and headers.
+    pub fn add_headers(&self, request: &Request, mut response: Response) -> Response {
+        if self.allowed_origins.is_empty() {
+            return response;
+        }
+        // the response differs between origins and must not be cached for all of them
+        response = response.with_additional_header("Vary", "Origin");
+        let origin = match self.allowed_origin(request) {
+            Some(origin) => origin,
+            None => return response,
+        };
+        response = response.with_additional_header("Access-Control-Allow-Origin", origin);
+
+        if self.allow_credentials {
+            response = response.with_additional_header("Access-Control-Allow-Credentials", "true");
+        }
+        if Self::is_preflight(request) {
+            response
+                .with_additional_header("Access-Control-Allow-Methods", ALLOWED_METHODS)
+                .with_additional_header(
+                    "Access-Control-Allow-Headers",
+                    self.allowed_headers.clone(),
+                )
+                .with_additional_header("Access-Control-Max-Age", self.max_age.to_string())
+        } else {
+            response.with_additional_header("Access-Control-Expose-Headers", EXPOSED_HEADERS)
+        }
+    }
+}
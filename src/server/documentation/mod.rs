@@ -2,83 +2,180 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
+use schemars::gen::SchemaSettings;
 use schemars::JsonSchema;
-use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+use std::any::TypeId;
+use std::collections::BTreeMap;
 
-use syntect::highlighting::ThemeSet;
-use syntect::html::highlighted_html_for_string;
-use syntect::parsing::SyntaxSet;
+use crate::server::naming::rename_schema;
+use crate::utils::config;
 
+pub(crate) const ENV_SWAGGER_UI_ASSETS_URL: &str = "SWAGGER_UI_ASSETS_URL";
+pub(crate) const DEFAULT_SWAGGER_UI_ASSETS_URL: &str = "https://unpkg.com/swagger-ui-dist@3";
+
+/// Collects the documented paths and renders them as OpenAPI document
+#[derive(Default)]
 pub struct RESTDocumentation {
-    paths: HashMap<String, String>,
-    base_path: String,
+    /// The OpenAPI operations by path and lowercase method
+    operations: BTreeMap<String, Map<String, Value>>,
+    /// The schemas referenced by the operations
+    schemas: Map<String, Value>,
 }
 
 impl RESTDocumentation {
-    pub fn new(base_path: &str) -> Self {
-        Self {
-            paths: HashMap::new(),
-            base_path: base_path.to_string(),
-        }
-    }
-
-    pub fn get(&self, path: String) -> String {
-        format!(
-            "<html><head><style type='text/css'>{}</style></head><body>{}</body></html>",
-            include_str!("style.css"),
-            self.paths.get(&path).unwrap_or(&self.landing())
-        )
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn landing(&self) -> String {
-        let mut keys = self.paths.keys().cloned().collect::<Vec<String>>();
-        keys.sort();
-        let types = keys.into_iter().fold("".to_string(), |a, b| {
-            format!("{}<br><a href='{}?path={2}'>{2}</a>", a, self.base_path, b)
-        });
-
-        format!("<h1>Paths</h1><br>{}", types)
-    }
-
-    pub fn add_path<I: JsonSchema, O: JsonSchema>(
+    /// Documents the path as OpenAPI operation and adds the schemas
+    /// of the request and response body to the components
+    pub fn add_path<I: JsonSchema + 'static, O: JsonSchema>(
         &mut self,
         path: &str,
         method: &str,
         description: &str,
     ) -> Result<(), serde_json::error::Error> {
-        log::trace!("Prerendering documentation for {}", path);
-        let input_schema = schema_for!(I);
-        let output_schema = schema_for!(O);
+        log::trace!("Rendering documentation for {}", path);
+        let mut generator = SchemaSettings::openapi3().into_generator();
+        let output_schema = generator.subschema_for::<O>();
+        let mut operation = Map::new();
+        let (path, parameters) = openapi_path(path);
 
-        let input_json = highlight_json(serde_json::to_string_pretty(&input_schema)?);
-        let output_json = highlight_json(serde_json::to_string_pretty(&output_schema)?);
-        let content = format!(
-            "\
-            <a href={}>Back</a>
-            <h1><code>{}: {}</code></h1>
-            <p>{}</p>
-            <h2>Input</h2>
-            <code>{}</code>
-            <h2>Output</h2>
-            <code>{}</code>
-        ",
-            self.base_path, method, path, description, input_json, output_json
+        operation.insert("summary".to_string(), json!(description));
+        if !parameters.is_empty() {
+            operation.insert("parameters".to_string(), json!(parameters));
+        }
+        if TypeId::of::<I>() != TypeId::of::<()>() {
+            let mut input_schema = serde_json::to_value(generator.subschema_for::<I>())?;
+            rename_schema(&mut input_schema);
+            operation.insert(
+                "requestBody".to_string(),
+                json!({
+                    "required": true,
+                    "content": { "application/json": { "schema": input_schema } },
+                }),
+            );
+        }
+        let mut output_schema = serde_json::to_value(output_schema)?;
+        rename_schema(&mut output_schema);
+        operation.insert(
+            "responses".to_string(),
+            json!({
+                "200": {
+                    "description": "Success",
+                    "content": { "application/json": { "schema": output_schema } },
+                },
+                "default": { "description": "Error" },
+            }),
         );
-        self.paths.insert(path.to_string(), content);
-        log::trace!("Documentation for {} rendered", path);
+        for (name, schema) in generator.take_definitions() {
+            let mut schema = serde_json::to_value(schema)?;
+            rename_schema(&mut schema);
+            self.schemas.insert(name, schema);
+        }
+        self.operations
+            .entry(path)
+            .or_default()
+            .insert(method.to_lowercase(), Value::Object(operation));
 
         Ok(())
     }
+
+    /// Documents a path that behaves like an already documented one
+    pub fn add_alias(
+        &mut self,
+        path: &str,
+        method: &str,
+        original_path: &str,
+        original_method: &str,
+    ) {
+        let (original_path, _) = openapi_path(original_path);
+        let operation = self
+            .operations
+            .get(&original_path)
+            .and_then(|operations| operations.get(&original_method.to_lowercase()))
+            .cloned();
+        if let Some(operation) = operation {
+            let (path, _) = openapi_path(path);
+            self.operations
+                .entry(path)
+                .or_default()
+                .insert(method.to_lowercase(), operation);
+        }
+    }
+
+    /// Returns the Swagger UI page that renders the OpenAPI document
+    /// at the given url. The assets of the ui are loaded from the configured url
+    /// so that they can be hosted alongside the server in offline deployments.
+    pub fn swagger_ui(&self, spec_url: &str) -> String {
+        let assets_url = config::var(ENV_SWAGGER_UI_ASSETS_URL)
+            .unwrap_or(DEFAULT_SWAGGER_UI_ASSETS_URL.to_string());
+
+        include_str!("swagger.html")
+            .replace("{assets_url}", assets_url.trim_end_matches('/'))
+            .replace("{spec_url}", spec_url)
+    }
+
+    /// Returns an OpenAPI 3.0 document of all documented paths
+    pub fn openapi(&self, title: &str, version: &str) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": title,
+                "version": version,
+            },
+            "servers": [{ "url": "/api/v1" }],
+            "paths": self.operations,
+            "components": {
+                "schemas": self.schemas,
+                "securitySchemes": {
+                    "bearerAuth": {
+                        "type": "http",
+                        "scheme": "bearer",
+                        "description": "The request token returned by /login or /new-token",
+                    },
+                    "apiKey": {
+                        "type": "apiKey",
+                        "in": "header",
+                        "name": "X-Api-Key",
+                        "description": "An api key of a service account",
+                    },
+                },
+            },
+            "security": [{ "bearerAuth": [] }, { "apiKey": [] }],
+        })
+    }
 }
 
-fn highlight_json(input: String) -> String {
-    lazy_static::lazy_static! { static ref PS: SyntaxSet = SyntaxSet::load_defaults_nonewlines(); }
-    lazy_static::lazy_static! { static ref TS: ThemeSet = ThemeSet::load_defaults(); }
+/// Converts a path with typed parameters like /users/{email:String}
+/// into an OpenAPI path and returns it together with the parameters
+fn openapi_path(path: &str) -> (String, Vec<Value>) {
+    let mut parameters = Vec::new();
+    let segments = path
+        .split('/')
+        .map(|segment| {
+            let inner = match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(inner) => inner,
+                None => return segment.to_string(),
+            };
+            let mut parts = inner.splitn(2, ':');
+            let name = parts.next().unwrap_or_default().trim();
+            let schema = match parts.next().map(str::trim) {
+                Some("i32") => json!({ "type": "integer", "format": "int32" }),
+                Some("i64") => json!({ "type": "integer", "format": "int64" }),
+                _ => json!({ "type": "string" }),
+            };
+            parameters.push(json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": schema,
+            }));
+
+            format!("{{{}}}", name)
+        })
+        .collect::<Vec<String>>();
 
-    highlighted_html_for_string(
-        input.as_str(),
-        &PS,
-        PS.find_syntax_by_token("json").unwrap(),
-        &TS.themes["InspiredGitHub"],
-    )
+    (segments.join("/"), parameters)
 }
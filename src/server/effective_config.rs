@@ -0,0 +1,364 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::database::broker::{
+    DEFAULT_EVENT_BROKER_TOPIC, ENV_EVENT_BROKER, ENV_EVENT_BROKER_TOPIC, ENV_EVENT_BROKER_URL,
+};
+use crate::database::events::{
+    DEFAULT_OUTBOX_MAX_ATTEMPTS, DEFAULT_OUTBOX_POLL_INTERVAL, ENV_NOTIFICATION_COMMAND,
+    ENV_OUTBOX_MAX_ATTEMPTS, ENV_OUTBOX_POLL_INTERVAL,
+};
+use crate::database::ldap_sync::{
+    DEFAULT_LDAP_EMAIL_ATTRIBUTE, DEFAULT_LDAP_GROUP_FILTER, DEFAULT_LDAP_GROUP_MEMBER_ATTRIBUTE,
+    DEFAULT_LDAP_NAME_ATTRIBUTE, DEFAULT_LDAP_SYNC_INTERVAL, DEFAULT_LDAP_USER_FILTER,
+    ENV_LDAP_BIND_DN, ENV_LDAP_BIND_PASSWORD, ENV_LDAP_EMAIL_ATTRIBUTE, ENV_LDAP_GROUP_BASE_DN,
+    ENV_LDAP_GROUP_FILTER, ENV_LDAP_GROUP_MEMBER_ATTRIBUTE, ENV_LDAP_GROUP_ROLES,
+    ENV_LDAP_NAME_ATTRIBUTE, ENV_LDAP_SYNC_INTERVAL, ENV_LDAP_URL, ENV_LDAP_USER_BASE_DN,
+    ENV_LDAP_USER_FILTER,
+};
+use crate::database::limits::{
+    ENV_MAX_ORGANIZATION_MEMBERS, ENV_MAX_ROLES, ENV_MAX_SESSIONS_PER_USER, ENV_MAX_USERS,
+};
+use crate::database::partitions::{
+    DEFAULT_AUDIT_RETENTION_MONTHS, ENV_AUDIT_ARCHIVE_DIR, ENV_AUDIT_RETENTION_MONTHS,
+};
+use crate::database::permission_cache::{DEFAULT_PERMISSION_CACHE_TTL, ENV_PERMISSION_CACHE_TTL};
+use crate::database::pool_monitor::{DEFAULT_CONNECTION_HOLD_WARN, ENV_CONNECTION_HOLD_WARN};
+use crate::database::redis_sessions::{DEFAULT_REDIS_URL, ENV_REDIS_URL};
+use crate::database::roles::{DEFAULT_ROLE_TRASH_RETENTION_DAYS, ENV_ROLE_TRASH_RETENTION_DAYS};
+use crate::database::statement_cache::{
+    DEFAULT_SLOW_QUERY_THRESHOLD, DEFAULT_STATEMENT_TIMEOUT, ENV_SLOW_QUERY_THRESHOLD,
+    ENV_STATEMENT_TIMEOUT,
+};
+use crate::database::tokens::{
+    DEFAULT_SESSION_IDLE_TIMEOUT, DEFAULT_SESSION_LIFETIME, DEFAULT_SESSION_MAX_LIFETIME,
+    DEFAULT_SESSION_STORE, ENV_SESSION_IDLE_TIMEOUT, ENV_SESSION_LIFETIME,
+    ENV_SESSION_MAX_LIFETIME, ENV_SESSION_SLIDING_EXPIRY, ENV_SESSION_STORE,
+    REQUEST_TOKEN_EXPIRE_SECONDS,
+};
+use crate::database::users::{
+    DEFAULT_ACCOUNT_EXPIRY_NOTICE_HOURS, DEFAULT_GUEST_TOKEN_LIMIT, DEFAULT_GUEST_TOKEN_TTL,
+    DEFAULT_USER_TRASH_RETENTION_DAYS, ENV_ACCOUNT_EXPIRY_NOTICE_HOURS, ENV_GUEST_PERMISSIONS,
+    ENV_GUEST_TOKEN_LIMIT, ENV_GUEST_TOKEN_TTL, ENV_REQUIRE_EMAIL_VERIFICATION,
+    ENV_USER_TRASH_RETENTION_DAYS,
+};
+use crate::database::webhooks::{
+    DEFAULT_WEBHOOK_MAX_ATTEMPTS, DEFAULT_WEBHOOK_POLL_INTERVAL, DEFAULT_WEBHOOK_TIMEOUT,
+    ENV_WEBHOOK_MAX_ATTEMPTS, ENV_WEBHOOK_POLL_INTERVAL, ENV_WEBHOOK_TIMEOUT,
+};
+use crate::database::{
+    Database, DB_CONNECTION_URL, DEFAULT_ADMIN_EMAIL, DEFAULT_CONNECTION, ENV_ADMIN_ACCOUNTS,
+    ENV_ADMIN_EMAIL, ENV_ADMIN_PASSWORD,
+};
+use crate::server::access_log::ENV_ACCESS_LOG;
+use crate::server::compression::{
+    DEFAULT_HTTP_COMPRESSION_THRESHOLD, DEFAULT_RPC_COMPRESSION_THRESHOLD,
+    ENV_HTTP_COMPRESSION_THRESHOLD, ENV_RPC_COMPRESSION_THRESHOLD,
+};
+use crate::server::cors::{
+    DEFAULT_CORS_ALLOWED_HEADERS, DEFAULT_CORS_MAX_AGE, ENV_CORS_ALLOWED_HEADERS,
+    ENV_CORS_ALLOWED_ORIGINS, ENV_CORS_ALLOW_CREDENTIALS, ENV_CORS_MAX_AGE,
+};
+use crate::server::documentation::{DEFAULT_SWAGGER_UI_ASSETS_URL, ENV_SWAGGER_UI_ASSETS_URL};
+#[cfg(feature = "grpc")]
+use crate::server::grpc_server::{DEFAULT_GRPC_SERVER_ADDRESS, ENV_GRPC_SERVER_ADDRESS};
+use crate::server::http_server::{
+    DEFAULT_CLIENT_CERT_PROXIES, DEFAULT_LISTEN_ADDRESS, DEFAULT_REQUEST_QUEUE_SIZE,
+    DEFAULT_REQUEST_QUEUE_TIMEOUT, DEFAULT_TRUSTED_PROXIES, DEFAULT_UNIX_SOCKET_MODE,
+    ENV_CLIENT_CERT_HEADER, ENV_CLIENT_CERT_PROXIES, ENV_ENABLE_METRICS, ENV_ENABLE_REGISTRATION,
+    ENV_MAX_CONCURRENT_REQUESTS, ENV_REQUEST_QUEUE_SIZE, ENV_REQUEST_QUEUE_TIMEOUT,
+    ENV_TRUSTED_PROXIES, ENV_UNIX_SOCKET_MODE, LISTEN_ADDRESS,
+};
+#[cfg(feature = "hyper-server")]
+use crate::server::hyper_server::ENV_KEEP_ALIVE;
+use crate::server::naming::{DEFAULT_JSON_NAMING_CONVENTION, ENV_JSON_NAMING_CONVENTION};
+use crate::server::rate_limit::{
+    DEFAULT_ACCOUNT_RATE_LIMIT, DEFAULT_ACCOUNT_RATE_LIMIT_BURST, DEFAULT_IP_RATE_LIMIT,
+    DEFAULT_IP_RATE_LIMIT_BURST, ENV_ACCOUNT_RATE_LIMIT, ENV_ACCOUNT_RATE_LIMIT_BURST,
+    ENV_IP_RATE_LIMIT, ENV_IP_RATE_LIMIT_BURST,
+};
+use crate::server::rpc_listener::{
+    DEFAULT_RPC_IDLE_TIMEOUT, DEFAULT_RPC_MAX_CONNECTIONS, ENV_RPC_IDLE_TIMEOUT,
+    ENV_RPC_MAX_CONNECTIONS,
+};
+use crate::server::rpc_tls::{ENV_RPC_TLS_CERT, ENV_RPC_TLS_CLIENT_CA, ENV_RPC_TLS_KEY};
+use crate::server::user_rpc::{DEFAULT_SERVER_ADDRESS, RPC_SERVER_ADDRESS};
+use crate::utils::config::{self, ENV_CONFIG_FILE};
+use crate::utils::hashing::{
+    DEFAULT_ARGON2_MEMORY_COST, DEFAULT_ARGON2_PARALLELISM, DEFAULT_ARGON2_TIME_COST,
+    DEFAULT_BCRYPT_COST, DEFAULT_PASSWORD_HASH_ALGORITHM, DEFAULT_PASSWORD_PEPPER_ID,
+    ENV_ARGON2_MEMORY_COST, ENV_ARGON2_PARALLELISM, ENV_ARGON2_TIME_COST, ENV_BCRYPT_COST,
+    ENV_PASSWORD_HASH_ALGORITHM, ENV_PASSWORD_PEPPER, ENV_PASSWORD_PEPPER_FILE,
+    ENV_PASSWORD_PEPPER_ID,
+};
+use crate::utils::hashing_pool::{
+    DEFAULT_HASHING_QUEUE_SIZE, ENV_HASHING_QUEUE_SIZE, ENV_HASHING_WORKERS,
+};
+use crate::utils::i18n::{DEFAULT_LANGUAGE, ENV_DEFAULT_LANGUAGE};
+use crate::utils::jwt::{DEFAULT_JWT_ISSUER, ENV_JWT_ISSUER, ENV_JWT_PRIVATE_KEY};
+use crate::utils::password::{
+    DEFAULT_PASSWORD_MIN_LENGTH, DEFAULT_PASSWORD_MIN_SCORE, ENV_PASSWORD_MIN_LENGTH,
+    ENV_PASSWORD_MIN_SCORE,
+};
+#[cfg(feature = "telemetry")]
+use crate::utils::telemetry::{DEFAULT_SERVICE_NAME, ENV_OTLP_ENDPOINT, ENV_SERVICE_NAME};
+use crate::utils::ENV_TOKEN_SECRET;
+
+/// The value returned instead of secrets
+const MASKED: &str = "***";
+
+lazy_static::lazy_static! {
+    static ref URL_PASSWORD_REGEX: Regex = Regex::new(r"^([^:/]+://[^:/@]+):[^@]*@").unwrap();
+}
+
+/// A single configuration value as it was resolved by the server
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct ConfigEntry {
+    pub name: String,
+    pub value: Option<String>,
+    pub source: ConfigSource,
+}
+
+/// Where a configuration value comes from
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Env,
+    File,
+    Default,
+    Unset,
+}
+
+/// Values that are derived from the configuration at runtime
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct RuntimeConfig {
+    pub db_pool_max_size: u32,
+    pub request_token_ttl: u32,
+}
+
+/// The effective configuration of the running server.
+/// Secrets are masked and never returned.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct EffectiveConfig {
+    pub settings: Vec<ConfigEntry>,
+    pub runtime: RuntimeConfig,
+}
+
+impl EffectiveConfig {
+    /// Resolves the configuration the same way the server components do
+    pub fn resolve(database: &Database) -> Self {
+        let max_concurrent_requests = config::var(ENV_MAX_CONCURRENT_REQUESTS)
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(num_cpus::get() * 4);
+        let mut settings = vec![
+            ConfigEntry::value::<&str>(ENV_CONFIG_FILE, None),
+            ConfigEntry::value(LISTEN_ADDRESS, Some(DEFAULT_LISTEN_ADDRESS)),
+            ConfigEntry::value(RPC_SERVER_ADDRESS, Some(DEFAULT_SERVER_ADDRESS)),
+            ConfigEntry::value(ENV_RPC_IDLE_TIMEOUT, Some(DEFAULT_RPC_IDLE_TIMEOUT)),
+            ConfigEntry::value(ENV_RPC_MAX_CONNECTIONS, Some(DEFAULT_RPC_MAX_CONNECTIONS)),
+            ConfigEntry::value::<&str>(ENV_RPC_TLS_CERT, None),
+            ConfigEntry::value::<&str>(ENV_RPC_TLS_KEY, None),
+            ConfigEntry::value::<&str>(ENV_RPC_TLS_CLIENT_CA, None),
+            ConfigEntry::value::<&str>(ENV_CORS_ALLOWED_ORIGINS, None),
+            ConfigEntry::value(ENV_CORS_ALLOWED_HEADERS, Some(DEFAULT_CORS_ALLOWED_HEADERS)),
+            ConfigEntry::value(ENV_CORS_MAX_AGE, Some(DEFAULT_CORS_MAX_AGE)),
+            ConfigEntry::value(ENV_CORS_ALLOW_CREDENTIALS, Some("false")),
+            ConfigEntry::value(ENV_ENABLE_METRICS, Some("false")),
+            ConfigEntry::value(ENV_ENABLE_REGISTRATION, Some("false")),
+            ConfigEntry::value(ENV_UNIX_SOCKET_MODE, Some(DEFAULT_UNIX_SOCKET_MODE)),
+            ConfigEntry::value(ENV_ACCESS_LOG, Some("false")),
+            ConfigEntry::value::<&str>(ENV_CLIENT_CERT_HEADER, None),
+            ConfigEntry::value(ENV_CLIENT_CERT_PROXIES, Some(DEFAULT_CLIENT_CERT_PROXIES)),
+            ConfigEntry::value(ENV_TRUSTED_PROXIES, Some(DEFAULT_TRUSTED_PROXIES)),
+            ConfigEntry::value(
+                ENV_MAX_CONCURRENT_REQUESTS,
+                Some(max_concurrent_requests.to_string()),
+            ),
+            ConfigEntry::value(ENV_REQUEST_QUEUE_SIZE, Some(DEFAULT_REQUEST_QUEUE_SIZE)),
+            ConfigEntry::value(
+                ENV_REQUEST_QUEUE_TIMEOUT,
+                Some(DEFAULT_REQUEST_QUEUE_TIMEOUT),
+            ),
+            ConfigEntry::value(ENV_IP_RATE_LIMIT, Some(DEFAULT_IP_RATE_LIMIT)),
+            ConfigEntry::value(ENV_IP_RATE_LIMIT_BURST, Some(DEFAULT_IP_RATE_LIMIT_BURST)),
+            ConfigEntry::value(ENV_ACCOUNT_RATE_LIMIT, Some(DEFAULT_ACCOUNT_RATE_LIMIT)),
+            ConfigEntry::value(
+                ENV_ACCOUNT_RATE_LIMIT_BURST,
+                Some(DEFAULT_ACCOUNT_RATE_LIMIT_BURST),
+            ),
+            ConfigEntry::value(
+                ENV_JSON_NAMING_CONVENTION,
+                Some(DEFAULT_JSON_NAMING_CONVENTION),
+            ),
+            ConfigEntry::value(
+                ENV_SWAGGER_UI_ASSETS_URL,
+                Some(DEFAULT_SWAGGER_UI_ASSETS_URL),
+            ),
+            ConfigEntry::value(
+                ENV_RPC_COMPRESSION_THRESHOLD,
+                Some(DEFAULT_RPC_COMPRESSION_THRESHOLD),
+            ),
+            ConfigEntry::value(
+                ENV_HTTP_COMPRESSION_THRESHOLD,
+                Some(DEFAULT_HTTP_COMPRESSION_THRESHOLD),
+            ),
+            ConfigEntry::connection_url(DB_CONNECTION_URL, Some(DEFAULT_CONNECTION)),
+            ConfigEntry::value(ENV_STATEMENT_TIMEOUT, Some(DEFAULT_STATEMENT_TIMEOUT)),
+            ConfigEntry::value(ENV_SLOW_QUERY_THRESHOLD, Some(DEFAULT_SLOW_QUERY_THRESHOLD)),
+            ConfigEntry::value(ENV_CONNECTION_HOLD_WARN, Some(DEFAULT_CONNECTION_HOLD_WARN)),
+            ConfigEntry::value(ENV_PERMISSION_CACHE_TTL, Some(DEFAULT_PERMISSION_CACHE_TTL)),
+            ConfigEntry::value(ENV_SESSION_LIFETIME, Some(DEFAULT_SESSION_LIFETIME)),
+            ConfigEntry::value(ENV_SESSION_IDLE_TIMEOUT, Some(DEFAULT_SESSION_IDLE_TIMEOUT)),
+            ConfigEntry::value(ENV_SESSION_SLIDING_EXPIRY, Some("false")),
+            ConfigEntry::value(ENV_SESSION_MAX_LIFETIME, Some(DEFAULT_SESSION_MAX_LIFETIME)),
+            ConfigEntry::value(ENV_SESSION_STORE, Some(DEFAULT_SESSION_STORE)),
+            ConfigEntry::connection_url(ENV_REDIS_URL, Some(DEFAULT_REDIS_URL)),
+            ConfigEntry::value::<&str>(ENV_JWT_PRIVATE_KEY, None),
+            ConfigEntry::value(ENV_JWT_ISSUER, Some(DEFAULT_JWT_ISSUER)),
+            ConfigEntry::value::<&str>(ENV_GUEST_PERMISSIONS, None),
+            ConfigEntry::value(ENV_GUEST_TOKEN_TTL, Some(DEFAULT_GUEST_TOKEN_TTL)),
+            ConfigEntry::value(ENV_GUEST_TOKEN_LIMIT, Some(DEFAULT_GUEST_TOKEN_LIMIT)),
+            ConfigEntry::value(ENV_REQUIRE_EMAIL_VERIFICATION, Some("false")),
+            ConfigEntry::value(
+                ENV_ACCOUNT_EXPIRY_NOTICE_HOURS,
+                Some(DEFAULT_ACCOUNT_EXPIRY_NOTICE_HOURS),
+            ),
+            ConfigEntry::value::<&str>(ENV_LDAP_URL, None),
+            ConfigEntry::value::<&str>(ENV_LDAP_BIND_DN, None),
+            ConfigEntry::secret(ENV_LDAP_BIND_PASSWORD),
+            ConfigEntry::value::<&str>(ENV_LDAP_USER_BASE_DN, None),
+            ConfigEntry::value(ENV_LDAP_USER_FILTER, Some(DEFAULT_LDAP_USER_FILTER)),
+            ConfigEntry::value(ENV_LDAP_EMAIL_ATTRIBUTE, Some(DEFAULT_LDAP_EMAIL_ATTRIBUTE)),
+            ConfigEntry::value(ENV_LDAP_NAME_ATTRIBUTE, Some(DEFAULT_LDAP_NAME_ATTRIBUTE)),
+            ConfigEntry::value::<&str>(ENV_LDAP_GROUP_BASE_DN, None),
+            ConfigEntry::value(ENV_LDAP_GROUP_FILTER, Some(DEFAULT_LDAP_GROUP_FILTER)),
+            ConfigEntry::value(
+                ENV_LDAP_GROUP_MEMBER_ATTRIBUTE,
+                Some(DEFAULT_LDAP_GROUP_MEMBER_ATTRIBUTE),
+            ),
+            ConfigEntry::value::<&str>(ENV_LDAP_GROUP_ROLES, None),
+            ConfigEntry::value(ENV_LDAP_SYNC_INTERVAL, Some(DEFAULT_LDAP_SYNC_INTERVAL)),
+            ConfigEntry::value::<&str>(ENV_MAX_USERS, None),
+            ConfigEntry::value::<&str>(ENV_MAX_ROLES, None),
+            ConfigEntry::value::<&str>(ENV_MAX_SESSIONS_PER_USER, None),
+            ConfigEntry::value::<&str>(ENV_MAX_ORGANIZATION_MEMBERS, None),
+            ConfigEntry::value(
+                ENV_AUDIT_RETENTION_MONTHS,
+                Some(DEFAULT_AUDIT_RETENTION_MONTHS),
+            ),
+            ConfigEntry::value::<&str>(ENV_AUDIT_ARCHIVE_DIR, None),
+            ConfigEntry::value(
+                ENV_ROLE_TRASH_RETENTION_DAYS,
+                Some(DEFAULT_ROLE_TRASH_RETENTION_DAYS),
+            ),
+            ConfigEntry::value(
+                ENV_USER_TRASH_RETENTION_DAYS,
+                Some(DEFAULT_USER_TRASH_RETENTION_DAYS),
+            ),
+            ConfigEntry::value(ENV_OUTBOX_POLL_INTERVAL, Some(DEFAULT_OUTBOX_POLL_INTERVAL)),
+            ConfigEntry::value(ENV_OUTBOX_MAX_ATTEMPTS, Some(DEFAULT_OUTBOX_MAX_ATTEMPTS)),
+            ConfigEntry::value::<&str>(ENV_EVENT_BROKER, None),
+            ConfigEntry::connection_url(ENV_EVENT_BROKER_URL, None),
+            ConfigEntry::value(ENV_EVENT_BROKER_TOPIC, Some(DEFAULT_EVENT_BROKER_TOPIC)),
+            ConfigEntry::value(
+                ENV_WEBHOOK_POLL_INTERVAL,
+                Some(DEFAULT_WEBHOOK_POLL_INTERVAL),
+            ),
+            ConfigEntry::value(ENV_WEBHOOK_TIMEOUT, Some(DEFAULT_WEBHOOK_TIMEOUT)),
+            ConfigEntry::value(ENV_WEBHOOK_MAX_ATTEMPTS, Some(DEFAULT_WEBHOOK_MAX_ATTEMPTS)),
+            ConfigEntry::value(ENV_PASSWORD_MIN_SCORE, Some(DEFAULT_PASSWORD_MIN_SCORE)),
+            ConfigEntry::value(ENV_PASSWORD_MIN_LENGTH, Some(DEFAULT_PASSWORD_MIN_LENGTH)),
+            ConfigEntry::value(
+                ENV_PASSWORD_HASH_ALGORITHM,
+                Some(DEFAULT_PASSWORD_HASH_ALGORITHM),
+            ),
+            ConfigEntry::value(ENV_BCRYPT_COST, Some(DEFAULT_BCRYPT_COST)),
+            ConfigEntry::value(ENV_ARGON2_MEMORY_COST, Some(DEFAULT_ARGON2_MEMORY_COST)),
+            ConfigEntry::value(ENV_ARGON2_TIME_COST, Some(DEFAULT_ARGON2_TIME_COST)),
+            ConfigEntry::value(ENV_ARGON2_PARALLELISM, Some(DEFAULT_ARGON2_PARALLELISM)),
+            ConfigEntry::value(ENV_HASHING_WORKERS, Some(num_cpus::get())),
+            ConfigEntry::value(ENV_HASHING_QUEUE_SIZE, Some(DEFAULT_HASHING_QUEUE_SIZE)),
+            ConfigEntry::value(ENV_PASSWORD_PEPPER_ID, Some(DEFAULT_PASSWORD_PEPPER_ID)),
+            ConfigEntry::value::<&str>(ENV_PASSWORD_PEPPER_FILE, None),
+            ConfigEntry::value(ENV_DEFAULT_LANGUAGE, Some(DEFAULT_LANGUAGE)),
+            ConfigEntry::value(ENV_ADMIN_EMAIL, Some(DEFAULT_ADMIN_EMAIL)),
+            ConfigEntry::value::<&str>(ENV_ADMIN_ACCOUNTS, None),
+            ConfigEntry::secret(ENV_ADMIN_PASSWORD),
+            ConfigEntry::secret(ENV_TOKEN_SECRET),
+            ConfigEntry::secret(ENV_PASSWORD_PEPPER),
+            ConfigEntry::secret(ENV_NOTIFICATION_COMMAND),
+        ];
+        #[cfg(feature = "hyper-server")]
+        settings.push(ConfigEntry::value(ENV_KEEP_ALIVE, Some("true")));
+        #[cfg(feature = "grpc")]
+        settings.push(ConfigEntry::value(
+            ENV_GRPC_SERVER_ADDRESS,
+            Some(DEFAULT_GRPC_SERVER_ADDRESS),
+        ));
+        #[cfg(feature = "telemetry")]
+        settings.extend(vec![
+            ConfigEntry::value::<&str>(ENV_OTLP_ENDPOINT, None),
+            ConfigEntry::value(ENV_SERVICE_NAME, Some(DEFAULT_SERVICE_NAME)),
+        ]);
+        settings.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self {
+            settings,
+            runtime: RuntimeConfig {
+                db_pool_max_size: database.pool_state().max_size,
+                request_token_ttl: REQUEST_TOKEN_EXPIRE_SECONDS,
+            },
+        }
+    }
+}
+
+impl ConfigEntry {
+    /// Reads a plain value from the env or the config file or falls back to the default
+    fn value<T: ToString>(name: &str, default: Option<T>) -> Self {
+        match config::var(name) {
+            Ok(value) => Self {
+                name: name.to_string(),
+                value: Some(value),
+                source: if dotenv::var(name).is_ok() {
+                    ConfigSource::Env
+                } else {
+                    ConfigSource::File
+                },
+            },
+            Err(_) => Self {
+                name: name.to_string(),
+                source: if default.is_some() {
+                    ConfigSource::Default
+                } else {
+                    ConfigSource::Unset
+                },
+                value: default.map(|d| d.to_string()),
+            },
+        }
+    }
+
+    /// Only reports if a secret value was configured
+    fn secret(name: &str) -> Self {
+        let mut entry = Self::value::<&str>(name, None);
+        entry.value = entry.value.map(|_| MASKED.to_string());
+
+        entry
+    }
+
+    /// Reads a connection url and masks the password contained in it
+    fn connection_url(name: &str, default: Option<&str>) -> Self {
+        let mut entry = Self::value(name, default);
+        entry.value = entry.value.map(|url| {
+            URL_PASSWORD_REGEX
+                .replace(&url, format!("$1:{}@", MASKED).as_str())
+                .to_string()
+        });
+
+        entry
+    }
+}
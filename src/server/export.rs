@@ -0,0 +1,246 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::Builder;
+
+use rouille::{Response, ResponseBody};
+use serde_json::Value;
+
+use crate::database::models::UserExport;
+use crate::database::users::Users;
+use crate::utils::error::DBError;
+
+/// The size of the chunks the export is sent to the client in
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The number of chunks that are buffered until the client has read them
+const BUFFERED_CHUNKS: usize = 4;
+const CSV_HEADER: &str =
+    "id,name,email,active,expires_at,last_login_at,last_seen_at,roles,attributes\r\n";
+
+/// The format users are exported in
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExportFormat {
+    /// A json array of all users
+    #[default]
+    Json,
+    /// One line per user. The roles are separated with semicolons
+    /// and the attributes are a json object.
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!("Unknown export format {}", s)),
+        }
+    }
+}
+
+impl ExportFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "users.json",
+            ExportFormat::Csv => "users.csv",
+        }
+    }
+}
+
+/// Streams all users in the given format. The users are read by a separate
+/// thread that waits for the client to read the previous chunks so that only a
+/// few chunks are held in memory. Attributes with the given names are removed.
+pub fn stream_users(
+    users: Users,
+    format: ExportFormat,
+    hidden_attributes: Vec<String>,
+) -> Response {
+    let (sender, receiver) = sync_channel(BUFFERED_CHUNKS);
+    let result = Builder::new()
+        .name("user-export".to_string())
+        .spawn(move || {
+            let mut writer = ChunkWriter::new(sender);
+            let result = write_users(&users, format, &hidden_attributes, &mut writer)
+                .and_then(|_| writer.flush());
+            if let Err(e) = result {
+                log::error!("Failed to export the users: {}", e);
+                // the response has already started so the connection is aborted instead
+                writer.abort();
+            }
+        });
+    if let Err(e) = result {
+        log::error!("Failed to start the export thread: {}", e);
+        return Response::text("Failed to export the users").with_status_code(500);
+    }
+
+    let mut response = Response::from_data(format.content_type(), Vec::new())
+        .with_additional_header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", format.file_name()),
+        )
+        .with_no_cache();
+    response.data = ResponseBody::from_reader(ChunkReader::new(receiver));
+
+    response
+}
+
+fn write_users(
+    users: &Users,
+    format: ExportFormat,
+    hidden_attributes: &[String],
+    writer: &mut ChunkWriter,
+) -> Result<(), DBError> {
+    let mut first = true;
+    match format {
+        ExportFormat::Json => writer.write("[")?,
+        ExportFormat::Csv => writer.write(CSV_HEADER)?,
+    }
+    users.export_users(|mut user| {
+        if let Value::Object(attributes) = &mut user.attributes {
+            for name in hidden_attributes {
+                attributes.remove(name);
+            }
+        }
+        match format {
+            ExportFormat::Json => {
+                if !first {
+                    writer.write(",")?;
+                }
+                writer.write(&serde_json::to_string(&user).map_err(|e| e.to_string())?)?;
+            }
+            ExportFormat::Csv => writer.write(&csv_line(&user))?,
+        }
+        first = false;
+
+        Ok(())
+    })?;
+    if format == ExportFormat::Json {
+        writer.write("]")?;
+    }
+
+    Ok(())
+}
+
+/// Formats the user as a line of the csv export
+fn csv_line(user: &UserExport) -> String {
+    let fields = [
+        user.id.to_string(),
+        user.name.clone(),
+        user.email.clone(),
+        user.active.to_string(),
+        user.expires_at.clone().unwrap_or_default(),
+        user.last_login_at.clone().unwrap_or_default(),
+        user.last_seen_at.clone().unwrap_or_default(),
+        user.roles.join(";"),
+        user.attributes.to_string(),
+    ];
+
+    let mut line = fields
+        .iter()
+        .map(|field| escape_csv_field(field))
+        .collect::<Vec<String>>()
+        .join(",");
+    line.push_str("\r\n");
+
+    line
+}
+
+/// Quotes a csv field if it contains a separator, quote or line break
+fn escape_csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Collects the written text into chunks and sends them to the reader
+struct ChunkWriter {
+    sender: SyncSender<io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(sender: SyncSender<io::Result<Vec<u8>>>) -> Self {
+        Self {
+            sender,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), DBError> {
+        self.buffer.extend_from_slice(text.as_bytes());
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the buffered text. This fails if the client has closed the connection.
+    fn flush(&mut self) -> Result<(), DBError> {
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.sender
+            .send(Ok(chunk))
+            .map_err(|_| DBError::GenericError("The client closed the connection".to_string()))
+    }
+
+    /// Makes the reader fail so that the client doesn't mistake
+    /// the incomplete export for a complete one
+    fn abort(&mut self) {
+        let _ = self.sender.send(Err(io::Error::new(
+            io::ErrorKind::Other,
+            "The export failed",
+        )));
+    }
+}
+
+/// Reads the chunks of the export until the writer is finished
+struct ChunkReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl ChunkReader {
+    fn new(receiver: Receiver<io::Result<Vec<u8>>>) -> Self {
+        Self {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                // the writer has finished or failed
+                Err(_) => return Ok(0),
+            }
+        }
+        let length = buf.len().min(self.chunk.len() - self.position);
+        buf[..length].copy_from_slice(&self.chunk[self.position..self.position + length]);
+        self.position += length;
+
+        Ok(length)
+    }
+}
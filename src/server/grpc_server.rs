@@ -0,0 +1,400 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::database::models::{CreatePermissionsEntry, Permission, Role};
+use crate::database::tokens::{TokenStoreEntry, GUEST_USER_ID};
+use crate::database::{change_history, Database};
+use crate::server::access_log;
+use crate::server::token_access::{self, granted_permissions, validation_result, visible_roles};
+use crate::utils::config;
+use crate::utils::error::DBError;
+use crate::utils::get_user_id_from_token;
+
+use self::proto::user_management_server::{UserManagement, UserManagementServer};
+
+pub mod proto {
+    tonic::include_proto!("user_management");
+}
+
+pub(crate) const ENV_GRPC_SERVER_ADDRESS: &str = "GRPC_SERVER_ADDRESS";
+pub(crate) const DEFAULT_GRPC_SERVER_ADDRESS: &str = "127.0.0.1:5556";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_RESOLVE_USERS: usize = 1000;
+
+/// A gRPC server that provides the operations of the rpc server
+/// for services that can't implement the msgpack protocol.
+/// The service is described in proto/user_management.proto.
+pub struct UserGrpcServer {
+    database: Database,
+}
+
+impl UserGrpcServer {
+    pub fn new(database: &Database) -> Self {
+        Self {
+            database: Database::clone(database),
+        }
+    }
+
+    /// Starts the gRPC server in a tokio runtime. The requests are handled
+    /// on the blocking thread pool of the runtime as the database is synchronous.
+    pub fn start(&self) {
+        let listen_address =
+            config::var(ENV_GRPC_SERVER_ADDRESS).unwrap_or(DEFAULT_GRPC_SERVER_ADDRESS.to_string());
+        let address = match listen_address.parse() {
+            Ok(address) => address,
+            Err(e) => {
+                log::error!("Invalid gRPC address {}: {}", listen_address, e);
+                return;
+            }
+        };
+        log::info!("Starting gRPC-Server...");
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("grpc-worker")
+            .build()
+            .unwrap();
+        let service = GrpcService {
+            database: Database::clone(&self.database),
+        };
+
+        runtime.block_on(async move {
+            log::info!("gRPC-Server running on {}", listen_address);
+            let result = Server::builder()
+                .add_service(UserManagementServer::new(service))
+                .serve(address)
+                .await;
+            if let Err(e) = result {
+                log::error!("gRPC-Server on {} failed: {}", listen_address, e);
+            }
+        });
+    }
+}
+
+struct GrpcService {
+    database: Database,
+}
+
+impl GrpcService {
+    /// Handles the request on the blocking thread pool with the same
+    /// request id and access log as the messages of the rpc server
+    async fn handle<I, O, F>(
+        &self,
+        method: &'static str,
+        request: Request<I>,
+        func: F,
+    ) -> Result<Response<O>, Status>
+    where
+        I: Send + 'static,
+        O: Send + 'static,
+        F: FnOnce(Database, I) -> Result<O, Status> + Send + 'static,
+    {
+        let database = Database::clone(&self.database);
+        let request_id = access_log::request_id(
+            request
+                .metadata()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|value| value.to_str().ok()),
+        );
+        let message = request.into_inner();
+
+        tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            let response = access_log::with_request_id(&request_id, || {
+                change_history::with_actor_scope(|| {
+                    change_history::set_request_id(&request_id);
+                    func(database, message)
+                })
+            });
+            access_log::log_rpc(&request_id, method, response.is_ok(), started, None);
+
+            response.map(Response::new)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+    }
+}
+
+#[tonic::async_trait]
+impl UserManagement for GrpcService {
+    async fn validate_token(
+        &self,
+        request: Request<proto::TokenRequest>,
+    ) -> Result<Response<proto::ValidateTokenResponse>, Status> {
+        self.handle("ValidateToken", request, |database, message| {
+            let entry = database
+                .users
+                .get_request_token_entry(&message.token)
+                .unwrap_or(None);
+            let (valid, request_ttl) = validation_result(entry.as_ref());
+
+            Ok(proto::ValidateTokenResponse { valid, request_ttl })
+        })
+        .await
+    }
+
+    async fn get_user_id(
+        &self,
+        request: Request<proto::TokenRequest>,
+    ) -> Result<Response<proto::UserIdResponse>, Status> {
+        self.handle("GetUserId", request, |_, message| {
+            let user_id = get_user_id_from_token(&message.token)
+                .ok_or(Status::unauthenticated("Invalid request token"))?;
+
+            Ok(proto::UserIdResponse { user_id })
+        })
+        .await
+    }
+
+    async fn get_user(
+        &self,
+        request: Request<proto::TokenRequest>,
+    ) -> Result<Response<proto::UserResponse>, Status> {
+        self.handle("GetUser", request, |database, message| {
+            let entry = token_entry(&database, &message.token)?;
+            let user_id = entry.user_id();
+            if user_id == GUEST_USER_ID {
+                return Err(Status::invalid_argument(
+                    "Guest tokens don't belong to a user",
+                ));
+            }
+            let user = database.users.get_user(user_id)?;
+            let permissions = database.users.get_permission_names(user_id)?;
+
+            Ok(proto::UserResponse {
+                id: user.id,
+                name: user.name,
+                email: user.email,
+                roles: roles_message(&database, &entry)?,
+                permissions: granted_permissions(entry.kind(), (*permissions).clone())
+                    .into_iter()
+                    .collect(),
+            })
+        })
+        .await
+    }
+
+    async fn get_roles(
+        &self,
+        request: Request<proto::TokenRequest>,
+    ) -> Result<Response<proto::RolesResponse>, Status> {
+        self.handle("GetRoles", request, |database, message| {
+            let entry = token_entry(&database, &message.token)?;
+
+            Ok(proto::RolesResponse {
+                roles: roles_message(&database, &entry)?,
+            })
+        })
+        .await
+    }
+
+    async fn get_token_permissions(
+        &self,
+        request: Request<proto::TokenRequest>,
+    ) -> Result<Response<proto::PermissionNamesResponse>, Status> {
+        self.handle("GetTokenPermissions", request, |database, message| {
+            let permissions = database
+                .users
+                .get_token_permissions(&message.token)?
+                .ok_or(Status::unauthenticated("Invalid request token"))?;
+
+            Ok(proto::PermissionNamesResponse { permissions })
+        })
+        .await
+    }
+
+    async fn get_organization_permissions(
+        &self,
+        request: Request<proto::OrganizationPermissionsRequest>,
+    ) -> Result<Response<proto::PermissionNamesResponse>, Status> {
+        self.handle(
+            "GetOrganizationPermissions",
+            request,
+            |database, message| {
+                let entry = token_entry(&database, &message.token)?;
+                let user_id = entry.user_id();
+                let mut permissions = database
+                    .organizations
+                    .get_permission_names(user_id, &message.organization)?;
+                permissions.extend(
+                    database
+                        .users
+                        .get_permission_names(user_id)?
+                        .iter()
+                        .cloned(),
+                );
+
+                Ok(proto::PermissionNamesResponse {
+                    permissions: granted_permissions(entry.kind(), permissions)
+                        .into_iter()
+                        .collect(),
+                })
+            },
+        )
+        .await
+    }
+
+    async fn get_role_permissions(
+        &self,
+        request: Request<proto::RolePermissionsRequest>,
+    ) -> Result<Response<proto::RolePermissionsResponse>, Status> {
+        self.handle("GetRolePermissions", request, |database, message| {
+            let mut roles = HashMap::new();
+            for role_id in message.roles {
+                let permissions = database.role_permission.by_role(role_id)?;
+                roles.insert(role_id, permissions_response(permissions));
+            }
+
+            Ok(proto::RolePermissionsResponse { roles })
+        })
+        .await
+    }
+
+    async fn resolve_users(
+        &self,
+        request: Request<proto::ResolveUsersRequest>,
+    ) -> Result<Response<proto::ResolveUsersResponse>, Status> {
+        self.handle("ResolveUsers", request, |database, message| {
+            if message.ids.len() > MAX_RESOLVE_USERS {
+                return Err(Status::invalid_argument(format!(
+                    "At most {} users can be resolved at once",
+                    MAX_RESOLVE_USERS
+                )));
+            }
+            let users = database
+                .users
+                .resolve_users(&message.ids)?
+                .into_iter()
+                .map(|(id, user)| {
+                    (
+                        id,
+                        proto::ResolvedUser {
+                            name: user.name,
+                            email: user.email,
+                            active: user.active,
+                        },
+                    )
+                })
+                .collect();
+
+            Ok(proto::ResolveUsersResponse { users })
+        })
+        .await
+    }
+
+    async fn create_role(
+        &self,
+        request: Request<proto::CreateRoleRequest>,
+    ) -> Result<Response<proto::Role>, Status> {
+        self.handle("CreateRole", request, |database, message| {
+            let description = Some(message.description).filter(|d| !d.is_empty());
+            let role =
+                database
+                    .roles
+                    .create_role(message.name, description, message.permissions)?;
+
+            Ok(role_message(role))
+        })
+        .await
+    }
+
+    async fn create_permissions(
+        &self,
+        request: Request<proto::CreatePermissionsRequest>,
+    ) -> Result<Response<proto::PermissionsResponse>, Status> {
+        self.handle("CreatePermissions", request, |database, message| {
+            let permissions = database.permissions.create_permissions(
+                message
+                    .permissions
+                    .into_iter()
+                    .map(|entry| CreatePermissionsEntry {
+                        name: entry.name,
+                        description: entry.description,
+                    })
+                    .collect(),
+            )?;
+
+            Ok(permissions_response(permissions))
+        })
+        .await
+    }
+
+    async fn assign_roles(
+        &self,
+        request: Request<proto::AssignRolesRequest>,
+    ) -> Result<Response<proto::RolesResponse>, Status> {
+        self.handle("AssignRoles", request, |database, message| {
+            let roles = database
+                .user_roles
+                .assign_roles(message.user_id, message.roles)?;
+
+            Ok(proto::RolesResponse {
+                roles: roles.into_iter().map(role_message).collect(),
+            })
+        })
+        .await
+    }
+}
+
+impl From<DBError> for Status {
+    fn from(error: DBError) -> Self {
+        match error {
+            DBError::RecordDoesNotExist => Status::not_found(error.to_string()),
+            DBError::RecordExists => Status::already_exists(error.to_string()),
+            DBError::ValidationError(_) | DBError::GenericError(_) => {
+                Status::invalid_argument(error.to_string())
+            }
+            DBError::LimitExceeded(_, _) => Status::resource_exhausted(error.to_string()),
+            DBError::HashingBusy => Status::unavailable(error.to_string()),
+            DBError::SystemRecord(_) => Status::failed_precondition(error.to_string()),
+            _ => {
+                log::error!("gRPC request failed: {}", error);
+                Status::internal(error.to_string())
+            }
+        }
+    }
+}
+
+/// Returns the session of a request token that can be used with services
+fn token_entry(database: &Database, token: &String) -> Result<TokenStoreEntry, Status> {
+    token_access::service_token_entry(database, token)?
+        .ok_or(Status::unauthenticated("Invalid request token"))
+}
+
+/// Returns the roles of the user of the token that the token may reveal
+fn roles_message(database: &Database, entry: &TokenStoreEntry) -> Result<Vec<proto::Role>, Status> {
+    Ok(
+        visible_roles(entry.kind(), database.user_roles.by_user(entry.user_id())?)
+            .into_iter()
+            .map(role_message)
+            .collect(),
+    )
+}
+
+fn role_message(role: Role) -> proto::Role {
+    proto::Role {
+        id: role.id,
+        name: role.name,
+        description: role.description,
+        enabled: role.enabled,
+    }
+}
+
+fn permissions_response(permissions: Vec<Permission>) -> proto::PermissionsResponse {
+    proto::PermissionsResponse {
+        permissions: permissions
+            .into_iter()
+            .map(|permission| proto::Permission {
+                id: permission.id,
+                name: permission.name,
+                description: permission.description,
+            })
+            .collect(),
+    }
+}
@@ -2,55 +2,348 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::Formatter;
 use std::fmt::{self, Display};
-use std::io::Read;
-
+use std::fs;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
 use regex::Regex;
 use rouille::{Request, Response, Server};
+use schemars::JsonSchema;
 use serde::Serialize;
-
-use crate::database::models::{Permission, Role, UserFullInformation, UserInformation};
+use serde_json::Value;
+
+use crate::database::change_history::{self, ENTITY_ROLE, ENTITY_USER};
+use crate::database::models::{
+    ApiKey, ChangeHistoryEntry, ClientCertificate, FieldVisibility, OAuthClient, OAuthConsent,
+    Organization, Pagination, Permission, PermissionSync, Role, RoleImport, SortOrder, Sorting,
+    TrashedRole, TrashedUser, UserExport, UserFieldDefinition, UserFilter, UserFullInformation,
+    UserInformation, Webhook, WebhookDelivery,
+};
+use crate::database::oauth_clients::OPENID_SCOPE;
 use crate::database::permissions::{
-    ROLE_CREATE_PERM, ROLE_DELETE_PERM, ROLE_UPDATE_PERM, ROLE_VIEW_PERM, USER_CREATE_PERM,
-    USER_DELETE_PERM, USER_UPDATE_PERM, USER_VIEW_PERM,
+    API_KEY_MANAGE_PERM, CONFIG_VIEW_PERM, OAUTH_CLIENT_MANAGE_PERM, ORGANIZATION_MANAGE_PERM,
+    ORGANIZATION_VIEW_PERM, PERMISSION_MANAGE_PERM, ROLE_CREATE_PERM, ROLE_DELETE_PERM,
+    ROLE_UPDATE_PERM, ROLE_VIEW_PERM, USER_APPROVE_PERM, USER_CREATE_PERM, USER_DELETE_PERM,
+    USER_FIELDS_MANAGE_PERM, USER_IMPERSONATE_PERM, USER_ROLES_UPDATE_PERM, USER_UPDATE_PERM,
+    USER_VIEW_PERM, WEBHOOK_MANAGE_PERM,
+};
+use crate::database::tokens::{
+    GuestToken, ImpersonationToken, SessionInfo, SessionTokens, TokenKind, TokenStoreEntry,
 };
-use crate::database::tokens::SessionTokens;
-use crate::database::Database;
+use crate::database::user_fields::remove_private_fields;
+use crate::database::users::apply_attribute_changes;
+use crate::database::{admin_email, Database, ADMIN_ROLE_NAME};
+use crate::server::build_info::BuildInfo;
+use crate::server::concurrency::ConcurrencyLimiter;
+use crate::server::cors::CORS;
 use crate::server::documentation::RESTDocumentation;
+use crate::server::effective_config::EffectiveConfig;
+use crate::server::export::{self, ExportFormat};
 use crate::server::messages::{
-    CreateUserRequest, DeleteRoleResponse, DeleteUserRequest, DeleteUserResponse, ErrorMessage,
-    FullRoleData, LoginRequest, LoginResponse, LogoutConfirmation, LogoutMessage,
-    ModifyRoleRequest, RefreshMessage, UpdateUserRequest,
+    AcceptInvitationRequest, AddClientCertificateRequest, AuthorizeRequest, AuthorizeResponse,
+    ChangePasswordRequest, CreateApiKeyRequest, CreateApiKeyResponse, CreateOAuthClientRequest,
+    CreateOAuthClientResponse, CreateOrganizationRequest, CreateUserRequest, CreateWebhookRequest,
+    CreateWebhookResponse, DeleteApiKeyResponse, DeleteClientCertificateResponse,
+    DeleteOAuthClientResponse, DeleteOrganizationResponse, DeleteRoleResponse,
+    DeleteUserFieldResponse, DeleteUserRequest, DeleteUserResponse, DeleteWebhookResponse,
+    EmailChangeResponse, ExportRolesResponse, FullOrganizationData, FullRoleData,
+    GuestTokenRequest, HealthResponse, HeartbeatResponse, ImportRolesRequest, InviteUserRequest,
+    LivenessResponse, LoginRequest, LoginResponse, LogoutConfirmation, LogoutMessage,
+    MergeUsersRequest, MergeUsersResponse, ModifyRoleRequest, ModifyUserFieldRequest,
+    OAuthTokenRequest, OAuthTokenResponse, OpenIdConfiguration, Page, PasswordStrengthRequest,
+    PasswordStrengthResponse, PoolHealth, ReadinessResponse, RefreshMessage, RegisterRequest,
+    RejectUserResponse, RemoveOrganizationMemberResponse, RevokeConsentResponse,
+    RotateAdminRequest, RotateAdminResponse, SetOrganizationMemberRequest, SetRequestQuotaRequest,
+    SetRequestQuotaResponse, SetUserActiveResponse, SyncPermissionsRequest,
+    UpdateUserAttributesRequest, UpdateUserRequest, UpdateUserRolesRequest, UserInfoResponse,
+    VerifyEmailRequest,
 };
-use crate::utils::error::DBError;
-use crate::utils::get_user_id_from_token;
+use crate::server::metrics;
+use crate::server::naming::{from_json_value, json_response};
+use crate::server::quota::{QuotaCheck, QuotaLimiter};
+use crate::server::rate_limit::{ACCOUNT_RATE_LIMITER, IP_RATE_LIMITER, RATE_LIMITED_PATHS};
+use crate::server::readiness::READINESS;
+use crate::server::validation;
+use crate::server::versioning::{ApiVersion, API_VERSION_HEADER};
+use crate::server::{access_log, compression};
+use crate::utils::config::{self, Config};
+use crate::utils::error::{DBError, DatabaseResult, FieldError};
+use crate::utils::i18n::{self, Language};
+use crate::utils::jwt::{self, JwkSet};
+use crate::utils::password::{estimate_strength, PasswordPolicy};
+use crate::utils::telemetry::TRACEPARENT_HEADER;
+use crate::utils::{get_user_id_from_token, verify_encoded_token};
 use serde::de::DeserializeOwned;
 
 macro_rules! require_permission {
-    ($database:expr,$request:expr,$permission:expr) => {
-        let (_token, id) = validate_request_token($request, $database)?;
-        if !$database.users.has_permission(id, $permission)? {
-            return Err(HTTPError::new("Insufficient permissions".to_string(), 403));
+    ($context:expr,$permission:expr) => {
+        if !$context.has_permission($permission) {
+            return Err(HTTPError::from_code(
+                i18n::ERR_INSUFFICIENT_PERMISSIONS,
+                403,
+            ));
         }
     };
 }
 
-const LISTEN_ADDRESS: &str = "HTTP_SERVER_ADDRESS";
-const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:8080";
-const ENV_ENABLE_CORS: &str = "ENABLE_CORS";
+pub(crate) const LISTEN_ADDRESS: &str = "HTTP_SERVER_ADDRESS";
+pub(crate) const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:8080";
+pub(crate) const ENV_ENABLE_METRICS: &str = "ENABLE_METRICS";
+pub(crate) const ENV_ENABLE_REGISTRATION: &str = "ENABLE_REGISTRATION";
+pub(crate) const ENV_UNIX_SOCKET_MODE: &str = "HTTP_UNIX_SOCKET_MODE";
+pub(crate) const DEFAULT_UNIX_SOCKET_MODE: &str = "660";
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+pub(crate) const ENV_MAX_CONCURRENT_REQUESTS: &str = "HTTP_MAX_CONCURRENT_REQUESTS";
+pub(crate) const ENV_REQUEST_QUEUE_SIZE: &str = "HTTP_REQUEST_QUEUE_SIZE";
+pub(crate) const ENV_REQUEST_QUEUE_TIMEOUT: &str = "HTTP_REQUEST_QUEUE_TIMEOUT_MS";
+pub(crate) const DEFAULT_REQUEST_QUEUE_SIZE: usize = 32;
+pub(crate) const DEFAULT_REQUEST_QUEUE_TIMEOUT: u64 = 2000;
+pub(crate) const ENV_CLIENT_CERT_HEADER: &str = "CLIENT_CERT_HEADER";
+pub(crate) const ENV_CLIENT_CERT_PROXIES: &str = "CLIENT_CERT_TRUSTED_PROXIES";
+pub(crate) const DEFAULT_CLIENT_CERT_PROXIES: &str = "127.0.0.1,::1";
+pub(crate) const ENV_TRUSTED_PROXIES: &str = "HTTP_TRUSTED_PROXIES";
+pub(crate) const DEFAULT_TRUSTED_PROXIES: &str = "127.0.0.1,::1";
+const RETRY_AFTER_SECONDS: u32 = 1;
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// The routes that use the HTTP method for the action with the
+/// POST route they behave like. The POST routes are kept for compatibility.
+const REST_ROUTES: &[(&str, &str, &str)] = &[
+    ("POST", "/oauth/clients", "/oauth/clients/create"),
+    (
+        "DELETE",
+        "/oauth/clients/{client_id:String}",
+        "/oauth/clients/{client_id:String}/delete",
+    ),
+    ("POST", "/webhooks", "/webhooks/create"),
+    ("DELETE", "/webhooks/{id:i32}", "/webhooks/{id:i32}/delete"),
+    ("POST", "/roles", "/roles/create"),
+    ("PUT", "/roles/{name:String}", "/roles/{name:String}/update"),
+    (
+        "DELETE",
+        "/roles/{name:String}",
+        "/roles/{name:String}/delete",
+    ),
+    (
+        "PUT",
+        "/users/{email:String}/roles",
+        "/users/{email:String}/roles",
+    ),
+    ("POST", "/users", "/users/create"),
+    (
+        "PUT",
+        "/users/{email:String}",
+        "/users/{email:String}/update",
+    ),
+    (
+        "DELETE",
+        "/users/{email:String}",
+        "/users/{email:String}/delete",
+    ),
+    (
+        "POST",
+        "/users/{email:String}/certificates",
+        "/users/{email:String}/certificates/create",
+    ),
+    (
+        "DELETE",
+        "/users/{email:String}/certificates/{fingerprint:String}",
+        "/users/{email:String}/certificates/{fingerprint:String}/delete",
+    ),
+    (
+        "POST",
+        "/users/{email:String}/api-keys",
+        "/users/{email:String}/api-keys/create",
+    ),
+    (
+        "DELETE",
+        "/users/{email:String}/api-keys/{id:i32}",
+        "/users/{email:String}/api-keys/{id:i32}/delete",
+    ),
+    ("POST", "/user-fields", "/user-fields/create"),
+    (
+        "PUT",
+        "/user-fields/{name:String}",
+        "/user-fields/{name:String}/update",
+    ),
+    (
+        "DELETE",
+        "/user-fields/{name:String}",
+        "/user-fields/{name:String}/delete",
+    ),
+    ("POST", "/organizations", "/organizations/create"),
+    (
+        "DELETE",
+        "/organizations/{name:String}",
+        "/organizations/{name:String}/delete",
+    ),
+    (
+        "PUT",
+        "/organizations/{name:String}/members/{email:String}",
+        "/organizations/{name:String}/members/{email:String}",
+    ),
+    (
+        "DELETE",
+        "/organizations/{name:String}/members/{email:String}",
+        "/organizations/{name:String}/members/{email:String}/remove",
+    ),
+];
 
 /// The HTTP server of the user management that provides a
 /// REST api for login and requesting tokens
 pub struct UserHttpServer {
     database: Database,
+    listen_address: ListenAddress,
+}
+
+/// Handles the requests of a http server by applying quotas,
+/// concurrency limits and CORS headers and routing them to the api.
+/// It is independent of the server implementation that receives the requests.
+pub struct RequestHandler {
+    database: Database,
+    limiter: ConcurrencyLimiter,
+    quotas: QuotaLimiter,
+}
+
+impl RequestHandler {
+    pub fn new(database: &Database) -> Self {
+        Self {
+            database: Database::clone(database),
+            limiter: UserHttpServer::create_limiter(),
+            quotas: QuotaLimiter::new(),
+        }
+    }
+
+    /// Returns the number of threads needed to handle
+    /// all requests that can be active or queued at once
+    pub fn pool_size(&self) -> usize {
+        self.limiter.capacity() + num_cpus::get()
+    }
+
+    /// Handles a single request and returns the response
+    pub fn handle(&self, request: &Request) -> Response {
+        let started = Instant::now();
+        let request_id = access_log::request_id(request.header("X-Request-Id"));
+        let language = Language::from_accept_language(request.header("Accept-Language"));
+        let negotiated = ApiVersion::negotiate(request);
+        // the request without the version prefix that is routed
+        let (version, routed) = match &negotiated {
+            Ok((version, Some(unprefixed))) => (Ok(*version), unprefixed),
+            Ok((version, None)) => (Ok(*version), request),
+            Err(version) => (Err(version), request),
+        };
+        let _span = crate::telemetry_span!(
+            parent: request.header(TRACEPARENT_HEADER),
+            "http_request",
+            http.method = request.method(),
+            http.target = routed.url(),
+            request_id = request_id,
+        );
+        let quota = UserHttpServer::check_quota(&self.database, &self.quotas, request);
+        let mut user_id = None;
+        let mut response = i18n::with_language(language, || {
+            let version = match version {
+                Ok(version) => version,
+                Err(version) => {
+                    return HTTPError::from_code_with_args(
+                        i18n::ERR_UNSUPPORTED_API_VERSION,
+                        404,
+                        &[("version", version)],
+                    )
+                    .into_response()
+                }
+            };
+            if let Some(response) = UserHttpServer::probe(&self.database, routed) {
+                response
+            } else if let Err(retry_after) = UserHttpServer::check_rate_limit(routed) {
+                HTTPError::rate_limited(retry_after).into_response()
+            } else if let QuotaCheck::Exceeded(status) = &quota {
+                let reset = status.reset.to_string();
+                HTTPError::from_code_with_args(i18n::ERR_QUOTA_EXCEEDED, 429, &[("reset", &reset)])
+                    .into_response()
+                    .with_additional_header("Retry-After", reset)
+            } else if let Some(_permit) = self.limiter.acquire() {
+                access_log::with_request_id(&request_id, || {
+                    let response = change_history::with_actor_scope(|| {
+                        change_history::set_request_id(&request_id);
+                        UserHttpServer::route(&self.database, routed, version)
+                    });
+                    user_id = access_log::current_user_id();
+                    response
+                })
+            } else {
+                HTTPError::from_code(i18n::ERR_TOO_MANY_REQUESTS, 503)
+                    .into_response()
+                    .with_additional_header("Retry-After", RETRY_AFTER_SECONDS.to_string())
+            }
+        });
+        match quota {
+            QuotaCheck::Allowed(status) | QuotaCheck::Exceeded(status) => {
+                response = status.add_headers(response)
+            }
+            QuotaCheck::Unlimited => {}
+        }
+        if let Ok(version) = version {
+            response =
+                response.with_additional_header(API_VERSION_HEADER, version.number().to_string());
+        }
+
+        response = compression::compress_response(routed, response);
+        response = CORS.add_headers(request, response);
+        access_log::log_http(
+            &request_id,
+            request.method(),
+            &request.url(),
+            response.status_code,
+            started,
+            user_id,
+        );
+
+        response.with_additional_header("X-Request-Id", request_id)
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct HTTPError {
     message: String,
+    code: &'static str,
     error_code: u16,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldErrorEntry>,
+    /// The number of seconds after which the request can be retried
+    #[serde(skip)]
+    retry_after: Option<u64>,
+}
+
+/// A localized validation failure of a single field
+#[derive(Debug, Serialize)]
+struct FieldErrorEntry {
+    field: String,
+    code: &'static str,
+    message: String,
+}
+
+impl FieldErrorEntry {
+    fn from_errors(errors: &[FieldError], language: Language) -> Vec<Self> {
+        errors
+            .iter()
+            .map(|e| Self {
+                field: e.field().clone(),
+                code: e.code(),
+                message: e.message(language),
+            })
+            .collect()
+    }
 }
 
 impl Display for HTTPError {
@@ -63,34 +356,197 @@ impl Error for HTTPError {}
 
 impl From<DBError> for HTTPError {
     fn from(other: DBError) -> Self {
+        let language = i18n::current_language();
+        let errors = match &other {
+            DBError::ValidationError(errors) => FieldErrorEntry::from_errors(errors, language),
+            _ => Vec::new(),
+        };
+        let error_code = match &other {
+            DBError::LimitExceeded(_, _) | DBError::SystemRecord(_) => 403,
+            DBError::HashingBusy => 503,
+            _ => 400,
+        };
+        let retry_after = match &other {
+            DBError::HashingBusy => Some(RETRY_AFTER_SECONDS as u64),
+            _ => None,
+        };
+
         Self {
-            message: other.to_string(),
-            error_code: 400,
+            message: other.message(language),
+            code: other.code(),
+            error_code,
+            errors,
+            retry_after,
         }
     }
 }
 
 impl Into<Response> for HTTPError {
     fn into(self) -> Response {
-        Response::json(&self).with_status_code(self.error_code)
+        let response = json_response(&self).with_status_code(self.error_code);
+        match self.retry_after {
+            Some(seconds) => response.with_additional_header("Retry-After", seconds.to_string()),
+            None => response,
+        }
     }
 }
 
 impl HTTPError {
+    /// Creates an error with a message that isn't part of the message catalog
     pub fn new(message: String, code: u16) -> Self {
         Self {
             message,
+            code: Self::status_code_name(code),
             error_code: code,
+            errors: Vec::new(),
+            retry_after: None,
+        }
+    }
+
+    /// Creates an error with the localized message of the given error code
+    pub fn from_code(code: &'static str, status: u16) -> Self {
+        Self::from_code_with_args(code, status, &[])
+    }
+
+    /// Creates an error with the localized message of the given error code
+    /// and the placeholders replaced by the given arguments
+    pub fn from_code_with_args(code: &'static str, status: u16, args: &[(&str, &str)]) -> Self {
+        Self {
+            message: i18n::message(code, i18n::current_language(), args)
+                .unwrap_or(code.to_string()),
+            code,
+            error_code: status,
+            errors: Vec::new(),
+            retry_after: None,
+        }
+    }
+
+    /// Creates an error for request data that doesn't match the expected schema
+    pub fn invalid_fields(errors: Vec<FieldError>) -> Self {
+        let language = i18n::current_language();
+        let summary = errors
+            .iter()
+            .map(|e| e.message(language))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let mut error =
+            Self::from_code_with_args(i18n::ERR_INVALID_FIELDS, 400, &[("errors", &summary)]);
+        error.errors = FieldErrorEntry::from_errors(&errors, language);
+
+        error
+    }
+
+    /// Creates an error for request data that can't be parsed
+    pub fn invalid_request_data<E: Display>(error: E) -> Self {
+        Self::from_code_with_args(
+            i18n::ERR_INVALID_REQUEST_DATA,
+            400,
+            &[("detail", &error.to_string())],
+        )
+    }
+
+    /// Creates an error for a client that exceeded the rate limit
+    pub fn rate_limited(retry_after: u64) -> Self {
+        let mut error = Self::from_code(i18n::ERR_TOO_MANY_REQUESTS, 429);
+        error.retry_after = Some(retry_after);
+
+        error
+    }
+
+    pub fn into_response(self) -> Response {
+        self.into()
+    }
+
+    /// Returns the error code used for errors without a specific one
+    fn status_code_name(status: u16) -> &'static str {
+        match status {
+            400 => i18n::ERR_BAD_REQUEST,
+            401 => i18n::ERR_UNAUTHORIZED,
+            403 => i18n::ERR_FORBIDDEN,
+            404 => i18n::ERR_NOT_FOUND,
+            503 => i18n::ERR_SERVICE_UNAVAILABLE,
+            _ => i18n::ERR_INTERNAL,
         }
     }
 }
 
 type HTTPResult<T> = Result<T, HTTPError>;
 
+lazy_static::lazy_static! {static ref BEARER_REGEX: Regex = Regex::new(r"^[bB]earer\s+").unwrap();}
+lazy_static::lazy_static! {static ref DOCS: RESTDocumentation = UserHttpServer::build_docs().unwrap();}
+
+lazy_static::lazy_static! {
+    static ref CLIENT_CERT_HEADER: Option<String> = config::var(ENV_CLIENT_CERT_HEADER).ok();
+    static ref CLIENT_CERT_PROXIES: Vec<IpAddr> = config::var(ENV_CLIENT_CERT_PROXIES)
+        .unwrap_or(DEFAULT_CLIENT_CERT_PROXIES.to_string())
+        .split(',')
+        .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+        .collect();
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = config::var(ENV_TRUSTED_PROXIES)
+        .unwrap_or(DEFAULT_TRUSTED_PROXIES.to_string())
+        .split(',')
+        .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+        .collect();
+}
+
+/// The address the HTTP server listens on. Addresses in the form
+/// unix:/path/to/socket bind a unix domain socket.
+#[derive(Clone, Debug)]
+pub enum ListenAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    pub fn parse(address: &str) -> Self {
+        match address.strip_prefix(UNIX_SOCKET_PREFIX) {
+            Some(path) => ListenAddress::Unix(PathBuf::from(path)),
+            None => ListenAddress::Tcp(address.to_string()),
+        }
+    }
+
+    /// Removes a socket file that was left behind by a previous run
+    /// so that the socket can be bound again
+    pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            )),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Sets the permissions of the socket to the mode configured in the env
+    pub fn set_socket_permissions(path: &Path) -> io::Result<()> {
+        let mode =
+            config::var(ENV_UNIX_SOCKET_MODE).unwrap_or(DEFAULT_UNIX_SOCKET_MODE.to_string());
+        let mode = u32::from_str_radix(&mode, 8).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid {} '{}'", ENV_UNIX_SOCKET_MODE, mode),
+            )
+        })?;
+
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{}", address),
+            ListenAddress::Unix(path) => write!(f, "{}{}", UNIX_SOCKET_PREFIX, path.display()),
+        }
+    }
+}
+
 impl UserHttpServer {
-    pub fn new(database: &Database) -> Self {
+    pub fn new(database: &Database, config: &Config) -> Self {
         Self {
             database: Database::clone(database),
+            listen_address: ListenAddress::parse(&config.http.listen_address),
         }
     }
 
@@ -98,173 +554,979 @@ impl UserHttpServer {
     /// This call blocks until the server is shut down.
     pub fn start(&self) {
         log::info!("Starting HTTP-Server...");
-        let listen_address =
-            dotenv::var(LISTEN_ADDRESS).unwrap_or(DEFAULT_LISTEN_ADDRESS.to_string());
-        let database = Database::clone(&self.database);
-        let server = Server::new(&listen_address, move |request| {
-            let mut response = router!(request,
-                (GET) (/info) => {
-                    Self::info(request).unwrap_or_else(HTTPError::into)
-                },
-                (POST) (/login) => {
-                    Self::login(&database, request).unwrap_or_else(HTTPError::into)
-                },
-                (POST) (/new-token) => {
-                    Self::new_token(&database, request).unwrap_or_else(HTTPError::into)
-                },
-                (POST) (/logout) => {
-                    Self::logout(&database, request).unwrap_or_else(HTTPError::into)
-                },
-                (GET) (/roles/{name: String}) => {
-                    Self::get_role(&database, request, name).unwrap_or_else(HTTPError::into)
-                },
-                (GET) (/roles) => {
-                    Self::get_roles(&database, request).unwrap_or_else(HTTPError::into)
-                },
-                (POST) (/roles/create) => {
-                    Self::create_role(&database, request).unwrap_or_else(HTTPError::into)
-                },
-                (POST) (/roles/{name:String}/update) => {
-                    Self::update_role(&database, request, name).unwrap_or_else(HTTPError::into)
-                },
-                (POST) (/roles/{name: String}/delete) => {
-                    Self::delete_role(&database, request, name).unwrap_or_else(HTTPError::into)
-                },
-                (GET) (/users/{email: String}) => {
-                    Self::get_user(&database, request, email).unwrap_or_else(HTTPError::into)
-                },
-                (GET) (/users/{email: String}/permissions) => {
-                    Self::get_user_permissions(&database, request, email).unwrap_or_else(HTTPError::into)
-                },
-                (GET) (/users) => {
-                    Self::get_users(&database, request).unwrap_or_else(HTTPError::into)
-                },
-                (POST) (/users/create) => {
-                    Self::create_user(&database, request).unwrap_or_else(HTTPError::into)
-                },
-                (POST) (/users/{email: String}/update) => {
-                    Self::update_user(&database, request, email).unwrap_or_else(HTTPError::into)
-                },
-                (POST) (/users/{email: String}/delete) => {
-                    Self::delete_user(&database, request, email).unwrap_or_else(HTTPError::into)
-                },
-                _ => if request.method() == "OPTIONS" {
-                    Response::empty_204()
-                } else {
-                    Response::empty_404()
-                }
-            );
+        let listen_address = match self.listen_address.clone() {
+            ListenAddress::Tcp(address) => address,
+            ListenAddress::Unix(path) => panic!(
+                "Can't listen on {}. Unix sockets are only supported by the hyper-server build",
+                path.display()
+            ),
+        };
+        let handler = RequestHandler::new(&self.database);
+        let pool_size = handler.pool_size();
+        let server = Server::new(&listen_address, move |request| handler.handle(request))
+            .unwrap()
+            .pool_size(pool_size);
+        log::info!("HTTP-Server running on {}", listen_address);
+        server.run()
+    }
 
-            if dotenv::var(ENV_ENABLE_CORS).unwrap_or("false".to_string()) == "true" {
-                response = response
-                    .with_additional_header("Access-Control-Allow-Origin", "*")
-                    .with_additional_header(
-                        "Access-Control-Allow-Methods",
-                        "GET,HEAD,PUT,PATCH,POST,DELETE",
-                    )
-                    .with_additional_header("Vary", "Access-Control-Request-Headers");
+    /// Routes the request to the handlers of the requested api version
+    fn route(database: &Database, request: &Request, version: ApiVersion) -> Response {
+        match version {
+            ApiVersion::V1 => Self::route_v1(database, request),
+        }
+    }
 
-                if let Some(request_headers) = request.header("Access-Control-Request-Headers") {
-                    response = response.with_additional_header(
-                        "Access-Control-Allow-Headers",
-                        request_headers.to_string(),
-                    );
-                }
+    /// Routes the request to the corresponding handler of the first api version
+    fn route_v1(database: &Database, request: &Request) -> Response {
+        if request.method() == "GET" && request.url() == "/.well-known/openid-configuration" {
+            return json_response(&Self::openid_configuration());
+        }
+        router!(request,
+            (GET) (/docs) => {
+                Response::html(DOCS.swagger_ui("/openapi.json"))
+            },
+            (GET) (/info) => {
+                Response::redirect_301("/docs")
+            },
+            (GET) (/openapi.json) => {
+                Response::json(&DOCS.openapi(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")))
+            },
+            (GET) (/metrics) => {
+                Self::metrics(database).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/version) => {
+                json_response(&BuildInfo::get())
+            },
+            (GET) (/health) => {
+                Self::health(database).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/jwks) => {
+                json_response(&jwt::jwks())
+            },
+            (GET) (/config) => {
+                Self::config(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/permissions/sync) => {
+                Self::sync_permissions(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/login) => {
+                Self::login(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/register) => {
+                Self::register(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/new-token) => {
+                Self::new_token(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/change-password) => {
+                Self::change_password(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/session/heartbeat) => {
+                Self::session_heartbeat(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/guest-token) => {
+                Self::guest_token(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/oauth/clients) => {
+                Self::get_oauth_clients(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/oauth/clients) => {
+                Self::create_oauth_client(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (DELETE) (/oauth/clients/{client_id: String}) => {
+                Self::delete_oauth_client(database, request, client_id).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/oauth/clients/create) => {
+                Self::create_oauth_client(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/oauth/clients/{client_id: String}) => {
+                Self::get_oauth_client(database, request, client_id).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/oauth/clients/{client_id: String}/delete) => {
+                Self::delete_oauth_client(database, request, client_id).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/webhooks) => {
+                Self::get_webhooks(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/webhooks) => {
+                Self::create_webhook(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (DELETE) (/webhooks/{id: i32}) => {
+                Self::delete_webhook(database, request, id).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/webhooks/create) => {
+                Self::create_webhook(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/webhooks/{id: i32}/delete) => {
+                Self::delete_webhook(database, request, id).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/webhooks/{id: i32}/deliveries) => {
+                Self::get_webhook_deliveries(database, request, id).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/oauth/authorize) => {
+                Self::oauth_authorize(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/oauth/authorize) => {
+                Self::oauth_consent(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/oauth/token) => {
+                Self::oauth_token(database, request)
+            },
+            (GET) (/userinfo) => {
+                Self::userinfo(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/oauth/consents) => {
+                Self::get_oauth_consents(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/oauth/consents/{client_id: String}/revoke) => {
+                Self::revoke_oauth_consent(database, request, client_id).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/logout) => {
+                Self::logout(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/roles/export) => {
+                Self::export_roles(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/roles/import) => {
+                Self::import_roles(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/roles/trash) => {
+                Self::get_trashed_roles(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/roles/{name: String}) => {
+                Self::get_role(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/roles) => {
+                Self::get_roles(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/roles) => {
+                Self::create_role(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (PUT) (/roles/{name: String}) => {
+                Self::update_role(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (DELETE) (/roles/{name: String}) => {
+                Self::delete_role(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/roles/create) => {
+                Self::create_role(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/roles/{name: String}/users) => {
+                Self::get_role_users(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/roles/{name: String}/history) => {
+                Self::get_role_history(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/roles/{name:String}/update) => {
+                Self::update_role(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/roles/{name: String}/delete) => {
+                Self::delete_role(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/roles/{name: String}/restore) => {
+                Self::restore_role(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/roles/{name: String}/enable) => {
+                Self::set_role_enabled(database, request, name, true).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/roles/{name: String}/disable) => {
+                Self::set_role_enabled(database, request, name, false).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/pending) => {
+                Self::get_pending_users(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/trash) => {
+                Self::get_trashed_users(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/export) => {
+                Self::export_users(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/restore) => {
+                Self::restore_user(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/pending/{email: String}/approve) => {
+                Self::approve_user(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/pending/{email: String}/reject) => {
+                Self::reject_user(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/{email: String}) => {
+                Self::get_user(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/{email: String}/history) => {
+                Self::get_user_history(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/{email: String}/attributes) => {
+                Self::get_user_attributes(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (PATCH) (/users/{email: String}/attributes) => {
+                Self::update_user_attributes(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/{email: String}/roles) => {
+                Self::get_user_roles(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (PUT) (/users/{email: String}/roles) => {
+                Self::update_user_roles(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/roles) => {
+                Self::update_user_roles(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/me/permissions) => {
+                Self::get_own_permissions(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/me/sessions) => {
+                Self::get_own_sessions(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/{email: String}/sessions) => {
+                Self::get_user_sessions(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/{email: String}/permissions) => {
+                Self::get_user_permissions(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users) => {
+                Self::get_users(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users) => {
+                Self::create_user(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (PUT) (/users/{email: String}) => {
+                Self::update_user(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (DELETE) (/users/{email: String}) => {
+                Self::delete_user(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/create) => {
+                Self::create_user(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/invite) => {
+                Self::invite_user(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/invitations/{token: String}/accept) => {
+                Self::accept_invitation(database, request, token).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/update) => {
+                Self::update_user(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/delete) => {
+                Self::delete_user(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/enable) => {
+                Self::set_user_active(database, request, email, true).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/disable) => {
+                Self::set_user_active(database, request, email, false).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/impersonate) => {
+                Self::impersonate_user(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/verify) => {
+                Self::verify_email(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/merge) => {
+                Self::merge_users(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/{email: String}/certificates) => {
+                Self::get_client_certificates(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/certificates) => {
+                Self::add_client_certificate(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (DELETE) (/users/{email: String}/certificates/{fingerprint: String}) => {
+                Self::delete_client_certificate(database, request, email, fingerprint).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/certificates/create) => {
+                Self::add_client_certificate(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/certificates/{fingerprint: String}/delete) => {
+                Self::delete_client_certificate(database, request, email, fingerprint).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/users/{email: String}/api-keys) => {
+                Self::get_api_keys(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/api-keys) => {
+                Self::create_api_key(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (DELETE) (/users/{email: String}/api-keys/{id: i32}) => {
+                Self::delete_api_key(database, request, email, id).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/api-keys/create) => {
+                Self::create_api_key(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/api-keys/{id: i32}/delete) => {
+                Self::delete_api_key(database, request, email, id).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/email-changes/{token: String}/confirm) => {
+                Self::confirm_email_change(database, token).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/email-changes/{token: String}/undo) => {
+                Self::undo_email_change(database, token).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/password-strength) => {
+                Self::password_strength(request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/admin/rotate) => {
+                Self::rotate_admin(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/users/{email: String}/quota) => {
+                Self::set_request_quota(database, request, email).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/user-fields) => {
+                Self::get_user_fields(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/user-fields) => {
+                Self::create_user_field(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (PUT) (/user-fields/{name: String}) => {
+                Self::update_user_field(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (DELETE) (/user-fields/{name: String}) => {
+                Self::delete_user_field(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/user-fields/create) => {
+                Self::create_user_field(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/user-fields/{name: String}/update) => {
+                Self::update_user_field(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/user-fields/{name: String}/delete) => {
+                Self::delete_user_field(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/organizations) => {
+                Self::get_organizations(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/organizations) => {
+                Self::create_organization(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (DELETE) (/organizations/{name: String}) => {
+                Self::delete_organization(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (PUT) (/organizations/{name: String}/members/{email: String}) => {
+                Self::set_organization_member(database, request, name, email).unwrap_or_else(HTTPError::into)
+            },
+            (DELETE) (/organizations/{name: String}/members/{email: String}) => {
+                Self::remove_organization_member(database, request, name, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/organizations/create) => {
+                Self::create_organization(database, request).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/organizations/{name: String}) => {
+                Self::get_organization(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/organizations/{name: String}/delete) => {
+                Self::delete_organization(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (GET) (/organizations/{name: String}/permissions) => {
+                Self::get_organization_permissions(database, request, name).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/organizations/{name: String}/members/{email: String}) => {
+                Self::set_organization_member(database, request, name, email).unwrap_or_else(HTTPError::into)
+            },
+            (POST) (/organizations/{name: String}/members/{email: String}/remove) => {
+                Self::remove_organization_member(database, request, name, email).unwrap_or_else(HTTPError::into)
+            },
+            _ => if request.method() == "OPTIONS" {
+                Response::empty_204()
+            } else {
+                Response::empty_404()
             }
+        )
+    }
 
-            response
-        })
-        .unwrap();
-        log::info!("HTTP-Server running on {}", listen_address);
-        server.run()
+    /// Counts the request against the quota of the account the request token belongs to.
+    /// Only tokens with a valid signature are counted so that forged tokens
+    /// can't exhaust the quota of other accounts.
+    fn check_quota(database: &Database, quotas: &QuotaLimiter, request: &Request) -> QuotaCheck {
+        let user_id = request
+            .header("authorization")
+            .map(|token| BEARER_REGEX.replace(token, "").to_string())
+            .filter(verify_encoded_token)
+            .and_then(|token| get_user_id_from_token(&token));
+
+        if let Some(id) = user_id {
+            quotas.check(id, || {
+                database
+                    .users
+                    .get_request_quota(id)
+                    .ok()
+                    .flatten()
+                    .map(|quota| quota.max(0) as u32)
+            })
+        } else {
+            QuotaCheck::Unlimited
+        }
+    }
+
+    /// Applies the rate limit per client address to the endpoints that
+    /// can be used to guess credentials
+    fn check_rate_limit(request: &Request) -> Result<(), u64> {
+        if request.method() == "POST" && RATE_LIMITED_PATHS.contains(&request.url().as_str()) {
+            IP_RATE_LIMITER.check(&client_ip(request).to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Creates the limiter for concurrently processed requests from the env configuration
+    fn create_limiter() -> ConcurrencyLimiter {
+        let max_active = config::var(ENV_MAX_CONCURRENT_REQUESTS)
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(num_cpus::get() * 4);
+        let max_queued = config::var(ENV_REQUEST_QUEUE_SIZE)
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_REQUEST_QUEUE_SIZE);
+        let queue_timeout = config::var(ENV_REQUEST_QUEUE_TIMEOUT)
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_REQUEST_QUEUE_TIMEOUT);
+        log::debug!(
+            "Processing at most {} requests concurrently with a queue of {}",
+            max_active,
+            max_queued
+        );
+
+        ConcurrencyLimiter::new(max_active, max_queued, Duration::from_millis(queue_timeout))
     }
 
     fn build_docs() -> Result<RESTDocumentation, serde_json::Error> {
-        let mut doc = RESTDocumentation::new("/info");
+        let mut doc = RESTDocumentation::new();
+        doc.add_path::<(), BuildInfo>(
+            "/version",
+            "GET",
+            "Returns the version, git commit, build time and features of the server",
+        )?;
+        doc.add_path::<(), serde_json::Value>(
+            "/openapi.json",
+            "GET",
+            "Returns an OpenAPI 3.0 specification of the api that clients can be generated from",
+        )?;
+        doc.add_path::<(), HealthResponse>(
+            "/health",
+            "GET",
+            "Returns if the database is reachable and statistics about the connection pool",
+        )?;
+        doc.add_path::<(), ReadinessResponse>(
+            "/ready",
+            "GET",
+            "Returns 200 if the database is reachable, its tables are initialized and the RPC server is listening. Returns 503 otherwise",
+        )?;
+        doc.add_path::<(), LivenessResponse>(
+            "/live",
+            "GET",
+            "Returns 200 as long as the server is responsive. Returns 503 if the RPC workers are stuck. The database is not checked",
+        )?;
+        doc.add_path::<(), JwkSet>(
+            "/jwks",
+            "GET",
+            "Returns the public keys to verify the JWTs that are issued with the session tokens",
+        )?;
+        doc.add_path::<(), OpenIdConfiguration>(
+            "/.well-known/openid-configuration",
+            "GET",
+            "Returns the OpenID Connect discovery document of the server",
+        )?;
+        doc.add_path::<(), EffectiveConfig>(
+            "/config",
+            "GET",
+            "Returns the resolved configuration of the server with secrets masked",
+        )?;
+        doc.add_path::<SyncPermissionsRequest, PermissionSync>(
+            "/permissions/sync",
+            "POST",
+            "Creates and updates the permissions of a namespace and reports permissions that aren't part of the set",
+        )?;
         doc.add_path::<LoginRequest, LoginResponse>(
             "/login",
             "POST",
             "Returns request and refresh tokens",
         )?;
+        doc.add_path::<RegisterRequest, UserInformation>(
+            "/register",
+            "POST",
+            "Creates a user that can log in after an administrator approved it. Only available if ENABLE_REGISTRATION is set to true",
+        )?;
         doc.add_path::<RefreshMessage, SessionTokens>(
             "/new-token",
             "POST",
             "Returns a new request token",
         )?;
-        doc.add_path::<LogoutMessage, LogoutConfirmation>(
-            "/logout",
+        doc.add_path::<ChangePasswordRequest, SessionTokens>(
+            "/change-password",
             "POST",
-            "Invalidates the refresh and request tokens",
+            "Changes the password of the user, revokes all sessions and returns new tokens. This is the only endpoint tokens of users that must change their password can be used for",
         )?;
-        doc.add_path::<(), FullRoleData>(
-            "/roles/{name:String}",
+        doc.add_path::<(), HeartbeatResponse>(
+            "/session/heartbeat",
+            "POST",
+            "Extends the ttl of the request token without rotating the tokens and returns the remaining ttls",
+        )?;
+        doc.add_path::<GuestTokenRequest, GuestToken>(
+            "/guest-token",
+            "POST",
+            "Returns a short-lived anonymous token that only grants whitelisted permissions",
+        )?;
+        doc.add_path::<(), Vec<OAuthClient>>(
+            "/oauth/clients",
             "GET",
-            "Returns the role with the given name",
+            "Returns all client applications that can request access to user accounts",
         )?;
-        doc.add_path::<(), Vec<Role>>("/roles", "GET", "Returns a list of all roles")?;
-        doc.add_path::<ModifyRoleRequest, FullRoleData>(
-            "/roles/create",
+        doc.add_path::<CreateOAuthClientRequest, CreateOAuthClientResponse>(
+            "/oauth/clients/create",
             "POST",
-            "Creates a new role",
+            "Registers a client application. The secret is only returned once",
         )?;
-        doc.add_path::<ModifyRoleRequest, FullRoleData>(
-            "/roles/{name:String}/update",
+        doc.add_path::<(), OAuthClient>(
+            "/oauth/clients/{client_id:String}",
+            "GET",
+            "Returns a client application",
+        )?;
+        doc.add_path::<(), DeleteOAuthClientResponse>(
+            "/oauth/clients/{client_id:String}/delete",
             "POST",
-            "Updates an existing role",
+            "Deletes a client application and revokes all of its sessions",
         )?;
-        doc.add_path::<(), DeleteRoleResponse>(
-            "/roles/{name:String}/delete",
+        doc.add_path::<(), Vec<Webhook>>("/webhooks", "GET", "Returns all registered webhooks")?;
+        doc.add_path::<CreateWebhookRequest, CreateWebhookResponse>(
+            "/webhooks/create",
             "POST",
-            "Deletes a role",
+            "Registers a url that is notified about user events. The secret the requests are signed with is only returned once",
         )?;
-        doc.add_path::<UpdateUserRequest, UserInformation>(
-            "/users/{email:String}/update",
+        doc.add_path::<(), DeleteWebhookResponse>(
+            "/webhooks/{id:i32}/delete",
             "POST",
-            "Change user information",
+            "Deletes a webhook together with its delivery log",
         )?;
-        doc.add_path::<(), UserFullInformation>(
+        doc.add_path::<(), Vec<WebhookDelivery>>(
+            "/webhooks/{id:i32}/deliveries",
+            "GET",
+            "Returns the latest deliveries of a webhook with their status",
+        )?;
+        doc.add_path::<(), AuthorizeResponse>(
+            "/oauth/authorize?response_type=code&client_id&redirect_uri&scope&state",
+            "GET",
+            "Starts the authorization code flow. Returns the redirect to the client if the user already consented, otherwise the data for the consent screen",
+        )?;
+        doc.add_path::<AuthorizeRequest, AuthorizeResponse>(
+            "/oauth/authorize",
+            "POST",
+            "Records the decision of the consent screen and returns the redirect to the client",
+        )?;
+        doc.add_path::<OAuthTokenRequest, OAuthTokenResponse>(
+            "/oauth/token",
+            "POST",
+            "Exchanges an authorization code for tokens. Accepts form encoded and json bodies and client credentials via basic auth. Includes a signed id token if the openid scope was granted",
+        )?;
+        doc.add_path::<(), UserInfoResponse>(
+            "/userinfo",
+            "GET",
+            "Returns the OpenID Connect claims about the user of the token",
+        )?;
+        doc.add_path::<(), Vec<OAuthConsent>>(
+            "/oauth/consents",
+            "GET",
+            "Returns the client applications the user granted access to",
+        )?;
+        doc.add_path::<(), RevokeConsentResponse>(
+            "/oauth/consents/{client_id:String}/revoke",
+            "POST",
+            "Revokes the access of a client application to the account",
+        )?;
+        doc.add_path::<LogoutMessage, LogoutConfirmation>(
+            "/logout",
+            "POST",
+            "Invalidates the refresh and request tokens",
+        )?;
+        doc.add_path::<(), FullRoleData>(
+            "/roles/{name:String}",
+            "GET",
+            "Returns the role with the given name",
+        )?;
+        doc.add_path::<(), Page<Role>>(
+            "/roles",
+            "GET",
+            "Returns a page of roles. Use the limit and offset query parameters to page through them and sort=id|name and order=asc|desc to sort them",
+        )?;
+        doc.add_path::<(), ExportRolesResponse>(
+            "/roles/export",
+            "GET",
+            "Returns all roles except the admin role with the names of their permissions",
+        )?;
+        doc.add_path::<ImportRolesRequest, RoleImport>(
+            "/roles/import",
+            "POST",
+            "Imports exported roles. Existing roles are skipped, overwritten or imported with a new name",
+        )?;
+        doc.add_path::<ModifyRoleRequest, FullRoleData>(
+            "/roles/create",
+            "POST",
+            "Creates a new role",
+        )?;
+        doc.add_path::<ModifyRoleRequest, FullRoleData>(
+            "/roles/{name:String}/update",
+            "POST",
+            "Updates an existing role",
+        )?;
+        doc.add_path::<(), DeleteRoleResponse>(
+            "/roles/{name:String}/delete",
+            "POST",
+            "Moves a role to the trash. Its permissions aren't granted until it's restored",
+        )?;
+        doc.add_path::<(), Vec<TrashedRole>>(
+            "/roles/trash",
+            "GET",
+            "Returns the roles in the trash with the time they are purged at",
+        )?;
+        doc.add_path::<(), FullRoleData>(
+            "/roles/{name:String}/restore",
+            "POST",
+            "Restores a role from the trash together with its assignments",
+        )?;
+        doc.add_path::<(), Vec<UserInformation>>(
+            "/roles/{name:String}/users",
+            "GET",
+            "Returns all users that are assigned to the role",
+        )?;
+        doc.add_path::<(), Vec<ChangeHistoryEntry>>(
+            "/roles/{name:String}/history",
+            "GET",
+            "Returns all recorded changes of a role starting with the latest one",
+        )?;
+        doc.add_path::<(), FullRoleData>(
+            "/roles/{name:String}/enable",
+            "POST",
+            "Enables a role so that its permissions are granted to its members again",
+        )?;
+        doc.add_path::<(), FullRoleData>(
+            "/roles/{name:String}/disable",
+            "POST",
+            "Disables a role without removing its members or permissions",
+        )?;
+        doc.add_path::<UpdateUserRequest, UserInformation>(
+            "/users/{email:String}/update",
+            "POST",
+            "Change user information",
+        )?;
+        doc.add_path::<(), UserFullInformation>(
             "/users/{email:String}",
             "GET",
             "See user information",
         )?;
-        doc.add_path::<(), Vec<UserFullInformation>>(
+        doc.add_path::<(), Page<UserFullInformation>>(
             "/users",
             "GET",
-            "Returns information for all users",
+            "Returns information for a page of users. Use the limit and offset query parameters to page through them. The users can be filtered with the search, role and email_domain query parameters and sorted with sort=id|name|email and order=asc|desc. inactive_since=<RFC 3339 time> only returns users that haven't logged in or made a request since then",
         )?;
         doc.add_path::<CreateUserRequest, UserInformation>(
             "/users/create",
             "POST",
             "Creates a new user",
         )?;
+        doc.add_path::<InviteUserRequest, UserInformation>(
+            "/users/invite",
+            "POST",
+            "Creates a user that can't log in until it accepted the invitation. The invitation token is sent to the user with the invitation_created event",
+        )?;
+        doc.add_path::<AcceptInvitationRequest, UserInformation>(
+            "/invitations/{token:String}/accept",
+            "POST",
+            "Sets the password of an invited user with the token sent to it. Invitations expire after 7 days",
+        )?;
         doc.add_path::<DeleteUserRequest, DeleteUserResponse>(
             "/users/{email:String}/delete",
             "POST",
-            "Deletes a user",
+            "Moves a user to the trash. The user can't log in until it's restored",
+        )?;
+        doc.add_path::<(), Vec<UserExport>>(
+            "/users/export",
+            "GET",
+            "Exports all users with their roles and attributes. Use format=json|csv to choose the format. The export is streamed as a download",
+        )?;
+        doc.add_path::<(), Vec<TrashedUser>>(
+            "/users/trash",
+            "GET",
+            "Returns the users in the trash with the time they are purged at",
+        )?;
+        doc.add_path::<(), UserInformation>(
+            "/users/{email:String}/restore",
+            "POST",
+            "Restores a user from the trash together with its roles",
+        )?;
+        doc.add_path::<(), Vec<ChangeHistoryEntry>>(
+            "/users/{email:String}/history",
+            "GET",
+            "Returns all recorded changes of a user starting with the latest one",
+        )?;
+        doc.add_path::<MergeUsersRequest, MergeUsersResponse>(
+            "/users/{email:String}/merge",
+            "POST",
+            "Merges a duplicate account into the user. Use dry_run to preview the result",
+        )?;
+        doc.add_path::<(), Vec<ClientCertificate>>(
+            "/users/{email:String}/certificates",
+            "GET",
+            "Returns the client certificates the user can authenticate with",
+        )?;
+        doc.add_path::<AddClientCertificateRequest, ClientCertificate>(
+            "/users/{email:String}/certificates/create",
+            "POST",
+            "Maps the SHA-256 fingerprint of a client certificate to the service account",
+        )?;
+        doc.add_path::<(), DeleteClientCertificateResponse>(
+            "/users/{email:String}/certificates/{fingerprint:String}/delete",
+            "POST",
+            "Removes a client certificate of the user",
+        )?;
+        doc.add_path::<(), Vec<ApiKey>>(
+            "/users/{email:String}/api-keys",
+            "GET",
+            "Returns the api keys of the service account",
+        )?;
+        doc.add_path::<CreateApiKeyRequest, CreateApiKeyResponse>(
+            "/users/{email:String}/api-keys/create",
+            "POST",
+            "Creates an api key for the service account that is limited to the given permissions. The key is sent in the X-Api-Key header",
+        )?;
+        doc.add_path::<(), DeleteApiKeyResponse>(
+            "/users/{email:String}/api-keys/{id:i32}/delete",
+            "POST",
+            "Deletes an api key of the service account",
+        )?;
+        doc.add_path::<(), Value>(
+            "/users/{email:String}/attributes",
+            "GET",
+            "Returns the attributes of the user. Private attributes are only returned to the user and administrators",
+        )?;
+        doc.add_path::<UpdateUserAttributesRequest, Value>(
+            "/users/{email:String}/attributes",
+            "PATCH",
+            "Adds, replaces or removes (with null) single attributes of the user and returns all attributes",
+        )?;
+        doc.add_path::<(), Vec<Role>>(
+            "/users/{email:String}/roles",
+            "GET",
+            "Returns the roles of the user",
+        )?;
+        doc.add_path::<UpdateUserRolesRequest, Vec<Role>>(
+            "/users/{email:String}/roles",
+            "POST",
+            "Replaces the roles of the user and returns the new roles",
         )?;
         doc.add_path::<(), Vec<Permission>>(
             "/users/{email:String}/permissions",
             "GET",
             "Returns a list of permissions the user was granted",
         )?;
+        doc.add_path::<(), Vec<Permission>>(
+            "/me/permissions",
+            "GET",
+            "Returns the permissions the token of the request can be used for",
+        )?;
+        doc.add_path::<(), Vec<SessionInfo>>(
+            "/me/sessions",
+            "GET",
+            "Returns the active sessions of the user of the request",
+        )?;
+        doc.add_path::<(), Vec<SessionInfo>>(
+            "/users/{email:String}/sessions",
+            "GET",
+            "Returns the active sessions of a user",
+        )?;
+        doc.add_path::<(), Vec<UserInformation>>(
+            "/users/pending",
+            "GET",
+            "Returns all users that are waiting for approval",
+        )?;
+        doc.add_path::<(), UserInformation>(
+            "/users/pending/{email:String}/approve",
+            "POST",
+            "Approves a pending user so that it can log in",
+        )?;
+        doc.add_path::<(), RejectUserResponse>(
+            "/users/pending/{email:String}/reject",
+            "POST",
+            "Rejects and deletes a pending user",
+        )?;
+        doc.add_path::<(), SetUserActiveResponse>(
+            "/users/{email:String}/enable",
+            "POST",
+            "Enables a disabled user so that it can log in again",
+        )?;
+        doc.add_path::<(), SetUserActiveResponse>(
+            "/users/{email:String}/disable",
+            "POST",
+            "Disables a user without deleting it and revokes all of its sessions",
+        )?;
+        doc.add_path::<(), ImpersonationToken>(
+            "/users/{email:String}/impersonate",
+            "POST",
+            "Returns a request token that acts as the user until it expires. It can't be refreshed and users with permissions the own user doesn't have can't be impersonated",
+        )?;
+        doc.add_path::<VerifyEmailRequest, UserInformation>(
+            "/users/{email:String}/verify",
+            "POST",
+            "Verifies the email address of a new user with the token sent to it",
+        )?;
+        doc.add_path::<(), EmailChangeResponse>(
+            "/email-changes/{token:String}/confirm",
+            "POST",
+            "Confirms a requested email change with the token sent to the new address",
+        )?;
+        doc.add_path::<(), EmailChangeResponse>(
+            "/email-changes/{token:String}/undo",
+            "POST",
+            "Undoes an email change with the token sent to the old address and revokes all sessions",
+        )?;
+        doc.add_path::<PasswordStrengthRequest, PasswordStrengthResponse>(
+            "/password-strength",
+            "POST",
+            "Estimates the strength of a password and returns if it satisfies the password policy",
+        )?;
+        doc.add_path::<RotateAdminRequest, RotateAdminResponse>(
+            "/admin/rotate",
+            "POST",
+            "Resets the password of the admin user and revokes all of its sessions",
+        )?;
+        doc.add_path::<SetRequestQuotaRequest, SetRequestQuotaResponse>(
+            "/users/{email:String}/quota",
+            "POST",
+            "Sets the number of requests per minute the user is allowed to make",
+        )?;
+        doc.add_path::<(), Vec<UserFieldDefinition>>(
+            "/user-fields",
+            "GET",
+            "Returns the definitions of all custom user fields",
+        )?;
+        doc.add_path::<ModifyUserFieldRequest, UserFieldDefinition>(
+            "/user-fields/create",
+            "POST",
+            "Creates a new custom user field",
+        )?;
+        doc.add_path::<ModifyUserFieldRequest, UserFieldDefinition>(
+            "/user-fields/{name:String}/update",
+            "POST",
+            "Updates a custom user field",
+        )?;
+        doc.add_path::<(), DeleteUserFieldResponse>(
+            "/user-fields/{name:String}/delete",
+            "POST",
+            "Deletes a custom user field. Stored values are kept.",
+        )?;
+        doc.add_path::<(), Vec<Organization>>(
+            "/organizations",
+            "GET",
+            "Returns a list of all organizations and teams",
+        )?;
+        doc.add_path::<CreateOrganizationRequest, FullOrganizationData>(
+            "/organizations/create",
+            "POST",
+            "Creates a new organization or a team of the given parent",
+        )?;
+        doc.add_path::<(), FullOrganizationData>(
+            "/organizations/{name:String}",
+            "GET",
+            "Returns the organization with its teams and members",
+        )?;
+        doc.add_path::<(), DeleteOrganizationResponse>(
+            "/organizations/{name:String}/delete",
+            "POST",
+            "Deletes an organization with all of its teams",
+        )?;
+        doc.add_path::<(), Vec<String>>(
+            "/organizations/{name:String}/permissions",
+            "GET",
+            "Returns the permissions the user has inside the organization",
+        )?;
+        doc.add_path::<SetOrganizationMemberRequest, FullOrganizationData>(
+            "/organizations/{name:String}/members/{email:String}",
+            "POST",
+            "Adds a member to the organization or replaces the roles of the member",
+        )?;
+        doc.add_path::<(), RemoveOrganizationMemberResponse>(
+            "/organizations/{name:String}/members/{email:String}/remove",
+            "POST",
+            "Removes a member from the organization",
+        )?;
+
+        for (method, path, original_path) in REST_ROUTES {
+            doc.add_alias(path, method, original_path, "POST");
+        }
 
         Ok(doc)
     }
 
-    fn info(request: &Request) -> HTTPResult<Response> {
-        lazy_static::lazy_static! {static ref DOCS: RESTDocumentation = UserHttpServer::build_docs().unwrap();}
+    /// Returns metrics about sessions and the database pool in the prometheus format
+    fn metrics(database: &Database) -> HTTPResult<Response> {
+        if config::var(ENV_ENABLE_METRICS).unwrap_or("false".to_string()) != "true" {
+            return Ok(Response::empty_404());
+        }
 
-        Ok(Response::html(
-            DOCS.get(request.get_param("path").unwrap_or("/".to_string())),
+        Ok(Response::from_data(
+            "text/plain; version=0.0.4",
+            metrics::collect_metrics(database)?,
         ))
     }
 
+    /// Returns if the database is reachable together with statistics about the connection pool
+    /// Answers the readiness and liveness probes. They are answered before
+    /// the concurrency limit is applied so that a busy server isn't restarted.
+    fn probe(database: &Database, request: &Request) -> Option<Response> {
+        if request.method() != "GET" {
+            return None;
+        }
+        match request.url().as_str() {
+            "/ready" => {
+                let readiness = READINESS.readiness(database);
+                let status = if readiness.ready { 200 } else { 503 };
+                Some(json_response(&readiness).with_status_code(status))
+            }
+            "/live" => {
+                let liveness = READINESS.liveness();
+                let status = if liveness.live { 200 } else { 503 };
+                Some(json_response(&liveness).with_status_code(status))
+            }
+            _ => None,
+        }
+    }
+
+    fn health(database: &Database) -> HTTPResult<Response> {
+        let healthy = database.is_healthy();
+        let response = HealthResponse {
+            healthy,
+            pool: PoolHealth::from(database.pool_state()),
+        };
+
+        Ok(json_response(&response).with_status_code(if healthy { 200 } else { 503 }))
+    }
+
+    /// Returns the configuration the server is running with
+    fn config(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, CONFIG_VIEW_PERM);
+
+        Ok(json_response(&EffectiveConfig::resolve(database)))
+    }
+
+    /// Reconciles the permissions of a service namespace with the given set
+    fn sync_permissions(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, PERMISSION_MANAGE_PERM);
+        let message = deserialize_body::<SyncPermissionsRequest>(request)?;
+        let sync = database
+            .permissions
+            .sync_permissions(&message.namespace, &message.permissions)?;
+
+        Ok(json_response(&sync))
+    }
+
     /// Handles the login part of the REST api
     fn login(database: &Database, request: &Request) -> HTTPResult<Response> {
-        let mut login_request: LoginRequest =
-            serde_json::from_str(parse_string_body(request)?.as_str())
-                .map_err(|e| HTTPError::new(e.to_string(), 400))?;
+        let mut login_request = deserialize_body::<LoginRequest>(request)?;
         login_request.email.make_ascii_lowercase();
+        ACCOUNT_RATE_LIMITER
+            .check(&login_request.email)
+            .map_err(HTTPError::rate_limited)?;
 
         let tokens = database
             .users
@@ -273,181 +1535,875 @@ impl UserHttpServer {
             .users
             .get_user(get_user_id_from_token(&tokens.request_token).unwrap())?;
         let roles = database.user_roles.by_user(user.id)?;
+        let must_change_password = database.users.must_change_password(user.id)?;
 
-        Ok(Response::json(&LoginResponse {
+        Ok(json_response(&LoginResponse {
             request_token: tokens.request_token.clone(),
             refresh_token: tokens.refresh_token.clone(),
             request_ttl: tokens.request_ttl,
             refresh_ttl: tokens.refresh_ttl,
             user: UserFullInformation {
+                expires_at: database.users.get_expiry(user.id)?,
+                active: database.users.is_active(user.id)?,
+                last_login_at: user.last_login_at,
+                last_seen_at: user.last_seen_at,
                 id: user.id,
                 name: user.name,
                 email: user.email,
                 attributes: user.attributes,
                 roles,
             },
+            must_change_password,
         })
         .with_status_code(201))
     }
 
-    /// Handles the new token part of the rest api
-    fn new_token(database: &Database, request: &Request) -> HTTPResult<Response> {
-        let message: RefreshMessage = serde_json::from_str(parse_string_body(request)?.as_str())
-            .map_err(|e| HTTPError::new(e.to_string(), 400))?;
+    /// Issues an anonymous token with a subset of the permissions
+    /// that are whitelisted for guests
+    fn guest_token(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let message = if request.header("Content-Length") == Some("0") {
+            GuestTokenRequest { permissions: None }
+        } else {
+            deserialize_body::<GuestTokenRequest>(request)?
+        };
+        let token = database
+            .users
+            .create_guest_token(message.permissions)
+            .map_err(|e| HTTPError::new(e.to_string(), 403))?;
 
-        let tokens = database.users.refresh_tokens(&message.refresh_token)?;
+        Ok(json_response(&token).with_status_code(201))
+    }
 
-        Ok(Response::json(&tokens))
+    /// Returns all registered client applications
+    fn get_oauth_clients(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, OAUTH_CLIENT_MANAGE_PERM);
+        let clients = database.oauth_clients.get_clients()?;
+
+        Ok(json_response(&clients))
     }
 
-    fn logout(database: &Database, request: &Request) -> HTTPResult<Response> {
-        let message: LogoutMessage = serde_json::from_str(parse_string_body(request)?.as_str())
-            .map_err(|e| HTTPError::new(e.to_string(), 400))?;
-        let success = database.users.delete_tokens(&message.request_token)?;
+    /// Registers a new client application
+    fn create_oauth_client(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, OAUTH_CLIENT_MANAGE_PERM);
+        let message = deserialize_body::<CreateOAuthClientRequest>(request)?;
+        let (client, client_secret) = database.oauth_clients.create_client(
+            message.name,
+            message.redirect_uris,
+            message.scopes,
+        )?;
 
-        Ok(Response::json(&LogoutConfirmation { success }).with_status_code(205))
+        Ok(json_response(&CreateOAuthClientResponse {
+            client,
+            client_secret,
+        })
+        .with_status_code(201))
     }
 
-    /// Returns the data for a given role
-    fn get_role(database: &Database, request: &Request, name: String) -> HTTPResult<Response> {
-        require_permission!(database, request, ROLE_VIEW_PERM);
-        let role = database.roles.get_role(name)?;
-        let permissions = database.role_permission.by_role(role.id)?;
+    /// Returns a single client application
+    fn get_oauth_client(
+        database: &Database,
+        request: &Request,
+        client_id: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, OAUTH_CLIENT_MANAGE_PERM);
+        let client = database.oauth_clients.get_client(&client_id)?;
 
-        Ok(Response::json(&FullRoleData {
-            id: role.id,
-            name: role.name,
-            permissions,
+        Ok(json_response(&client))
+    }
+
+    /// Deletes a client application and invalidates all sessions that were issued to it
+    fn delete_oauth_client(
+        database: &Database,
+        request: &Request,
+        client_id: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, OAUTH_CLIENT_MANAGE_PERM);
+        database.oauth_clients.delete_client(&client_id)?;
+        database.users.revoke_client_sessions(None, &client_id)?;
+
+        Ok(json_response(&DeleteOAuthClientResponse {
+            success: true,
+            client_id,
         }))
     }
 
-    /// Returns a list of all roles
-    fn get_roles(database: &Database, request: &Request) -> HTTPResult<Response> {
-        require_permission!(database, request, ROLE_VIEW_PERM);
-        let roles = database.roles.get_roles()?;
+    /// Returns all registered webhooks
+    fn get_webhooks(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, WEBHOOK_MANAGE_PERM);
+        let webhooks = database.webhooks.get_webhooks()?;
 
-        Ok(Response::json(&roles))
+        Ok(json_response(&webhooks))
     }
 
-    /// Creates a new role with the given permissions
-    fn create_role(database: &Database, request: &Request) -> HTTPResult<Response> {
-        require_permission!(database, request, ROLE_CREATE_PERM);
-        let message: ModifyRoleRequest = serde_json::from_str(parse_string_body(request)?.as_str())
-            .map_err(|e| HTTPError::new(e.to_string(), 400))?;
-        let not_existing = database
-            .permissions
-            .get_not_existing(&message.permissions)?;
-        if !not_existing.is_empty() {
-            return Ok(Response::json(&ErrorMessage::new(format!(
-                "The permissions {:?} don't exist",
-                not_existing
-            )))
-            .with_status_code(400));
-        }
-        let role =
-            database
-                .roles
-                .create_role(message.name, message.description, message.permissions)?;
-        let permissions = database.role_permission.by_role(role.id)?;
+    /// Registers a url that is notified about user events
+    fn create_webhook(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, WEBHOOK_MANAGE_PERM);
+        let message = deserialize_body::<CreateWebhookRequest>(request)?;
+        let (webhook, secret) = database
+            .webhooks
+            .create_webhook(message.url, message.events)?;
 
-        Ok(Response::json(&FullRoleData {
-            id: role.id,
-            permissions,
-            name: role.name,
-        })
-        .with_status_code(201))
+        Ok(json_response(&CreateWebhookResponse { webhook, secret }).with_no_cache())
     }
 
-    /// Updates information for a single role
-    fn update_role(database: &Database, request: &Request, name: String) -> HTTPResult<Response> {
-        require_permission!(database, request, ROLE_UPDATE_PERM);
-        let message: ModifyRoleRequest = deserialize_body(&request)?;
+    /// Deletes a webhook
+    fn delete_webhook(database: &Database, request: &Request, id: i32) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, WEBHOOK_MANAGE_PERM);
+        database.webhooks.delete_webhook(id)?;
 
-        let not_existing = database
-            .permissions
-            .get_not_existing(&message.permissions)?;
-        if !not_existing.is_empty() {
-            return Ok(Response::json(&ErrorMessage::new(format!(
-                "The permissions {:?} don't exist",
-                not_existing
-            )))
-            .with_status_code(400));
-        }
-        let role = database.roles.update_role(
-            name,
-            message.name,
-            message.description,
-            message.permissions,
-        )?;
-        let permissions = database.role_permission.by_role(role.id)?;
+        Ok(json_response(&DeleteWebhookResponse { success: true, id }))
+    }
 
-        Ok(Response::json(&FullRoleData {
-            id: role.id,
-            permissions,
-            name: role.name,
+    /// Returns the delivery log of a webhook
+    fn get_webhook_deliveries(
+        database: &Database,
+        request: &Request,
+        id: i32,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, WEBHOOK_MANAGE_PERM);
+        let deliveries = database.webhooks.get_deliveries(id)?;
+
+        Ok(json_response(&deliveries))
+    }
+
+    /// Handles the authorization request a client application redirected the user with.
+    /// If the user already consented to the requested scopes a code is issued right away.
+    fn oauth_authorize(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        context.require_user_session()?;
+        if request.get_param("response_type").as_deref() != Some("code") {
+            return Err(HTTPError::new(
+                "Only the response type code is supported".to_string(),
+                400,
+            ));
+        }
+        let client_id = request
+            .get_param("client_id")
+            .ok_or(HTTPError::from_code(i18n::ERR_MISSING_REQUEST_DATA, 400))?;
+        let redirect_uri = request
+            .get_param("redirect_uri")
+            .ok_or(HTTPError::from_code(i18n::ERR_MISSING_REQUEST_DATA, 400))?;
+        let scopes = request
+            .get_param("scope")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<String>>();
+        let (client, scopes) =
+            Self::resolve_authorization(database, &client_id, &redirect_uri, scopes)?;
+        let redirect_to =
+            if database
+                .oauth_clients
+                .has_consent(context.user.id, &client_id, &scopes)?
+            {
+                let code = database.oauth_clients.create_code(
+                    context.user.id,
+                    &client_id,
+                    &redirect_uri,
+                    &scopes,
+                    request.get_param("nonce").as_ref(),
+                )?;
+                Some(oauth_redirect(
+                    &redirect_uri,
+                    &[
+                        ("code", Some(&code)),
+                        ("state", request.get_param("state").as_ref()),
+                    ],
+                ))
+            } else {
+                None
+            };
+
+        Ok(json_response(&AuthorizeResponse {
+            consent_required: redirect_to.is_none(),
+            client_name: client.name,
+            scopes,
+            redirect_to,
         }))
     }
 
-    /// Deletes a role from the database
-    fn delete_role(database: &Database, request: &Request, role: String) -> HTTPResult<Response> {
-        require_permission!(database, request, ROLE_DELETE_PERM);
-        database.roles.delete_role(&role)?;
+    /// Records the decision of the user on the consent screen
+    /// and returns the redirect to the client application
+    fn oauth_consent(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        context.require_user_session()?;
+        let message = deserialize_body::<AuthorizeRequest>(request)?;
+        let (client, scopes) = Self::resolve_authorization(
+            database,
+            &message.client_id,
+            &message.redirect_uri,
+            message.scopes,
+        )?;
+        let redirect_to = if message.approve {
+            database
+                .oauth_clients
+                .grant_consent(context.user.id, &message.client_id, &scopes)?;
+            let code = database.oauth_clients.create_code(
+                context.user.id,
+                &message.client_id,
+                &message.redirect_uri,
+                &scopes,
+                message.nonce.as_ref(),
+            )?;
+            oauth_redirect(
+                &message.redirect_uri,
+                &[("code", Some(&code)), ("state", message.state.as_ref())],
+            )
+        } else {
+            oauth_redirect(
+                &message.redirect_uri,
+                &[
+                    ("error", Some(&"access_denied".to_string())),
+                    ("state", message.state.as_ref()),
+                ],
+            )
+        };
 
-        Ok(Response::json(&DeleteRoleResponse {
-            success: true,
-            role,
+        Ok(json_response(&AuthorizeResponse {
+            consent_required: false,
+            client_name: client.name,
+            scopes,
+            redirect_to: Some(redirect_to),
         }))
     }
 
-    /// Returns information for a single user
+    /// Validates that the redirect uri is registered for the client and that the client
+    /// may request the scopes. If no scopes are requested all scopes of the client are used.
+    fn resolve_authorization(
+        database: &Database,
+        client_id: &String,
+        redirect_uri: &String,
+        scopes: Vec<String>,
+    ) -> HTTPResult<(OAuthClient, Vec<String>)> {
+        let client = database.oauth_clients.get_client(client_id)?;
+        if !client.redirect_uris.contains(redirect_uri) {
+            return Err(HTTPError::new(
+                "The redirect uri isn't registered for the client".to_string(),
+                400,
+            ));
+        }
+        let scopes = if scopes.is_empty() {
+            client.scopes.clone()
+        } else {
+            scopes
+        };
+        if let Some(scope) = scopes
+            .iter()
+            .find(|s| *s != OPENID_SCOPE && !client.scopes.contains(s))
+        {
+            return Err(HTTPError::new(
+                format!("The scope {} can't be requested by the client", scope),
+                400,
+            ));
+        }
+
+        Ok((client, scopes))
+    }
+
+    /// Exchanges an authorization code for tokens. The response and errors use
+    /// the format of RFC 6749 so that standard client libraries can handle them.
+    fn oauth_token(database: &Database, request: &Request) -> Response {
+        let message = match parse_oauth_token_request(request) {
+            Ok(message) => message,
+            Err(e) => return oauth_error("invalid_request", &e.to_string(), 400),
+        };
+        if message.grant_type != "authorization_code" {
+            return oauth_error(
+                "unsupported_grant_type",
+                "Only the authorization_code grant is supported",
+                400,
+            );
+        }
+        let (client_id, client_secret) = match (&message.client_id, &message.client_secret) {
+            (Some(id), Some(secret)) => (id, secret),
+            _ => return oauth_error("invalid_client", "Missing client credentials", 401),
+        };
+        // the attempts to guess a client secret are limited like logins
+        if let Err(retry_after) = ACCOUNT_RATE_LIMITER.check(&format!("client:{}", client_id)) {
+            return oauth_error("slow_down", "Too many requests", 429)
+                .with_additional_header("Retry-After", retry_after.to_string());
+        }
+        let grant = match database.oauth_clients.exchange_code(
+            client_id,
+            client_secret,
+            &message.code,
+            &message.redirect_uri,
+        ) {
+            Ok(grant) => grant,
+            Err(DBError::RecordDoesNotExist) => {
+                return oauth_error("invalid_grant", "Invalid authorization code", 400)
+            }
+            Err(DBError::GenericError(e)) => return oauth_error("invalid_client", &e, 401),
+            Err(e) => {
+                log::error!("Failed to exchange authorization code: {}", e);
+                return oauth_error("server_error", "Failed to exchange the code", 500);
+            }
+        };
+        let scope = grant.scopes.join(" ");
+        let openid = grant.scopes.iter().any(|s| s == OPENID_SCOPE);
+        let tokens =
+            match database
+                .users
+                .create_client_tokens(grant.user_id, client_id, grant.scopes)
+            {
+                Ok(tokens) => tokens,
+                Err(e) => return oauth_error("invalid_grant", &e.to_string(), 400),
+            };
+        let id_token = if openid {
+            match Self::create_id_token(database, grant.user_id, client_id, grant.nonce, &tokens) {
+                Ok(id_token) => id_token,
+                Err(e) => {
+                    log::error!("Failed to create the id token: {}", e);
+                    return oauth_error("server_error", "Failed to create the id token", 500);
+                }
+            }
+        } else {
+            None
+        };
+
+        Response::json(&OAuthTokenResponse {
+            access_token: tokens.request_token.clone(),
+            token_type: "Bearer".to_string(),
+            expires_in: tokens.request_ttl,
+            refresh_token: tokens.refresh_token.clone(),
+            refresh_expires_in: tokens.refresh_ttl,
+            scope,
+            id_token,
+        })
+        .with_no_cache()
+    }
+
+    /// Creates the OpenID Connect id token for the user of an authorization grant.
+    /// Returns None if no JWT signing key is configured.
+    fn create_id_token(
+        database: &Database,
+        user_id: i32,
+        client_id: &String,
+        nonce: Option<String>,
+        tokens: &SessionTokens,
+    ) -> DatabaseResult<Option<String>> {
+        if !jwt::is_enabled() {
+            return Ok(None);
+        }
+        let user = database.users.get_user(user_id)?;
+        let email_verified = database.users.is_email_verified(user_id)?;
+
+        Ok(jwt::create_id_token(
+            &user,
+            email_verified,
+            client_id,
+            nonce,
+            tokens.request_ttl,
+        )?)
+    }
+
+    /// Returns the claims about the user of the token.
+    /// Tokens of client applications need to be granted the openid scope.
+    fn userinfo(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        if let Some(scopes) = &context.scopes {
+            if !scopes.iter().any(|s| s == OPENID_SCOPE) {
+                return Err(HTTPError::from_code(
+                    i18n::ERR_INSUFFICIENT_PERMISSIONS,
+                    403,
+                ));
+            }
+        }
+        let roles = database
+            .user_roles
+            .by_user(context.user.id)?
+            .into_iter()
+            .map(|role| role.name)
+            .collect();
+
+        Ok(json_response(&UserInfoResponse {
+            sub: context.user.id.to_string(),
+            email_verified: database.users.is_email_verified(context.user.id)?,
+            name: context.user.name,
+            email: context.user.email,
+            roles,
+        }))
+    }
+
+    /// Returns the OpenID Connect discovery document. The issuer needs to be
+    /// configured as the public url of the server for the endpoints to be valid.
+    fn openid_configuration() -> OpenIdConfiguration {
+        let issuer = jwt::issuer().trim_end_matches('/');
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+
+        OpenIdConfiguration {
+            issuer: issuer.to_string(),
+            authorization_endpoint: format!("{}/oauth/authorize", issuer),
+            token_endpoint: format!("{}/oauth/token", issuer),
+            userinfo_endpoint: format!("{}/userinfo", issuer),
+            jwks_uri: format!("{}/jwks", issuer),
+            response_types_supported: strings(&["code"]),
+            grant_types_supported: strings(&["authorization_code"]),
+            subject_types_supported: strings(&["public"]),
+            id_token_signing_alg_values_supported: strings(&["RS256"]),
+            scopes_supported: strings(&[OPENID_SCOPE]),
+            token_endpoint_auth_methods_supported: strings(&[
+                "client_secret_basic",
+                "client_secret_post",
+            ]),
+            claims_supported: strings(&[
+                "sub",
+                "iss",
+                "aud",
+                "name",
+                "email",
+                "email_verified",
+                "nonce",
+            ]),
+        }
+    }
+
+    /// Returns the client applications the user granted access to
+    fn get_oauth_consents(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        context.require_user_session()?;
+        let consents = database.oauth_clients.get_consents(context.user.id)?;
+
+        Ok(json_response(&consents))
+    }
+
+    /// Revokes the consent of the user and invalidates
+    /// the sessions the client holds for the user
+    fn revoke_oauth_consent(
+        database: &Database,
+        request: &Request,
+        client_id: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        context.require_user_session()?;
+        database
+            .oauth_clients
+            .revoke_consent(context.user.id, &client_id)?;
+        database
+            .users
+            .revoke_client_sessions(Some(context.user.id), &client_id)?;
+
+        Ok(json_response(&RevokeConsentResponse {
+            success: true,
+            client_id,
+        }))
+    }
+
+    /// Handles the new token part of the rest api
+    fn new_token(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let message = deserialize_body::<RefreshMessage>(request)?;
+
+        let tokens = database.users.refresh_tokens(&message.refresh_token)?;
+
+        Ok(json_response(&tokens))
+    }
+
+    /// Changes the password of the user of the request. Users that have to change
+    /// their password get tokens that can only be used for this endpoint.
+    fn change_password(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve_with(request, database, true)?;
+        context.require_user_session()?;
+        if context.token.is_none() {
+            return Err(HTTPError::from_code(i18n::ERR_INVALID_TOKEN, 401));
+        }
+        let message = deserialize_body::<ChangePasswordRequest>(request)?;
+        check_own_password(database, &context, &message.old_password)?;
+        check_password_policy(
+            &message.new_password,
+            &[&context.user.name, &context.user.email],
+        )?;
+        database
+            .users
+            .change_password(&context.user.email, &message.new_password)?;
+        let tokens = database
+            .users
+            .create_tokens(&context.user.email, &message.new_password)?;
+
+        Ok(json_response(&tokens).with_status_code(201))
+    }
+
+    /// Keeps the session of the request token alive
+    fn session_heartbeat(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        let token = context
+            .token
+            .ok_or_else(|| HTTPError::from_code(i18n::ERR_INVALID_TOKEN, 401))?;
+        let (request_ttl, refresh_ttl) = database.users.extend_session(&token)?;
+
+        Ok(json_response(&HeartbeatResponse {
+            request_ttl,
+            refresh_ttl,
+        }))
+    }
+
+    fn logout(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let message = deserialize_body::<LogoutMessage>(request)?;
+        let success = database.users.delete_tokens(&message.request_token)?;
+
+        Ok(json_response(&LogoutConfirmation { success }).with_status_code(205))
+    }
+
+    /// Returns the data for a given role
+    fn get_role(database: &Database, request: &Request, name: String) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_VIEW_PERM);
+        let role = database.roles.get_role(name)?;
+        let permissions = database.role_permission.by_role(role.id)?;
+
+        Ok(json_response(&FullRoleData {
+            id: role.id,
+            name: role.name,
+            enabled: role.enabled,
+            permissions,
+        }))
+    }
+
+    /// Returns all roles with the names of their permissions
+    fn export_roles(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_VIEW_PERM);
+        let roles = database.roles.export_roles()?;
+
+        Ok(json_response(&ExportRolesResponse { roles }))
+    }
+
+    /// Imports exported roles and handles existing roles with the given strategy
+    fn import_roles(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_CREATE_PERM);
+        require_permission!(context, ROLE_UPDATE_PERM);
+        let message = deserialize_body::<ImportRolesRequest>(request)?;
+        let result = database
+            .roles
+            .import_roles(&message.roles, message.strategy)?;
+
+        Ok(json_response(&result))
+    }
+
+    /// Returns the users that are assigned to a role
+    fn get_role_users(
+        database: &Database,
+        request: &Request,
+        name: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_VIEW_PERM);
+        let show_private = context.has_permission(USER_UPDATE_PERM);
+        let role = database.roles.get_role(name)?;
+        let definitions = if show_private {
+            Vec::new()
+        } else {
+            database.user_fields.get_definitions()?
+        };
+        let mut users = Vec::new();
+
+        for mut user in database.user_roles.by_role(role.id)? {
+            if !show_private && user.id != context.user.id {
+                user.attributes = remove_private_fields(&definitions, user.attributes);
+            }
+            users.push(user);
+        }
+
+        Ok(json_response(&users))
+    }
+
+    /// Returns the change history of a role
+    fn get_role_history(
+        database: &Database,
+        request: &Request,
+        name: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_VIEW_PERM);
+        let role = database.roles.get_role(name)?;
+        let history = database.change_history.get_history(ENTITY_ROLE, role.id)?;
+
+        Ok(json_response(&history))
+    }
+
+    /// Returns a list of all roles
+    fn get_roles(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_VIEW_PERM);
+        let sorting = parse_sorting(request)?;
+        let pagination = parse_pagination(request)?;
+        let (roles, total) = database.roles.get_roles(&sorting, &pagination)?;
+
+        Ok(json_response(&Page::new(roles, total, &pagination)))
+    }
+
+    /// Creates a new role with the given permissions
+    fn create_role(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_CREATE_PERM);
+        let message = deserialize_body::<ModifyRoleRequest>(request)?;
+        let not_existing = database
+            .permissions
+            .get_not_existing(&message.permissions)?;
+        if !not_existing.is_empty() {
+            return Err(HTTPError::from_code_with_args(
+                i18n::ERR_PERMISSIONS_NOT_FOUND,
+                400,
+                &[("permissions", &format!("{:?}", not_existing))],
+            ));
+        }
+        let role =
+            database
+                .roles
+                .create_role(message.name, message.description, message.permissions)?;
+        let permissions = database.role_permission.by_role(role.id)?;
+
+        Ok(json_response(&FullRoleData {
+            id: role.id,
+            permissions,
+            name: role.name,
+            enabled: role.enabled,
+        })
+        .with_status_code(201))
+    }
+
+    /// Updates information for a single role
+    fn update_role(database: &Database, request: &Request, name: String) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_UPDATE_PERM);
+        let message: ModifyRoleRequest = deserialize_body(request)?;
+
+        let not_existing = database
+            .permissions
+            .get_not_existing(&message.permissions)?;
+        if !not_existing.is_empty() {
+            return Err(HTTPError::from_code_with_args(
+                i18n::ERR_PERMISSIONS_NOT_FOUND,
+                400,
+                &[("permissions", &format!("{:?}", not_existing))],
+            ));
+        }
+        let role = database.roles.update_role(
+            name,
+            message.name,
+            message.description,
+            message.permissions,
+        )?;
+        let permissions = database.role_permission.by_role(role.id)?;
+
+        Ok(json_response(&FullRoleData {
+            id: role.id,
+            permissions,
+            name: role.name,
+            enabled: role.enabled,
+        }))
+    }
+
+    /// Moves a role to the trash
+    fn delete_role(database: &Database, request: &Request, role: String) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_DELETE_PERM);
+        let purge_at = database.roles.delete_role(&role)?;
+
+        Ok(json_response(&DeleteRoleResponse {
+            success: true,
+            role,
+            purge_at,
+        }))
+    }
+
+    /// Returns all roles in the trash
+    fn get_trashed_roles(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_VIEW_PERM);
+        let roles = database.roles.get_trashed_roles()?;
+
+        Ok(json_response(&roles))
+    }
+
+    /// Restores a role from the trash
+    fn restore_role(database: &Database, request: &Request, name: String) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_DELETE_PERM);
+        let role = database.roles.restore_role(&name)?;
+        let permissions = database.role_permission.by_role(role.id)?;
+
+        Ok(json_response(&FullRoleData {
+            id: role.id,
+            name: role.name,
+            enabled: role.enabled,
+            permissions,
+        }))
+    }
+
+    /// Enables or disables a role
+    fn set_role_enabled(
+        database: &Database,
+        request: &Request,
+        name: String,
+        enabled: bool,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ROLE_UPDATE_PERM);
+        let role = database.roles.set_enabled(&name, enabled)?;
+        let permissions = database.role_permission.by_role(role.id)?;
+
+        Ok(json_response(&FullRoleData {
+            id: role.id,
+            name: role.name,
+            enabled: role.enabled,
+            permissions,
+        }))
+    }
+
+    /// Returns information for a single user
     fn get_user(database: &Database, request: &Request, mut email: String) -> HTTPResult<Response> {
         email.make_ascii_lowercase();
-        check_user_permission_or_self(request, database, &email, USER_VIEW_PERM)?;
+        let context = RequestContext::resolve(request, database)?;
+        check_user_permission_or_self(&context, &email, USER_VIEW_PERM)?;
         let user = database.users.get_user_by_email(&email)?;
         let roles = database.user_roles.by_user(user.id)?;
+        let attributes = if context.user.id == user.id || context.has_permission(USER_UPDATE_PERM) {
+            user.attributes
+        } else {
+            database.user_fields.filter_private(user.attributes)?
+        };
 
-        Ok(Response::json(&UserFullInformation {
+        Ok(json_response(&UserFullInformation {
+            expires_at: database.users.get_expiry(user.id)?,
+            active: database.users.is_active(user.id)?,
+            last_login_at: user.last_login_at,
+            last_seen_at: user.last_seen_at,
             id: user.id,
             name: user.name,
             email: user.email,
-            attributes: user.attributes,
+            attributes,
             roles,
         }))
     }
 
     /// Returns a list of all users
     fn get_users(database: &Database, request: &Request) -> HTTPResult<Response> {
-        require_permission!(database, request, USER_VIEW_PERM);
-        let users = database.users.get_users()?;
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_VIEW_PERM);
+        let show_private = context.has_permission(USER_UPDATE_PERM);
+        let pagination = parse_pagination(request)?;
+        let inactive_since = request
+            .get_param("inactive_since")
+            .filter(|s| !s.is_empty());
+        if let Some(inactive_since) = &inactive_since {
+            DateTime::parse_from_rfc3339(inactive_since)
+                .map_err(HTTPError::invalid_request_data)?;
+        }
+        let filter = UserFilter {
+            search: request.get_param("search").filter(|s| !s.is_empty()),
+            role: request.get_param("role").filter(|s| !s.is_empty()),
+            email_domain: request.get_param("email_domain").filter(|s| !s.is_empty()),
+            inactive_since,
+        };
+        let sorting = parse_sorting(request)?;
+        let (users, total) = database.users.get_users(&filter, &sorting, &pagination)?;
+        let definitions = if show_private {
+            Vec::new()
+        } else {
+            database.user_fields.get_definitions()?
+        };
         let mut full_information = Vec::new();
 
         for user in users {
             let roles = database.user_roles.by_user(user.id)?;
+            let attributes = if show_private || user.id == context.user.id {
+                user.attributes
+            } else {
+                remove_private_fields(&definitions, user.attributes)
+            };
             full_information.push(UserFullInformation {
+                expires_at: database.users.get_expiry(user.id)?,
+                active: database.users.is_active(user.id)?,
+                last_login_at: user.last_login_at,
+                last_seen_at: user.last_seen_at,
                 id: user.id,
                 name: user.name,
-                attributes: user.attributes,
+                attributes,
                 email: user.email,
                 roles,
             });
         }
 
-        Ok(Response::json(&full_information))
+        Ok(json_response(&Page::new(
+            full_information,
+            total,
+            &pagination,
+        )))
     }
 
     /// Creates a new user
     fn create_user(database: &Database, request: &Request) -> HTTPResult<Response> {
-        require_permission!(database, request, USER_CREATE_PERM);
-        let mut message = deserialize_body::<CreateUserRequest>(&request)?;
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_CREATE_PERM);
+        let mut message = deserialize_body::<CreateUserRequest>(request)?;
         message.email.make_ascii_lowercase();
+        check_password_policy(&message.password, &[&message.name, &message.email])?;
+        database
+            .user_fields
+            .validate_attributes(&message.attributes)?;
+        let expires_at = parse_expiry(&message.expires_at)?;
         let result = database.users.create_user(
             message.name.clone(),
             message.email.clone(),
             message.password.clone(),
             message.attributes.clone(),
         )?;
+        if expires_at.is_some() {
+            database.users.set_expiry(&result.email, expires_at)?;
+        }
+        if message.service_account {
+            database.users.set_service_account(&result.email, true)?;
+        }
+
+        Ok(json_response(&UserInformation::from(result)).with_status_code(201))
+    }
+
+    /// Invites a new user that sets its own password
+    fn invite_user(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_CREATE_PERM);
+        let mut message = deserialize_body::<InviteUserRequest>(request)?;
+        message.email.make_ascii_lowercase();
+        database
+            .user_fields
+            .validate_attributes(&message.attributes)?;
+        let expires_at = parse_expiry(&message.expires_at)?;
+        let result = database.users.invite_user(
+            message.name,
+            message.email,
+            message.attributes,
+            &context.user.email,
+        )?;
+        if expires_at.is_some() {
+            database.users.set_expiry(&result.email, expires_at)?;
+        }
 
-        Ok(Response::json(&UserInformation::from(result)).with_status_code(201))
+        Ok(json_response(&UserInformation::from(result)).with_status_code(201))
+    }
+
+    /// Sets the password of an invited user with the token of the invitation
+    fn accept_invitation(
+        database: &Database,
+        request: &Request,
+        token: String,
+    ) -> HTTPResult<Response> {
+        let message = deserialize_body::<AcceptInvitationRequest>(request)?;
+        let user = database.users.get_invited_user(&token)?;
+        check_password_policy(&message.password, &[&user.name, &user.email])?;
+        let user = database
+            .users
+            .accept_invitation(&token, &message.password)?;
+
+        Ok(json_response(&user))
     }
 
     /// Updates the information of a user. This requires the operating user to revalidate his password
@@ -457,40 +2413,81 @@ impl UserHttpServer {
         mut email: String,
     ) -> HTTPResult<Response> {
         email.make_ascii_lowercase();
-        let logged_in_user =
-            check_user_permission_or_self(request, database, &email, USER_UPDATE_PERM)?;
-        let mut message = deserialize_body::<UpdateUserRequest>(&request)?;
+        let context = RequestContext::resolve(request, database)?;
+        check_user_permission_or_self(&context, &email, USER_UPDATE_PERM)?;
+        let mut message = deserialize_body::<UpdateUserRequest>(request)?;
+        // the permissions for all changes are checked before anything is written
+        let expires_at = match &message.expires_at {
+            Some(expires_at) => {
+                require_permission!(context, USER_UPDATE_PERM);
+                Some(parse_expiry(expires_at)?)
+            }
+            None => None,
+        };
+        if message.roles.is_some() {
+            require_permission!(context, USER_ROLES_UPDATE_PERM);
+        }
 
         if let Some(email) = message.email {
             message.email = Some(email.to_ascii_lowercase());
         }
 
-        if !database
-            .users
-            .validate_login(&logged_in_user.email, &message.own_password)?
-        {
-            return Err(HTTPError::new(
-                "Invalid authentication data".to_string(),
-                401,
-            ));
-        }
+        check_own_password(database, &context, &message.own_password)?;
 
         let user_record = database.users.get_user_by_email(&email)?;
+        if let Some(new_email) = &message.email {
+            // users changing their own email need to confirm the new address first
+            if new_email != &user_record.email && context.user.id == user_record.id {
+                database.email_changes.request_change(
+                    user_record.id,
+                    &user_record.email,
+                    new_email,
+                )?;
+                message.email = None;
+            }
+        }
+        if let Some(password) = &message.password {
+            check_password_policy(
+                password,
+                &[
+                    message.name.as_ref().unwrap_or(&user_record.name),
+                    message.email.as_ref().unwrap_or(&user_record.email),
+                ],
+            )?;
+        }
+        if let Some(attributes) = &message.attributes {
+            database.user_fields.validate_attributes(attributes)?;
+        }
+        let (password, reset_password) = if context.user.id == user_record.id {
+            (message.password.clone(), None)
+        } else {
+            // passwords set by administrators need to be changed by the user
+            (None, message.password.clone())
+        };
         let record = database.users.update_user(
             &email,
             &message.name.clone().unwrap_or(user_record.name),
             &message.email.clone().unwrap_or(user_record.email),
             &message.attributes.clone().unwrap_or(user_record.attributes),
-            &message.password,
+            &password,
         )?;
+        if let Some(password) = &reset_password {
+            database.users.reset_password(&record.email, password)?;
+        }
+        if let Some(expires_at) = expires_at {
+            database.users.set_expiry(&record.email, expires_at)?;
+        }
         let roles = if let Some(roles) = &message.roles {
-            require_permission!(database, request, USER_UPDATE_PERM);
             database.user_roles.update_roles(record.id, roles.clone())?
         } else {
             database.user_roles.by_user(record.id)?
         };
 
-        Ok(Response::json(&UserFullInformation {
+        Ok(json_response(&UserFullInformation {
+            expires_at: database.users.get_expiry(record.id)?,
+            active: database.users.is_active(record.id)?,
+            last_login_at: record.last_login_at,
+            last_seen_at: record.last_seen_at,
             id: record.id,
             email: record.email,
             name: record.name,
@@ -499,99 +2496,1248 @@ impl UserHttpServer {
         }))
     }
 
-    /// Deletes a user completely
+    /// Moves a user to the trash
     fn delete_user(
         database: &Database,
         request: &Request,
         mut email: String,
     ) -> HTTPResult<Response> {
         email.make_ascii_lowercase();
-        let logged_in_user =
-            check_user_permission_or_self(request, database, &email, USER_DELETE_PERM)?;
+        let context = RequestContext::resolve(request, database)?;
+        check_user_permission_or_self(&context, &email, USER_DELETE_PERM)?;
         let message = deserialize_body::<DeleteUserRequest>(request)?;
 
-        if !database
-            .users
-            .validate_login(&logged_in_user.email, &message.own_password)?
-        {
-            return Err(HTTPError::new(
-                "Invalid authentication data".to_string(),
-                401,
-            ));
-        }
+        check_own_password(database, &context, &message.own_password)?;
 
-        database.users.delete_user(&email)?;
+        let purge_at = database.users.delete_user(&email)?;
 
-        Ok(Response::json(&DeleteUserResponse {
+        Ok(json_response(&DeleteUserResponse {
             success: true,
             email,
+            purge_at,
         }))
     }
 
-    /// Returns a list of permissions the user has
-    fn get_user_permissions(
+    /// Issues a short-lived token that acts as the user so that support staff
+    /// can reproduce problems the user reports. Only users whose permissions
+    /// are a subset of the own permissions can be impersonated.
+    fn impersonate_user(
         database: &Database,
         request: &Request,
         mut email: String,
     ) -> HTTPResult<Response> {
         email.make_ascii_lowercase();
-        check_user_permission_or_self(request, database, &email, USER_VIEW_PERM)?;
-        let permissions = database.users.get_permissions(&email)?;
+        let context = RequestContext::resolve(request, database)?;
+        context.require_user_session()?;
+        require_permission!(context, USER_IMPERSONATE_PERM);
+        let user = database.users.get_user_by_email(&email)?;
+        if user.id == context.user.id {
+            return Err(HTTPError::invalid_request_data(
+                "Users can't impersonate themselves",
+            ));
+        }
+        let permissions = database.users.get_permission_names(user.id)?;
+        if !permissions.iter().all(|p| context.has_permission(p)) {
+            return Err(HTTPError::from_code(
+                i18n::ERR_INSUFFICIENT_PERMISSIONS,
+                403,
+            ));
+        }
+        let token = database
+            .users
+            .create_impersonation_token(&user, context.user.id)?;
+        log::info!(
+            "User {} ({}) started impersonating user {} ({})",
+            context.user.id,
+            context.user.email,
+            user.id,
+            user.email
+        );
+
+        Ok(json_response(&token).with_no_cache())
+    }
+
+    /// Enables or disables a user
+    fn set_user_active(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+        active: bool,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_UPDATE_PERM);
+        let revoked_sessions = database.users.set_active(&email, active)?;
 
-        Ok(Response::json(&permissions))
+        Ok(json_response(&SetUserActiveResponse {
+            email,
+            active,
+            revoked_sessions,
+        }))
     }
-}
+
+    /// Exports all users. Private attributes are only exported
+    /// for users that are allowed to update users.
+    fn export_users(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_VIEW_PERM);
+        let format = match request.get_param("format") {
+            Some(format) => {
+                ExportFormat::from_str(&format).map_err(HTTPError::invalid_request_data)?
+            }
+            None => ExportFormat::default(),
+        };
+        let hidden_attributes = if context.has_permission(USER_UPDATE_PERM) {
+            Vec::new()
+        } else {
+            database
+                .user_fields
+                .get_definitions()?
+                .into_iter()
+                .filter(|definition| definition.visibility == FieldVisibility::Private)
+                .map(|definition| definition.name)
+                .collect()
+        };
+
+        Ok(export::stream_users(
+            database.users.clone(),
+            format,
+            hidden_attributes,
+        ))
+    }
+
+    /// Returns all users in the trash
+    fn get_trashed_users(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_VIEW_PERM);
+        let users = database.users.get_trashed_users()?;
+
+        Ok(json_response(&users))
+    }
+
+    /// Restores a user from the trash
+    fn restore_user(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_DELETE_PERM);
+        let user = database.users.restore_user(&email)?;
+
+        Ok(json_response(&user))
+    }
+
+    /// Merges a duplicate account into the user with the given email.
+    /// With dry_run the result is returned without applying the merge.
+    fn merge_users(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_UPDATE_PERM);
+        require_permission!(context, USER_DELETE_PERM);
+        let mut message = deserialize_body::<MergeUsersRequest>(request)?;
+        message.duplicate.make_ascii_lowercase();
+
+        check_own_password(database, &context, &message.own_password)?;
+        let merge = database
+            .users
+            .merge_users(&email, &message.duplicate, message.dry_run)?;
+
+        Ok(json_response(&MergeUsersResponse {
+            dry_run: message.dry_run,
+            merge,
+        }))
+    }
+
+    /// Returns the client certificates that are mapped to a user
+    fn get_client_certificates(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_VIEW_PERM);
+        let user = database.users.get_user_by_email(&email)?;
+        let certificates = database.client_certificates.get_certificates(user.id)?;
+
+        Ok(json_response(&certificates))
+    }
+
+    /// Maps a client certificate to a service account so that it can authenticate with it.
+    /// Only accounts whose permissions are a subset of the own permissions can get certificates.
+    fn add_client_certificate(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        context.require_user_session()?;
+        require_permission!(context, USER_UPDATE_PERM);
+        let message = deserialize_body::<AddClientCertificateRequest>(request)?;
+        let user = database.users.get_user_by_email(&email)?;
+        if !database.users.is_service_account(user.id)? {
+            return Err(HTTPError::invalid_request_data(
+                "Client certificates can only be added to service accounts",
+            ));
+        }
+        let permissions = database.users.get_permission_names(user.id)?;
+        if !permissions.iter().all(|p| context.has_permission(p)) {
+            return Err(HTTPError::from_code(
+                i18n::ERR_INSUFFICIENT_PERMISSIONS,
+                403,
+            ));
+        }
+        let certificate = database.client_certificates.add_certificate(
+            user.id,
+            &message.fingerprint,
+            message.description,
+        )?;
+
+        Ok(json_response(&certificate))
+    }
+
+    /// Removes a client certificate from a user
+    fn delete_client_certificate(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+        fingerprint: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_UPDATE_PERM);
+        let user = database.users.get_user_by_email(&email)?;
+        database
+            .client_certificates
+            .delete_certificate(user.id, &fingerprint)?;
+
+        Ok(json_response(&DeleteClientCertificateResponse {
+            success: true,
+            fingerprint,
+        }))
+    }
+
+    /// Returns the api keys of a service account
+    fn get_api_keys(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, API_KEY_MANAGE_PERM);
+        let user = database.users.get_user_by_email(&email)?;
+        let keys = database.api_keys.get_keys(user.id)?;
+
+        Ok(json_response(&keys))
+    }
+
+    /// Creates an api key for a service account or the own account. The key can only
+    /// grant permissions the user of the request has and is further limited to the
+    /// permissions the account has.
+    fn create_api_key(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        context.require_user_session()?;
+        require_permission!(context, API_KEY_MANAGE_PERM);
+        let message = deserialize_body::<CreateApiKeyRequest>(request)?;
+        if let Some(expires_at) = &message.expires_at {
+            DateTime::parse_from_rfc3339(expires_at).map_err(HTTPError::invalid_request_data)?;
+        }
+        if !message
+            .permissions
+            .iter()
+            .all(|p| context.has_permission(p))
+        {
+            return Err(HTTPError::from_code(
+                i18n::ERR_INSUFFICIENT_PERMISSIONS,
+                403,
+            ));
+        }
+        let user = database.users.get_user_by_email(&email)?;
+        if user.id != context.user.id && !database.users.is_service_account(user.id)? {
+            return Err(HTTPError::invalid_request_data(
+                "Api keys can only be created for service accounts",
+            ));
+        }
+        let (key, api_key) = database.api_keys.create_key(
+            user.id,
+            message.name,
+            message.permissions,
+            message.expires_at,
+        )?;
+
+        Ok(json_response(&CreateApiKeyResponse { key, api_key }).with_no_cache())
+    }
+
+    /// Deletes an api key of a service account
+    fn delete_api_key(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+        id: i32,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, API_KEY_MANAGE_PERM);
+        let user = database.users.get_user_by_email(&email)?;
+        database.api_keys.delete_key(user.id, id)?;
+
+        Ok(json_response(&DeleteApiKeyResponse { success: true, id }))
+    }
+
+    /// Returns the change history of a user
+    fn get_user_history(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_VIEW_PERM);
+        let user = database.users.get_user_by_email(&email)?;
+        let history = database.change_history.get_history(ENTITY_USER, user.id)?;
+
+        Ok(json_response(&history))
+    }
+
+    /// Returns the custom attributes of a user
+    fn get_user_attributes(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        check_user_permission_or_self(&context, &email, USER_VIEW_PERM)?;
+        let user = database.users.get_user_by_email(&email)?;
+        let attributes = if context.user.id == user.id || context.has_permission(USER_UPDATE_PERM) {
+            user.attributes
+        } else {
+            database.user_fields.filter_private(user.attributes)?
+        };
+
+        Ok(json_response(&attributes))
+    }
+
+    /// Updates single attributes of a user. The other attributes are kept.
+    fn update_user_attributes(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        check_user_permission_or_self(&context, &email, USER_UPDATE_PERM)?;
+        let message = deserialize_body::<UpdateUserAttributesRequest>(request)?;
+        let user = database.users.get_user_by_email(&email)?;
+        database
+            .user_fields
+            .validate_attributes(&apply_attribute_changes(
+                &user.attributes,
+                &message.attributes,
+            ))?;
+        let attributes = database
+            .users
+            .update_attributes(&email, &message.attributes)?;
+
+        Ok(json_response(&attributes))
+    }
+
+    /// Returns the roles of a user
+    fn get_user_roles(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        check_user_permission_or_self(&context, &email, USER_VIEW_PERM)?;
+        let user = database.users.get_user_by_email(&email)?;
+        let roles = database.user_roles.by_user(user.id)?;
+
+        Ok(json_response(&roles))
+    }
+
+    /// Replaces the roles of a user
+    fn update_user_roles(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_ROLES_UPDATE_PERM);
+        let message = deserialize_body::<UpdateUserRolesRequest>(request)?;
+        let user = database.users.get_user_by_email(&email)?;
+        let roles = database.user_roles.update_roles(user.id, message.roles)?;
+
+        Ok(json_response(&roles))
+    }
+
+    /// Returns a list of permissions the user has
+    fn get_user_permissions(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        check_user_permission_or_self(&context, &email, USER_VIEW_PERM)?;
+        let permissions = database.users.get_permissions(&email)?;
+
+        Ok(json_response(&permissions))
+    }
+
+    /// Returns the permissions of the user of the request. For tokens of client
+    /// applications and api keys only the permissions they are limited to are returned.
+    fn get_own_permissions(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        let permissions = database
+            .users
+            .get_permissions(&context.user.email)?
+            .into_iter()
+            .filter(|permission| context.has_permission(&permission.name))
+            .collect::<Vec<Permission>>();
+
+        Ok(json_response(&permissions))
+    }
+
+    /// Returns the active sessions of the user of the request
+    fn get_own_sessions(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        let sessions = database.users.list_sessions(context.user.id)?;
+
+        Ok(json_response(&sessions))
+    }
+
+    /// Returns the active sessions of a user so that administrators
+    /// can see where the account is logged in
+    fn get_user_sessions(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        check_user_permission_or_self(&context, &email, USER_VIEW_PERM)?;
+        let user = database.users.get_user_by_email(&email)?;
+        let sessions = database.users.list_sessions(user.id)?;
+
+        Ok(json_response(&sessions))
+    }
+
+    /// Verifies the email address of a new user with the token that was sent to it
+    fn verify_email(database: &Database, request: &Request, email: String) -> HTTPResult<Response> {
+        let message = deserialize_body::<VerifyEmailRequest>(request)?;
+        let user = database.users.verify_email(&email, &message.token)?;
+
+        Ok(json_response(&user))
+    }
+
+    /// Applies a requested email change
+    fn confirm_email_change(database: &Database, token: String) -> HTTPResult<Response> {
+        let change = database.email_changes.confirm_change(&token)?;
+
+        Ok(json_response(&EmailChangeResponse {
+            success: true,
+            email: change.new_email,
+        }))
+    }
+
+    /// Undoes an email change and revokes all sessions of the user
+    /// in case the change was requested by someone else
+    fn undo_email_change(database: &Database, token: String) -> HTTPResult<Response> {
+        let change = database.email_changes.undo_change(&token)?;
+        database.users.revoke_sessions(change.user_id)?;
+
+        Ok(json_response(&EmailChangeResponse {
+            success: true,
+            email: change.old_email,
+        }))
+    }
+
+    /// Returns the estimated strength of a password with suggestions for improving it.
+    /// The same estimation is used when creating or updating users.
+    fn password_strength(request: &Request) -> HTTPResult<Response> {
+        let message = deserialize_body::<PasswordStrengthRequest>(request)?;
+        let user_inputs = [message.name.as_ref(), message.email.as_ref()];
+        let user_inputs = user_inputs
+            .iter()
+            .filter_map(|i| i.map(|i| i.as_str()))
+            .collect::<Vec<&str>>();
+        let strength = estimate_strength(&message.password, &user_inputs);
+        let language = i18n::current_language();
+
+        Ok(json_response(&PasswordStrengthResponse {
+            score: strength.score,
+            accepted: PasswordPolicy::get().accepts(&strength),
+            warning: strength.warning_message(language),
+            suggestions: strength.suggestion_messages(language),
+        }))
+    }
+
+    /// Generates a new password for the admin user and revokes all of its sessions.
+    /// Only members of the admin role are allowed to rotate the password.
+    fn rotate_admin(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        let message = deserialize_body::<RotateAdminRequest>(request)?;
+        let is_admin = database
+            .user_roles
+            .by_user(context.user.id)?
+            .iter()
+            .any(|role| role.name == ADMIN_ROLE_NAME);
+        if !is_admin {
+            return Err(HTTPError::from_code(
+                i18n::ERR_INSUFFICIENT_PERMISSIONS,
+                403,
+            ));
+        }
+        check_own_password(database, &context, &message.own_password)?;
+        let password = database.rotate_admin_password()?;
+
+        Ok(json_response(&RotateAdminResponse {
+            email: admin_email(),
+            password,
+        }))
+    }
+
+    /// Lets people sign up by themselves. The new users are added to the
+    /// approval queue and can't log in until they were approved.
+    fn register(database: &Database, request: &Request) -> HTTPResult<Response> {
+        if config::var(ENV_ENABLE_REGISTRATION).unwrap_or("false".to_string()) != "true" {
+            return Ok(Response::empty_404());
+        }
+        let mut message = deserialize_body::<RegisterRequest>(request)?;
+        message.email.make_ascii_lowercase();
+        check_password_policy(&message.password, &[&message.name, &message.email])?;
+        let attributes = message
+            .attributes
+            .take()
+            .unwrap_or_else(|| Value::Object(Default::default()));
+        database.user_fields.validate_attributes(&attributes)?;
+        let result = database.users.create_pending_user(
+            message.name,
+            message.email,
+            message.password,
+            attributes,
+        )?;
+
+        Ok(json_response(&UserInformation::from(result)).with_status_code(201))
+    }
+
+    /// Returns all users that are waiting for approval
+    fn get_pending_users(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_APPROVE_PERM);
+        let users = database.users.get_pending_users()?;
+
+        Ok(json_response(&users))
+    }
+
+    /// Approves a pending user
+    fn approve_user(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_APPROVE_PERM);
+        let user = database.users.approve_user(&email, &context.user.email)?;
+
+        Ok(json_response(&user))
+    }
+
+    /// Rejects a pending user
+    fn reject_user(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_APPROVE_PERM);
+        database.users.reject_user(&email, &context.user.email)?;
+
+        Ok(json_response(&RejectUserResponse {
+            email,
+            success: true,
+        }))
+    }
+
+    /// Sets the requests per minute quota of a user. A quota of null removes the limit.
+    fn set_request_quota(
+        database: &Database,
+        request: &Request,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_UPDATE_PERM);
+        let message = deserialize_body::<SetRequestQuotaRequest>(request)?;
+        let quota = message.request_quota.map(|q| q.min(i32::MAX as u32) as i32);
+        database.users.set_request_quota(&email, quota)?;
+
+        Ok(json_response(&SetRequestQuotaResponse {
+            email,
+            request_quota: message.request_quota,
+        }))
+    }
+
+    /// Returns the definitions of all custom user fields
+    fn get_user_fields(database: &Database, request: &Request) -> HTTPResult<Response> {
+        validate_request_token(request, database)?;
+        let fields = database.user_fields.get_definitions()?;
+
+        Ok(json_response(&fields))
+    }
+
+    /// Creates a new custom user field
+    fn create_user_field(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_FIELDS_MANAGE_PERM);
+        let message = deserialize_body::<ModifyUserFieldRequest>(request)?;
+        let field = database.user_fields.create_definition(
+            message.name,
+            message.field_type,
+            message.required,
+            message.visibility,
+        )?;
+
+        Ok(json_response(&field).with_status_code(201))
+    }
+
+    /// Updates the definition of a custom user field
+    fn update_user_field(
+        database: &Database,
+        request: &Request,
+        name: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_FIELDS_MANAGE_PERM);
+        let message = deserialize_body::<ModifyUserFieldRequest>(request)?;
+        let field = database.user_fields.update_definition(
+            &name,
+            message.name,
+            message.field_type,
+            message.required,
+            message.visibility,
+        )?;
+
+        Ok(json_response(&field))
+    }
+
+    /// Deletes the definition of a custom user field
+    fn delete_user_field(
+        database: &Database,
+        request: &Request,
+        name: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, USER_FIELDS_MANAGE_PERM);
+        database.user_fields.delete_definition(&name)?;
+
+        Ok(json_response(&DeleteUserFieldResponse {
+            success: true,
+            name,
+        }))
+    }
+
+    /// Returns all organizations and teams
+    fn get_organizations(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        require_permission!(context, ORGANIZATION_VIEW_PERM);
+        let organizations = database.organizations.get_organizations()?;
+
+        Ok(json_response(&organizations))
+    }
+
+    /// Returns an organization with its direct teams and members
+    fn get_organization(
+        database: &Database,
+        request: &Request,
+        name: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        context.require_organization_permission(database, &name, ORGANIZATION_VIEW_PERM)?;
+
+        Ok(json_response(&Self::full_organization_data(
+            database, &name,
+        )?))
+    }
+
+    /// Creates a new organization. Teams can be created by users that
+    /// are allowed to manage the parent organization.
+    fn create_organization(database: &Database, request: &Request) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        let message = deserialize_body::<CreateOrganizationRequest>(request)?;
+        if let Some(parent) = &message.parent {
+            context.require_organization_permission(database, parent, ORGANIZATION_MANAGE_PERM)?;
+        } else {
+            require_permission!(context, ORGANIZATION_MANAGE_PERM);
+        }
+        let organization = database.organizations.create_organization(
+            message.name,
+            message.description,
+            message.parent,
+        )?;
+
+        Ok(
+            json_response(&Self::full_organization_data(database, &organization.name)?)
+                .with_status_code(201),
+        )
+    }
+
+    /// Deletes an organization. Teams can be deleted by users that
+    /// are allowed to manage the parent organization.
+    fn delete_organization(
+        database: &Database,
+        request: &Request,
+        name: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        let organization = database.organizations.get_organization(&name)?;
+        if let Some(parent) = &organization.parent {
+            context.require_organization_permission(database, parent, ORGANIZATION_MANAGE_PERM)?;
+        } else {
+            require_permission!(context, ORGANIZATION_MANAGE_PERM);
+        }
+        database.organizations.delete_organization(&name)?;
+
+        Ok(json_response(&DeleteOrganizationResponse {
+            success: true,
+            name,
+        }))
+    }
+
+    /// Returns the permissions the user of the request has inside the organization
+    /// including the permissions of its global roles
+    fn get_organization_permissions(
+        database: &Database,
+        request: &Request,
+        name: String,
+    ) -> HTTPResult<Response> {
+        let context = RequestContext::resolve(request, database)?;
+        database.organizations.get_organization(&name)?;
+        let mut permissions = context
+            .organization_permissions(database, &name)?
+            .into_iter()
+            .collect::<Vec<String>>();
+        permissions.sort();
+
+        Ok(json_response(&permissions))
+    }
+
+    /// Adds a user to an organization or replaces its roles inside the organization
+    fn set_organization_member(
+        database: &Database,
+        request: &Request,
+        name: String,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        context.require_organization_permission(database, &name, ORGANIZATION_MANAGE_PERM)?;
+        let message = deserialize_body::<SetOrganizationMemberRequest>(request)?;
+        let user = database.users.get_user_by_email(&email)?;
+        database
+            .organizations
+            .set_member(&name, user.id, &message.roles)?;
+
+        Ok(json_response(&Self::full_organization_data(
+            database, &name,
+        )?))
+    }
+
+    /// Removes a user from an organization
+    fn remove_organization_member(
+        database: &Database,
+        request: &Request,
+        name: String,
+        mut email: String,
+    ) -> HTTPResult<Response> {
+        email.make_ascii_lowercase();
+        let context = RequestContext::resolve(request, database)?;
+        context.require_organization_permission(database, &name, ORGANIZATION_MANAGE_PERM)?;
+        let user = database.users.get_user_by_email(&email)?;
+        database.organizations.remove_member(&name, user.id)?;
+
+        Ok(json_response(&RemoveOrganizationMemberResponse {
+            success: true,
+            email,
+        }))
+    }
+
+    fn full_organization_data(
+        database: &Database,
+        name: &String,
+    ) -> HTTPResult<FullOrganizationData> {
+        let organization = database.organizations.get_organization(name)?;
+        let teams = database.organizations.get_teams(name)?;
+        let members = database.organizations.get_members(name)?;
+
+        Ok(FullOrganizationData {
+            id: organization.id,
+            name: organization.name,
+            description: organization.description,
+            parent: organization.parent,
+            teams,
+            members,
+        })
+    }
+}
 
 /// Parses the body of a http request into a string representation
 fn parse_string_body(request: &Request) -> HTTPResult<String> {
     let mut body = request
         .data()
-        .ok_or(HTTPError::new("Missing request data!".to_string(), 400))?;
+        .ok_or(HTTPError::from_code(i18n::ERR_MISSING_REQUEST_DATA, 400))?;
     let mut string_body = String::new();
     body.read_to_string(&mut string_body)
-        .map_err(|e| HTTPError::new(format!("Failed to parse request data {}", e), 400))?;
+        .map_err(HTTPError::invalid_request_data)?;
+    log::debug!(
+        "[{}] Request body: {}",
+        access_log::current_request_id(),
+        access_log::redacted_body(&string_body)
+    );
 
     Ok(string_body)
 }
 
-/// Deserialized a json body into the given type
-fn deserialize_body<T: DeserializeOwned>(request: &Request) -> HTTPResult<T> {
-    serde_json::from_str(parse_string_body(request)?.as_str())
-        .map_err(|e| HTTPError::new(e.to_string(), 400))
+/// Validates a json body against the schema of the given type and deserializes it
+fn deserialize_body<T: DeserializeOwned + JsonSchema>(request: &Request) -> HTTPResult<T> {
+    let body: Value = serde_json::from_str(parse_string_body(request)?.as_str())
+        .map_err(HTTPError::invalid_request_data)?;
+    let errors = validation::validate::<T>(&body);
+    if !errors.is_empty() {
+        return Err(HTTPError::invalid_fields(errors));
+    }
+
+    from_json_value(body).map_err(HTTPError::invalid_request_data)
 }
 
 /// Parses and validates the request token from the http header
-fn validate_request_token(request: &Request, database: &Database) -> HTTPResult<(String, i32)> {
-    lazy_static::lazy_static! {static ref BEARER_REGEX: Regex = Regex::new(r"^[bB]earer\s+").unwrap();}
+/// and returns it with its session
+fn validate_request_token(
+    request: &Request,
+    database: &Database,
+) -> HTTPResult<(String, TokenStoreEntry)> {
     let token = request
         .header("authorization")
-        .ok_or(HTTPError::new("401 Unauthorized".to_string(), 401))?;
-    let token = BEARER_REGEX.replace(token, "");
-    let (valid, _) = database.users.validate_request_token(&token.to_string())?;
-    if !valid {
-        Err(HTTPError::new("Invalid request token".to_string(), 401))
+        .ok_or(HTTPError::from_code(i18n::ERR_UNAUTHORIZED, 401))?;
+    let token = BEARER_REGEX.replace(token, "").to_string();
+    let entry = database
+        .users
+        .get_request_token_entry(&token)?
+        .ok_or(HTTPError::from_code(i18n::ERR_INVALID_TOKEN, 401))?;
+    access_log::set_user_id(entry.user_id());
+
+    Ok((token, entry))
+}
+
+/// Parses the body of a token request which is form encoded for standard clients.
+/// The client credentials can be passed with basic auth instead of the body.
+fn parse_oauth_token_request(request: &Request) -> HTTPResult<OAuthTokenRequest> {
+    let form_encoded = matches!(
+        request.header("Content-Type"),
+        Some(content_type) if content_type.starts_with("application/x-www-form-urlencoded")
+    );
+    let mut message = if form_encoded {
+        let fields = rouille::input::post::raw_urlencoded_post_input(request)
+            .map_err(HTTPError::invalid_request_data)?
+            .into_iter()
+            .collect::<HashMap<String, String>>();
+        serde_json::from_value::<OAuthTokenRequest>(serde_json::json!(fields))
+            .map_err(HTTPError::invalid_request_data)?
     } else {
-        Ok((
-            token.to_string(),
-            get_user_id_from_token(&token.to_string())
-                .ok_or(HTTPError::new("Invalid request token".to_string(), 401))?,
-        ))
+        deserialize_body::<OAuthTokenRequest>(request)?
+    };
+    let basic_credentials = request
+        .header("authorization")
+        .and_then(|header| header.strip_prefix("Basic "))
+        .and_then(|credentials| base64::decode(credentials.trim()).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok());
+    if let Some(credentials) = basic_credentials {
+        let mut parts = credentials.splitn(2, ':');
+        message.client_id = parts.next().map(String::from);
+        message.client_secret = parts.next().map(String::from);
     }
+
+    Ok(message)
 }
 
-/// Returns if the user has a certain permission or queries him/herself
-fn check_user_permission_or_self(
+/// Creates an error response in the format of RFC 6749
+fn oauth_error(error: &str, description: &str, status: u16) -> Response {
+    Response::json(&serde_json::json!({
+        "error": error,
+        "error_description": description,
+    }))
+    .with_status_code(status)
+}
+
+/// Appends the given query parameters to the redirect uri of a client application
+fn oauth_redirect(redirect_uri: &str, params: &[(&str, Option<&String>)]) -> String {
+    let query = params
+        .iter()
+        .filter_map(|(name, value)| value.map(|v| format!("{}={}", name, encode_query_value(v))))
+        .collect::<Vec<String>>()
+        .join("&");
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+
+    format!("{}{}{}", redirect_uri, separator, query)
+}
+
+/// Percent encodes all characters of a query value that aren't unreserved
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Returns the id of the service account and the permissions the api key
+/// of the request is limited to if the request was made with an api key
+fn authenticate_api_key(
     request: &Request,
     database: &Database,
+) -> HTTPResult<Option<(i32, Vec<String>)>> {
+    let key = match request.header(API_KEY_HEADER) {
+        Some(key) => key.trim(),
+        None => return Ok(None),
+    };
+
+    database
+        .api_keys
+        .authenticate(key)?
+        .map(Some)
+        .ok_or(HTTPError::from_code(i18n::ERR_INVALID_API_KEY, 401))
+}
+
+/// Returns the id of the user that is mapped to the client certificate of the request.
+/// TLS is terminated by a proxy that forwards the SHA-256 fingerprint of the verified
+/// client certificate in the configured header. The header is only trusted if the request
+/// comes from one of the configured proxies and no authorization header was sent.
+fn authenticate_client_certificate(
+    request: &Request,
+    database: &Database,
+) -> HTTPResult<Option<i32>> {
+    let header = match CLIENT_CERT_HEADER.as_ref() {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let fingerprint = match request.header(header) {
+        Some(fingerprint) if request.header("authorization").is_none() => fingerprint,
+        _ => return Ok(None),
+    };
+    if !CLIENT_CERT_PROXIES.contains(&request.remote_addr().ip()) {
+        log::warn!(
+            "Ignoring client certificate of untrusted address {}",
+            request.remote_addr()
+        );
+        return Ok(None);
+    }
+
+    database
+        .client_certificates
+        .get_user_id(fingerprint)?
+        .map(Some)
+        .ok_or(HTTPError::from_code(i18n::ERR_UNKNOWN_CERTIFICATE, 401))
+}
+
+/// Returns the address of the client that sent the request.
+/// Requests of a trusted proxy are attributed to the last address in the
+/// X-Forwarded-For header that wasn't added by another trusted proxy.
+fn client_ip(request: &Request) -> IpAddr {
+    let remote_ip = request.remote_addr().ip();
+    if !TRUSTED_PROXIES.contains(&remote_ip) {
+        return remote_ip;
+    }
+    let forwarded: Vec<IpAddr> = request
+        .header("X-Forwarded-For")
+        .map(|header| {
+            header
+                .split(',')
+                .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    forwarded
+        .iter()
+        .rev()
+        .find(|address| !TRUSTED_PROXIES.contains(address))
+        .or_else(|| forwarded.first())
+        .copied()
+        .unwrap_or(remote_ip)
+}
+
+/// Returns an error with the warning and suggestions of the password
+/// estimation if the password doesn't satisfy the password policy
+fn check_password_policy(password: &str, user_inputs: &[&String]) -> HTTPResult<()> {
+    let user_inputs = user_inputs
+        .iter()
+        .map(|i| i.as_str())
+        .collect::<Vec<&str>>();
+    let strength = estimate_strength(password, &user_inputs);
+
+    if PasswordPolicy::get().accepts(&strength) {
+        Ok(())
+    } else {
+        let language = i18n::current_language();
+        let mut error = HTTPError::from_code(i18n::ERR_WEAK_PASSWORD, 400);
+        if let Some(warning) = strength.warning_message(language) {
+            error.message = format!("{}: {}", error.message, warning);
+        }
+        Err(error)
+    }
+}
+
+/// Validates an RFC 3339 expiry time and returns it in UTC
+fn parse_expiry(expires_at: &Option<String>) -> HTTPResult<Option<String>> {
+    expires_at
+        .as_ref()
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc).to_rfc3339())
+                .map_err(HTTPError::invalid_request_data)
+        })
+        .transpose()
+}
+
+/// Reads the limit and offset query parameters of list endpoints
+fn parse_pagination(request: &Request) -> HTTPResult<Pagination> {
+    let parse = |name: &str| {
+        request
+            .get_param(name)
+            .map(|value| {
+                value.parse::<i64>().ok().filter(|value| *value >= 0).ok_or(
+                    HTTPError::invalid_request_data(format!(
+                        "{} needs to be a positive number",
+                        name
+                    )),
+                )
+            })
+            .transpose()
+    };
+
+    Ok(Pagination {
+        limit: parse("limit")?,
+        offset: parse("offset")?.unwrap_or(0),
+    })
+}
+
+/// Reads the sort and order query parameters of list endpoints
+fn parse_sorting(request: &Request) -> HTTPResult<Sorting> {
+    let order = match request.get_param("order") {
+        Some(order) => SortOrder::from_str(&order).map_err(HTTPError::invalid_request_data)?,
+        None => SortOrder::default(),
+    };
+
+    Ok(Sorting {
+        field: request.get_param("sort").filter(|s| !s.is_empty()),
+        order,
+    })
+}
+
+/// Verifies the password of the user of the request. The attempts count
+/// towards the rate limit of the account like logins do.
+fn check_own_password(
+    database: &Database,
+    context: &RequestContext,
+    password: &String,
+) -> HTTPResult<()> {
+    ACCOUNT_RATE_LIMITER
+        .check(&context.user.email)
+        .map_err(HTTPError::rate_limited)?;
+    if database
+        .users
+        .validate_login(&context.user.email, password)?
+    {
+        Ok(())
+    } else {
+        Err(HTTPError::from_code(i18n::ERR_INVALID_CREDENTIALS, 401))
+    }
+}
+
+/// Returns if the user has a certain permission or queries him/herself
+fn check_user_permission_or_self(
+    context: &RequestContext,
     email: &String,
     permission: &str,
-) -> HTTPResult<UserInformation> {
-    let (_, id) = validate_request_token(request, database)?;
-    let logged_in_user = database.users.get_user(id)?;
-
-    if &logged_in_user.email != email && !database.users.has_permission(id, permission)? {
-        Err(HTTPError::new("Insufficient permission".to_string(), 403))
+) -> HTTPResult<()> {
+    if &context.user.email != email && !context.has_permission(permission) {
+        Err(HTTPError::from_code(
+            i18n::ERR_INSUFFICIENT_PERMISSIONS,
+            403,
+        ))
     } else {
-        Ok(logged_in_user)
+        Ok(())
+    }
+}
+
+/// The authentication state of a request. It is resolved once per request
+/// and used for all permission checks of the handler.
+pub struct RequestContext {
+    pub token: Option<String>,
+    pub user: UserInformation,
+    /// The scopes the token is limited to if it was issued to a client application
+    pub scopes: Option<Vec<String>>,
+    /// The administrator that acts as the user if the token is an impersonation token
+    pub impersonated_by: Option<UserInformation>,
+    permissions: Arc<HashSet<String>>,
+}
+
+impl RequestContext {
+    /// Validates the api key, request token or client certificate
+    /// and resolves the user with its permissions
+    fn resolve(request: &Request, database: &Database) -> HTTPResult<Self> {
+        Self::resolve_with(request, database, false)
+    }
+
+    /// Resolves the context of the request. Sessions of users that must change
+    /// their password are only accepted if the password change is allowed.
+    fn resolve_with(
+        request: &Request,
+        database: &Database,
+        allow_password_change: bool,
+    ) -> HTTPResult<Self> {
+        let mut impersonated_by = None;
+        let (token, id, scopes) =
+            if let Some((id, permissions)) = authenticate_api_key(request, database)? {
+                (None, id, Some(permissions))
+            } else if let Some(id) = authenticate_client_certificate(request, database)? {
+                (None, id, None)
+            } else {
+                let (token, entry) = validate_request_token(request, database)?;
+                let id = entry.user_id();
+                let scopes = match entry.kind() {
+                    TokenKind::Client { scopes, .. } => Some(scopes.clone()),
+                    TokenKind::PasswordChange if !allow_password_change => {
+                        return Err(HTTPError::from_code(
+                            i18n::ERR_PASSWORD_CHANGE_REQUIRED,
+                            403,
+                        ))
+                    }
+                    TokenKind::Impersonation { admin_id } => {
+                        impersonated_by = Some(database.users.get_user(*admin_id)?);
+                        None
+                    }
+                    _ => None,
+                };
+                (Some(token), id, scopes)
+            };
+        let user = database.users.get_user(id).map_err(|e| match e {
+            DBError::RecordDoesNotExist => HTTPError::from_code(i18n::ERR_INVALID_TOKEN, 401),
+            e => HTTPError::from(e),
+        })?;
+        access_log::set_user_id(user.id);
+        let mut permissions = database.users.get_permission_names(id)?;
+        if let Some(scopes) = &scopes {
+            permissions = Arc::new(
+                permissions
+                    .iter()
+                    .filter(|p| scopes.contains(p))
+                    .cloned()
+                    .collect(),
+            );
+        }
+        if let Some(admin) = &impersonated_by {
+            log::info!(
+                "{} {} is made by user {} impersonating user {}",
+                request.method(),
+                request.url(),
+                admin.id,
+                user.id
+            );
+            // changes made while impersonating are attributed to the administrator
+            change_history::set_actor(&admin.email);
+        } else {
+            change_history::set_actor(&user.email);
+        }
+
+        Ok(Self {
+            token,
+            user,
+            scopes,
+            impersonated_by,
+            permissions,
+        })
+    }
+
+    /// Returns an error if the request was made with a token of a client application
+    /// or an impersonation token. Client applications must not be able to authorize
+    /// themselves or other clients and administrators must not be able to create
+    /// credentials of the users they impersonate.
+    fn require_user_session(&self) -> HTTPResult<()> {
+        if self.scopes.is_some() || self.impersonated_by.is_some() {
+            Err(HTTPError::from_code(
+                i18n::ERR_INSUFFICIENT_PERMISSIONS,
+                403,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns if the user of the request has been granted the permission
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.contains(permission)
+    }
+
+    /// Returns the permissions of the user inside the organization and its global
+    /// permissions. Tokens of client applications are limited to their scopes.
+    fn organization_permissions(
+        &self,
+        database: &Database,
+        organization: &String,
+    ) -> HTTPResult<HashSet<String>> {
+        let mut permissions = database
+            .organizations
+            .get_permission_names(self.user.id, organization)?;
+        if let Some(scopes) = &self.scopes {
+            permissions.retain(|p| scopes.contains(p));
+        }
+        permissions.extend(self.permissions.iter().cloned());
+
+        Ok(permissions)
+    }
+
+    /// Returns an error if the user of the request has neither been granted the permission
+    /// globally nor by a role inside the organization or one of its parents
+    fn require_organization_permission(
+        &self,
+        database: &Database,
+        organization: &String,
+        permission: &str,
+    ) -> HTTPResult<()> {
+        if self.has_permission(permission)
+            || self
+                .organization_permissions(database, organization)?
+                .contains(permission)
+        {
+            Ok(())
+        } else {
+            Err(HTTPError::from_code(
+                i18n::ERR_INSUFFICIENT_PERMISSIONS,
+                403,
+            ))
+        }
     }
 }
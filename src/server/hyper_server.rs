@@ -0,0 +1,190 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::convert::Infallible;
+use std::error::Error;
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+
+use hyper::server::accept;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse, Server, StatusCode};
+use rouille::Request;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::database::Database;
+use crate::server::http_server::{ListenAddress, RequestHandler};
+use crate::utils::config::{self, Config};
+
+pub(crate) const ENV_KEEP_ALIVE: &str = "HTTP_KEEP_ALIVE";
+
+/// A HTTP server based on hyper that supports HTTP/2 and keep-alive connections.
+/// The requests are handled by the same handler as the rouille based server.
+pub struct UserHttpServer {
+    database: Database,
+    listen_address: ListenAddress,
+}
+
+impl UserHttpServer {
+    pub fn new(database: &Database, config: &Config) -> Self {
+        Self {
+            database: Database::clone(database),
+            listen_address: ListenAddress::parse(&config.http.listen_address),
+        }
+    }
+
+    /// Starts the http server in a tokio runtime. The requests are passed to
+    /// the blocking thread pool of the runtime as the handlers are synchronous.
+    pub fn start(&self) {
+        log::info!("Starting HTTP-Server...");
+        let listen_address = self.listen_address.clone();
+        let keep_alive = config::var(ENV_KEEP_ALIVE).unwrap_or("true".to_string()) == "true";
+        let handler = Arc::new(RequestHandler::new(&self.database));
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("http-worker")
+            .max_blocking_threads(handler.pool_size())
+            .build()
+            .unwrap();
+
+        runtime.block_on(async move {
+            let result = match &listen_address {
+                ListenAddress::Tcp(address) => serve_tcp(handler, address, keep_alive).await,
+                ListenAddress::Unix(path) => serve_unix(handler, path, keep_alive).await,
+            };
+            if let Err(e) = result {
+                log::error!("HTTP-Server on {} failed: {}", listen_address, e);
+            }
+        });
+    }
+}
+
+async fn serve_tcp(
+    handler: Arc<RequestHandler>,
+    address: &str,
+    keep_alive: bool,
+) -> Result<(), Box<dyn Error>> {
+    let address: SocketAddr = address.parse()?;
+    let make_service = make_service_fn(move |connection: &AddrStream| {
+        let remote_address = connection.remote_addr();
+        let handler = Arc::clone(&handler);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle_request(Arc::clone(&handler), remote_address, request)
+            }))
+        }
+    });
+    let server = Server::try_bind(&address)?
+        .http1_keepalive(keep_alive)
+        .serve(make_service);
+    log::info!("HTTP-Server running on {}", address);
+
+    Ok(server.await?)
+}
+
+/// Serves the api on a unix socket for reverse proxies on the same host.
+/// The peers of a unix socket have no address so the requests are
+/// handled as if they came from the loopback address. The client address
+/// is taken from the X-Forwarded-For header while the loopback address
+/// is one of the HTTP_TRUSTED_PROXIES.
+async fn serve_unix(
+    handler: Arc<RequestHandler>,
+    path: &Path,
+    keep_alive: bool,
+) -> Result<(), Box<dyn Error>> {
+    let remote_address = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    ListenAddress::remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    ListenAddress::set_socket_permissions(path)?;
+    let incoming = accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    });
+    let make_service = make_service_fn(move |_: &UnixStream| {
+        let handler = Arc::clone(&handler);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle_request(Arc::clone(&handler), remote_address, request)
+            }))
+        }
+    });
+    let server = Server::builder(incoming)
+        .http1_keepalive(keep_alive)
+        .serve(make_service);
+    log::info!("HTTP-Server running on {}", path.display());
+
+    Ok(server.await?)
+}
+
+/// Converts the hyper request into a rouille request, handles it
+/// on the blocking thread pool and converts the response back
+async fn handle_request(
+    handler: Arc<RequestHandler>,
+    remote_address: SocketAddr,
+    request: HyperRequest<Body>,
+) -> Result<HyperResponse<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let data = match hyper::body::to_bytes(body).await {
+        Ok(data) => data.to_vec(),
+        Err(e) => {
+            log::debug!("Failed to read request body: {}", e);
+            return Ok(status_response(StatusCode::BAD_REQUEST));
+        }
+    };
+    let headers = parts
+        .headers
+        .iter()
+        .filter_map(|(name, value)| {
+            value
+                .to_str()
+                .ok()
+                .map(|value| (name.to_string(), value.to_string()))
+        })
+        .collect::<Vec<(String, String)>>();
+    let url = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.to_string())
+        .unwrap_or("/".to_string());
+    let method = parts.method.to_string();
+
+    let response = tokio::task::spawn_blocking(move || {
+        let request = Request::fake_http_from(remote_address, method, url, headers, data);
+        let response = handler.handle(&request);
+        let mut builder = HyperResponse::builder().status(response.status_code);
+        for (name, value) in &response.headers {
+            builder = builder.header(name.as_ref(), value.as_ref());
+        }
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = Vec::new();
+        reader
+            .read_to_end(&mut body)
+            .map_err(|e| e.to_string())
+            .and_then(|_| builder.body(Body::from(body)).map_err(|e| e.to_string()))
+    })
+    .await;
+
+    match response {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(e)) => {
+            log::error!("Failed to convert response: {}", e);
+            Ok(status_response(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+        Err(e) => {
+            log::error!("Request handler failed: {}", e);
+            Ok(status_response(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+fn status_response(status: StatusCode) -> HyperResponse<Body> {
+    let mut response = HyperResponse::new(Body::empty());
+    *response.status_mut() = status;
+
+    response
+}
@@ -2,23 +2,81 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
+use std::any::TypeId;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
 
-use serde::{Deserialize, Serialize};
+use schemars::gen::SchemaSettings;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt::Formatter;
 use zeroize::Zeroize;
 
-use crate::database::models::{CreatePermissionsEntry, Permission, UserFullInformation};
+use crate::database::models::{
+    ApiKey, ConflictStrategy, CreatePermissionsEntry, FieldType, FieldVisibility, OAuthClient,
+    Organization, OrganizationMember, Pagination, Permission, Role, RoleExport,
+    UserFullInformation, UserMerge, Webhook,
+};
+use crate::database::PoolState;
 use crate::utils::error::DBError;
-use serde_json::Value;
+use serde_json::{Map, Value};
 
-#[derive(Deserialize)]
+/// Deserializes a field that can be missing, null or set.
+/// A missing field is None while null is Some(None).
+fn deserialize_nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Ok(Some(Option::deserialize(deserializer)?))
+}
+
+#[derive(Deserialize, JsonSchema)]
 pub struct TokenRequest {
     pub token: String,
 }
 
+/// The optional trace id that can be added to any rpc message
+/// to correlate it with the request of the calling service
+#[derive(Deserialize)]
+pub struct TraceContext {
+    pub trace_id: Option<String>,
+    /// The W3C traceparent of the calling service to continue its trace
+    pub traceparent: Option<String>,
+}
+
+/// A page of a list together with the total number of entries
+#[derive(Serialize, JsonSchema)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub limit: Option<i64>,
+    pub offset: i64,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, total: i64, pagination: &Pagination) -> Self {
+        Self {
+            items,
+            total,
+            limit: pagination.limit,
+            offset: pagination.offset,
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct GuestTokenRequest {
+    pub permissions: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct OrganizationPermissionsRequest {
+    pub token: String,
+    pub organization: String,
+}
+
 #[derive(Debug, Serialize)]
 pub struct ErrorMessage {
     message: String,
@@ -44,12 +102,66 @@ impl From<DBError> for ErrorMessage {
     }
 }
 
+/// The version of the rpc protocol a client implements
+#[derive(Deserialize, JsonSchema)]
+pub struct HandshakeRequest {
+    pub protocol_version: u32,
+    /// The algorithms the client can decompress responses with in order of preference
+    #[serde(default)]
+    pub compression: Vec<String>,
+}
+
+/// The protocol version the server and the client agreed on
+#[derive(Serialize, JsonSchema)]
+pub struct HandshakeResponse {
+    /// The version that is used for the connection
+    pub protocol_version: u32,
+    /// The newest version the server implements
+    pub server_protocol_version: u32,
+    /// The oldest version the server still supports
+    pub min_protocol_version: u32,
+    pub server_version: String,
+    /// The algorithm large responses of the connection are compressed with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+}
+
+/// Requests the description of the rpc methods. Clients of the first protocol
+/// version send no data and get a list of info entries instead.
+#[derive(Deserialize, JsonSchema)]
+pub struct InfoRequest {
+    pub protocol_version: u32,
+}
+
+/// The rpc methods with the json schemas of their request and response data
+#[derive(Serialize, JsonSchema)]
+pub struct ProtocolDescription {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub methods: Vec<MethodDescription>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct MethodDescription {
+    pub name: String,
+    pub method: String,
+    pub description: String,
+    /// The json schema of the request data. Null if the method takes no data.
+    pub request: Value,
+    /// The json schema of the response data
+    pub response: Value,
+}
+
 #[derive(Serialize)]
 pub struct InfoEntry {
     name: String,
     method: String,
     description: String,
     data: String,
+    #[serde(skip)]
+    request: Value,
+    #[serde(skip)]
+    response: Value,
 }
 
 impl InfoEntry {
@@ -63,15 +175,72 @@ impl InfoEntry {
             name: name.to_string(),
             description: description.to_string(),
             data: data.to_string(),
+            request: Value::Null,
+            response: Value::Null,
+        }
+    }
+
+    /// Adds the json schemas of the request and response data.
+    /// Methods that take no data use () as request type.
+    pub fn with_schema<I: JsonSchema + 'static, O: JsonSchema>(mut self) -> Self {
+        if TypeId::of::<I>() != TypeId::of::<()>() {
+            self.request = json_schema::<I>();
+        }
+        self.response = json_schema::<O>();
+
+        self
+    }
+
+    /// Returns the description of the method with its schemas
+    pub fn describe(&self) -> MethodDescription {
+        MethodDescription {
+            name: self.name.clone(),
+            method: self.method.clone(),
+            description: self.description.clone(),
+            request: self.request.clone(),
+            response: self.response.clone(),
         }
     }
 }
 
-#[derive(Deserialize)]
+fn json_schema<T: JsonSchema>() -> Value {
+    serde_json::to_value(
+        SchemaSettings::draft07()
+            .into_generator()
+            .into_root_schema_for::<T>(),
+    )
+    .unwrap_or(Value::Null)
+}
+
+#[derive(Deserialize, JsonSchema)]
 pub struct GetPermissionsRequest {
     pub roles: Vec<i32>,
 }
 
+#[derive(Deserialize, JsonSchema)]
+pub struct ResolveUsersRequest {
+    pub ids: Vec<i32>,
+}
+
+/// The user a request token belongs to with its enabled roles
+/// and the permissions the token grants
+#[derive(Serialize, JsonSchema)]
+pub struct TokenUserResponse {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub roles: Vec<Role>,
+    pub permissions: Vec<String>,
+}
+
+/// The names of the roles that are assigned to the user
+/// in addition to the roles it already has
+#[derive(Deserialize, JsonSchema)]
+pub struct AssignRolesRequest {
+    pub user_id: i32,
+    pub roles: Vec<String>,
+}
+
 #[derive(Deserialize, JsonSchema)]
 pub struct ModifyRoleRequest {
     pub name: String,
@@ -79,11 +248,29 @@ pub struct ModifyRoleRequest {
     pub permissions: Vec<i32>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct CreatePermissionsRequest {
     pub permissions: Vec<CreatePermissionsEntry>,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ExportRolesResponse {
+    pub roles: Vec<RoleExport>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ImportRolesRequest {
+    pub roles: Vec<RoleExport>,
+    #[serde(default)]
+    pub strategy: ConflictStrategy,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SyncPermissionsRequest {
+    pub namespace: String,
+    pub permissions: Vec<CreatePermissionsEntry>,
+}
+
 #[derive(Deserialize, Zeroize, JsonSchema)]
 #[zeroize(drop)]
 pub struct LoginRequest {
@@ -98,6 +285,15 @@ pub struct LoginResponse {
     pub request_ttl: i32,
     pub refresh_ttl: i32,
     pub user: UserFullInformation,
+    /// If set the tokens can only be used to change the password
+    pub must_change_password: bool,
+}
+
+#[derive(Deserialize, Zeroize, JsonSchema)]
+#[zeroize(drop)]
+pub struct ChangePasswordRequest {
+    pub old_password: String,
+    pub new_password: String,
 }
 
 #[derive(Deserialize, Zeroize, JsonSchema)]
@@ -112,6 +308,64 @@ pub struct LogoutMessage {
     pub request_token: String,
 }
 
+#[derive(Serialize, JsonSchema)]
+pub struct HealthResponse {
+    pub healthy: bool,
+    pub pool: PoolHealth,
+}
+
+/// Whether the server can handle requests
+#[derive(Serialize, JsonSchema)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub database_reachable: bool,
+    pub migrations_applied: bool,
+    pub rpc_listening: bool,
+}
+
+/// Whether the server is responsive
+#[derive(Serialize, JsonSchema)]
+pub struct LivenessResponse {
+    pub live: bool,
+    /// The time since the RPC workers last reported that they are responsive
+    pub rpc_heartbeat_age_ms: Option<u64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct PoolHealth {
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub active_connections: u32,
+    pub max_size: u32,
+    pub checkouts: u64,
+    pub timeouts: u64,
+    pub long_checkouts: u64,
+    pub max_checkout_ms: u64,
+    pub longest_active_checkout_ms: u64,
+}
+
+impl From<PoolState> for PoolHealth {
+    fn from(state: PoolState) -> Self {
+        Self {
+            connections: state.state.connections,
+            idle_connections: state.state.idle_connections,
+            active_connections: state.active_connections(),
+            max_size: state.max_size,
+            checkouts: state.checkouts.checkouts,
+            timeouts: state.checkouts.timeouts,
+            long_checkouts: state.checkouts.long_checkouts,
+            max_checkout_ms: state.checkouts.max_checkout_ms,
+            longest_active_checkout_ms: state.checkouts.longest_active_checkout_ms,
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct HeartbeatResponse {
+    pub request_ttl: i32,
+    pub refresh_ttl: i32,
+}
+
 #[derive(Serialize, JsonSchema)]
 pub struct LogoutConfirmation {
     pub success: bool,
@@ -121,6 +375,7 @@ pub struct LogoutConfirmation {
 pub struct FullRoleData {
     pub id: i32,
     pub name: String,
+    pub enabled: bool,
     pub permissions: Vec<Permission>,
 }
 
@@ -128,6 +383,7 @@ pub struct FullRoleData {
 pub struct DeleteRoleResponse {
     pub success: bool,
     pub role: String,
+    pub purge_at: String,
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -137,6 +393,8 @@ pub struct UpdateUserRequest {
     pub password: Option<String>,
     pub roles: Option<Vec<String>>,
     pub attributes: Option<Value>,
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    pub expires_at: Option<Option<String>>,
     pub own_password: String,
 }
 
@@ -146,6 +404,37 @@ pub struct CreateUserRequest {
     pub email: String,
     pub password: String,
     pub attributes: Value,
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    /// Service accounts can get api keys and client certificates
+    #[serde(default)]
+    pub service_account: bool,
+}
+
+/// A user that signs up by itself and needs to be approved before it can log in
+#[derive(Deserialize, JsonSchema)]
+pub struct RegisterRequest {
+    pub name: String,
+    pub email: String,
+    pub password: String,
+    #[serde(default)]
+    pub attributes: Option<Value>,
+}
+
+/// A user that is invited to set its own password
+#[derive(Deserialize, JsonSchema)]
+pub struct InviteUserRequest {
+    pub name: String,
+    pub email: String,
+    pub attributes: Value,
+    #[serde(default)]
+    pub expires_at: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema, Zeroize)]
+#[zeroize(drop)]
+pub struct AcceptInvitationRequest {
+    pub password: String,
 }
 
 #[derive(Deserialize, JsonSchema, Zeroize)]
@@ -158,4 +447,307 @@ pub struct DeleteUserRequest {
 pub struct DeleteUserResponse {
     pub email: String,
     pub success: bool,
+    /// The time after which the user is permanently deleted
+    pub purge_at: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SetUserActiveResponse {
+    pub email: String,
+    pub active: bool,
+    pub revoked_sessions: usize,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ModifyUserFieldRequest {
+    pub name: String,
+    pub field_type: FieldType,
+    pub required: bool,
+    pub visibility: FieldVisibility,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DeleteUserFieldResponse {
+    pub success: bool,
+    pub name: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SetRequestQuotaRequest {
+    pub request_quota: Option<u32>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SetRequestQuotaResponse {
+    pub email: String,
+    pub request_quota: Option<u32>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct AddClientCertificateRequest {
+    pub fingerprint: String,
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DeleteClientCertificateResponse {
+    pub success: bool,
+    pub fingerprint: String,
+}
+
+/// The roles of a user. Roles the user has that aren't
+/// in the list are removed.
+#[derive(Deserialize, JsonSchema)]
+pub struct UpdateUserRolesRequest {
+    pub roles: Vec<String>,
+}
+
+/// The attributes that are added or replaced. Attributes set to null are removed,
+/// attributes that aren't listed stay unchanged.
+#[derive(Deserialize, JsonSchema)]
+pub struct UpdateUserAttributesRequest {
+    pub attributes: Map<String, Value>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub permissions: Vec<String>,
+    /// The time the key expires at as RFC 3339 string
+    pub expires_at: Option<String>,
+}
+
+/// The created key. The key is only returned once and needs
+/// to be sent in the X-Api-Key header.
+#[derive(Serialize, JsonSchema)]
+pub struct CreateApiKeyResponse {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    pub api_key: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DeleteApiKeyResponse {
+    pub success: bool,
+    pub id: i32,
+}
+
+/// The url that is notified and the events it subscribes to.
+/// The events are user.created, user.updated, user.deleted and user.roles_changed.
+#[derive(Deserialize, JsonSchema)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    pub events: Vec<String>,
+}
+
+/// The registered webhook. The secret is only returned once. Requests are
+/// signed with it in the X-Flotte-Signature header as sha256=hex(HMAC-SHA256)
+/// of the X-Flotte-Timestamp header and the body joined with a dot.
+#[derive(Serialize, JsonSchema)]
+pub struct CreateWebhookResponse {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DeleteWebhookResponse {
+    pub success: bool,
+    pub id: i32,
+}
+
+#[derive(Deserialize, JsonSchema, Zeroize)]
+#[zeroize(drop)]
+pub struct RotateAdminRequest {
+    pub own_password: String,
+}
+
+#[derive(Serialize, JsonSchema, Zeroize)]
+#[zeroize(drop)]
+pub struct RotateAdminResponse {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, JsonSchema, Zeroize)]
+#[zeroize(drop)]
+pub struct PasswordStrengthRequest {
+    pub password: String,
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct PasswordStrengthResponse {
+    pub score: u8,
+    pub accepted: bool,
+    pub warning: Option<String>,
+    pub suggestions: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct RejectUserResponse {
+    pub email: String,
+    pub success: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct EmailChangeResponse {
+    pub success: bool,
+    pub email: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct MergeUsersRequest {
+    pub duplicate: String,
+    #[serde(default)]
+    pub dry_run: bool,
+    pub own_password: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct MergeUsersResponse {
+    pub dry_run: bool,
+    #[serde(flatten)]
+    pub merge: UserMerge,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct CreateOrganizationRequest {
+    pub name: String,
+    pub description: Option<String>,
+    pub parent: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct FullOrganizationData {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub parent: Option<String>,
+    pub teams: Vec<Organization>,
+    pub members: Vec<OrganizationMember>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DeleteOrganizationResponse {
+    pub success: bool,
+    pub name: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SetOrganizationMemberRequest {
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct RemoveOrganizationMemberResponse {
+    pub success: bool,
+    pub email: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct CreateOAuthClientRequest {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CreateOAuthClientResponse {
+    #[serde(flatten)]
+    pub client: OAuthClient,
+    pub client_secret: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DeleteOAuthClientResponse {
+    pub success: bool,
+    pub client_id: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct AuthorizeRequest {
+    pub client_id: String,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub approve: bool,
+}
+
+/// The answer to an authorization request of a client application.
+/// If the user didn't consent to the scopes yet the consent screen
+/// needs to be shown, otherwise the user is redirected to the client.
+#[derive(Serialize, JsonSchema)]
+pub struct AuthorizeResponse {
+    pub consent_required: bool,
+    pub client_name: String,
+    pub scopes: Vec<String>,
+    pub redirect_to: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema, Zeroize)]
+#[zeroize(drop)]
+pub struct OAuthTokenRequest {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema, Zeroize)]
+#[zeroize(drop)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i32,
+    pub refresh_token: String,
+    pub refresh_expires_in: i32,
+    pub scope: String,
+    /// The signed OpenID Connect id token if the openid scope was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+/// The claims about the user returned by the OpenID Connect userinfo endpoint
+#[derive(Serialize, JsonSchema)]
+pub struct UserInfoResponse {
+    pub sub: String,
+    pub name: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub roles: Vec<String>,
+}
+
+/// The OpenID Connect discovery document that describes the provider
+#[derive(Serialize, JsonSchema)]
+pub struct OpenIdConfiguration {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub scopes_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct RevokeConsentResponse {
+    pub success: bool,
+    pub client_id: String,
 }
@@ -0,0 +1,216 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::fmt::Write;
+
+use crate::database::limits::{Resource, ResourceLimits};
+use crate::database::Database;
+use crate::server::rpc_listener::RPC_CONNECTIONS;
+use crate::utils::error::DatabaseResult;
+use crate::utils::hashing_pool::HASHING_POOL;
+
+/// Builds metrics in the prometheus text exposition format
+pub struct MetricsBuilder {
+    output: String,
+}
+
+impl MetricsBuilder {
+    pub fn new() -> Self {
+        Self {
+            output: String::new(),
+        }
+    }
+
+    /// Adds a gauge with a single value
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) -> &mut Self {
+        self.gauge_with_labels(name, help, &[(&[], value)])
+    }
+
+    /// Adds a gauge with multiple values that are distinguished by their labels
+    pub fn gauge_with_labels(
+        &mut self,
+        name: &str,
+        help: &str,
+        values: &[(&[(&str, &str)], f64)],
+    ) -> &mut Self {
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} gauge", name);
+
+        for (labels, value) in values {
+            if labels.is_empty() {
+                let _ = writeln!(self.output, "{} {}", name, value);
+            } else {
+                let labels = labels
+                    .iter()
+                    .map(|(k, v)| format!("{}=\"{}\"", k, v))
+                    .collect::<Vec<String>>()
+                    .join(",");
+                let _ = writeln!(self.output, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+
+        self
+    }
+
+    pub fn build(&self) -> String {
+        self.output.clone()
+    }
+}
+
+/// Collects the metrics of the token store, the database pool and the resource limits
+pub fn collect_metrics(database: &Database) -> DatabaseResult<String> {
+    let mut builder = MetricsBuilder::new();
+    let token_stats = database.users.token_store_stats()?;
+    let pool_state = database.pool_state();
+    let limits = ResourceLimits::get();
+    let resource_labels = Resource::ALL
+        .iter()
+        .map(|resource| [("resource", resource.name())])
+        .collect::<Vec<[(&str, &str); 1]>>();
+    let limit_values = Resource::ALL
+        .iter()
+        .zip(&resource_labels)
+        .filter_map(|(resource, labels)| {
+            limits
+                .limit(*resource)
+                .map(|limit| (&labels[..], limit as f64))
+        })
+        .collect::<Vec<(&[(&str, &str)], f64)>>();
+    let rejection_values = Resource::ALL
+        .iter()
+        .zip(&resource_labels)
+        .map(|(resource, labels)| (&labels[..], limits.rejections(*resource) as f64))
+        .collect::<Vec<(&[(&str, &str)], f64)>>();
+
+    builder
+        .gauge(
+            "flotte_sessions_active",
+            "Number of sessions with a valid refresh token",
+            token_stats.active_sessions as f64,
+        )
+        .gauge(
+            "flotte_sessions_users",
+            "Number of users with at least one active session",
+            token_stats.users as f64,
+        )
+        .gauge(
+            "flotte_token_store_entries",
+            "Number of entries in the token store including expired ones",
+            token_stats.stored_entries as f64,
+        )
+        .gauge(
+            "flotte_token_store_size_bytes",
+            "Size of the sessions table including its indexes",
+            token_stats.size_bytes as f64,
+        )
+        .gauge(
+            "flotte_sessions_per_user_max",
+            "Maximum number of active sessions of a single user",
+            token_stats.max_sessions_per_user() as f64,
+        )
+        .gauge_with_labels(
+            "flotte_sessions_per_user",
+            "Percentiles of active sessions per user",
+            &[
+                (
+                    &[("quantile", "0.5")],
+                    token_stats.sessions_per_user_percentile(0.5) as f64,
+                ),
+                (
+                    &[("quantile", "0.9")],
+                    token_stats.sessions_per_user_percentile(0.9) as f64,
+                ),
+                (
+                    &[("quantile", "0.99")],
+                    token_stats.sessions_per_user_percentile(0.99) as f64,
+                ),
+            ],
+        )
+        .gauge_with_labels(
+            "flotte_db_pool_connections",
+            "Number of database connections in the pool",
+            &[
+                (
+                    &[("state", "idle")],
+                    pool_state.state.idle_connections as f64,
+                ),
+                (
+                    &[("state", "active")],
+                    pool_state.active_connections() as f64,
+                ),
+            ],
+        )
+        .gauge(
+            "flotte_db_pool_max_size",
+            "Maximum number of connections in the pool",
+            pool_state.max_size as f64,
+        )
+        .gauge(
+            "flotte_db_pool_checkouts_total",
+            "Number of connections taken from the pool",
+            pool_state.checkouts.checkouts as f64,
+        )
+        .gauge(
+            "flotte_db_pool_timeouts_total",
+            "Number of times no connection became available in time",
+            pool_state.checkouts.timeouts as f64,
+        )
+        .gauge(
+            "flotte_db_pool_long_checkouts_total",
+            "Number of connections that were held longer than the warning threshold",
+            pool_state.checkouts.long_checkouts as f64,
+        )
+        .gauge(
+            "flotte_db_pool_checkout_max_ms",
+            "Longest time a connection was held",
+            pool_state.checkouts.max_checkout_ms as f64,
+        )
+        .gauge(
+            "flotte_db_pool_active_checkout_max_ms",
+            "Time the longest currently checked out connection is held",
+            pool_state.checkouts.longest_active_checkout_ms as f64,
+        )
+        .gauge_with_labels(
+            "flotte_resource_limit",
+            "Configured maximum number of a resource",
+            &limit_values,
+        )
+        .gauge_with_labels(
+            "flotte_resource_limit_rejections_total",
+            "Number of times a resource wasn't created because its limit was reached",
+            &rejection_values,
+        )
+        .gauge(
+            "flotte_rpc_connections_open",
+            "Number of open rpc client connections",
+            RPC_CONNECTIONS.open() as f64,
+        )
+        .gauge(
+            "flotte_rpc_connections_total",
+            "Number of rpc client connections accepted since the start",
+            RPC_CONNECTIONS.accepted() as f64,
+        )
+        .gauge(
+            "flotte_rpc_connections_rejected_total",
+            "Number of rpc client connections that were closed because too many were open",
+            RPC_CONNECTIONS.rejected() as f64,
+        )
+        .gauge(
+            "flotte_rpc_idle_timeouts_total",
+            "Number of rpc client connections that were closed because they were idle",
+            RPC_CONNECTIONS.idle_timeouts() as f64,
+        )
+        .gauge(
+            "flotte_password_hashes_queued",
+            "Number of password hashes that are waiting for or running on a hashing worker",
+            HASHING_POOL.queued() as f64,
+        )
+        .gauge(
+            "flotte_password_hashes_rejected_total",
+            "Number of password hashes that were rejected because the hashing queue was full",
+            HASHING_POOL.rejected() as f64,
+        );
+
+    Ok(builder.build())
+}
@@ -2,8 +2,31 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
+pub mod access_log;
+pub mod batch;
+pub mod build_info;
+pub mod compression;
+pub mod concurrency;
+pub mod cors;
 pub mod documentation;
+pub mod effective_config;
+pub mod export;
+#[cfg(feature = "grpc")]
+pub mod grpc_server;
 pub mod http_server;
+#[cfg(feature = "hyper-server")]
+pub mod hyper_server;
 pub mod messages;
+pub mod metrics;
+pub mod naming;
+pub mod pipeline;
+pub mod quota;
+pub mod rate_limit;
+pub mod readiness;
+pub mod rpc_listener;
 pub mod rpc_methods;
+pub mod rpc_tls;
+pub mod token_access;
 pub mod user_rpc;
+pub mod validation;
+pub mod versioning;
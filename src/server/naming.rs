@@ -0,0 +1,173 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use rouille::Response;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::utils::config;
+
+pub(crate) const ENV_JSON_NAMING_CONVENTION: &str = "JSON_NAMING_CONVENTION";
+pub(crate) const DEFAULT_JSON_NAMING_CONVENTION: &str = "snake_case";
+
+/// Fields whose keys are user defined and must never be renamed
+const VERBATIM_FIELDS: &[&str] = &["attributes"];
+
+lazy_static::lazy_static! {
+    static ref NAMING_CONVENTION: NamingConvention = NamingConvention::from_env();
+}
+
+/// The naming convention of the fields in json request and response bodies.
+/// The models are defined in snake_case so renaming is only
+/// necessary if a different convention is configured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NamingConvention {
+    SnakeCase,
+    CamelCase,
+}
+
+impl NamingConvention {
+    fn from_env() -> Self {
+        let convention = config::var(ENV_JSON_NAMING_CONVENTION)
+            .unwrap_or(DEFAULT_JSON_NAMING_CONVENTION.to_string());
+
+        match convention.as_str() {
+            "camelCase" => NamingConvention::CamelCase,
+            "snake_case" => NamingConvention::SnakeCase,
+            _ => {
+                log::warn!(
+                    "Unknown {} '{}'. Using {}.",
+                    ENV_JSON_NAMING_CONVENTION,
+                    convention,
+                    DEFAULT_JSON_NAMING_CONVENTION
+                );
+                NamingConvention::SnakeCase
+            }
+        }
+    }
+}
+
+/// Returns the naming convention configured via the env
+pub fn naming_convention() -> NamingConvention {
+    *NAMING_CONVENTION
+}
+
+/// Creates a json response with the field names in the configured convention
+pub fn json_response<T: Serialize>(data: &T) -> Response {
+    if naming_convention() == NamingConvention::SnakeCase {
+        return Response::json(data);
+    }
+    match serde_json::to_value(data) {
+        Ok(mut value) => {
+            rename_keys(&mut value, &to_camel_case);
+            Response::json(&value)
+        }
+        Err(e) => {
+            log::error!("Failed to serialize response: {}", e);
+            Response::text("Failed to serialize response").with_status_code(500)
+        }
+    }
+}
+
+/// Deserializes a json body with the field names in the configured convention
+pub fn from_json_str<T: DeserializeOwned>(body: &str) -> serde_json::Result<T> {
+    if naming_convention() == NamingConvention::SnakeCase {
+        return serde_json::from_str(body);
+    }
+    from_json_value(serde_json::from_str(body)?)
+}
+
+/// Deserializes a json value with the field names in the configured convention
+pub fn from_json_value<T: DeserializeOwned>(mut value: Value) -> serde_json::Result<T> {
+    if naming_convention() != NamingConvention::SnakeCase {
+        rename_keys(&mut value, &to_snake_case);
+    }
+
+    serde_json::from_value(value)
+}
+
+/// Renames the properties of a json schema to the configured convention
+/// so that the documentation matches the actual request and response bodies
+pub fn rename_schema(schema: &mut Value) {
+    if naming_convention() == NamingConvention::SnakeCase {
+        return;
+    }
+    match schema {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match (key.as_str(), value) {
+                    ("properties", Value::Object(properties)) => {
+                        *properties = rename_map(std::mem::take(properties), &to_camel_case);
+                        properties.values_mut().for_each(rename_schema);
+                    }
+                    ("required", Value::Array(required)) => {
+                        for name in required.iter_mut() {
+                            if let Value::String(s) = name {
+                                *s = to_camel_case(s);
+                            }
+                        }
+                    }
+                    (_, value) => rename_schema(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(rename_schema),
+        _ => {}
+    }
+}
+
+/// Recursively renames all keys of a json value except for the keys
+/// of user defined fields
+fn rename_keys(value: &mut Value, rename: &dyn Fn(&str) -> String) {
+    match value {
+        Value::Object(map) => {
+            *map = rename_map(std::mem::take(map), rename);
+            for (key, value) in map.iter_mut() {
+                if !VERBATIM_FIELDS.contains(&key.as_str()) {
+                    rename_keys(value, rename);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| rename_keys(v, rename)),
+        _ => {}
+    }
+}
+
+fn rename_map(map: Map<String, Value>, rename: &dyn Fn(&str) -> String) -> Map<String, Value> {
+    map.into_iter().map(|(k, v)| (rename(&k), v)).collect()
+}
+
+fn to_camel_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut uppercase_next = false;
+
+    for c in name.chars() {
+        if c == '_' {
+            uppercase_next = true;
+        } else if uppercase_next {
+            result.extend(c.to_uppercase());
+            uppercase_next = false;
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+fn to_snake_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len() + 4);
+
+    for c in name.chars() {
+        if c.is_uppercase() {
+            result.push('_');
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
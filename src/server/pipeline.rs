@@ -0,0 +1,51 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+//! Pipelined messages let clients send further requests over a connection before
+//! the previous ones were answered. A pipelined message wraps a message with an id
+//! the client chooses as `id (u32) | method (4 bytes) | data` in big endian.
+//! The response wraps the response to the message with the same id.
+//! Pipelined messages are handled in parallel and answered as soon as they are done
+//! while the other messages of the connection are still answered in order.
+
+use byteorder::{BigEndian, ByteOrder};
+use msgrpc::message::Message;
+
+use crate::server::messages::ErrorMessage;
+use crate::server::rpc_methods::{ERROR, PIPELINE};
+
+const HEADER_LENGTH: usize = 8;
+
+/// Handles the message wrapped by a pipelined message and wraps its response
+pub fn handle_pipelined<F>(message: &Message, handler: F) -> Message
+where
+    F: FnOnce(Message) -> Message,
+{
+    match decode_pipelined(&message.data) {
+        Ok((id, message)) => encode_pipelined(id, handler(message)),
+        Err(e) => Message::new_with_serialize(ERROR, ErrorMessage::new(e)),
+    }
+}
+
+/// Returns the id and the wrapped message of a pipelined message
+fn decode_pipelined(data: &[u8]) -> Result<(u32, Message), String> {
+    if data.len() < HEADER_LENGTH {
+        return Err("The pipelined message is missing its id or method".to_string());
+    }
+    let id = BigEndian::read_u32(&data[..4]);
+    let mut method = [0u8; 4];
+    method.copy_from_slice(&data[4..HEADER_LENGTH]);
+
+    Ok((id, Message::new(method, data[HEADER_LENGTH..].to_vec())))
+}
+
+/// Wraps the response to a pipelined message with the id of the message
+fn encode_pipelined(id: u32, response: Message) -> Message {
+    let mut data = Vec::with_capacity(HEADER_LENGTH + response.data.len());
+    data.extend_from_slice(&id.to_be_bytes());
+    data.extend_from_slice(&response.method);
+    data.extend_from_slice(&response.data);
+
+    Message::new(PIPELINE, data)
+}
@@ -0,0 +1,125 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rouille::Response;
+
+const QUOTA_WINDOW: Duration = Duration::from_secs(60);
+/// The number of tracked accounts after which expired windows are removed
+const MAX_TRACKED_ACCOUNTS: usize = 10000;
+
+/// Enforces the requests-per-minute quotas of accounts.
+/// Each account has a fixed window that starts with its first request.
+/// The quota is loaded again whenever a new window starts so changes
+/// take effect after at most one minute.
+pub struct QuotaLimiter {
+    windows: Mutex<HashMap<i32, QuotaWindow>>,
+}
+
+struct QuotaWindow {
+    limit: Option<u32>,
+    started: Instant,
+    count: u32,
+}
+
+/// The state of the quota of an account after a request was counted
+#[derive(Clone, Debug)]
+pub struct QuotaStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: u64,
+}
+
+pub enum QuotaCheck {
+    Unlimited,
+    Allowed(QuotaStatus),
+    Exceeded(QuotaStatus),
+}
+
+impl QuotaLimiter {
+    pub fn new() -> Self {
+        Self {
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request of the given account. The quota is only retrieved
+    /// with the given function when a new window starts. The windows aren't
+    /// locked while the quota is retrieved so that requests of other accounts
+    /// don't wait for it.
+    pub fn check<F: FnOnce() -> Option<u32>>(&self, account_id: i32, load_quota: F) -> QuotaCheck {
+        if let Some(window) = self.windows.lock().get_mut(&account_id) {
+            if !window.is_expired() {
+                return window.count_request();
+            }
+        }
+        let limit = load_quota();
+
+        let mut windows = self.windows.lock();
+        if windows.len() >= MAX_TRACKED_ACCOUNTS {
+            windows.retain(|_, window| !window.is_expired());
+        }
+        let window = windows.entry(account_id).or_insert(QuotaWindow {
+            limit,
+            started: Instant::now(),
+            count: 0,
+        });
+        // another request of the account may have started a new window meanwhile
+        if window.is_expired() {
+            *window = QuotaWindow {
+                limit,
+                started: Instant::now(),
+                count: 0,
+            };
+        }
+
+        window.count_request()
+    }
+}
+
+impl QuotaWindow {
+    fn is_expired(&self) -> bool {
+        self.started.elapsed() >= QUOTA_WINDOW
+    }
+
+    /// Counts a request in the window if the quota allows it
+    fn count_request(&mut self) -> QuotaCheck {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return QuotaCheck::Unlimited,
+        };
+        let reset = QUOTA_WINDOW
+            .checked_sub(self.started.elapsed())
+            .unwrap_or_default()
+            .as_secs();
+
+        if self.count >= limit {
+            QuotaCheck::Exceeded(QuotaStatus {
+                limit,
+                remaining: 0,
+                reset,
+            })
+        } else {
+            self.count += 1;
+            QuotaCheck::Allowed(QuotaStatus {
+                limit,
+                remaining: limit - self.count,
+                reset,
+            })
+        }
+    }
+}
+
+impl QuotaStatus {
+    /// Adds the X-RateLimit headers to the response
+    pub fn add_headers(&self, response: Response) -> Response {
+        response
+            .with_additional_header("X-RateLimit-Limit", self.limit.to_string())
+            .with_additional_header("X-RateLimit-Remaining", self.remaining.to_string())
+            .with_additional_header("X-RateLimit-Reset", self.reset.to_string())
+    }
+}
@@ -0,0 +1,126 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+use crate::utils::config::Config;
+
+pub(crate) const ENV_IP_RATE_LIMIT: &str = "IP_RATE_LIMIT";
+pub(crate) const ENV_IP_RATE_LIMIT_BURST: &str = "IP_RATE_LIMIT_BURST";
+pub(crate) const ENV_ACCOUNT_RATE_LIMIT: &str = "ACCOUNT_RATE_LIMIT";
+pub(crate) const ENV_ACCOUNT_RATE_LIMIT_BURST: &str = "ACCOUNT_RATE_LIMIT_BURST";
+pub(crate) const DEFAULT_IP_RATE_LIMIT: u32 = 30;
+pub(crate) const DEFAULT_IP_RATE_LIMIT_BURST: u32 = 10;
+pub(crate) const DEFAULT_ACCOUNT_RATE_LIMIT: u32 = 10;
+pub(crate) const DEFAULT_ACCOUNT_RATE_LIMIT_BURST: u32 = 5;
+
+/// The number of tracked keys after which full buckets are removed.
+/// If all buckets are in use the fullest ones are removed to stay below it.
+const MAX_TRACKED_KEYS: usize = 10000;
+
+/// The endpoints that are rate limited per client address
+pub const RATE_LIMITED_PATHS: &[&str] = &[
+    "/login",
+    "/new-token",
+    "/register",
+    "/change-password",
+    "/oauth/token",
+];
+
+lazy_static::lazy_static! {
+    pub static ref IP_RATE_LIMITER: RateLimiter = RateLimiter::new(
+        Config::get().rate_limits.ip_limit,
+        Config::get().rate_limits.ip_burst,
+    );
+    pub static ref ACCOUNT_RATE_LIMITER: RateLimiter = RateLimiter::new(
+        Config::get().rate_limits.account_limit,
+        Config::get().rate_limits.account_burst,
+    );
+}
+
+/// Limits the number of requests per key with a token bucket.
+/// Each key can make a burst of requests after which the bucket
+/// is refilled with the configured number of requests per minute.
+pub struct RateLimiter {
+    tokens_per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter that allows the given number of requests per minute.
+    /// A limit of 0 disables the limiter.
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        Self {
+            tokens_per_second: per_minute as f64 / 60.0,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of the key. If the bucket is empty
+    /// the number of seconds until the next request is allowed is returned.
+    pub fn check(&self, key: &str) -> Result<(), u64> {
+        if self.tokens_per_second <= 0.0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= MAX_TRACKED_KEYS && !buckets.contains_key(key) {
+            self.remove_full_buckets(&mut buckets);
+            if buckets.len() >= MAX_TRACKED_KEYS {
+                self.remove_fullest_bucket(&mut buckets);
+            }
+        }
+        let now = Instant::now();
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refilled_tokens(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.tokens_per_second).ceil() as u64)
+        }
+    }
+
+    fn refilled_tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+
+        (bucket.tokens + elapsed * self.tokens_per_second).min(self.burst)
+    }
+
+    /// Removes the buckets of keys that didn't make a request
+    /// for long enough that they are full again
+    fn remove_full_buckets(&self, buckets: &mut HashMap<String, Bucket>) {
+        let now = Instant::now();
+        buckets.retain(|_, bucket| self.refilled_tokens(bucket, now) < self.burst);
+    }
+
+    /// Removes the bucket that limits its key the least
+    /// so that the map of buckets can't grow without bound
+    fn remove_fullest_bucket(&self, buckets: &mut HashMap<String, Bucket>) {
+        let now = Instant::now();
+        let fullest = buckets
+            .iter()
+            .map(|(key, bucket)| (key, self.refilled_tokens(bucket, now)))
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .map(|(key, _)| key.clone());
+        if let Some(key) = fullest {
+            buckets.remove(&key);
+        }
+    }
+}
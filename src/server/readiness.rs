@@ -0,0 +1,87 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::database::Database;
+use crate::server::messages::{LivenessResponse, ReadinessResponse};
+
+/// The interval the RPC worker pool reports that it is responsive in
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// The number of missed heartbeats after which the RPC workers count as stuck
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+lazy_static::lazy_static! {
+    pub static ref READINESS: Readiness = Readiness::new();
+}
+
+/// The state of the server components that is shared between the
+/// HTTP and the RPC threads to answer the readiness and liveness probes.
+/// The server is ready when it can handle requests and live as long as
+/// none of its threads are stuck.
+pub struct Readiness {
+    database_initialized: AtomicBool,
+    rpc_listening: AtomicBool,
+    rpc_heartbeat: Mutex<Option<Instant>>,
+}
+
+impl Readiness {
+    fn new() -> Self {
+        Self {
+            database_initialized: AtomicBool::new(false),
+            rpc_listening: AtomicBool::new(false),
+            rpc_heartbeat: Mutex::new(None),
+        }
+    }
+
+    /// Marks that the tables and migrations of the database have been applied
+    pub fn set_database_initialized(&self) {
+        self.database_initialized.store(true, Ordering::SeqCst);
+    }
+
+    /// Sets if the RPC server is listening for connections
+    pub fn set_rpc_listening(&self, listening: bool) {
+        self.rpc_listening.store(listening, Ordering::SeqCst);
+    }
+
+    /// Records that the RPC worker pool is still executing jobs
+    pub fn rpc_heartbeat(&self) {
+        *self.rpc_heartbeat.lock() = Some(Instant::now());
+    }
+
+    /// Returns if the server can handle requests. The database is only
+    /// checked once the other components are ready.
+    pub fn readiness(&self, database: &Database) -> ReadinessResponse {
+        let migrations_applied = self.database_initialized.load(Ordering::SeqCst);
+        let rpc_listening = self.rpc_listening.load(Ordering::SeqCst);
+        let database_reachable = migrations_applied && rpc_listening && database.is_healthy();
+
+        ReadinessResponse {
+            ready: database_reachable,
+            database_reachable,
+            migrations_applied,
+            rpc_listening,
+        }
+    }
+
+    /// Returns if the server is responsive. The HTTP server answering the probe
+    /// shows that it is responsive so only the RPC workers need to be checked.
+    /// This never checks the database so that an outage of the database
+    /// doesn't cause the server to be restarted.
+    pub fn liveness(&self) -> LivenessResponse {
+        let heartbeat_age = self.rpc_heartbeat.lock().map(|beat| beat.elapsed());
+        let live = match heartbeat_age {
+            Some(age) => age < HEARTBEAT_INTERVAL * MAX_MISSED_HEARTBEATS,
+            None => true,
+        };
+
+        LivenessResponse {
+            live,
+            rpc_heartbeat_age_ms: heartbeat_age.map(|age| age.as_millis() as u64),
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+//! The connections of rpc clients. Every message is framed as
+//! `length (u32) | method (4 bytes) | data | crc32 (u32)` in big endian
+//! where the length includes the whole frame and the checksum covers
+//! everything before it. This is the framing of the msgrpc protocol.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::Builder;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use msgrpc::message::Message;
+use parking_lot::Mutex;
+use rustls::ServerConfig;
+use scheduled_thread_pool::ScheduledThreadPool;
+
+use crate::server::rpc_methods::PIPELINE;
+use crate::server::{pipeline, rpc_tls};
+use crate::utils::config::RpcConfig;
+
+pub(crate) const ENV_RPC_IDLE_TIMEOUT: &str = "RPC_IDLE_TIMEOUT";
+pub(crate) const ENV_RPC_MAX_CONNECTIONS: &str = "RPC_MAX_CONNECTIONS";
+pub(crate) const DEFAULT_RPC_IDLE_TIMEOUT: u64 = 300;
+pub(crate) const DEFAULT_RPC_MAX_CONNECTIONS: usize = 256;
+/// The interval in which blocked reads check if the connection has been idle for too long
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The size of the length, the method and the checksum of a frame
+const FRAME_OVERHEAD: usize = 12;
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+/// The number of pipelined messages of a connection that are handled at the same time.
+/// Further messages are handled by the connection itself until one of them is done.
+const MAX_PIPELINED_MESSAGES: usize = 32;
+
+lazy_static::lazy_static! {
+    pub static ref RPC_CONNECTIONS: ConnectionStats = ConnectionStats::default();
+}
+
+/// Handles a message of a client and returns the response
+pub type MessageHandler = Arc<dyn Fn(Message) -> Message + Send + Sync>;
+
+/// Counters of the connections of rpc clients
+#[derive(Default)]
+pub struct ConnectionStats {
+    open: AtomicUsize,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    idle_timeouts: AtomicU64,
+}
+
+impl ConnectionStats {
+    /// Returns the number of currently open connections
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections accepted since the start
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections that were closed because
+    /// the maximum number of connections was open
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections that were closed because they were idle
+    pub fn idle_timeouts(&self) -> u64 {
+        self.idle_timeouts.load(Ordering::Relaxed)
+    }
+}
+
+/// Accepts the connections of rpc clients and handles their messages.
+/// Every connection is served by its own thread that handles its messages in order.
+/// Pipelined messages are handled in parallel by the workers of the listener.
+/// Connections over the maximum are closed right away.
+/// The connections are encrypted with TLS if a certificate is configured.
+pub struct RpcListener {
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    workers: Arc<ScheduledThreadPool>,
+    /// The time after which connections without any messages are closed
+    idle_timeout: Option<Duration>,
+    max_connections: usize,
+}
+
+impl RpcListener {
+    pub fn bind(settings: &RpcConfig) -> io::Result<Self> {
+        let tls = rpc_tls::server_config()?;
+        if tls.is_some() {
+            log::info!("RPC connections are encrypted with TLS");
+        }
+
+        Ok(Self::new(
+            TcpListener::bind(&settings.listen_address)?,
+            tls,
+            settings,
+        ))
+    }
+
+    fn new(listener: TcpListener, tls: Option<Arc<ServerConfig>>, settings: &RpcConfig) -> Self {
+        Self {
+            listener,
+            tls,
+            workers: Arc::new(ScheduledThreadPool::with_name(
+                "rpc-worker-{}",
+                num_cpus::get() * 2,
+            )),
+            idle_timeout: Some(settings.idle_timeout)
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            max_connections: settings.max_connections.max(1),
+        }
+    }
+
+    /// Returns the address the listener is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the listener fails. Every connection gets its own
+    /// handler from `connection_handler` so that it can keep the state of the connection.
+    pub fn serve<F>(&self, connection_handler: F) -> io::Result<()>
+    where
+        F: Fn() -> MessageHandler,
+    {
+        loop {
+            let (client, address) = self.listener.accept()?;
+            RPC_CONNECTIONS.accepted.fetch_add(1, Ordering::Relaxed);
+            if RPC_CONNECTIONS.open.fetch_add(1, Ordering::SeqCst) >= self.max_connections {
+                RPC_CONNECTIONS.open.fetch_sub(1, Ordering::SeqCst);
+                RPC_CONNECTIONS.rejected.fetch_add(1, Ordering::Relaxed);
+                log::warn!(
+                    "Rejected the rpc connection of {} because {} connections are open",
+                    address,
+                    self.max_connections
+                );
+                continue;
+            }
+            let tls = self.tls.clone();
+            let workers = Arc::clone(&self.workers);
+            let handler = connection_handler();
+            let idle_timeout = self.idle_timeout;
+            let result = Builder::new()
+                .name("rpc-connection".to_string())
+                .spawn(move || {
+                    if let Err(e) = serve_connection(client, tls, idle_timeout, handler, workers) {
+                        log::debug!("RPC connection of {} failed: {}", address, e);
+                    }
+                    RPC_CONNECTIONS.open.fetch_sub(1, Ordering::SeqCst);
+                });
+            if let Err(e) = result {
+                RPC_CONNECTIONS.open.fetch_sub(1, Ordering::SeqCst);
+                log::error!("Failed to start the thread of an rpc connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Handles the messages of a client until it closes the connection or it times out
+fn serve_connection(
+    client: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    idle_timeout: Option<Duration>,
+    handler: MessageHandler,
+    workers: Arc<ScheduledThreadPool>,
+) -> io::Result<()> {
+    client.set_nodelay(true)?;
+    client.set_read_timeout(Some(IDLE_CHECK_INTERVAL))?;
+    let mut connection = Connection {
+        idle_timeout,
+        last_activity: Instant::now(),
+    };
+
+    match tls {
+        Some(config) => {
+            let (reader, writer) = rpc_tls::split(client, config)?;
+            connection.handle_messages(reader, writer, handler, &workers)
+        }
+        None => connection.handle_messages(client.try_clone()?, client, handler, &workers),
+    }
+}
+
+struct Connection {
+    idle_timeout: Option<Duration>,
+    last_activity: Instant,
+}
+
+impl Connection {
+    fn handle_messages<R: Read, W: Write + Send + 'static>(
+        &mut self,
+        mut reader: R,
+        writer: W,
+        handler: MessageHandler,
+        workers: &ScheduledThreadPool,
+    ) -> io::Result<()> {
+        let writer = Arc::new(Mutex::new(writer));
+        let pipelined = Arc::new(AtomicUsize::new(0));
+
+        while let Some(message) = self.read_message(&mut reader)? {
+            if message.method != PIPELINE {
+                write_message(&writer, &handler(message))?;
+            } else if pipelined.fetch_add(1, Ordering::SeqCst) < MAX_PIPELINED_MESSAGES {
+                let writer = Arc::clone(&writer);
+                let handler = Arc::clone(&handler);
+                let pipelined = Arc::clone(&pipelined);
+                workers.execute(move || {
+                    let response = pipeline::handle_pipelined(&message, |message| handler(message));
+                    if let Err(e) = write_message(&writer, &response) {
+                        log::debug!("Failed to send a pipelined rpc response: {}", e);
+                    }
+                    pipelined.fetch_sub(1, Ordering::SeqCst);
+                });
+            } else {
+                pipelined.fetch_sub(1, Ordering::SeqCst);
+                let response = pipeline::handle_pipelined(&message, |message| handler(message));
+                write_message(&writer, &response)?;
+            }
+            self.last_activity = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next message of the client. Returns None if the client
+    /// closed the connection or it was idle for too long.
+    fn read_message<R: Read>(&mut self, reader: &mut R) -> io::Result<Option<Message>> {
+        let mut frame = vec![0u8; 4];
+        if !self.read_exact(reader, &mut frame, true)? {
+            return Ok(None);
+        }
+        let length = BigEndian::read_u32(&frame) as usize;
+        if !(FRAME_OVERHEAD..=MAX_FRAME_LENGTH).contains(&length) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid message length {}", length),
+            ));
+        }
+        frame.resize(length, 0);
+        self.read_exact(reader, &mut frame[4..], false)?;
+
+        decode_message(&frame).map(Some)
+    }
+
+    /// Fills the buffer with the data of the client. If the client closes the
+    /// connection before any data was read false is returned when closing is allowed.
+    fn read_exact<R: Read>(
+        &mut self,
+        reader: &mut R,
+        buffer: &mut [u8],
+        allow_close: bool,
+    ) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match reader.read(&mut buffer[filled..]) {
+                Ok(0) if filled == 0 && allow_close => return Ok(false),
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(length) => {
+                    filled += length;
+                    self.last_activity = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    let idle = self.last_activity.elapsed();
+                    if matches!(self.idle_timeout, Some(timeout) if idle >= timeout) {
+                        RPC_CONNECTIONS
+                            .idle_timeouts
+                            .fetch_add(1, Ordering::Relaxed);
+                        log::debug!(
+                            "Closing rpc connection that was idle for {}s",
+                            idle.as_secs()
+                        );
+                        return Ok(false);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Returns the message of a complete frame after verifying its checksum
+fn decode_message(frame: &[u8]) -> io::Result<Message> {
+    let (content, checksum) = frame.split_at(frame.len() - 4);
+    if crc32fast::hash(content) != BigEndian::read_u32(checksum) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Invalid message checksum",
+        ));
+    }
+    let mut method = [0u8; 4];
+    method.copy_from_slice(&content[4..8]);
+
+    Ok(Message::new(method, content[8..].to_vec()))
+}
+
+/// Sends the frame of a message to the client
+fn write_message<W: Write>(writer: &Mutex<W>, message: &Message) -> io::Result<()> {
+    let frame = encode_message(message);
+    let mut writer = writer.lock();
+    writer.write_all(&frame)?;
+
+    writer.flush()
+}
+
+/// Returns the frame of a message
+fn encode_message(message: &Message) -> Vec<u8> {
+    let length = message.data.len() + FRAME_OVERHEAD;
+    let mut frame = Vec::with_capacity(length);
+    frame.extend_from_slice(&(length as u32).to_be_bytes());
+    frame.extend_from_slice(&message.method);
+    frame.extend_from_slice(&message.data);
+    let checksum = crc32fast::hash(&frame);
+    frame.extend_from_slice(&checksum.to_be_bytes());
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn settings() -> RpcConfig {
+        RpcConfig {
+            listen_address: "127.0.0.1:0".to_string(),
+            idle_timeout: DEFAULT_RPC_IDLE_TIMEOUT,
+            max_connections: DEFAULT_RPC_MAX_CONNECTIONS,
+        }
+    }
+
+    /// Starts a listener on a free port that answers every message with its data
+    fn start_echo_listener(tls: Option<Arc<ServerConfig>>) -> SocketAddr {
+        let listener =
+            RpcListener::new(TcpListener::bind("127.0.0.1:0").unwrap(), tls, &settings());
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || listener.serve(|| Arc::new(|message| message)));
+
+        address
+    }
+
+    fn read_response<R: Read>(reader: &mut R) -> io::Result<Option<Message>> {
+        Connection {
+            idle_timeout: Some(Duration::from_secs(5)),
+            last_activity: Instant::now(),
+        }
+        .read_message(reader)
+    }
+
+    #[test]
+    fn it_answers_framed_messages() {
+        let mut client = TcpStream::connect(start_echo_listener(None)).unwrap();
+        let message = Message::new(*b"PING", vec![1, 2, 3]);
+        client.write_all(&encode_message(&message)).unwrap();
+
+        let response = read_response(&mut client).unwrap().unwrap();
+        assert_eq!(response.method, *b"PING");
+        assert_eq!(response.data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn it_answers_pipelined_messages_with_their_ids() {
+        let mut client = TcpStream::connect(start_echo_listener(None)).unwrap();
+        for id in 1u32..=3 {
+            let mut data = id.to_be_bytes().to_vec();
+            data.extend_from_slice(b"PING");
+            data.push(id as u8);
+            client
+                .write_all(&encode_message(&Message::new(PIPELINE, data)))
+                .unwrap();
+        }
+
+        let mut ids = (0..3)
+            .map(|_| {
+                let response = read_response(&mut client).unwrap().unwrap();
+                assert_eq!(response.method, PIPELINE);
+                assert_eq!(&response.data[4..8], b"PING");
+                assert_eq!(response.data[8], response.data[3]);
+                BigEndian::read_u32(&response.data[..4])
+            })
+            .collect::<Vec<u32>>();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn it_closes_connections_with_invalid_checksums() {
+        let mut client = TcpStream::connect(start_echo_listener(None)).unwrap();
+        let mut frame = encode_message(&Message::new(*b"PING", vec![1, 2, 3]));
+        let last = frame.len() - 1;
+        frame[last] ^= 0xff;
+        client.write_all(&frame).unwrap();
+
+        assert!(matches!(read_response(&mut client), Ok(None) | Err(_)));
+    }
+
+    fn fixture(name: &str) -> String {
+        format!("{}/testdata/rpc_tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Starts a TLS listener that requires client certificates signed by the test CA
+    fn start_mutual_tls_listener() -> SocketAddr {
+        let config = rpc_tls::build_server_config(
+            &fixture("server.pem"),
+            &fixture("server.key"),
+            Some(&fixture("ca.pem")),
+        )
+        .unwrap();
+
+        start_echo_listener(Some(config))
+    }
+
+    /// Connects to the listener with the given client certificate and sends a message.
+    /// Returns the response if the server accepted the connection.
+    fn send_with_client_cert(address: SocketAddr, client_cert: Option<&str>) -> Option<Message> {
+        let read_pem = |path: String| {
+            rustls_pemfile::read_all(&mut io::BufReader::new(std::fs::File::open(path).unwrap()))
+                .unwrap()
+        };
+        let mut roots = rustls::RootCertStore::empty();
+        for item in read_pem(fixture("ca.pem")) {
+            if let rustls_pemfile::Item::X509Certificate(der) = item {
+                roots.add(&rustls::Certificate(der)).unwrap();
+            }
+        }
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some(name) => {
+                let certificates = read_pem(fixture(&format!("{}.pem", name)))
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        rustls_pemfile::Item::X509Certificate(der) => {
+                            Some(rustls::Certificate(der))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                let key = read_pem(fixture(&format!("{}.key", name)))
+                    .into_iter()
+                    .find_map(|item| match item {
+                        rustls_pemfile::Item::PKCS8Key(der) => Some(rustls::PrivateKey(der)),
+                        _ => None,
+                    })
+                    .unwrap();
+                builder.with_single_cert(certificates, key).unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let connection =
+            rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap())
+                .unwrap();
+        let socket = TcpStream::connect(address).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut stream = rustls::StreamOwned::new(connection, socket);
+        stream
+            .write_all(&encode_message(&Message::new(*b"PING", vec![1, 2, 3])))
+            .ok()?;
+
+        read_response(&mut stream).ok().flatten()
+    }
+
+    #[test]
+    fn it_accepts_clients_with_a_trusted_certificate() {
+        let response = send_with_client_cert(start_mutual_tls_listener(), Some("client")).unwrap();
+        assert_eq!(response.data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn it_refuses_clients_without_a_certificate() {
+        assert!(send_with_client_cert(start_mutual_tls_listener(), None).is_none());
+    }
+
+    #[test]
+    fn it_refuses_clients_with_an_untrusted_certificate() {
+        assert!(send_with_client_cert(start_mutual_tls_listener(), Some("untrusted")).is_none());
+    }
+}
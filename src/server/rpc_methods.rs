@@ -6,10 +6,21 @@
 
 pub(crate) const NULL: [u8; 4] = [0x00, 0x00, 0x00, 0x00];
 pub(crate) const ERROR: [u8; 4] = [0x0F, 0x0F, 0x0F, 0x0F];
+pub(crate) const DEFLATE: [u8; 4] = [0x44, 0x46, 0x4c, 0x54];
+pub(crate) const BATCH: [u8; 4] = [0x42, 0x54, 0x43, 0x48];
+pub(crate) const PIPELINE: [u8; 4] = [0x50, 0x49, 0x50, 0x45];
 pub(crate) const INFO: [u8; 4] = [0x49, 0x4e, 0x46, 0x4f];
+pub(crate) const VERSION: [u8; 4] = [0x56, 0x45, 0x52, 0x53];
+pub(crate) const HELLO: [u8; 4] = [0x48, 0x45, 0x4c, 0x4f];
+pub(crate) const PING: [u8; 4] = [0x50, 0x49, 0x4e, 0x47];
 pub(crate) const VALIDATE_TOKEN: [u8; 4] = [0x56, 0x41, 0x4c, 0x49];
 pub(crate) const GET_ROLES: [u8; 4] = [0x52, 0x4f, 0x4c, 0x45];
 pub(crate) const GET_ROLE_PERMISSIONS: [u8; 4] = [0x50, 0x45, 0x52, 0x4d];
 pub(crate) const CREATE_ROLE: [u8; 4] = [0x43, 0x52, 0x4f, 0x4c];
 pub(crate) const CREATE_PERMISSION: [u8; 4] = [0x43, 0x50, 0x45, 0x52];
+pub(crate) const ASSIGN_ROLES: [u8; 4] = [0x41, 0x52, 0x4f, 0x4c];
 pub(crate) const GET_USER_ID: [u8; 4] = [0x55, 0x53, 0x45, 0x52];
+pub(crate) const GET_USER: [u8; 4] = [0x47, 0x55, 0x53, 0x52];
+pub(crate) const RESOLVE_USERS: [u8; 4] = [0x52, 0x55, 0x53, 0x52];
+pub(crate) const GET_TOKEN_PERMISSIONS: [u8; 4] = [0x54, 0x50, 0x45, 0x52];
+pub(crate) const GET_ORGANIZATION_PERMISSIONS: [u8; 4] = [0x4f, 0x50, 0x45, 0x52];
@@ -0,0 +1,187 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection};
+use rustls_pemfile::Item;
+
+use crate::utils::config;
+
+pub(crate) const ENV_RPC_TLS_CERT: &str = "RPC_TLS_CERT";
+pub(crate) const ENV_RPC_TLS_KEY: &str = "RPC_TLS_KEY";
+pub(crate) const ENV_RPC_TLS_CLIENT_CA: &str = "RPC_TLS_CLIENT_CA";
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Loads the TLS configuration of the rpc server if a certificate and key are configured.
+/// Clients have to present a certificate signed by one of the client CAs if they are configured.
+pub fn server_config() -> io::Result<Option<Arc<ServerConfig>>> {
+    let (cert_path, key_path) = match (
+        config::var(ENV_RPC_TLS_CERT).ok(),
+        config::var(ENV_RPC_TLS_KEY).ok(),
+    ) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(invalid_input(format!(
+                "{} and {} have to be set together",
+                ENV_RPC_TLS_CERT, ENV_RPC_TLS_KEY
+            )))
+        }
+    };
+    let client_ca_path = config::var(ENV_RPC_TLS_CLIENT_CA).ok();
+
+    build_server_config(&cert_path, &key_path, client_ca_path.as_deref()).map(Some)
+}
+
+/// Creates the TLS configuration from the PEM files of the certificate, its key
+/// and optionally the CAs that have to sign the certificates of clients
+pub fn build_server_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> io::Result<Arc<ServerConfig>> {
+    let certificates = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<Certificate>>();
+    if certificates.is_empty() {
+        return Err(invalid_input(format!(
+            "No certificate found in {}",
+            cert_path
+        )));
+    }
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or(invalid_input(format!(
+            "No private key found in {}",
+            key_path
+        )))?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = if let Some(ca_path) = client_ca_path {
+        let mut roots = RootCertStore::empty();
+        for item in read_pem(ca_path)? {
+            if let Item::X509Certificate(der) = item {
+                roots
+                    .add(&Certificate(der))
+                    .map_err(|e| invalid_input(format!("Invalid CA in {}: {}", ca_path, e)))?;
+            }
+        }
+        if roots.is_empty() {
+            return Err(invalid_input(format!("No CA found in {}", ca_path)));
+        }
+        builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+    } else {
+        builder.with_no_client_auth()
+    };
+    let config = builder
+        .with_single_cert(certificates, key)
+        .map_err(|e| invalid_input(e.to_string()))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Starts a TLS session on the socket and returns a reader and a writer
+/// for the decrypted data that can be used by different threads
+pub fn split(socket: TcpStream, config: Arc<ServerConfig>) -> io::Result<(TlsReader, TlsWriter)> {
+    let connection = ServerConnection::new(config).map_err(|e| invalid_input(e.to_string()))?;
+    let connection = Arc::new(Mutex::new(connection));
+
+    Ok((
+        TlsReader {
+            socket: socket.try_clone()?,
+            connection: Arc::clone(&connection),
+            pending: Vec::new(),
+        },
+        TlsWriter { socket, connection },
+    ))
+}
+
+/// Reads the decrypted data of a TLS session. The socket is read
+/// without holding the lock of the session so that the writer
+/// isn't blocked while waiting for data of the client.
+pub struct TlsReader {
+    socket: TcpStream,
+    connection: Arc<Mutex<ServerConnection>>,
+    pending: Vec<u8>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // the previous record can contain more data than the caller requested
+            match self.connection.lock().reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            if self.pending.is_empty() {
+                let mut received = [0u8; BUFFER_SIZE];
+                let length = self.socket.read(&mut received)?;
+                if length == 0 {
+                    return Ok(0);
+                }
+                self.pending.extend_from_slice(&received[..length]);
+            }
+            let mut connection = self.connection.lock();
+            let consumed = connection.read_tls(&mut self.pending.as_slice())?;
+            self.pending.drain(..consumed);
+            let result = connection.process_new_packets();
+            // sends handshake messages and alerts
+            write_pending(&mut connection, &mut self.socket)?;
+            result.map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+    }
+}
+
+/// Encrypts data and sends it to the client of a TLS session
+pub struct TlsWriter {
+    socket: TcpStream,
+    connection: Arc<Mutex<ServerConnection>>,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.connection.lock();
+        let length = connection.writer().write(buf)?;
+        write_pending(&mut connection, &mut self.socket)?;
+
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        write_pending(&mut self.connection.lock(), &mut self.socket)
+    }
+}
+
+fn write_pending(connection: &mut ServerConnection, socket: &mut TcpStream) -> io::Result<()> {
+    while connection.wants_write() {
+        connection.write_tls(socket)?;
+    }
+
+    Ok(())
+}
+
+fn read_pem(path: &str) -> io::Result<Vec<Item>> {
+    let file = File::open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to open {}: {}", path, e)))?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message)
+}
@@ -0,0 +1,165 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::HashSet;
+
+use crate::database::models::Role;
+use crate::database::tokens::{TokenKind, TokenStoreEntry};
+use crate::database::Database;
+use crate::utils::error::DatabaseResult;
+
+/// How much of the authority of its user a request token grants to services
+#[derive(Debug, PartialEq)]
+pub enum TokenAccess {
+    /// The token can't be used with services
+    Denied,
+    /// The token grants the roles and permissions of the user
+    Full,
+    /// The token only grants the permissions of its scopes or its guest whitelist
+    Restricted,
+}
+
+impl TokenAccess {
+    pub fn of(kind: &TokenKind) -> Self {
+        match kind {
+            TokenKind::Session | TokenKind::Impersonation { .. } => TokenAccess::Full,
+            TokenKind::Client { .. } | TokenKind::Guest(_) => TokenAccess::Restricted,
+            TokenKind::PasswordChange => TokenAccess::Denied,
+        }
+    }
+}
+
+/// Returns the session of a request token if it can be used with services
+pub fn service_token_entry(
+    database: &Database,
+    token: &String,
+) -> DatabaseResult<Option<TokenStoreEntry>> {
+    Ok(database
+        .users
+        .get_request_token_entry(token)?
+        .filter(|entry| TokenAccess::of(entry.kind()) != TokenAccess::Denied))
+}
+
+/// Returns the result of validating the session of a token.
+/// Tokens that only allow changing the password are reported as invalid.
+pub fn validation_result(entry: Option<&TokenStoreEntry>) -> (bool, i32) {
+    match entry {
+        Some(entry) if TokenAccess::of(entry.kind()) != TokenAccess::Denied => {
+            (true, entry.request_ttl())
+        }
+        _ => (false, -1),
+    }
+}
+
+/// Returns the roles of the user that a token of the kind may reveal.
+/// Roles imply all of their permissions, so restricted tokens get none.
+pub fn visible_roles(kind: &TokenKind, roles: Vec<Role>) -> Vec<Role> {
+    match TokenAccess::of(kind) {
+        TokenAccess::Full => roles.into_iter().filter(|role| role.enabled).collect(),
+        TokenAccess::Restricted | TokenAccess::Denied => Vec::new(),
+    }
+}
+
+/// Restricts the permissions of the user to the ones a token of the kind grants
+/// the same way [crate::database::users::Users::get_token_permissions] does
+pub fn granted_permissions(kind: &TokenKind, permissions: HashSet<String>) -> HashSet<String> {
+    match kind {
+        TokenKind::Session | TokenKind::Impersonation { .. } => permissions,
+        TokenKind::Client { scopes, .. } => permissions
+            .into_iter()
+            .filter(|permission| scopes.contains(permission))
+            .collect(),
+        TokenKind::Guest(whitelist) => whitelist.iter().cloned().collect(),
+        TokenKind::PasswordChange => HashSet::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles() -> Vec<Role> {
+        vec![
+            Role {
+                id: 1,
+                name: "admin".to_string(),
+                description: String::new(),
+                enabled: true,
+            },
+            Role {
+                id: 2,
+                name: "disabled".to_string(),
+                description: String::new(),
+                enabled: false,
+            },
+        ]
+    }
+
+    fn permissions() -> HashSet<String> {
+        vec!["users.read".to_string(), "users.write".to_string()]
+            .into_iter()
+            .collect()
+    }
+
+    fn entry(kind: TokenKind) -> TokenStoreEntry {
+        TokenStoreEntry::new(1, kind, 60, 3600)
+    }
+
+    #[test]
+    fn session_tokens_grant_the_user_authority() {
+        let kind = TokenKind::Session;
+        assert_eq!(validation_result(Some(&entry(kind.clone()))), (true, 60));
+        let roles = visible_roles(&kind, roles());
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[0].name, "admin");
+        assert_eq!(granted_permissions(&kind, permissions()), permissions());
+    }
+
+    #[test]
+    fn impersonation_tokens_grant_the_user_authority() {
+        let kind = TokenKind::Impersonation { admin_id: 2 };
+        assert_eq!(validation_result(Some(&entry(kind.clone()))), (true, 60));
+        assert_eq!(visible_roles(&kind, roles()).len(), 1);
+        assert_eq!(granted_permissions(&kind, permissions()), permissions());
+    }
+
+    #[test]
+    fn password_change_tokens_are_invalid() {
+        let kind = TokenKind::PasswordChange;
+        assert_eq!(TokenAccess::of(&kind), TokenAccess::Denied);
+        assert_eq!(validation_result(Some(&entry(kind.clone()))), (false, -1));
+        assert!(visible_roles(&kind, roles()).is_empty());
+        assert!(granted_permissions(&kind, permissions()).is_empty());
+    }
+
+    #[test]
+    fn client_tokens_are_restricted_to_their_scopes() {
+        let kind = TokenKind::Client {
+            client_id: "client".to_string(),
+            scopes: vec!["users.read".to_string(), "roles.read".to_string()],
+        };
+        assert_eq!(TokenAccess::of(&kind), TokenAccess::Restricted);
+        assert_eq!(validation_result(Some(&entry(kind.clone()))), (true, 60));
+        assert!(visible_roles(&kind, roles()).is_empty());
+        let granted = granted_permissions(&kind, permissions());
+        assert_eq!(granted.len(), 1);
+        assert!(granted.contains("users.read"));
+    }
+
+    #[test]
+    fn guest_tokens_are_restricted_to_their_whitelist() {
+        let kind = TokenKind::Guest(vec!["public.read".to_string()]);
+        assert_eq!(TokenAccess::of(&kind), TokenAccess::Restricted);
+        assert_eq!(validation_result(Some(&entry(kind.clone()))), (true, 60));
+        assert!(visible_roles(&kind, roles()).is_empty());
+        let granted = granted_permissions(&kind, permissions());
+        assert_eq!(granted.len(), 1);
+        assert!(granted.contains("public.read"));
+    }
+
+    #[test]
+    fn unknown_tokens_are_invalid() {
+        assert_eq!(validation_result(None), (false, -1));
+    }
+}
@@ -2,83 +2,239 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread::Builder;
+use std::time::{Duration, Instant};
 
 use msgrpc::message::Message;
-use msgrpc::server::RpcServer;
 use rmp_serde::Deserializer;
 use scheduled_thread_pool::ScheduledThreadPool;
 use serde::Deserialize;
 
+use crate::database::change_history;
+use crate::database::models::{Permission, ResolvedUser, Role};
+use crate::database::tokens::{TokenStoreEntry, GUEST_USER_ID};
 use crate::database::Database;
+use crate::server::batch::{self, MAX_BATCH_SIZE};
+use crate::server::build_info::BuildInfo;
 use crate::server::messages::{
-    CreatePermissionsRequest, ErrorMessage, GetPermissionsRequest, InfoEntry, ModifyRoleRequest,
-    TokenRequest,
+    AssignRolesRequest, CreatePermissionsRequest, ErrorMessage, GetPermissionsRequest,
+    HandshakeRequest, HandshakeResponse, InfoEntry, InfoRequest, ModifyRoleRequest,
+    OrganizationPermissionsRequest, ProtocolDescription, ResolveUsersRequest, TokenRequest,
+    TokenUserResponse, TraceContext,
 };
+use crate::server::readiness::{HEARTBEAT_INTERVAL, READINESS};
+use crate::server::rpc_listener::{MessageHandler, RpcListener};
+use crate::server::token_access::{self, granted_permissions, validation_result, visible_roles};
+use crate::server::{access_log, compression};
+use crate::utils::config::{Config, RpcConfig};
 use crate::utils::get_user_id_from_token;
 
 use super::rpc_methods::*;
 
-const RPC_SERVER_ADDRESS: &str = "RPC_SERVER_ADDRESS";
-const DEFAULT_SERVER_ADDRESS: &str = "127.0.0.1:5555";
+pub(crate) const RPC_SERVER_ADDRESS: &str = "RPC_SERVER_ADDRESS";
+pub(crate) const DEFAULT_SERVER_ADDRESS: &str = "127.0.0.1:5555";
+const MAX_RESOLVE_USERS: usize = 1000;
+/// The version of the rpc protocol. It's increased when messages change
+/// in a way that clients of the previous version would misinterpret.
+pub(crate) const PROTOCOL_VERSION: u32 = 2;
+/// The oldest protocol version the server still answers
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 1;
 
 /// The RPC server that provides an interface
 /// for applications to validate request tokens
 /// and request the assigned roles
 pub struct UserRpcServer {
     database: Database,
+    settings: RpcConfig,
 }
 
 type RpcResult<T> = Result<T, ErrorMessage>;
 
+/// The state of a client connection that was agreed on in the handshake
+#[derive(Default)]
+struct Session {
+    /// If large responses are compressed even if the request wasn't
+    compress_responses: AtomicBool,
+}
+
 impl UserRpcServer {
-    pub fn new(database: &Database) -> Self {
+    pub fn new(database: &Database, config: &Config) -> Self {
         Self {
             database: Database::clone(database),
+            settings: config.rpc.clone(),
         }
     }
 
-    /// Stats the user rpc server with 2 x num-cpus threads.
+    /// Starts the user rpc server. Every connection handles its messages one at a time
+    /// and responds in order. Clients that need concurrent requests on one connection
+    /// send them as pipelined messages with ids that are answered as soon as they are done.
     pub fn start(&self) {
-        let listen_address =
-            dotenv::var(RPC_SERVER_ADDRESS).unwrap_or(DEFAULT_SERVER_ADDRESS.to_string());
         log::info!("Starting RPC-Server...");
-        let mut server = RpcServer::new(listen_address.clone());
-        let receiver = Arc::clone(&server.receiver);
-        Builder::new()
-            .name("tcp-receiver".to_string())
-            .spawn(move || {
-                server.start().unwrap();
+        let listener = RpcListener::bind(&self.settings).unwrap();
+        let pool = ScheduledThreadPool::new(1);
+        pool.execute_at_fixed_rate(Duration::from_secs(0), HEARTBEAT_INTERVAL, || {
+            READINESS.rpc_heartbeat()
+        });
+        let database = Database::clone(&self.database);
+        log::info!("RPC-Server running on {}", self.settings.listen_address);
+        READINESS.set_rpc_listening(true);
+        let result = listener.serve(|| -> MessageHandler {
+            let database = Database::clone(&database);
+            let session = Session::default();
+            Arc::new(move |message| {
+                Self::handle_request(Database::clone(&database), &session, message)
             })
-            .unwrap();
-        let pool = ScheduledThreadPool::new(num_cpus::get());
-        log::info!("RPC-Server running on {}", listen_address);
-        while let Ok(h) = receiver.lock().unwrap().recv() {
-            let database = Database::clone(&self.database);
-            log::trace!("Scheduling message for execution in pool");
-            pool.execute(move || {
-                let mut handler = h.lock().unwrap();
-                log::debug!("Received message {:?}", handler.message);
-                let response = match handler.message.method {
-                    INFO => Self::handle_info(),
-                    GET_ROLES => Self::handle_get_roles(database, &handler.message.data),
-                    VALIDATE_TOKEN => Self::handle_validate_token(database, &handler.message.data),
-                    GET_ROLE_PERMISSIONS => {
-                        Self::handle_get_permissions(database, &handler.message.data)
-                    }
-                    CREATE_ROLE => Self::handle_create_role(database, &handler.message.data),
-                    CREATE_PERMISSION => {
-                        Self::handle_create_permissions(database, &handler.message.data)
-                    }
-                    GET_USER_ID => Self::handle_get_user_id(&handler.message.data),
-                    _ => Err(ErrorMessage::new("Invalid Method".to_string())),
+        });
+        READINESS.set_rpc_listening(false);
+        result.unwrap();
+    }
+
+    /// Handles a message of a client with its request id and logs it.
+    /// Compressed messages are unpacked once before they are handled and
+    /// answered with a compressed response if the response is large enough.
+    fn handle_request(database: Database, session: &Session, message: Message) -> Message {
+        let started = Instant::now();
+        let compressed = message.method == DEFLATE;
+        let message = if compressed {
+            match compression::decompress_message(&message.data) {
+                Ok(message) => message,
+                Err(e) => {
+                    let request_id = access_log::request_id(None);
+                    let method = String::from_utf8_lossy(&DEFLATE);
+                    access_log::log_rpc(&request_id, &method, false, started, None);
+                    return Message::new_with_serialize(ERROR, ErrorMessage::new(e));
                 }
-                .unwrap_or_else(|e| Message::new_with_serialize(ERROR, e));
-                log::debug!("Responding with message {:?}", &response);
-                handler.done(response);
+            }
+        } else {
+            message
+        };
+        let trace_context = Self::get_message_trace_context(&message);
+        let request_id = access_log::request_id(
+            trace_context
+                .as_ref()
+                .and_then(|context| context.trace_id.as_deref()),
+        );
+        let method = String::from_utf8_lossy(&message.method).to_string();
+        let _span = crate::telemetry_span!(
+            parent: trace_context
+                .as_ref()
+                .and_then(|context| context.traceparent.as_deref()),
+            "rpc_message",
+            rpc.method = method,
+            request_id = request_id,
+        );
+        log::debug!(
+            "[{}] Received message {} {}",
+            request_id,
+            method,
+            access_log::redacted_msgpack(&message.data, Self::get_request_fields(&message.method))
+        );
+        let (response, user_id) = access_log::with_request_id(&request_id, || {
+            let response = change_history::with_actor_scope(|| {
+                change_history::set_request_id(&request_id);
+                Self::handle_message(database, session, &message)
             });
+            (response, access_log::current_user_id())
+        });
+        access_log::log_rpc(
+            &request_id,
+            &method,
+            response.method != ERROR,
+            started,
+            user_id,
+        );
+        log::debug!(
+            "[{}] Responding with message {} {}",
+            request_id,
+            String::from_utf8_lossy(&response.method),
+            access_log::redacted_msgpack(&response.data, &[])
+        );
+
+        if compressed || session.compress_responses.load(Ordering::Relaxed) {
+            compression::compress_message(response)
+        } else {
+            response
+        }
+    }
+
+    /// Handles a single message and returns the response.
+    /// The messages of a batch are handled in order and answered with a batch.
+    /// Compressed messages can't be part of a batch or of another compressed message.
+    fn handle_message(database: Database, session: &Session, message: &Message) -> Message {
+        if message.method == BATCH {
+            return match batch::decode_batch(&message.data) {
+                Ok(messages) => batch::encode_batch(
+                    messages
+                        .iter()
+                        .map(|message| {
+                            if message.method == BATCH {
+                                Message::new_with_serialize(
+                                    ERROR,
+                                    ErrorMessage::new("Batches can't be nested".to_string()),
+                                )
+                            } else {
+                                Self::handle_message(Database::clone(&database), session, message)
+                            }
+                        })
+                        .collect(),
+                ),
+                Err(e) => Message::new_with_serialize(ERROR, ErrorMessage::new(e)),
+            };
+        }
+        match message.method {
+            DEFLATE => Err(ErrorMessage::new(
+                "Compressed messages can't be nested".to_string(),
+            )),
+            PIPELINE => Err(ErrorMessage::new(
+                "Pipelined messages can't be nested".to_string(),
+            )),
+            INFO => Self::handle_info(&message.data),
+            HELLO => Self::handle_hello(session, &message.data),
+            PING => Ok(Message::new(PING, message.data.clone())),
+            VERSION => Ok(Message::new_with_serialize(VERSION, BuildInfo::get())),
+            GET_ROLES => Self::handle_get_roles(database, &message.data),
+            VALIDATE_TOKEN => Self::handle_validate_token(database, &message.data),
+            GET_ROLE_PERMISSIONS => Self::handle_get_permissions(database, &message.data),
+            CREATE_ROLE => Self::handle_create_role(database, &message.data),
+            CREATE_PERMISSION => Self::handle_create_permissions(database, &message.data),
+            ASSIGN_ROLES => Self::handle_assign_roles(database, &message.data),
+            GET_USER_ID => Self::handle_get_user_id(&message.data),
+            GET_USER => Self::handle_get_user(database, &message.data),
+            GET_TOKEN_PERMISSIONS => Self::handle_get_token_permissions(database, &message.data),
+            RESOLVE_USERS => Self::handle_resolve_users(database, &message.data),
+            GET_ORGANIZATION_PERMISSIONS => {
+                Self::handle_get_organization_permissions(database, &message.data)
+            }
+            _ => Err(ErrorMessage::new("Invalid Method".to_string())),
+        }
+        .unwrap_or_else(|e| Message::new_with_serialize(ERROR, e))
+    }
+
+    /// Returns the trace context the calling service added to the message if there is one
+    fn get_message_trace_context(message: &Message) -> Option<TraceContext> {
+        TraceContext::deserialize(&mut Deserializer::new(&mut message.data.as_slice())).ok()
+    }
+
+    /// Returns the fields of the request of a method in the order
+    /// they have when the request is encoded as an array
+    fn get_request_fields(method: &[u8; 4]) -> &'static [&'static str] {
+        match *method {
+            INFO => access_log::field_names::<InfoRequest>(),
+            HELLO => access_log::field_names::<HandshakeRequest>(),
+            VALIDATE_TOKEN | GET_ROLES | GET_USER_ID | GET_USER | GET_TOKEN_PERMISSIONS => {
+                access_log::field_names::<TokenRequest>()
+            }
+            GET_ROLE_PERMISSIONS => access_log::field_names::<GetPermissionsRequest>(),
+            CREATE_ROLE => access_log::field_names::<ModifyRoleRequest>(),
+            CREATE_PERMISSION => access_log::field_names::<CreatePermissionsRequest>(),
+            ASSIGN_ROLES => access_log::field_names::<AssignRolesRequest>(),
+            RESOLVE_USERS => access_log::field_names::<ResolveUsersRequest>(),
+            GET_ORGANIZATION_PERMISSIONS => {
+                access_log::field_names::<OrganizationPermissionsRequest>()
+            }
+            _ => &[],
         }
     }
 
@@ -87,60 +243,200 @@ impl UserRpcServer {
         log::trace!("Validating token.");
         let message = TokenRequest::deserialize(&mut Deserializer::new(&mut data.as_slice()))
             .map_err(|e| ErrorMessage::new(e.to_string()))?;
-        let valid = database
+        let entry = database
             .users
-            .validate_request_token(&message.token)
-            .unwrap_or((false, -1));
+            .get_request_token_entry(&message.token)
+            .unwrap_or(None);
+        let valid = validation_result(entry.as_ref());
+        if let (Some(entry), (true, _)) = (&entry, valid) {
+            access_log::set_user_id(entry.user_id());
+        }
         log::trace!("Serializing...");
         let data = rmp_serde::to_vec(&valid).map_err(|e| ErrorMessage::new(e.to_string()))?;
 
         Ok(Message::new(VALIDATE_TOKEN, data))
     }
 
-    /// Handles a INFO message that returns all valid methods of the rpc sserver
-    fn handle_info() -> RpcResult<Message> {
+    /// Handles a INFO message that returns all valid methods of the rpc server.
+    /// Clients that send their protocol version get the json schemas of the methods.
+    /// Clients of the first version send no data and get the list of info entries.
+    fn handle_info(data: &Vec<u8>) -> RpcResult<Message> {
         log::trace!("Get Info");
-        Ok(Message::new_with_serialize(
-            INFO,
-            vec![
-                InfoEntry::new("info", INFO, "Shows this entry", ""),
-                InfoEntry::new(
-                    "validate token",
-                    VALIDATE_TOKEN,
-                    "Validates a request token",
-                    "{token: String}",
-                ),
-                InfoEntry::new(
-                    "get roles",
-                    GET_ROLES,
-                    "Returns the roles the user is assigned to",
-                    "{token: String}",
-                ),
-                InfoEntry::new(
-                    "get permissions",
-                    GET_ROLE_PERMISSIONS,
-                    "Returns all permissions the given roles are assigned to",
-                    "{roles: [i32]}",
-                ),
-                InfoEntry::new(
-                    "create role",
-                    CREATE_ROLE,
-                    "Creates a new role with the given permissions",
-                    "{name: String, description: String, permissions: [i32]}",
-                ),
-                InfoEntry::new(
-                    "create permissions",
-                    CREATE_PERMISSION,
-                    "Creates all given permissions if they don't exist.",
-                    "{permissions: [{name: String, description: String}]}",
+        let entries = Self::method_info();
+        match InfoRequest::deserialize(&mut Deserializer::new(&mut data.as_slice())) {
+            Ok(request) if request.protocol_version >= 2 => Ok(Message::new_with_serialize(
+                INFO,
+                ProtocolDescription {
+                    protocol_version: PROTOCOL_VERSION,
+                    min_protocol_version: MIN_PROTOCOL_VERSION,
+                    methods: entries.iter().map(InfoEntry::describe).collect(),
+                },
+            )),
+            _ => Ok(Message::new_with_serialize(INFO, entries)),
+        }
+    }
+
+    /// Returns the descriptions of all methods of the rpc server
+    fn method_info() -> Vec<InfoEntry> {
+        vec![
+            InfoEntry::new(
+                "info",
+                INFO,
+                "Shows this entry. Clients of protocol version 2 or later get the schemas of the methods",
+                "{protocol_version: u32}",
+            )
+            .with_schema::<InfoRequest, ProtocolDescription>(),
+            InfoEntry::new(
+                "hello",
+                HELLO,
+                "Returns the protocol version that is used for the connection. Fails if the server doesn't support the version of the client. Large responses of the connection are compressed with the first of the compression algorithms the server supports",
+                "{protocol_version: u32, compression: [String]}",
+            )
+            .with_schema::<HandshakeRequest, HandshakeResponse>(),
+            InfoEntry::new(
+                "ping",
+                PING,
+                "Responds with the data of the message. Idle clients can use it to keep their connection from being closed by the server or a NAT gateway",
+                "[u8]",
+            ),
+            InfoEntry::new(
+                "version",
+                VERSION,
+                &format!(
+                    "Returns the version, commit, build time and features of {}",
+                    BuildInfo::get().summary()
                 ),
-                InfoEntry::new(
-                    "get user id",
-                    GET_USER_ID,
-                    "Returns the userId for a token",
-                    "{token: String}",
+                "",
+            )
+            .with_schema::<(), BuildInfo>(),
+            InfoEntry::new(
+                "deflate",
+                DEFLATE,
+                "Handles a deflate compressed message that isn't compressed again. Large responses are compressed as well",
+                "deflate(method: [u8; 4], data: [u8])",
+            ),
+            InfoEntry::new(
+                "batch",
+                BATCH,
+                &format!(
+                    "Handles up to {} messages in one request and responds with a batch of their responses in the same order. The messages can't be batches or compressed",
+                    MAX_BATCH_SIZE
                 ),
-            ],
+                "batch([method: [u8; 4], length: u32, data: [u8]])",
+            ),
+            InfoEntry::new(
+                "pipeline",
+                PIPELINE,
+                "Handles a message in parallel to the other messages of the connection and responds with the id of the message as soon as it is done. The message can't be pipelined again",
+                "pipeline(id: u32, method: [u8; 4], data: [u8])",
+            ),
+            InfoEntry::new(
+                "validate token",
+                VALIDATE_TOKEN,
+                "Validates a request token and returns if it's valid and its remaining ttl",
+                "{token: String}",
+            )
+            .with_schema::<TokenRequest, (bool, i32)>(),
+            InfoEntry::new(
+                "get roles",
+                GET_ROLES,
+                "Returns the roles the user is assigned to",
+                "{token: String}",
+            )
+            .with_schema::<TokenRequest, Vec<Role>>(),
+            InfoEntry::new(
+                "get permissions",
+                GET_ROLE_PERMISSIONS,
+                "Returns all permissions the given roles are assigned to",
+                "{roles: [i32]}",
+            )
+            .with_schema::<GetPermissionsRequest, HashMap<String, Vec<Permission>>>(),
+            InfoEntry::new(
+                "create role",
+                CREATE_ROLE,
+                "Creates a new role with the given permissions",
+                "{name: String, description: String, permissions: [i32]}",
+            )
+            .with_schema::<ModifyRoleRequest, Role>(),
+            InfoEntry::new(
+                "create permissions",
+                CREATE_PERMISSION,
+                "Creates all given permissions if they don't exist.",
+                "{permissions: [{name: String, description: String}]}",
+            )
+            .with_schema::<CreatePermissionsRequest, Vec<Permission>>(),
+            InfoEntry::new(
+                "assign roles",
+                ASSIGN_ROLES,
+                "Assigns the roles to the user in addition to its current roles and returns all of its roles. Roles that grant built-in permissions can't be assigned",
+                "{user_id: i32, roles: [String]}",
+            )
+            .with_schema::<AssignRolesRequest, Vec<Role>>(),
+            InfoEntry::new(
+                "get user id",
+                GET_USER_ID,
+                "Returns the userId for a token",
+                "{token: String}",
+            )
+            .with_schema::<TokenRequest, i32>(),
+            InfoEntry::new(
+                "get user",
+                GET_USER,
+                "Returns the id, name, email, enabled roles and granted permissions of the user a token belongs to",
+                "{token: String}",
+            )
+            .with_schema::<TokenRequest, TokenUserResponse>(),
+            InfoEntry::new(
+                "resolve users",
+                RESOLVE_USERS,
+                "Returns the name, email and active state of up to 1000 users by their ids",
+                "{ids: [i32]}",
+            )
+            .with_schema::<ResolveUsersRequest, HashMap<String, ResolvedUser>>(),
+            InfoEntry::new(
+                "get token permissions",
+                GET_TOKEN_PERMISSIONS,
+                "Returns the names of all permissions a user or guest token grants",
+                "{token: String}",
+            )
+            .with_schema::<TokenRequest, Vec<String>>(),
+            InfoEntry::new(
+                "get organization permissions",
+                GET_ORGANIZATION_PERMISSIONS,
+                "Returns the names of all permissions the user has inside an organization",
+                "{token: String, organization: String}",
+            )
+            .with_schema::<OrganizationPermissionsRequest, HashSet<String>>(),
+        ]
+    }
+
+    /// Agrees on the protocol version and the compression of responses with the client.
+    /// The newest version both implement is used if the server still supports it.
+    fn handle_hello(session: &Session, data: &Vec<u8>) -> RpcResult<Message> {
+        log::trace!("Hello");
+        let message = HandshakeRequest::deserialize(&mut Deserializer::new(&mut data.as_slice()))
+            .map_err(|e| ErrorMessage::new(e.to_string()))?;
+        let protocol_version = message.protocol_version.min(PROTOCOL_VERSION);
+        if protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(ErrorMessage::new(format!(
+                "Unsupported protocol version {}. The server supports the versions {} to {}",
+                message.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+        let compression = compression::negotiate_message_compression(&message.compression);
+        session
+            .compress_responses
+            .store(compression.is_some(), Ordering::Relaxed);
+
+        Ok(Message::new_with_serialize(
+            HELLO,
+            HandshakeResponse {
+                protocol_version,
+                server_protocol_version: PROTOCOL_VERSION,
+                min_protocol_version: MIN_PROTOCOL_VERSION,
+                server_version: BuildInfo::get().version.to_string(),
+                compression: compression.map(str::to_string),
+            },
         ))
     }
 
@@ -167,17 +463,9 @@ impl UserRpcServer {
         log::trace!("Get Roles");
         let message = TokenRequest::deserialize(&mut Deserializer::new(&mut data.as_slice()))
             .map_err(|e| ErrorMessage::new(e.to_string()))?;
-        if !database
-            .users
-            .validate_request_token(&message.token)
-            .unwrap_or((false, -1))
-            .0
-        {
-            return Err(ErrorMessage::new("Invalid request token".to_string()));
-        }
-        let user_id = get_user_id_from_token(&message.token)
-            .ok_or(ErrorMessage::new("Invalid request token".to_string()))?;
-        let response_data = database.user_roles.by_user(user_id)?;
+        let entry = Self::get_token_entry(&database, &message.token)?;
+        let response_data =
+            visible_roles(entry.kind(), database.user_roles.by_user(entry.user_id())?);
 
         Ok(Message::new_with_serialize(GET_ROLES, response_data))
     }
@@ -208,6 +496,18 @@ impl UserRpcServer {
         Ok(Message::new_with_serialize(CREATE_PERMISSION, permissions))
     }
 
+    /// Assigns roles to a user without removing the roles it already has
+    fn handle_assign_roles(database: Database, data: &Vec<u8>) -> RpcResult<Message> {
+        log::trace!("Assign Roles");
+        let message = AssignRolesRequest::deserialize(&mut Deserializer::new(&mut data.as_slice()))
+            .map_err(|e| ErrorMessage::new(e.to_string()))?;
+        let roles = database
+            .user_roles
+            .assign_roles(message.user_id, message.roles)?;
+
+        Ok(Message::new_with_serialize(ASSIGN_ROLES, roles))
+    }
+
     /// Returns the userId for a request token
     fn handle_get_user_id(data: &Vec<u8>) -> RpcResult<Message> {
         log::trace!("Get User ID");
@@ -219,4 +519,119 @@ impl UserRpcServer {
                 .ok_or(ErrorMessage::new("Invalid request token".to_string()))?,
         ))
     }
+
+    /// Returns the user of a request token together with its roles and the
+    /// permissions the token grants so that services need only one request
+    fn handle_get_user(database: Database, data: &Vec<u8>) -> RpcResult<Message> {
+        log::trace!("Get User");
+        let message = TokenRequest::deserialize(&mut Deserializer::new(&mut data.as_slice()))
+            .map_err(|e| ErrorMessage::new(e.to_string()))?;
+        let entry = Self::get_token_entry(&database, &message.token)?;
+        let user_id = entry.user_id();
+        if user_id == GUEST_USER_ID {
+            return Err(ErrorMessage::new(
+                "Guest tokens don't belong to a user".to_string(),
+            ));
+        }
+        let user = database.users.get_user(user_id)?;
+        let roles = visible_roles(entry.kind(), database.user_roles.by_user(user_id)?);
+        let permissions = granted_permissions(
+            entry.kind(),
+            (*database.users.get_permission_names(user_id)?).clone(),
+        )
+        .into_iter()
+        .collect();
+
+        Ok(Message::new_with_serialize(
+            GET_USER,
+            TokenUserResponse {
+                id: user.id,
+                name: user.name,
+                email: user.email,
+                roles,
+                permissions,
+            },
+        ))
+    }
+
+    /// Returns the public information of multiple users by their ids
+    fn handle_resolve_users(database: Database, data: &Vec<u8>) -> RpcResult<Message> {
+        log::trace!("Resolve Users");
+        let message =
+            ResolveUsersRequest::deserialize(&mut Deserializer::new(&mut data.as_slice()))
+                .map_err(|e| ErrorMessage::new(e.to_string()))?;
+        if message.ids.len() > MAX_RESOLVE_USERS {
+            return Err(ErrorMessage::new(format!(
+                "At most {} users can be resolved at once",
+                MAX_RESOLVE_USERS
+            )));
+        }
+        let response_data = database
+            .users
+            .resolve_users(&message.ids)?
+            .into_iter()
+            .map(|(id, user)| (id.to_string(), user))
+            .collect::<HashMap<String, ResolvedUser>>();
+
+        Ok(Message::new_with_serialize(RESOLVE_USERS, response_data))
+    }
+
+    /// Returns the names of the permissions granted by a token.
+    /// Guest tokens only grant their whitelisted permissions.
+    fn handle_get_token_permissions(database: Database, data: &Vec<u8>) -> RpcResult<Message> {
+        log::trace!("Get Token Permissions");
+        let message = TokenRequest::deserialize(&mut Deserializer::new(&mut data.as_slice()))
+            .map_err(|e| ErrorMessage::new(e.to_string()))?;
+        let permissions = database
+            .users
+            .get_token_permissions(&message.token)?
+            .ok_or(ErrorMessage::new("Invalid request token".to_string()))?;
+        if let Some(user_id) = get_user_id_from_token(&message.token) {
+            access_log::set_user_id(user_id);
+        }
+
+        Ok(Message::new_with_serialize(
+            GET_TOKEN_PERMISSIONS,
+            permissions,
+        ))
+    }
+
+    /// Returns the names of the permissions granted to the user inside an organization.
+    /// This includes the permissions of global roles and roles of parent organizations.
+    fn handle_get_organization_permissions(
+        database: Database,
+        data: &Vec<u8>,
+    ) -> RpcResult<Message> {
+        log::trace!("Get Organization Permissions");
+        let message = OrganizationPermissionsRequest::deserialize(&mut Deserializer::new(
+            &mut data.as_slice(),
+        ))
+        .map_err(|e| ErrorMessage::new(e.to_string()))?;
+        let entry = Self::get_token_entry(&database, &message.token)?;
+        let user_id = entry.user_id();
+        let mut permissions = database
+            .organizations
+            .get_permission_names(user_id, &message.organization)?;
+        permissions.extend(
+            database
+                .users
+                .get_permission_names(user_id)?
+                .iter()
+                .cloned(),
+        );
+
+        Ok(Message::new_with_serialize(
+            GET_ORGANIZATION_PERMISSIONS,
+            granted_permissions(entry.kind(), permissions),
+        ))
+    }
+
+    /// Returns the session of a request token that can be used with services
+    fn get_token_entry(database: &Database, token: &String) -> RpcResult<TokenStoreEntry> {
+        let entry = token_access::service_token_entry(database, token)?
+            .ok_or(ErrorMessage::new("Invalid request token".to_string()))?;
+        access_log::set_user_id(entry.user_id());
+
+        Ok(entry)
+    }
 }
@@ -0,0 +1,187 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::any::type_name;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use schemars::JsonSchema;
+use serde_json::Value;
+
+use crate::server::naming::rename_schema;
+use crate::utils::error::FieldError;
+
+lazy_static::lazy_static! {
+    static ref SCHEMAS: RwLock<HashMap<&'static str, Arc<Value>>> = RwLock::new(HashMap::new());
+}
+
+/// Validates a json body against the schema of the type it is deserialized into.
+/// The fields of the returned errors are JSON pointers to the invalid values.
+pub fn validate<T: JsonSchema>(value: &Value) -> Vec<FieldError> {
+    let schema = get_schema::<T>();
+    let mut errors = Vec::new();
+    validate_value(&schema, &schema, value, "", &mut errors);
+
+    errors
+}
+
+/// Returns the schema of the type with the field names in the configured convention.
+/// Schemas are only generated once per type.
+fn get_schema<T: JsonSchema>() -> Arc<Value> {
+    let name = type_name::<T>();
+    if let Some(schema) = SCHEMAS.read().get(name) {
+        return Arc::clone(schema);
+    }
+    let mut schema = serde_json::to_value(schema_for!(T)).unwrap_or(Value::Bool(true));
+    rename_schema(&mut schema);
+    let schema = Arc::new(schema);
+    SCHEMAS.write().insert(name, Arc::clone(&schema));
+
+    schema
+}
+
+fn validate_value(
+    schema: &Value,
+    root: &Value,
+    value: &Value,
+    pointer: &str,
+    errors: &mut Vec<FieldError>,
+) {
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        Value::Bool(false) => {
+            errors.push(FieldError::InvalidValue(pointer.to_string()));
+            return;
+        }
+        _ => return,
+    };
+    if let Some(Value::String(reference)) = schema.get("$ref") {
+        if let Some(definition) = resolve_reference(root, reference) {
+            validate_value(definition, root, value, pointer, errors);
+        }
+        return;
+    }
+    if let Some(Value::Array(subschemas)) = schema.get("allOf") {
+        for subschema in subschemas {
+            validate_value(subschema, root, value, pointer, errors);
+        }
+    }
+    for key in &["anyOf", "oneOf"] {
+        if let Some(Value::Array(subschemas)) = schema.get(*key) {
+            validate_alternatives(subschemas, root, value, pointer, errors);
+        }
+    }
+    if let Some(instance_type) = schema.get("type") {
+        let types = match instance_type {
+            Value::Array(types) => types.iter().filter_map(|t| t.as_str()).collect(),
+            Value::String(t) => vec![t.as_str()],
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|t| has_type(value, t)) {
+            errors.push(FieldError::TypeMismatch(
+                pointer.to_string(),
+                types.join(" or "),
+            ));
+            return;
+        }
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        if !values.contains(value) {
+            errors.push(FieldError::InvalidValue(pointer.to_string()));
+            return;
+        }
+    }
+    if let Some(number) = value.as_f64() {
+        let below =
+            matches!(schema.get("minimum").and_then(Value::as_f64), Some(min) if number < min);
+        let above =
+            matches!(schema.get("maximum").and_then(Value::as_f64), Some(max) if number > max);
+        if below || above {
+            errors.push(FieldError::InvalidValue(pointer.to_string()));
+        }
+    }
+
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(|n| n.as_str()) {
+                    if !object.contains_key(name) {
+                        errors.push(FieldError::Required(child_pointer(pointer, name)));
+                    }
+                }
+            }
+            if let Some(Value::Object(properties)) = schema.get("properties") {
+                for (name, property) in properties {
+                    if let Some(value) = object.get(name) {
+                        validate_value(
+                            property,
+                            root,
+                            value,
+                            &child_pointer(pointer, name),
+                            errors,
+                        );
+                    }
+                }
+            }
+        }
+        Value::Array(values) => {
+            if let Some(items) = schema.get("items") {
+                for (index, value) in values.iter().enumerate() {
+                    let pointer = child_pointer(pointer, &index.to_string());
+                    validate_value(items, root, value, &pointer, errors);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Validates a value that needs to match one of the given schemas.
+/// If none matches the errors of the closest match are reported.
+fn validate_alternatives(
+    subschemas: &Vec<Value>,
+    root: &Value,
+    value: &Value,
+    pointer: &str,
+    errors: &mut Vec<FieldError>,
+) {
+    let mut closest: Option<Vec<FieldError>> = None;
+    for subschema in subschemas {
+        let mut subschema_errors = Vec::new();
+        validate_value(subschema, root, value, pointer, &mut subschema_errors);
+        if subschema_errors.is_empty() {
+            return;
+        }
+        if !matches!(&closest, Some(c) if subschema_errors.len() >= c.len()) {
+            closest = Some(subschema_errors);
+        }
+    }
+    errors.append(&mut closest.unwrap_or_default());
+}
+
+/// Returns the definition a local reference like `#/definitions/Name` points to
+fn resolve_reference<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    reference
+        .strip_prefix('#')
+        .and_then(|pointer| root.pointer(pointer))
+}
+
+fn has_type(value: &Value, instance_type: &str) -> bool {
+    match instance_type {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "string" => value.is_string(),
+        _ => true,
+    }
+}
+
+/// Appends a property name or array index to a JSON pointer
+fn child_pointer(pointer: &str, name: &str) -> String {
+    format!("{}/{}", pointer, name.replace('~', "~0").replace('/', "~1"))
+}
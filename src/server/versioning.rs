@@ -0,0 +1,56 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use rouille::Request;
+
+/// The header the version of the api is requested with and returned in
+pub const API_VERSION_HEADER: &str = "Api-Version";
+const API_PREFIX: &str = "/api/v";
+
+/// The versions of the api. Breaking changes to requests or responses
+/// are only made in a new version so that existing clients keep working.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiVersion {
+    V1,
+}
+
+impl ApiVersion {
+    pub fn number(&self) -> u32 {
+        match self {
+            ApiVersion::V1 => 1,
+        }
+    }
+
+    pub fn from_number(number: &str) -> Option<Self> {
+        match number.trim().trim_start_matches(|c| c == 'v' || c == 'V') {
+            "1" => Some(ApiVersion::V1),
+            _ => None,
+        }
+    }
+
+    /// Determines the version of the api a request is made for. Requests to
+    /// /api/v{n}/... use the version of the path and are returned without the prefix.
+    /// Requests to the legacy paths without a prefix use the version of the
+    /// Api-Version header and default to the first version.
+    /// Returns the requested version as error if it isn't supported.
+    pub fn negotiate(request: &Request) -> Result<(ApiVersion, Option<Request>), String> {
+        let url = request.url();
+        if let Some(rest) = url.strip_prefix(API_PREFIX) {
+            let number = rest.split('/').next().unwrap_or_default();
+            let version = Self::from_number(number).ok_or(number.to_string())?;
+            let prefix = format!("{}{}", API_PREFIX, number);
+
+            return match request.remove_prefix(&prefix) {
+                Some(request) => Ok((version, Some(request))),
+                None => Err(number.to_string()),
+            };
+        }
+        match request.header(API_VERSION_HEADER) {
+            Some(number) => Self::from_number(number)
+                .map(|version| (version, None))
+                .ok_or(number.to_string()),
+            None => Ok((ApiVersion::V1, None)),
+        }
+    }
+}
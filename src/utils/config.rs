@@ -0,0 +1,309 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+//! The configuration is read from the env, a TOML or YAML file and the defaults in that order.
+//! Every setting is named after its env variable. In the file the settings can be grouped
+//! in tables whose names are prefixes of the variable, so `[cors] max_age = 60`
+//! sets CORS_MAX_AGE. Lists are joined with commas.
+
+use std::collections::HashMap;
+use std::env::VarError;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::database::tokens::{
+    DEFAULT_SESSION_IDLE_TIMEOUT, DEFAULT_SESSION_LIFETIME, DEFAULT_SESSION_MAX_LIFETIME,
+    ENV_SESSION_IDLE_TIMEOUT, ENV_SESSION_LIFETIME, ENV_SESSION_MAX_LIFETIME,
+    ENV_SESSION_SLIDING_EXPIRY,
+};
+use crate::database::webhooks::{
+    DEFAULT_WEBHOOK_MAX_ATTEMPTS, DEFAULT_WEBHOOK_POLL_INTERVAL, DEFAULT_WEBHOOK_TIMEOUT,
+    ENV_WEBHOOK_MAX_ATTEMPTS, ENV_WEBHOOK_POLL_INTERVAL, ENV_WEBHOOK_TIMEOUT,
+};
+use crate::database::{DB_CONNECTION_URL, DEFAULT_CONNECTION};
+use crate::server::cors::{
+    DEFAULT_CORS_ALLOWED_HEADERS, DEFAULT_CORS_MAX_AGE, ENV_CORS_ALLOWED_HEADERS,
+    ENV_CORS_ALLOWED_ORIGINS, ENV_CORS_ALLOW_CREDENTIALS, ENV_CORS_MAX_AGE,
+};
+use crate::server::http_server::{DEFAULT_LISTEN_ADDRESS, LISTEN_ADDRESS};
+use crate::server::rate_limit::{
+    DEFAULT_ACCOUNT_RATE_LIMIT, DEFAULT_ACCOUNT_RATE_LIMIT_BURST, DEFAULT_IP_RATE_LIMIT,
+    DEFAULT_IP_RATE_LIMIT_BURST, ENV_ACCOUNT_RATE_LIMIT, ENV_ACCOUNT_RATE_LIMIT_BURST,
+    ENV_IP_RATE_LIMIT, ENV_IP_RATE_LIMIT_BURST,
+};
+use crate::server::rpc_listener::{
+    DEFAULT_RPC_IDLE_TIMEOUT, DEFAULT_RPC_MAX_CONNECTIONS, ENV_RPC_IDLE_TIMEOUT,
+    ENV_RPC_MAX_CONNECTIONS,
+};
+use crate::server::user_rpc::{DEFAULT_SERVER_ADDRESS, RPC_SERVER_ADDRESS};
+use crate::utils::hashing::{
+    DEFAULT_ARGON2_MEMORY_COST, DEFAULT_ARGON2_PARALLELISM, DEFAULT_ARGON2_TIME_COST,
+    DEFAULT_BCRYPT_COST, DEFAULT_PASSWORD_HASH_ALGORITHM, ENV_ARGON2_MEMORY_COST,
+    ENV_ARGON2_PARALLELISM, ENV_ARGON2_TIME_COST, ENV_BCRYPT_COST, ENV_PASSWORD_HASH_ALGORITHM,
+};
+use crate::utils::hashing_pool::{
+    DEFAULT_HASHING_QUEUE_SIZE, ENV_HASHING_QUEUE_SIZE, ENV_HASHING_WORKERS,
+};
+use crate::utils::jwt::{DEFAULT_JWT_ISSUER, ENV_JWT_ISSUER, ENV_JWT_PRIVATE_KEY};
+
+pub(crate) const ENV_CONFIG_FILE: &str = "CONFIG_FILE";
+
+lazy_static::lazy_static! {
+    static ref FILE_SETTINGS: Result<HashMap<String, String>, String> = read_config_file();
+    static ref CONFIG: Result<Config, String> = Config::resolve();
+}
+
+/// Returns the value of a setting from the env or the config file
+pub fn var(name: &str) -> Result<String, VarError> {
+    match dotenv::var(name) {
+        Ok(value) => Ok(value),
+        Err(_) => FILE_SETTINGS
+            .as_ref()
+            .ok()
+            .and_then(|settings| settings.get(name).cloned())
+            .ok_or(VarError::NotPresent),
+    }
+}
+
+/// The settings of the servers, the database, sessions, password hashing,
+/// JWTs, webhooks and rate limits. Secrets and the settings of optional
+/// integrations like LDAP, brokers and telemetry are read where they are used.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub http: HttpConfig,
+    pub rpc: RpcConfig,
+    pub sessions: SessionConfig,
+    pub cors: CorsSettings,
+    pub hashing: HashingConfig,
+    pub jwt: JwtConfig,
+    pub webhooks: WebhookConfig,
+    pub rate_limits: RateLimitConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct DatabaseConfig {
+    pub url: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// A tcp address or unix:/path/to/socket
+    pub listen_address: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct RpcConfig {
+    pub listen_address: String,
+    /// The seconds after which idle connections are closed. 0 disables the timeout.
+    pub idle_timeout: u64,
+    pub max_connections: usize,
+}
+
+/// The lifetimes of sessions in seconds
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    pub lifetime: u32,
+    pub idle_timeout: u32,
+    pub sliding_expiry: bool,
+    pub max_lifetime: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct CorsSettings {
+    pub allowed_origins: Vec<String>,
+    pub allowed_headers: String,
+    pub max_age: u32,
+    pub allow_credentials: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct HashingConfig {
+    pub algorithm: String,
+    pub bcrypt_cost: u32,
+    pub argon2_memory_cost: u32,
+    pub argon2_time_cost: u32,
+    pub argon2_parallelism: u32,
+    /// The number of threads that hash passwords
+    pub workers: usize,
+    /// The number of hashes that can wait for a free worker
+    pub queue_size: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    pub issuer: String,
+    /// The path of the PEM file with the RSA signing key
+    pub private_key: Option<String>,
+}
+
+/// The delivery settings of webhooks with durations in milliseconds
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub poll_interval: u64,
+    pub timeout: u64,
+    pub max_attempts: i32,
+}
+
+/// The requests per minute and bursts of the rate limits
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    pub ip_limit: u32,
+    pub ip_burst: u32,
+    pub account_limit: u32,
+    pub account_burst: u32,
+}
+
+impl Config {
+    /// Returns the configuration. Fails if the config file can't be read
+    /// or a setting has an invalid value.
+    pub fn load() -> Result<&'static Self, String> {
+        FILE_SETTINGS.as_ref().map_err(String::clone)?;
+        super::check_token_secret()?;
+        let config = CONFIG.as_ref().map_err(String::clone)?;
+        super::jwt::check_key()?;
+
+        Ok(config)
+    }
+
+    /// Returns the configuration that was checked with [Config::load] before
+    pub fn get() -> &'static Self {
+        CONFIG.as_ref().expect("The configuration is invalid")
+    }
+
+    fn resolve() -> Result<Self, String> {
+        Ok(Self {
+            database: DatabaseConfig {
+                url: string_var(DB_CONNECTION_URL, DEFAULT_CONNECTION),
+            },
+            http: HttpConfig {
+                listen_address: string_var(LISTEN_ADDRESS, DEFAULT_LISTEN_ADDRESS),
+            },
+            rpc: RpcConfig {
+                listen_address: string_var(RPC_SERVER_ADDRESS, DEFAULT_SERVER_ADDRESS),
+                idle_timeout: parse_var(ENV_RPC_IDLE_TIMEOUT, DEFAULT_RPC_IDLE_TIMEOUT)?,
+                max_connections: parse_var(ENV_RPC_MAX_CONNECTIONS, DEFAULT_RPC_MAX_CONNECTIONS)?,
+            },
+            sessions: SessionConfig {
+                lifetime: parse_var(ENV_SESSION_LIFETIME, DEFAULT_SESSION_LIFETIME)?,
+                idle_timeout: parse_var(ENV_SESSION_IDLE_TIMEOUT, DEFAULT_SESSION_IDLE_TIMEOUT)?,
+                sliding_expiry: parse_var(ENV_SESSION_SLIDING_EXPIRY, false)?,
+                max_lifetime: parse_var(ENV_SESSION_MAX_LIFETIME, DEFAULT_SESSION_MAX_LIFETIME)?,
+            },
+            cors: CorsSettings {
+                allowed_origins: var(ENV_CORS_ALLOWED_ORIGINS)
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                    .filter(|origin| !origin.is_empty())
+                    .collect(),
+                allowed_headers: string_var(ENV_CORS_ALLOWED_HEADERS, DEFAULT_CORS_ALLOWED_HEADERS),
+                max_age: parse_var(ENV_CORS_MAX_AGE, DEFAULT_CORS_MAX_AGE)?,
+                allow_credentials: parse_var(ENV_CORS_ALLOW_CREDENTIALS, false)?,
+            },
+            hashing: HashingConfig {
+                algorithm: string_var(ENV_PASSWORD_HASH_ALGORITHM, DEFAULT_PASSWORD_HASH_ALGORITHM),
+                bcrypt_cost: parse_var(ENV_BCRYPT_COST, DEFAULT_BCRYPT_COST)?,
+                argon2_memory_cost: parse_var(ENV_ARGON2_MEMORY_COST, DEFAULT_ARGON2_MEMORY_COST)?,
+                argon2_time_cost: parse_var(ENV_ARGON2_TIME_COST, DEFAULT_ARGON2_TIME_COST)?,
+                argon2_parallelism: parse_var(ENV_ARGON2_PARALLELISM, DEFAULT_ARGON2_PARALLELISM)?,
+                workers: parse_var(ENV_HASHING_WORKERS, num_cpus::get())?,
+                queue_size: parse_var(ENV_HASHING_QUEUE_SIZE, DEFAULT_HASHING_QUEUE_SIZE)?,
+            },
+            jwt: JwtConfig {
+                issuer: string_var(ENV_JWT_ISSUER, DEFAULT_JWT_ISSUER),
+                private_key: var(ENV_JWT_PRIVATE_KEY).ok(),
+            },
+            webhooks: WebhookConfig {
+                poll_interval: parse_var(ENV_WEBHOOK_POLL_INTERVAL, DEFAULT_WEBHOOK_POLL_INTERVAL)?,
+                timeout: parse_var(ENV_WEBHOOK_TIMEOUT, DEFAULT_WEBHOOK_TIMEOUT)?,
+                max_attempts: parse_var(ENV_WEBHOOK_MAX_ATTEMPTS, DEFAULT_WEBHOOK_MAX_ATTEMPTS)?,
+            },
+            rate_limits: RateLimitConfig {
+                ip_limit: parse_var(ENV_IP_RATE_LIMIT, DEFAULT_IP_RATE_LIMIT)?,
+                ip_burst: parse_var(ENV_IP_RATE_LIMIT_BURST, DEFAULT_IP_RATE_LIMIT_BURST)?,
+                account_limit: parse_var(ENV_ACCOUNT_RATE_LIMIT, DEFAULT_ACCOUNT_RATE_LIMIT)?,
+                account_burst: parse_var(
+                    ENV_ACCOUNT_RATE_LIMIT_BURST,
+                    DEFAULT_ACCOUNT_RATE_LIMIT_BURST,
+                )?,
+            },
+        })
+    }
+}
+
+fn string_var(name: &str, default: &str) -> String {
+    var(name).unwrap_or(default.to_string())
+}
+
+/// Parses the value of the setting. Fails with the name of the setting
+/// if the value is invalid instead of silently using the default.
+fn parse_var<T: FromStr>(name: &str, default: T) -> Result<T, String> {
+    match var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid value '{}' for the setting {}", value, name)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Reads the settings of the config file if one is configured
+fn read_config_file() -> Result<HashMap<String, String>, String> {
+    let mut settings = HashMap::new();
+    let path = match dotenv::var(ENV_CONFIG_FILE) {
+        Ok(path) => path,
+        Err(_) => return Ok(settings),
+    };
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read the config file {}: {}", path, e))?;
+    let extension = Path::new(&path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let value: Value = match extension.as_str() {
+        "toml" => toml::from_str(&content).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        _ => Err("Only .toml, .yaml and .yml files are supported".to_string()),
+    }
+    .map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+    flatten_settings("", &value, &mut settings);
+    log::info!("Read {} settings from {}", settings.len(), path);
+
+    Ok(settings)
+}
+
+/// Adds the values of the file with the names of the tables as prefixes
+fn flatten_settings(prefix: &str, value: &Value, settings: &mut HashMap<String, String>) {
+    let value = match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = key.to_ascii_uppercase().replace('-', "_");
+                let name = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}_{}", prefix, key)
+                };
+                flatten_settings(&name, value, settings);
+            }
+            return;
+        }
+        Value::Null => return,
+        Value::String(value) => value.clone(),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(value) => value.clone(),
+                other => other.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(","),
+        other => other.to_string(),
+    };
+    settings.insert(prefix.to_string(), value);
+}
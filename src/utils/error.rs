@@ -8,17 +8,71 @@ use std::fmt::{self, Display, Formatter};
 use r2d2::Error;
 use serde_postgres::DeError;
 
+use crate::database::limits::Resource;
+use crate::utils::i18n::{self, Language};
+
 #[derive(Debug)]
 pub enum DBError {
     Postgres(PostgresError),
     Pool(r2d2::Error),
+    Redis(redis::RedisError),
+    Ldap(ldap3::LdapError),
     RecordExists,
     RecordDoesNotExist,
     BCryptError,
+    /// All password hashing workers are busy and their queue is full
+    HashingBusy,
     DeserializeError(serde_postgres::DeError),
+    ValidationError(Vec<FieldError>),
+    LimitExceeded(Resource, u64),
+    SystemRecord(String),
     GenericError(String),
 }
 
+/// The reason a single field failed the validation
+#[derive(Clone, Debug)]
+pub enum FieldError {
+    Required(String),
+    TypeMismatch(String, String),
+    InvalidValue(String),
+}
+
+impl FieldError {
+    /// Returns the machine-readable code of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            FieldError::Required(_) => i18n::ERR_FIELD_REQUIRED,
+            FieldError::TypeMismatch(_, _) => i18n::ERR_FIELD_TYPE_MISMATCH,
+            FieldError::InvalidValue(_) => i18n::ERR_FIELD_INVALID_VALUE,
+        }
+    }
+
+    /// Returns the name of the field that failed the validation
+    pub fn field(&self) -> &String {
+        match self {
+            FieldError::Required(field) => field,
+            FieldError::TypeMismatch(field, _) => field,
+            FieldError::InvalidValue(field) => field,
+        }
+    }
+
+    /// Returns the message of the error in the given language
+    pub fn message(&self, language: Language) -> String {
+        let message = match self {
+            FieldError::Required(field) | FieldError::InvalidValue(field) => {
+                i18n::message(self.code(), language, &[("field", field)])
+            }
+            FieldError::TypeMismatch(field, field_type) => i18n::message(
+                self.code(),
+                language,
+                &[("field", field), ("type", field_type)],
+            ),
+        };
+
+        message.unwrap_or(self.code().to_string())
+    }
+}
+
 impl Display for DBError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.to_string())
@@ -35,10 +89,63 @@ impl DBError {
             DBError::Postgres(p) => p.to_string(),
             DBError::DeserializeError(de) => de.to_string(),
             DBError::BCryptError => "BCrypt Hash creation error".to_string(),
+            DBError::HashingBusy => self.message(Language::English),
             DBError::Pool(p) => p.to_string(),
+            DBError::Redis(r) => r.to_string(),
+            DBError::Ldap(l) => l.to_string(),
             DBError::RecordDoesNotExist => "Record does not exist".to_string(),
+            DBError::ValidationError(_)
+            | DBError::LimitExceeded(_, _)
+            | DBError::SystemRecord(_) => self.message(Language::English),
         }
     }
+
+    /// Returns the machine-readable code of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            DBError::GenericError(_) => i18n::ERR_GENERIC,
+            DBError::RecordExists => i18n::ERR_RECORD_EXISTS,
+            DBError::Postgres(_)
+            | DBError::Pool(_)
+            | DBError::Redis(_)
+            | DBError::Ldap(_)
+            | DBError::DeserializeError(_) => i18n::ERR_DATABASE,
+            DBError::BCryptError => i18n::ERR_HASH,
+            DBError::HashingBusy => i18n::ERR_HASHING_BUSY,
+            DBError::RecordDoesNotExist => i18n::ERR_RECORD_DOES_NOT_EXIST,
+            DBError::ValidationError(_) => i18n::ERR_INVALID_ATTRIBUTES,
+            DBError::LimitExceeded(_, _) => i18n::ERR_LIMIT_EXCEEDED,
+            DBError::SystemRecord(_) => i18n::ERR_SYSTEM_RECORD,
+        }
+    }
+
+    /// Returns the message of the error in the given language.
+    /// Errors without a catalog entry keep their original message.
+    pub fn message(&self, language: Language) -> String {
+        let message = match self {
+            DBError::ValidationError(errors) => {
+                let errors = errors
+                    .iter()
+                    .map(|e| e.message(language))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                i18n::message(self.code(), language, &[("errors", &errors)])
+            }
+            DBError::LimitExceeded(resource, limit) => i18n::message(
+                self.code(),
+                language,
+                &[("resource", resource.name()), ("limit", &limit.to_string())],
+            ),
+            DBError::SystemRecord(name) => i18n::message(self.code(), language, &[("name", name)]),
+            DBError::RecordExists
+            | DBError::RecordDoesNotExist
+            | DBError::BCryptError
+            | DBError::HashingBusy => i18n::message(self.code(), language, &[]),
+            _ => None,
+        };
+
+        message.unwrap_or_else(|| self.to_string())
+    }
 }
 
 pub type DatabaseResult<T> = Result<T, DBError>;
@@ -55,6 +162,18 @@ impl From<r2d2::Error> for DBError {
     }
 }
 
+impl From<redis::RedisError> for DBError {
+    fn from(other: redis::RedisError) -> Self {
+        Self::Redis(other)
+    }
+}
+
+impl From<ldap3::LdapError> for DBError {
+    fn from(other: ldap3::LdapError) -> Self {
+        Self::Ldap(other)
+    }
+}
+
 impl From<serde_postgres::DeError> for DBError {
     fn from(other: DeError) -> Self {
         Self::DeserializeError(other)
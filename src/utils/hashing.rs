@@ -0,0 +1,273 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::fmt;
+use std::fs;
+use std::panic;
+
+use argon2::{Config, ThreadMode, Variant, Version};
+use bcrypt::DEFAULT_COST;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::utils::config::{self, HashingConfig};
+
+pub(crate) const ENV_PASSWORD_HASH_ALGORITHM: &str = "PASSWORD_HASH_ALGORITHM";
+pub(crate) const ENV_BCRYPT_COST: &str = "BCRYPT_COST";
+pub(crate) const ENV_ARGON2_MEMORY_COST: &str = "ARGON2_MEMORY_COST";
+pub(crate) const ENV_ARGON2_TIME_COST: &str = "ARGON2_TIME_COST";
+pub(crate) const ENV_ARGON2_PARALLELISM: &str = "ARGON2_PARALLELISM";
+pub(crate) const ENV_PASSWORD_PEPPER: &str = "PASSWORD_PEPPER";
+pub(crate) const ENV_PASSWORD_PEPPER_ID: &str = "PASSWORD_PEPPER_ID";
+pub(crate) const ENV_PASSWORD_PEPPER_FILE: &str = "PASSWORD_PEPPER_FILE";
+pub(crate) const DEFAULT_PASSWORD_PEPPER_ID: &str = "1";
+pub(crate) const DEFAULT_PASSWORD_HASH_ALGORITHM: &str = ARGON2ID;
+pub(crate) const DEFAULT_BCRYPT_COST: u32 = DEFAULT_COST;
+pub(crate) const DEFAULT_ARGON2_MEMORY_COST: u32 = 19456;
+pub(crate) const DEFAULT_ARGON2_TIME_COST: u32 = 2;
+pub(crate) const DEFAULT_ARGON2_PARALLELISM: u32 = 1;
+
+const BCRYPT: &str = "bcrypt";
+const ARGON2ID: &str = "argon2id";
+const ARGON2_HASH_LENGTH: u32 = 32;
+const MIN_BCRYPT_COST: u32 = 4;
+const MAX_BCRYPT_COST: u32 = 31;
+
+lazy_static::lazy_static! {
+    static ref CONFIGURED_ALGORITHM: PasswordAlgorithm = PasswordAlgorithm::from_config(&config::Config::get().hashing);
+    static ref PEPPERS: Peppers = Peppers::from_env();
+}
+
+/// A secret that is mixed into all password hashes and is not stored in the database.
+/// The id is stored with each hash so that the pepper can be rotated.
+pub struct Pepper {
+    pub id: String,
+    secret: Zeroizing<Vec<u8>>,
+}
+
+/// The configured peppers. Only the current pepper is used for new hashes,
+/// older ones are kept to verify hashes that haven't been upgraded yet.
+struct Peppers {
+    current: Option<Pepper>,
+    previous: Vec<Pepper>,
+}
+
+impl Pepper {
+    /// Returns the pepper new passwords are hashed with
+    pub fn current() -> Option<&'static Pepper> {
+        PEPPERS.current.as_ref()
+    }
+
+    /// Returns the pepper with the given id if it is still configured
+    pub fn by_id(id: &str) -> Option<&'static Pepper> {
+        PEPPERS
+            .current
+            .iter()
+            .chain(PEPPERS.previous.iter())
+            .find(|pepper| pepper.id == id)
+    }
+
+    /// Mixes the pepper into the password with HMAC-SHA256
+    pub(crate) fn apply(&self, password: &[u8]) -> Zeroizing<Vec<u8>> {
+        let mut mac =
+            Hmac::<Sha256>::new_varkey(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(password);
+
+        Zeroizing::new(mac.finalize().into_bytes().to_vec())
+    }
+}
+
+impl Peppers {
+    /// Reads the peppers from the env and the pepper file. The file contains one
+    /// pepper per line in the format id=secret with the current pepper in the last line.
+    /// A pepper set in the env takes precedence over the ones in the file.
+    fn from_env() -> Self {
+        let mut peppers = Vec::new();
+        if let Ok(path) = config::var(ENV_PASSWORD_PEPPER_FILE) {
+            match fs::read_to_string(&path) {
+                Ok(content) => peppers.extend(
+                    content
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .filter_map(|line| {
+                            let mut parts = line.splitn(2, '=');
+                            let id = parts.next()?.trim();
+                            let secret = parts.next()?.trim();
+                            if id.is_empty() || secret.is_empty() {
+                                log::error!("Invalid entry in the pepper file {}", path);
+                                None
+                            } else {
+                                Some(Pepper {
+                                    id: id.to_string(),
+                                    secret: Zeroizing::new(secret.as_bytes().to_vec()),
+                                })
+                            }
+                        }),
+                ),
+                Err(e) => log::error!("Failed to read the pepper file {}: {}", path, e),
+            }
+        }
+        if let Ok(secret) = config::var(ENV_PASSWORD_PEPPER) {
+            let id = config::var(ENV_PASSWORD_PEPPER_ID)
+                .unwrap_or(DEFAULT_PASSWORD_PEPPER_ID.to_string());
+            peppers.retain(|pepper| pepper.id != id);
+            peppers.push(Pepper {
+                id,
+                secret: Zeroizing::new(secret.into_bytes()),
+            });
+        }
+        let current = peppers.pop();
+
+        Self {
+            current,
+            previous: peppers,
+        }
+    }
+}
+
+/// The algorithm a password hash was created with together with its parameters.
+/// The identifier is stored with each hash so that hashes created with an
+/// older algorithm or older parameters can still be verified and upgraded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PasswordAlgorithm {
+    /// BCrypt of the SHA-256 digest of the password
+    Bcrypt { cost: u32 },
+    Argon2id {
+        /// The memory cost in KiB
+        memory_cost: u32,
+        time_cost: u32,
+        parallelism: u32,
+    },
+}
+
+impl PasswordAlgorithm {
+    /// Returns the algorithm new passwords are hashed with
+    pub fn configured() -> &'static PasswordAlgorithm {
+        &*CONFIGURED_ALGORITHM
+    }
+
+    /// Parses the identifier stored with a hash
+    pub fn parse(identifier: &str) -> Result<Self, String> {
+        let mut parts = identifier.splitn(2, '$');
+        let name = parts.next().unwrap_or_default();
+        let params = parts
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|p| !p.is_empty())
+            .map(|param| {
+                let mut kv = param.splitn(2, '=');
+                let key = kv.next().unwrap_or_default();
+                let value = kv
+                    .next()
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .ok_or(format!("Invalid hash parameter '{}'", param))?;
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<(&str, u32)>, String>>()?;
+        let param = |key: &str, default: u32| {
+            params
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| *v)
+                .unwrap_or(default)
+        };
+
+        match name {
+            // hashes created before the cost was stored use the default cost
+            BCRYPT => Ok(PasswordAlgorithm::Bcrypt {
+                cost: param("c", DEFAULT_BCRYPT_COST),
+            }),
+            ARGON2ID => Ok(PasswordAlgorithm::Argon2id {
+                memory_cost: param("m", DEFAULT_ARGON2_MEMORY_COST),
+                time_cost: param("t", DEFAULT_ARGON2_TIME_COST),
+                parallelism: param("p", DEFAULT_ARGON2_PARALLELISM),
+            }),
+            _ => Err(format!("Unknown password hash algorithm '{}'", name)),
+        }
+    }
+
+    /// Hashes the password with the salt
+    pub fn hash(&self, password: &[u8], salt: &[u8]) -> Result<Vec<u8>, String> {
+        let _span = crate::telemetry_span!("hash_password", algorithm = self);
+        match self {
+            PasswordAlgorithm::Bcrypt { cost } => panic::catch_unwind(|| {
+                let mut pw_hash = [0u8; 24];
+                let password = sha2::Sha256::digest(password);
+                bcrypt::bcrypt(*cost, salt, &password, &mut pw_hash);
+                pw_hash.to_vec()
+            })
+            .map_err(|_| "Hashing failed".to_string()),
+            PasswordAlgorithm::Argon2id {
+                memory_cost,
+                time_cost,
+                parallelism,
+            } => {
+                let config = Config {
+                    variant: Variant::Argon2id,
+                    version: Version::Version13,
+                    mem_cost: *memory_cost,
+                    time_cost: *time_cost,
+                    lanes: *parallelism,
+                    thread_mode: ThreadMode::Sequential,
+                    secret: &[],
+                    ad: &[],
+                    hash_length: ARGON2_HASH_LENGTH,
+                };
+                argon2::hash_raw(password, salt, &config).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Reads the algorithm from the configuration. Falls back to the default
+    /// algorithm if the configured one is unknown.
+    fn from_config(settings: &HashingConfig) -> Self {
+        let name = &settings.algorithm;
+
+        match name.to_ascii_lowercase().as_str() {
+            BCRYPT => PasswordAlgorithm::Bcrypt {
+                cost: settings
+                    .bcrypt_cost
+                    .max(MIN_BCRYPT_COST)
+                    .min(MAX_BCRYPT_COST),
+            },
+            ARGON2ID => PasswordAlgorithm::Argon2id {
+                memory_cost: settings.argon2_memory_cost,
+                time_cost: settings.argon2_time_cost.max(1),
+                parallelism: settings.argon2_parallelism.max(1),
+            },
+            _ => {
+                log::error!(
+                    "Unknown password hash algorithm '{}'. Using {}",
+                    name,
+                    DEFAULT_PASSWORD_HASH_ALGORITHM
+                );
+                PasswordAlgorithm::Argon2id {
+                    memory_cost: DEFAULT_ARGON2_MEMORY_COST,
+                    time_cost: DEFAULT_ARGON2_TIME_COST,
+                    parallelism: DEFAULT_ARGON2_PARALLELISM,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for PasswordAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordAlgorithm::Bcrypt { cost } => write!(f, "{}$c={}", BCRYPT, cost),
+            PasswordAlgorithm::Argon2id {
+                memory_cost,
+                time_cost,
+                parallelism,
+            } => write!(
+                f,
+                "{}$m={},t={},p={}",
+                ARGON2ID, memory_cost, time_cost, parallelism
+            ),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::Builder;
+
+use parking_lot::Mutex;
+
+use crate::utils::config::{Config, HashingConfig};
+use crate::utils::error::{DBError, DatabaseResult};
+
+pub(crate) const ENV_HASHING_WORKERS: &str = "HASHING_WORKERS";
+pub(crate) const ENV_HASHING_QUEUE_SIZE: &str = "HASHING_QUEUE_SIZE";
+pub(crate) const DEFAULT_HASHING_QUEUE_SIZE: usize = 64;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+lazy_static::lazy_static! {
+    pub static ref HASHING_POOL: HashingPool = HashingPool::from_config(&Config::get().hashing);
+}
+
+/// A fixed number of threads that hash passwords so that a burst of logins
+/// can't occupy all request threads. Jobs wait in a bounded queue and are
+/// rejected with [DBError::HashingBusy] when the queue is full.
+pub struct HashingPool {
+    sender: SyncSender<Job>,
+    workers: usize,
+    queue_size: usize,
+    queued: Arc<AtomicUsize>,
+    rejected: AtomicU64,
+}
+
+impl HashingPool {
+    /// Starts the workers with the number of workers and the queue size of the configuration.
+    /// There is one worker per cpu by default.
+    fn from_config(settings: &HashingConfig) -> Self {
+        Self::new(settings.workers.max(1), settings.queue_size.max(1))
+    }
+
+    pub fn new(workers: usize, queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+        for _ in 0..workers {
+            let receiver = Arc::clone(&receiver);
+            let queued = Arc::clone(&queued);
+            Builder::new()
+                .name("password-hashing".to_string())
+                .spawn(move || HashingPool::work(receiver, queued))
+                .expect("Failed to start a password hashing thread");
+        }
+        log::debug!(
+            "Started {} password hashing workers with a queue of {}",
+            workers,
+            queue_size
+        );
+
+        Self {
+            sender,
+            workers,
+            queue_size,
+            queued,
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs the function on one of the workers and waits for its result.
+    /// Fails immediately if all workers are busy and the queue is full.
+    pub fn execute<T, F>(&self, func: F) -> DatabaseResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (result_tx, result_rx) = mpsc::channel();
+        let job: Job = Box::new(move || {
+            let _ = result_tx.send(func());
+        });
+        self.queued.fetch_add(1, Ordering::Relaxed);
+
+        match self.sender.try_send(job) {
+            Ok(_) => result_rx
+                .recv()
+                .map_err(|_| DBError::GenericError("Password hashing failed".to_string())),
+            Err(TrySendError::Full(_)) => {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                log::warn!("Rejected a password hash because the hashing queue is full");
+                Err(DBError::HashingBusy)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                Err(DBError::GenericError(
+                    "The password hashing workers have stopped".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Returns the number of threads that hash passwords
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Returns the maximum number of hashes that wait for a free worker
+    pub fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+
+    /// Returns the number of hashes that are waiting or being computed
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of hashes that were rejected since the start
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    fn work(receiver: Arc<Mutex<Receiver<Job>>>, queued: Arc<AtomicUsize>) {
+        loop {
+            let job = match receiver.lock().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            // a panicking job drops its result sender which fails the waiting request
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            queued.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
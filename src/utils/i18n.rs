@@ -0,0 +1,289 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+
+use crate::utils::config;
+use crate::utils::password::{
+    SUGGEST_AVOID_PATTERNS, SUGGEST_AVOID_PERSONAL, SUGGEST_LONGER, SUGGEST_MIX_CHARACTERS,
+    WARN_COMMON, WARN_REPEATED, WARN_SEQUENCE, WARN_TOO_SHORT, WARN_USER_INPUT,
+};
+
+pub const ERR_BAD_REQUEST: &str = "BAD_REQUEST";
+pub const ERR_UNAUTHORIZED: &str = "UNAUTHORIZED";
+pub const ERR_FORBIDDEN: &str = "FORBIDDEN";
+pub const ERR_NOT_FOUND: &str = "NOT_FOUND";
+pub const ERR_SERVICE_UNAVAILABLE: &str = "SERVICE_UNAVAILABLE";
+pub const ERR_INTERNAL: &str = "INTERNAL_ERROR";
+pub const ERR_INVALID_TOKEN: &str = "INVALID_TOKEN";
+pub const ERR_UNKNOWN_CERTIFICATE: &str = "UNKNOWN_CERTIFICATE";
+pub const ERR_INVALID_API_KEY: &str = "INVALID_API_KEY";
+pub const ERR_PASSWORD_CHANGE_REQUIRED: &str = "PASSWORD_CHANGE_REQUIRED";
+pub const ERR_INVALID_CREDENTIALS: &str = "INVALID_CREDENTIALS";
+pub const ERR_INSUFFICIENT_PERMISSIONS: &str = "INSUFFICIENT_PERMISSIONS";
+pub const ERR_MISSING_REQUEST_DATA: &str = "MISSING_REQUEST_DATA";
+pub const ERR_INVALID_REQUEST_DATA: &str = "INVALID_REQUEST_DATA";
+pub const ERR_TOO_MANY_REQUESTS: &str = "TOO_MANY_REQUESTS";
+pub const ERR_UNSUPPORTED_API_VERSION: &str = "UNSUPPORTED_API_VERSION";
+pub const ERR_QUOTA_EXCEEDED: &str = "QUOTA_EXCEEDED";
+pub const ERR_LIMIT_EXCEEDED: &str = "LIMIT_EXCEEDED";
+pub const ERR_SYSTEM_RECORD: &str = "SYSTEM_RECORD";
+pub const ERR_PERMISSIONS_NOT_FOUND: &str = "PERMISSIONS_NOT_FOUND";
+pub const ERR_RECORD_EXISTS: &str = "RECORD_EXISTS";
+pub const ERR_RECORD_DOES_NOT_EXIST: &str = "RECORD_DOES_NOT_EXIST";
+pub const ERR_DATABASE: &str = "DATABASE_ERROR";
+pub const ERR_HASH: &str = "HASH_ERROR";
+pub const ERR_HASHING_BUSY: &str = "HASHING_BUSY";
+pub const ERR_GENERIC: &str = "GENERIC_ERROR";
+pub const ERR_INVALID_ATTRIBUTES: &str = "INVALID_ATTRIBUTES";
+pub const ERR_FIELD_REQUIRED: &str = "FIELD_REQUIRED";
+pub const ERR_FIELD_TYPE_MISMATCH: &str = "FIELD_TYPE_MISMATCH";
+pub const ERR_FIELD_INVALID_VALUE: &str = "FIELD_INVALID_VALUE";
+pub const ERR_INVALID_FIELDS: &str = "INVALID_FIELDS";
+pub const ERR_WEAK_PASSWORD: &str = "WEAK_PASSWORD";
+
+pub(crate) const ENV_DEFAULT_LANGUAGE: &str = "DEFAULT_LANGUAGE";
+pub(crate) const DEFAULT_LANGUAGE: &str = "en";
+
+thread_local! {
+    static LANGUAGE: Cell<Option<Language>> = const { Cell::new(None) };
+}
+
+lazy_static::lazy_static! {
+    static ref CONFIGURED_LANGUAGE: Language = Language::from_tag(
+        &config::var(ENV_DEFAULT_LANGUAGE).unwrap_or(DEFAULT_LANGUAGE.to_string())
+    ).unwrap_or(Language::English);
+}
+
+/// The languages messages are available in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    English,
+    German,
+}
+
+impl Language {
+    /// Returns the language that is used if the client doesn't request a supported one
+    pub fn default_language() -> Self {
+        *CONFIGURED_LANGUAGE
+    }
+
+    /// Returns the supported language with the highest quality value
+    /// of an Accept-Language header
+    pub fn from_accept_language(header: Option<&str>) -> Self {
+        header
+            .and_then(|header| {
+                let mut languages = header
+                    .split(',')
+                    .filter_map(|entry| {
+                        let mut parts = entry.trim().split(';');
+                        let tag = parts.next()?.trim();
+                        let quality = parts
+                            .find_map(|p| p.trim().strip_prefix("q="))
+                            .and_then(|q| q.parse::<f32>().ok())
+                            .unwrap_or(1.0);
+
+                        Some((tag, quality))
+                    })
+                    .filter(|(_, quality)| *quality > 0.0)
+                    .collect::<Vec<(&str, f32)>>();
+                languages.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+                languages
+                    .into_iter()
+                    .find_map(|(tag, _)| Self::from_tag(tag))
+            })
+            .unwrap_or_else(Self::default_language)
+    }
+
+    /// Returns the language for a language tag like en-US or de
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split('-').next()?.trim().to_lowercase();
+
+        match primary.as_str() {
+            "en" => Some(Language::English),
+            "de" => Some(Language::German),
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::German => "de",
+        }
+    }
+}
+
+/// Forgets the language of the current thread when dropped
+struct LanguageScope;
+
+impl Drop for LanguageScope {
+    fn drop(&mut self) {
+        LANGUAGE.with(|l| l.set(None));
+    }
+}
+
+/// Runs the given closure with the language of the current thread set
+/// to the language requested by the client.
+/// The language is forgotten afterwards, even if the closure panics.
+pub fn with_language<T, F: FnOnce() -> T>(language: Language, func: F) -> T {
+    let _scope = LanguageScope;
+    LANGUAGE.with(|l| l.set(Some(language)));
+    func()
+}
+
+/// Returns the language requested for the request handled by the current thread
+pub fn current_language() -> Language {
+    LANGUAGE
+        .with(|l| l.get())
+        .unwrap_or_else(Language::default_language)
+}
+
+/// Returns the message for an error code in the given language with all
+/// placeholders replaced by the given arguments.
+/// None is returned for codes that aren't part of the catalog.
+pub fn message(code: &str, language: Language, args: &[(&str, &str)]) -> Option<String> {
+    let (english, german) = catalog_entry(code)?;
+    let mut message = match language {
+        Language::English => english,
+        Language::German => german,
+    }
+    .to_string();
+
+    for (name, value) in args {
+        message = message.replace(&format!("{{{}}}", name), value);
+    }
+
+    Some(message)
+}
+
+/// Returns the english and german message of an error code
+fn catalog_entry(code: &str) -> Option<(&'static str, &'static str)> {
+    let entry = match code {
+        ERR_BAD_REQUEST => ("Bad request", "Ungültige Anfrage"),
+        ERR_UNAUTHORIZED => ("401 Unauthorized", "401 Nicht autorisiert"),
+        ERR_FORBIDDEN => ("Forbidden", "Zugriff verweigert"),
+        ERR_NOT_FOUND => ("Not found", "Nicht gefunden"),
+        ERR_SERVICE_UNAVAILABLE => ("Service unavailable", "Dienst nicht verfügbar"),
+        ERR_INTERNAL => ("Internal server error", "Interner Serverfehler"),
+        ERR_INVALID_TOKEN => ("Invalid request token", "Ungültiges Anfrage-Token"),
+        ERR_UNKNOWN_CERTIFICATE => (
+            "The client certificate isn't mapped to an active account",
+            "Das Client-Zertifikat ist keinem aktiven Konto zugeordnet",
+        ),
+        ERR_INVALID_API_KEY => (
+            "The api key is invalid or expired",
+            "Der API-Schlüssel ist ungültig oder abgelaufen",
+        ),
+        ERR_PASSWORD_CHANGE_REQUIRED => (
+            "The password needs to be changed before the account can be used",
+            "Das Passwort muss geändert werden, bevor das Konto verwendet werden kann",
+        ),
+        ERR_INVALID_CREDENTIALS => ("Invalid authentication data", "Ungültige Anmeldedaten"),
+        ERR_INSUFFICIENT_PERMISSIONS => {
+            ("Insufficient permissions", "Unzureichende Berechtigungen")
+        }
+        ERR_MISSING_REQUEST_DATA => ("Missing request data!", "Fehlende Anfragedaten!"),
+        ERR_INVALID_REQUEST_DATA => (
+            "Failed to parse request data: {detail}",
+            "Die Anfragedaten konnten nicht gelesen werden: {detail}",
+        ),
+        ERR_TOO_MANY_REQUESTS => (
+            "Too many requests. Try again later.",
+            "Zu viele Anfragen. Bitte später erneut versuchen.",
+        ),
+        ERR_UNSUPPORTED_API_VERSION => (
+            "The api version {version} is not supported",
+            "Die API-Version {version} wird nicht unterstützt",
+        ),
+        ERR_QUOTA_EXCEEDED => (
+            "The request quota of this account is exceeded. Try again in {reset} seconds.",
+            "Das Anfragekontingent dieses Kontos ist aufgebraucht. Bitte in {reset} Sekunden erneut versuchen.",
+        ),
+        ERR_LIMIT_EXCEEDED => (
+            "The limit of {limit} {resource} is reached",
+            "Das Limit von {limit} {resource} ist erreicht",
+        ),
+        ERR_SYSTEM_RECORD => (
+            "{name} is a system record and can't be changed",
+            "{name} ist ein Systemeintrag und kann nicht geändert werden",
+        ),
+        ERR_PERMISSIONS_NOT_FOUND => (
+            "The permissions {permissions} don't exist",
+            "Die Berechtigungen {permissions} existieren nicht",
+        ),
+        ERR_RECORD_EXISTS => ("Record exists", "Der Eintrag existiert bereits"),
+        ERR_RECORD_DOES_NOT_EXIST => ("Record does not exist", "Der Eintrag existiert nicht"),
+        ERR_HASH => (
+            "BCrypt Hash creation error",
+            "Fehler beim Erstellen des BCrypt-Hashes",
+        ),
+        ERR_HASHING_BUSY => (
+            "Too many passwords are being checked at the moment. Try again later.",
+            "Zurzeit werden zu viele Passwörter geprüft. Bitte später erneut versuchen.",
+        ),
+        ERR_INVALID_ATTRIBUTES => (
+            "Invalid attributes: {errors}",
+            "Ungültige Attribute: {errors}",
+        ),
+        ERR_FIELD_REQUIRED => (
+            "The field '{field}' is required",
+            "Das Feld '{field}' ist erforderlich",
+        ),
+        ERR_FIELD_TYPE_MISMATCH => (
+            "The field '{field}' needs to be of type {type}",
+            "Das Feld '{field}' muss vom Typ {type} sein",
+        ),
+        ERR_FIELD_INVALID_VALUE => (
+            "The field '{field}' has an invalid value",
+            "Das Feld '{field}' hat einen ungültigen Wert",
+        ),
+        ERR_INVALID_FIELDS => (
+            "Invalid request data: {errors}",
+            "Ungültige Anfragedaten: {errors}",
+        ),
+        ERR_WEAK_PASSWORD => (
+            "The password doesn't satisfy the password policy",
+            "Das Passwort erfüllt nicht die Passwortrichtlinie",
+        ),
+        WARN_TOO_SHORT => ("The password is too short", "Das Passwort ist zu kurz"),
+        WARN_COMMON => (
+            "This is a very common password",
+            "Dies ist ein sehr häufig verwendetes Passwort",
+        ),
+        WARN_REPEATED => (
+            "Repeated characters are easy to guess",
+            "Wiederholte Zeichen sind leicht zu erraten",
+        ),
+        WARN_SEQUENCE => (
+            "Sequences like abc or 123 are easy to guess",
+            "Folgen wie abc oder 123 sind leicht zu erraten",
+        ),
+        WARN_USER_INPUT => (
+            "The password contains your name or email address",
+            "Das Passwort enthält den Namen oder die E-Mail-Adresse",
+        ),
+        SUGGEST_LONGER => (
+            "Use a longer password or add a few more words",
+            "Ein längeres Passwort oder weitere Wörter verwenden",
+        ),
+        SUGGEST_MIX_CHARACTERS => (
+            "Mix upper and lower case letters, numbers and symbols",
+            "Groß- und Kleinbuchstaben, Zahlen und Sonderzeichen mischen",
+        ),
+        SUGGEST_AVOID_PATTERNS => (
+            "Avoid repeated characters, sequences and keyboard patterns",
+            "Wiederholungen, Folgen und Tastaturmuster vermeiden",
+        ),
+        SUGGEST_AVOID_PERSONAL => (
+            "Avoid using your name or email address",
+            "Den eigenen Namen oder die E-Mail-Adresse vermeiden",
+        ),
+        _ => return None,
+    };
+
+    Some(entry)
+}
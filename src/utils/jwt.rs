@@ -0,0 +1,192 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::convert::TryFrom;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rsa::{PublicKeyParts, RSAPrivateKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::database::models::UserInformation;
+use crate::utils::config::Config;
+
+pub(crate) const ENV_JWT_PRIVATE_KEY: &str = "JWT_PRIVATE_KEY";
+pub(crate) const ENV_JWT_ISSUER: &str = "JWT_ISSUER";
+pub(crate) const DEFAULT_JWT_ISSUER: &str = "flotte-user-management";
+
+lazy_static::lazy_static! {
+    static ref JWT_KEY: Result<Option<JwtKey>, String> = load_key();
+    static ref JWT_ISSUER: String = Config::get().jwt.issuer.clone();
+}
+
+/// The key used to sign the JWTs together with the public part as JWK
+struct JwtKey {
+    encoding_key: EncodingKey,
+    jwk: Jwk,
+}
+
+/// The public key used to verify JWTs in the JSON Web Key format
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    pub alg: String,
+    pub kid: String,
+    pub n: String,
+    pub e: String,
+}
+
+/// The set of public keys that can be used to verify JWTs
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/// The claims of a JWT request token
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    /// The id of the user
+    pub sub: String,
+    pub roles: Vec<String>,
+    pub iat: u64,
+    pub exp: u64,
+}
+
+/// The claims of an OpenID Connect id token issued to a client application
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    /// The id of the user
+    pub sub: String,
+    /// The id of the client application the token was issued to
+    pub aud: String,
+    pub iat: u64,
+    pub exp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    pub name: String,
+    pub email: String,
+    pub email_verified: bool,
+}
+
+/// Fails if a signing key is configured that can't be loaded
+pub fn check_key() -> Result<(), String> {
+    JWT_KEY.as_ref().map(|_| ()).map_err(String::clone)
+}
+
+/// Returns if JWTs are issued because a signing key is configured
+pub fn is_enabled() -> bool {
+    signing_key().is_some()
+}
+
+/// Returns the configured issuer of the JWTs
+pub fn issuer() -> &'static str {
+    JWT_ISSUER.as_str()
+}
+
+/// Returns the public keys that can be used to verify the issued JWTs
+pub fn jwks() -> JwkSet {
+    JwkSet {
+        keys: signing_key().iter().map(|key| key.jwk.clone()).collect(),
+    }
+}
+
+/// Creates a signed JWT for the user that expires after the given ttl.
+/// Returns None if no signing key is configured.
+pub fn create_token(user_id: i32, roles: Vec<String>, ttl: i32) -> Result<Option<String>, String> {
+    let now = unix_now();
+    sign(&Claims {
+        iss: JWT_ISSUER.clone(),
+        sub: user_id.to_string(),
+        roles,
+        iat: now,
+        exp: now + ttl.max(0) as u64,
+    })
+}
+
+/// Creates a signed OpenID Connect id token for the client application.
+/// Returns None if no signing key is configured.
+pub fn create_id_token(
+    user: &UserInformation,
+    email_verified: bool,
+    client_id: &str,
+    nonce: Option<String>,
+    ttl: i32,
+) -> Result<Option<String>, String> {
+    let now = unix_now();
+    sign(&IdTokenClaims {
+        iss: JWT_ISSUER.clone(),
+        sub: user.id.to_string(),
+        aud: client_id.to_string(),
+        iat: now,
+        exp: now + ttl.max(0) as u64,
+        nonce,
+        name: user.name.clone(),
+        email: user.email.clone(),
+        email_verified,
+    })
+}
+
+fn sign<T: Serialize>(claims: &T) -> Result<Option<String>, String> {
+    let key = match signing_key() {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(key.jwk.kid.clone());
+
+    jsonwebtoken::encode(&header, claims, &key.encoding_key)
+        .map(Some)
+        .map_err(|e| format!("Failed to create the JWT: {}", e))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the signing key that was checked with [check_key] on startup
+fn signing_key() -> Option<&'static JwtKey> {
+    JWT_KEY.as_ref().ok().and_then(Option::as_ref)
+}
+
+/// Loads the RSA private key from the configured PEM file.
+/// JWTs are disabled if no key is configured.
+fn load_key() -> Result<Option<JwtKey>, String> {
+    match &Config::get().jwt.private_key {
+        Some(path) => read_key(path)
+            .map(Some)
+            .map_err(|e| format!("Failed to load the JWT signing key {}: {}", path, e)),
+        None => Ok(None),
+    }
+}
+
+fn read_key(path: &str) -> Result<JwtKey, String> {
+    let content = fs::read(path).map_err(|e| e.to_string())?;
+    let encoding_key = EncodingKey::from_rsa_pem(&content).map_err(|e| e.to_string())?;
+    let pem = rsa::pem::parse(&content).map_err(|e| e.to_string())?;
+    let private_key = RSAPrivateKey::try_from(pem).map_err(|e| e.to_string())?;
+    let n = private_key.n().to_bytes_be();
+    let e = private_key.e().to_bytes_be();
+    let kid = base64::encode_config(&Sha256::digest(&n)[..8], base64::URL_SAFE_NO_PAD);
+
+    Ok(JwtKey {
+        encoding_key,
+        jwk: Jwk {
+            kty: "RSA".to_string(),
+            key_use: "sig".to_string(),
+            alg: "RS256".to_string(),
+            kid,
+            n: base64::encode_config(&n, base64::URL_SAFE_NO_PAD),
+            e: base64::encode_config(&e, base64::URL_SAFE_NO_PAD),
+        },
+    })
+}
@@ -2,17 +2,61 @@
 //  Copyright (C) 2020 trivernis
 //  See LICENSE for more information
 
-use std::panic;
-
-use bcrypt::DEFAULT_COST;
 use byteorder::{BigEndian, ByteOrder};
+use hmac::{Hmac, Mac, NewMac};
 use rand::Rng;
-use sha2::Digest;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use crate::utils::error::{DBError, DatabaseResult};
+use crate::utils::hashing::{PasswordAlgorithm, Pepper};
+use crate::utils::hashing_pool::HASHING_POOL;
 
+pub mod config;
 pub mod error;
+pub mod hashing;
+pub mod hashing_pool;
+pub mod i18n;
+pub mod jwt;
+pub mod password;
+pub mod telemetry;
 
-pub const TOKEN_LENGTH: usize = 32;
+/// The length of a token consisting of the random payload and the signature
+pub const TOKEN_LENGTH: usize = TOKEN_PAYLOAD_LENGTH + TOKEN_SIGNATURE_LENGTH;
+const TOKEN_PAYLOAD_LENGTH: usize = 32;
+const TOKEN_SIGNATURE_LENGTH: usize = 16;
 const SALT_LENGTH: usize = 16;
+const GENERATED_PASSWORD_LENGTH: usize = 24;
+const SECRET_TOKEN_LENGTH: usize = 32;
+pub(crate) const ENV_TOKEN_SECRET: &str = "TOKEN_SECRET";
+
+lazy_static::lazy_static! {
+    static ref TOKEN_SECRET: Result<Vec<u8>, String> = get_token_secret();
+}
+
+/// Generates a random alphanumeric password
+pub fn generate_password() -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(GENERATED_PASSWORD_LENGTH)
+        .collect()
+}
+
+/// Creates a random url safe token for single-use links like confirmations.
+/// Only the hash of the token should be stored.
+pub fn create_secret_token() -> String {
+    let mut token = [0u8; SECRET_TOKEN_LENGTH];
+    rand::thread_rng().fill(&mut token);
+
+    base64::encode_config(&token, base64::URL_SAFE_NO_PAD)
+}
+
+/// Returns the hash of a token created with [create_secret_token] that is stored
+/// to look up the token
+pub fn hash_secret_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
 
 /// Creates a new random salt
 pub fn create_salt() -> [u8; SALT_LENGTH] {
@@ -24,19 +68,71 @@ pub fn create_salt() -> [u8; SALT_LENGTH] {
 }
 
 /// Creates a new random user token where the first 4 bytes represent
-/// the userId
+/// the userId. The random payload is followed by a HMAC signature
+/// that is created with the server secret.
 pub fn create_user_token(user_id: i32) -> [u8; TOKEN_LENGTH] {
     let mut rng = rand::thread_rng();
     let mut value = [0u8; TOKEN_LENGTH];
-    rng.fill(&mut value);
+    rng.fill(&mut value[..TOKEN_PAYLOAD_LENGTH]);
     BigEndian::write_i32(&mut value, user_id);
+    let signature = sign_token_payload(&value[..TOKEN_PAYLOAD_LENGTH]);
+    value[TOKEN_PAYLOAD_LENGTH..].copy_from_slice(&signature);
 
     value
 }
 
+/// Verifies the signature of a token so forged tokens can be rejected
+/// without looking them up
+pub fn verify_token_signature(token: &[u8]) -> bool {
+    if token.len() != TOKEN_LENGTH {
+        return false;
+    }
+    let signature = sign_token_payload(&token[..TOKEN_PAYLOAD_LENGTH]);
+
+    constant_time_eq(&signature, &token[TOKEN_PAYLOAD_LENGTH..])
+}
+
+/// Verifies the signature of a base64 encoded token
+pub fn verify_encoded_token(token: &String) -> bool {
+    base64::decode(token)
+        .map(|token| verify_token_signature(&token))
+        .unwrap_or(false)
+}
+
+/// Creates the truncated HMAC-SHA256 signature for a token payload
+fn sign_token_payload(payload: &[u8]) -> [u8; TOKEN_SIGNATURE_LENGTH] {
+    let secret = TOKEN_SECRET
+        .as_ref()
+        .expect("The token secret is checked on startup");
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any size");
+    mac.update(payload);
+    let mut signature = [0u8; TOKEN_SIGNATURE_LENGTH];
+    signature.copy_from_slice(&mac.finalize().into_bytes()[..TOKEN_SIGNATURE_LENGTH]);
+
+    signature
+}
+
+/// Fails if no secret for signing tokens is configured
+pub fn check_token_secret() -> Result<(), String> {
+    TOKEN_SECRET.as_ref().map(|_| ()).map_err(String::clone)
+}
+
+/// Returns the secret used to sign tokens. Sessions are persisted in postgres
+/// or redis, so a random secret would invalidate them on every restart and
+/// differ between the instances sharing the session store.
+fn get_token_secret() -> Result<Vec<u8>, String> {
+    match config::var(ENV_TOKEN_SECRET) {
+        Ok(secret) if !secret.is_empty() => Ok(secret.into_bytes()),
+        _ => Err(format!(
+            "{} has to be set as the sessions are persisted",
+            ENV_TOKEN_SECRET
+        )),
+    }
+}
+
 /// Extracts the userId from a request token
-pub fn get_user_id_from_token(token: &String) -> Option<i32> {
-    let token = base64::decode(&token).ok()?;
+pub fn get_user_id_from_token(token: &str) -> Option<i32> {
+    let token = base64::decode(token).ok()?;
     if token.len() > 4 {
         Some(BigEndian::read_i32(token.as_slice()))
     } else {
@@ -44,13 +140,31 @@ pub fn get_user_id_from_token(token: &String) -> Option<i32> {
     }
 }
 
-/// Hashes a password with a salt by using BCrypt
-pub fn hash_password(password: &[u8], salt: &[u8]) -> Result<[u8; 24], String> {
-    panic::catch_unwind(|| {
-        let mut pw_hash = [0u8; 24];
-        let password = sha2::Sha256::digest(password);
-        bcrypt::bcrypt(DEFAULT_COST, salt, password.as_slice(), &mut pw_hash);
-        Ok(pw_hash)
-    })
-    .map_err(|_| "Hashing failed".to_string())?
+/// Compares two byte slices in constant time so that the duration of the
+/// comparison doesn't reveal how many leading bytes are equal
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Hashes a password with a salt by using the given algorithm. If a pepper is given
+/// it is mixed into the password before hashing. New passwords should be hashed with
+/// [PasswordAlgorithm::configured] and [Pepper::current].
+/// The hash is computed by the [HASHING_POOL] and fails with [DBError::HashingBusy]
+/// if too many passwords are being hashed at the same time.
+pub fn hash_password(
+    password: &[u8],
+    salt: &[u8],
+    algorithm: &PasswordAlgorithm,
+    pepper: Option<&'static Pepper>,
+) -> DatabaseResult<Vec<u8>> {
+    let password = Zeroizing::new(password.to_vec());
+    let salt = salt.to_vec();
+    let algorithm = algorithm.clone();
+
+    HASHING_POOL
+        .execute(move || match pepper {
+            Some(pepper) => algorithm.hash(&pepper.apply(&password), &salt),
+            None => algorithm.hash(&password, &salt),
+        })?
+        .map_err(DBError::GenericError)
 }
@@ -0,0 +1,257 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+use std::collections::HashSet;
+
+use crate::utils::config;
+use crate::utils::i18n::{self, Language};
+
+pub const WARN_TOO_SHORT: &str = "PASSWORD_TOO_SHORT";
+pub const WARN_COMMON: &str = "PASSWORD_COMMON";
+pub const WARN_REPEATED: &str = "PASSWORD_REPEATED";
+pub const WARN_SEQUENCE: &str = "PASSWORD_SEQUENCE";
+pub const WARN_USER_INPUT: &str = "PASSWORD_CONTAINS_USER_INPUT";
+pub const SUGGEST_LONGER: &str = "SUGGEST_LONGER";
+pub const SUGGEST_MIX_CHARACTERS: &str = "SUGGEST_MIX_CHARACTERS";
+pub const SUGGEST_AVOID_PATTERNS: &str = "SUGGEST_AVOID_PATTERNS";
+pub const SUGGEST_AVOID_PERSONAL: &str = "SUGGEST_AVOID_PERSONAL";
+
+pub(crate) const ENV_PASSWORD_MIN_SCORE: &str = "PASSWORD_MIN_SCORE";
+pub(crate) const ENV_PASSWORD_MIN_LENGTH: &str = "PASSWORD_MIN_LENGTH";
+pub(crate) const DEFAULT_PASSWORD_MIN_SCORE: u8 = 2;
+pub(crate) const DEFAULT_PASSWORD_MIN_LENGTH: usize = 8;
+
+const SEQUENCES: &[&str] = &[
+    "abcdefghijklmnopqrstuvwxyz",
+    "0123456789",
+    "qwertzuiopü",
+    "qwertyuiop",
+    "asdfghjklöä",
+    "yxcvbnm",
+    "zxcvbnm",
+];
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "passwort",
+    "123456",
+    "12345678",
+    "123456789",
+    "qwerty",
+    "qwertz",
+    "letmein",
+    "welcome",
+    "willkommen",
+    "admin",
+    "hallo",
+    "iloveyou",
+    "monkey",
+    "dragon",
+    "sommer",
+    "fussball",
+    "schatz",
+    "geheim",
+    "master",
+    "login",
+    "abc123",
+    "flotte",
+    "berlin",
+    "lastenrad",
+];
+
+lazy_static::lazy_static! {
+    static ref POLICY: PasswordPolicy = PasswordPolicy::from_env();
+}
+
+/// The minimum requirements a password needs to fulfill
+/// when creating or updating a user
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    pub min_score: u8,
+    pub min_length: usize,
+}
+
+impl PasswordPolicy {
+    fn from_env() -> Self {
+        Self {
+            min_score: config::var(ENV_PASSWORD_MIN_SCORE)
+                .ok()
+                .and_then(|v| v.parse::<u8>().ok())
+                .unwrap_or(DEFAULT_PASSWORD_MIN_SCORE)
+                .min(4),
+            min_length: config::var(ENV_PASSWORD_MIN_LENGTH)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(DEFAULT_PASSWORD_MIN_LENGTH),
+        }
+    }
+
+    /// Returns the policy configured via the env
+    pub fn get() -> &'static Self {
+        &POLICY
+    }
+
+    /// Returns if the estimated strength satisfies the policy
+    pub fn accepts(&self, strength: &PasswordStrength) -> bool {
+        strength.length >= self.min_length && strength.score >= self.min_score
+    }
+}
+
+/// The estimated strength of a password.
+/// The score ranges from 0 (too guessable) to 4 (very unguessable).
+#[derive(Clone, Debug)]
+pub struct PasswordStrength {
+    pub score: u8,
+    pub guesses_log10: f64,
+    pub length: usize,
+    pub warning: Option<&'static str>,
+    pub suggestions: Vec<&'static str>,
+}
+
+impl PasswordStrength {
+    /// Returns the localized warning
+    pub fn warning_message(&self, language: Language) -> Option<String> {
+        self.warning
+            .map(|code| i18n::message(code, language, &[]).unwrap_or(code.to_string()))
+    }
+
+    /// Returns the localized suggestions
+    pub fn suggestion_messages(&self, language: Language) -> Vec<String> {
+        self.suggestions
+            .iter()
+            .map(|code| i18n::message(code, language, &[]).unwrap_or(code.to_string()))
+            .collect()
+    }
+}
+
+/// Estimates the strength of a password similar to zxcvbn. The number of guesses
+/// is derived from the character pool and reduced for common passwords, repeated
+/// characters, sequences, keyboard rows and parts of the given user inputs.
+pub fn estimate_strength(password: &str, user_inputs: &[&str]) -> PasswordStrength {
+    let lowercase = password.to_lowercase();
+    let chars = lowercase.chars().collect::<Vec<char>>();
+    let length = password.chars().count();
+    let mut warning = None;
+    let mut suggestions = Vec::new();
+
+    let mut effective_length = length as f64;
+    let repeated = count_repeated(&chars);
+    let sequential = count_sequential(&chars);
+    effective_length -= repeated as f64 * 0.75 + sequential as f64 * 0.75;
+
+    if repeated * 2 >= length && length > 0 {
+        warning = Some(WARN_REPEATED);
+        suggestions.push(SUGGEST_AVOID_PATTERNS);
+    } else if sequential * 2 >= length && length > 0 {
+        warning = Some(WARN_SEQUENCE);
+        suggestions.push(SUGGEST_AVOID_PATTERNS);
+    }
+    let personal_inputs = user_inputs
+        .iter()
+        .flat_map(|input| {
+            input
+                .to_lowercase()
+                .split(|c: char| !c.is_alphanumeric())
+                .map(String::from)
+                .collect::<Vec<String>>()
+        })
+        .filter(|part| part.chars().count() >= 3)
+        .collect::<HashSet<String>>();
+    for part in &personal_inputs {
+        if lowercase.contains(part.as_str()) {
+            effective_length -= part.chars().count() as f64 * 0.9;
+            warning = Some(WARN_USER_INPUT);
+            suggestions.push(SUGGEST_AVOID_PERSONAL);
+        }
+    }
+    let stripped = lowercase.trim_end_matches(|c: char| c.is_ascii_digit() || !c.is_alphanumeric());
+    let is_common = COMMON_PASSWORDS
+        .iter()
+        .any(|common| lowercase == *common || stripped == *common);
+
+    let guesses_log10 = if is_common {
+        warning = Some(WARN_COMMON);
+        1.0
+    } else {
+        effective_length.max(0.0) * (character_pool(password) as f64).log10()
+    };
+    if length < PasswordPolicy::get().min_length {
+        warning = warning.or(Some(WARN_TOO_SHORT));
+    }
+    let score = match guesses_log10 {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    };
+    if score < 4 {
+        suggestions.push(SUGGEST_LONGER);
+        if character_classes(password) < 3 {
+            suggestions.push(SUGGEST_MIX_CHARACTERS);
+        }
+    }
+    suggestions.dedup();
+
+    PasswordStrength {
+        score,
+        guesses_log10,
+        length,
+        warning,
+        suggestions,
+    }
+}
+
+/// Returns the number of characters that repeat the previous one
+fn count_repeated(chars: &[char]) -> usize {
+    chars.windows(2).filter(|w| w[0] == w[1]).count()
+}
+
+/// Returns the number of characters that continue a sequence
+/// like abc, 321 or a keyboard row
+fn count_sequential(chars: &[char]) -> usize {
+    chars
+        .windows(2)
+        .filter(|w| {
+            let pair = format!("{}{}", w[0], w[1]);
+            let reversed = format!("{}{}", w[1], w[0]);
+            SEQUENCES
+                .iter()
+                .any(|s| s.contains(&pair) || s.contains(&reversed))
+        })
+        .count()
+}
+
+/// Returns the number of different character classes used in the password
+fn character_classes(password: &str) -> usize {
+    let checks: [fn(&char) -> bool; 4] = [
+        |c| c.is_lowercase(),
+        |c| c.is_uppercase(),
+        |c| c.is_numeric(),
+        |c| !c.is_alphanumeric(),
+    ];
+
+    checks
+        .iter()
+        .filter(|check| password.chars().any(|c| check(&c)))
+        .count()
+}
+
+/// Returns the size of the pool of characters the password is made of
+fn character_pool(password: &str) -> usize {
+    let mut pool = 0;
+    if password.chars().any(|c| c.is_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_numeric()) {
+        pool += 10;
+    }
+    if password.chars().any(|c| !c.is_alphanumeric()) {
+        pool += 33;
+    }
+
+    pool.max(1)
+}
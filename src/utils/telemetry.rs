@@ -0,0 +1,178 @@
+//  flotte-user-management server for managing users, roles and permissions
+//  Copyright (C) 2020 trivernis
+//  See LICENSE for more information
+
+//! Tracing of requests with OpenTelemetry. The spans are only recorded
+//! when the server is built with the telemetry feature and an OTLP endpoint
+//! is configured. Otherwise the spans compile to nothing.
+
+#[cfg(feature = "telemetry")]
+pub use tracing;
+
+#[cfg(feature = "telemetry")]
+pub(crate) const ENV_OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+#[cfg(feature = "telemetry")]
+pub(crate) const ENV_SERVICE_NAME: &str = "OTEL_SERVICE_NAME";
+#[cfg(feature = "telemetry")]
+pub(crate) const DEFAULT_SERVICE_NAME: &str = "flotte-user-management";
+/// The W3C header that carries the context of the trace of the caller
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Enters a span that is exported via OpenTelemetry and ends when the
+/// returned guard is dropped. Fields are recorded with their Display impl.
+/// A W3C traceparent of the caller can be passed to continue its trace.
+///
+/// telemetry_span!("name", field = value, db.statement = query)
+/// telemetry_span!(parent: Some(traceparent), "name", field = value)
+#[macro_export]
+macro_rules! telemetry_span {
+    (parent: $parent:expr, $name:expr $(, $($key:ident).+ = $value:expr)* $(,)?) => {{
+        #[cfg(feature = "telemetry")]
+        let guard = {
+            let span = $crate::utils::telemetry::tracing::info_span!(
+                $name $(, $($key).+ = %$value)*
+            );
+            $crate::utils::telemetry::set_remote_parent(&span, $parent);
+            $crate::utils::telemetry::SpanGuard::new(span)
+        };
+        #[cfg(not(feature = "telemetry"))]
+        let guard = {
+            let _ = $parent;
+            $crate::utils::telemetry::SpanGuard::disabled()
+        };
+        guard
+    }};
+    ($name:expr $(, $($key:ident).+ = $value:expr)* $(,)?) => {{
+        #[cfg(feature = "telemetry")]
+        let guard = $crate::utils::telemetry::SpanGuard::new(
+            $crate::utils::telemetry::tracing::info_span!($name $(, $($key).+ = %$value)*)
+        );
+        #[cfg(not(feature = "telemetry"))]
+        let guard = $crate::utils::telemetry::SpanGuard::disabled();
+        guard
+    }};
+}
+
+/// Keeps a span entered until it is dropped
+#[must_use]
+pub struct SpanGuard {
+    #[cfg(feature = "telemetry")]
+    _span: tracing::span::EnteredSpan,
+}
+
+impl SpanGuard {
+    #[cfg(feature = "telemetry")]
+    pub fn new(span: tracing::Span) -> Self {
+        Self {
+            _span: span.entered(),
+        }
+    }
+
+    #[cfg(not(feature = "telemetry"))]
+    pub fn disabled() -> Self {
+        Self {}
+    }
+}
+
+/// Records the time a database connection was waited for in the current span
+pub fn record_pool_wait(wait: std::time::Duration) {
+    #[cfg(feature = "telemetry")]
+    tracing::info!(
+        wait_ms = wait.as_millis() as u64,
+        "Checked out database connection"
+    );
+    #[cfg(not(feature = "telemetry"))]
+    let _ = wait;
+}
+
+#[cfg(feature = "telemetry")]
+mod exporter {
+    use std::collections::HashMap;
+
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{DEFAULT_SERVICE_NAME, ENV_OTLP_ENDPOINT, ENV_SERVICE_NAME, TRACEPARENT_HEADER};
+    use crate::utils::config;
+
+    lazy_static::lazy_static! {
+        /// The runtime the spans are exported in. The server itself is synchronous
+        /// so the batch exporter gets its own runtime.
+        static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("otlp-exporter")
+            .enable_all()
+            .build()
+            .unwrap();
+    }
+
+    pub fn init() {
+        let endpoint = match config::var(ENV_OTLP_ENDPOINT) {
+            Ok(endpoint) => endpoint,
+            Err(_) => return,
+        };
+        let service_name =
+            config::var(ENV_SERVICE_NAME).unwrap_or(DEFAULT_SERVICE_NAME.to_string());
+        let _runtime = RUNTIME.enter();
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint.clone()),
+            )
+            .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                KeyValue::new("service.name", service_name),
+                KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+            ])))
+            .install_batch(opentelemetry::runtime::Tokio);
+        let tracer = match tracer {
+            Ok(tracer) => tracer,
+            Err(e) => {
+                log::error!("Failed to create the OTLP exporter: {}", e);
+                return;
+            }
+        };
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        match tracing::subscriber::set_global_default(subscriber) {
+            Ok(_) => log::info!("Exporting traces to {}", endpoint),
+            Err(e) => log::error!("Failed to register the trace subscriber: {}", e),
+        }
+    }
+
+    pub fn shutdown() {
+        global::shutdown_tracer_provider();
+    }
+
+    pub fn set_remote_parent(span: &tracing::Span, traceparent: Option<&str>) {
+        if let Some(traceparent) = traceparent {
+            let mut carrier = HashMap::new();
+            carrier.insert(TRACEPARENT_HEADER.to_string(), traceparent.to_string());
+            let context =
+                global::get_text_map_propagator(|propagator| propagator.extract(&carrier));
+            span.set_parent(context);
+        }
+    }
+}
+
+#[cfg(feature = "telemetry")]
+pub use exporter::set_remote_parent;
+
+/// Starts exporting the spans if an OTLP endpoint is configured
+pub fn init() {
+    #[cfg(feature = "telemetry")]
+    exporter::init();
+}
+
+/// Exports the remaining spans
+pub fn shutdown() {
+    #[cfg(feature = "telemetry")]
+    exporter::shutdown();
+}